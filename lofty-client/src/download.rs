@@ -0,0 +1,116 @@
+use std::path::Path;
+
+use http_body_util::BodyExt;
+use hyper::{
+    header::{ACCEPT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, RANGE},
+    Method, StatusCode,
+};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::{empty, status_error, Client, Direction, Error, Progress};
+
+
+impl Client {
+    // download an upload to `dest`, continuing after the bytes a previous attempt left there
+    pub async fn download(&self, uuid: Uuid, dest: impl AsRef<Path>) -> Result<u64, Error> {
+        let dest = dest.as_ref();
+        let retry = &self.inner.retry;
+        let mut attempt = 0;
+        let mut reached = 0;
+
+        loop {
+            let have = match tokio::fs::metadata(dest).await {
+                Ok(metadata) => metadata.len(),
+                Err(_) => 0,
+            };
+            if have > reached {
+                reached = have;
+                attempt = 0;
+            }
+
+            let error = match self.fetch(uuid, dest, have).await {
+                Ok(total) => return Ok(total),
+                Err(error) if error.is_retryable() => error,
+                Err(error) => return Err(error),
+            };
+
+            attempt += 1;
+            if attempt >= retry.max_attempts {
+                return Err(Error::RetriesExhausted { attempts: attempt, last: Box::new(error) });
+            }
+            tokio::time::sleep(retry.backoff(attempt - 1, None)).await;
+        }
+    }
+
+    // one request for the bytes after `have`, appended to `dest`
+    async fn fetch(&self, uuid: Uuid, dest: &Path, have: u64) -> Result<u64, Error> {
+        let mut req = self.request(Method::GET, &format!("/files/{}/content", uuid))?
+            // stored bytes as they are, a range of an encoded body could not be appended
+            .header(ACCEPT_ENCODING, "identity");
+        if have > 0 {
+            req = req.header(RANGE, format!("bytes={}-", have));
+        }
+        let resp = self.send_raw(req.body(empty()).map_err(|e| Error::Url(e.to_string()))?).await?;
+
+        let (mut written, total, truncate) = match resp.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                let (start, total) = content_range(resp.headers().get(CONTENT_RANGE))?;
+                if start != have {
+                    return Err(Error::Mismatch { expected: have, received: start });
+                }
+                (have, total, false)
+            },
+            StatusCode::OK => {
+                let total = resp
+                    .headers()
+                    .get(CONTENT_LENGTH)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse().ok())
+                    .ok_or(Error::Mismatch { expected: have, received: 0 })?;
+                (0, total, true)
+            },
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                let total = resp
+                    .headers()
+                    .get(CONTENT_RANGE)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("bytes */"))
+                    .and_then(|value| value.parse().ok());
+                if total == Some(have) {
+                    return Ok(have);
+                }
+                // more on disk than the server holds, start over
+                tokio::fs::File::create(dest).await?;
+                return Err(Error::Mismatch { expected: total.unwrap_or_default(), received: have });
+            },
+            _ => return Err(status_error(resp).await),
+        };
+
+        let mut file = OpenOptions::new().create(true).append(!truncate).write(true).truncate(truncate).open(dest).await?;
+        let mut body = resp.into_body();
+        while let Some(frame) = body.frame().await {
+            if let Ok(chunk) = frame?.into_data() {
+                file.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+                self.report(Progress { uuid, direction: Direction::Download, transferred: written, total });
+            }
+        }
+        file.flush().await?;
+
+        if written != total {
+            return Err(Error::Mismatch { expected: total, received: written });
+        }
+        Ok(total)
+    }
+}
+
+// start and complete length of `bytes start-end/total`
+fn content_range(value: Option<&hyper::header::HeaderValue>) -> Result<(u64, u64), Error> {
+    let parsed = value
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("bytes "))
+        .and_then(|value| value.split_once('/'))
+        .and_then(|(range, total)| Some((range.split_once('-')?.0.parse().ok()?, total.parse().ok()?)));
+    parsed.ok_or(Error::Mismatch { expected: 0, received: 0 })
+}
@@ -0,0 +1,82 @@
+use hyper::StatusCode;
+use serde::Deserialize;
+
+
+// RFC 7807 body the server answers failures with
+#[derive(Debug, Clone, Deserialize)]
+pub struct Problem {
+    pub code: String,
+    pub title: String,
+    #[serde(default)]
+    pub detail: Option<String>,
+    #[serde(default)]
+    pub field: Option<String>,
+    // bytes of the upload the server holds, where a retry continues from
+    #[serde(default)]
+    pub offset: Option<u64>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid url: {0}")]
+    Url(String),
+
+    #[error("connection failed: {0}")]
+    Transport(#[from] hyper_util::client::legacy::Error),
+
+    #[error("reading the response failed: {0}")]
+    Body(#[from] hyper::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("unexpected response body: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("server answered {status}{}", .problem.as_ref().map(|problem| format!(" ({})", problem.code)).unwrap_or_default())]
+    Status { status: StatusCode, problem: Option<Box<Problem>>, retry_after: Option<u64> },
+
+    #[error("upload was denied: {0}")]
+    Denied(String),
+
+    #[error("gave up after {attempts} attempts: {last}")]
+    RetriesExhausted { attempts: u32, last: Box<Error> },
+
+    #[error("the server sent {received} bytes where {expected} were expected")]
+    Mismatch { expected: u64, received: u64 },
+}
+
+impl Error {
+    // the stable code of the server's problem document, if it sent one
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            Error::Status { problem: Some(problem), .. } => Some(&problem.code),
+            Error::RetriesExhausted { last, .. } => last.code(),
+            _ => None,
+        }
+    }
+
+    // the offset the server reported holding when the request failed
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            Error::Status { problem: Some(problem), .. } => problem.offset,
+            _ => None,
+        }
+    }
+
+    // failures that may go away when the same request is sent again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Transport(_) | Error::Body(_) | Error::Io(_) | Error::Mismatch { .. } => true,
+            Error::Status { status, problem, .. } => {
+                let code = problem.as_ref().map(|problem| problem.code.as_str());
+                matches!(code, Some("checksum_mismatch" | "stored_prefix_corrupt" | "upload_incomplete" | "upload_busy"))
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+                    || (status.is_server_error() && *status != StatusCode::INSUFFICIENT_STORAGE)
+            },
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use hyper::Method;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{empty, read_json, Client, Error, UploadState};
+
+
+// An upload as `/files` lists it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub uuid: Uuid,
+    pub name: String,
+    // what the upload declared, when the server stored it under a cleaned up `name`
+    #[serde(default)]
+    pub raw_name: Option<String>,
+    pub file_size: u64,
+    pub state: UploadState,
+    // RFC 3339
+    pub created_at: String,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    // why the hooks of the server rejected the upload
+    #[serde(default)]
+    pub rejection: Option<String>,
+    #[serde(default)]
+    pub digest: Option<String>,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    // what the server sniffed from the content
+    #[serde(default)]
+    pub detected_type: Option<String>,
+    #[serde(default)]
+    pub stored_size: u64,
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilePage {
+    pub files: Vec<FileInfo>,
+    // pass back as `cursor` for the following page, absent on the last one
+    pub next_cursor: Option<String>,
+}
+
+// Filters of `/files`, unset fields match everything among the uploads of the caller
+#[derive(Debug, Clone, Default)]
+pub struct ListQuery {
+    // comma separated state kinds, e.g. `complete,broken`
+    pub state: Option<String>,
+    pub name_prefix: Option<String>,
+    pub tenant: Option<String>,
+    // comma separated `key=value` pairs
+    pub metadata: Option<String>,
+    // comma separated tags, all of them have to be present
+    pub tags: Option<String>,
+    pub sort: Option<String>,
+    pub order: Option<String>,
+    pub limit: Option<usize>,
+    // `next_cursor` of the previous page
+    pub cursor: Option<String>,
+}
+
+impl ListQuery {
+    fn to_query(&self) -> String {
+        let limit = self.limit.map(|limit| limit.to_string());
+        let fields = [
+            ("state", self.state.as_deref()),
+            ("name_prefix", self.name_prefix.as_deref()),
+            ("tenant", self.tenant.as_deref()),
+            ("metadata", self.metadata.as_deref()),
+            ("tags", self.tags.as_deref()),
+            ("sort", self.sort.as_deref()),
+            ("order", self.order.as_deref()),
+            ("limit", limit.as_deref()),
+            ("cursor", self.cursor.as_deref()),
+        ];
+
+        let pairs: Vec<_> = fields
+            .iter()
+            .filter_map(|(key, value)| value.map(|value| format!("{}={}", key, encode(value))))
+            .collect();
+        match pairs.is_empty() {
+            true => String::new(),
+            false => format!("?{}", pairs.join("&")),
+        }
+    }
+}
+
+impl Client {
+    // one page of uploads matching `query`
+    pub async fn list(&self, query: &ListQuery) -> Result<FilePage, Error> {
+        let req = self.request(Method::GET, &format!("/files{}", query.to_query()))?
+            .body(empty())
+            .map_err(|e| Error::Url(e.to_string()))?;
+        read_json(self.send(req).await?).await
+    }
+
+    // every upload matching `query`, following the cursors page by page
+    pub async fn list_all(&self, query: &ListQuery) -> Result<Vec<FileInfo>, Error> {
+        let mut query = query.clone();
+        let mut files = Vec::new();
+        loop {
+            let page = self.list(&query).await?;
+            files.extend(page.files);
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => return Ok(files),
+            }
+        }
+    }
+
+    // drop an upload and its content, refused while it is still being written
+    pub async fn delete(&self, uuid: Uuid) -> Result<(), Error> {
+        let req = self.request(Method::DELETE, &format!("/files/{}", uuid))?
+            .body(empty())
+            .map_err(|e| Error::Url(e.to_string()))?;
+        self.send(req).await?;
+        Ok(())
+    }
+}
+
+// percent encode everything outside the unreserved characters of RFC 3986
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
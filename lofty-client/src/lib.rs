@@ -0,0 +1,296 @@
+//! Client for the lofty upload server.
+//!
+//! Uploads are scheduled, sent and, whenever a request fails on the way, resumed from the offset
+//! the server reports holding. Downloads continue from the bytes already written to disk.
+//!
+//! Parallelism is across files: `upload_files` keeps several uploads in flight. The pieces of a
+//! single upload go one after another unless `parallel_pieces` is set, the server then stages the
+//! pieces that arrive ahead of the offset it holds and appends them once the gap is filled.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), lofty_client::Error> {
+//! let client = lofty_client::Client::builder("http://127.0.0.1:2053")
+//!     .chunk_size(64 * 1024 * 1024)
+//!     .on_progress(|progress| println!("{}/{}", progress.transferred, progress.total))
+//!     .build()?;
+//!
+//! let uuid = client.upload_file(&lofty_client::Source::path("report.tar")).await?;
+//! client.download(uuid, "report.copy.tar").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Empty, Full};
+use hyper::{
+    body::Incoming,
+    header::{HeaderName, HeaderValue, CONTENT_TYPE, RETRY_AFTER},
+    http::request::Builder,
+    HeaderMap, Method, Request, Response, Uri,
+};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client as HttpClient},
+    rt::TokioExecutor,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+mod download;
+mod error;
+mod files;
+mod retry;
+mod source;
+mod upload;
+
+pub use error::{Error, Problem};
+pub use files::{FileInfo, FilePage, ListQuery};
+pub use retry::RetryPolicy;
+pub use source::Source;
+
+
+// request bodies streamed out of a `Source`
+pub type Body = UnsyncBoxBody<Bytes, std::io::Error>;
+
+// error bodies longer than this are not worth reading for their problem document
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+// Reported as bytes are handed to the connection or written to disk
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub uuid: Uuid,
+    pub direction: Direction,
+    pub transferred: u64,
+    pub total: u64,
+}
+
+type ProgressFn = Arc<dyn Fn(Progress) + Send + Sync>;
+
+// State of an upload as `/status` reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UploadState {
+    UnInit,
+    Init,
+    Broken(u64),
+    Progress(u64),
+    Resume(u64),
+    Complete,
+    // every byte arrived, the post upload hooks of the server are still running
+    Processing,
+    // a post upload hook of the server turned the content down
+    Rejected,
+    // the content passed the virus scan of the server
+    Clean,
+    // the virus scan of the server flagged the content, it is never served
+    Quarantined,
+    Failed,
+}
+
+impl std::fmt::Display for UploadState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadState::UnInit => f.write_str("scheduled"),
+            UploadState::Init => f.write_str("starting"),
+            UploadState::Broken(offset) => write!(f, "interrupted at {}", offset),
+            UploadState::Progress(offset) => write!(f, "uploading, {} received", offset),
+            UploadState::Resume(offset) => write!(f, "resuming from {}", offset),
+            UploadState::Complete => f.write_str("complete"),
+            UploadState::Processing => f.write_str("processing"),
+            UploadState::Rejected => f.write_str("rejected"),
+            UploadState::Clean => f.write_str("clean"),
+            UploadState::Quarantined => f.write_str("quarantined"),
+            UploadState::Failed => f.write_str("failed"),
+        }
+    }
+}
+
+// Answer to `schedule`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Scheduled {
+    pub uuid: Uuid,
+    // `Approved`, or `Complete` when the server already held the content
+    pub status: String,
+    // the declared hash named content the server already had, nothing has to be sent
+    #[serde(default)]
+    pub skipped: bool,
+}
+
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    base: String,
+    http: HttpClient<HttpConnector, Body>,
+    retry: RetryPolicy,
+    chunk_size: Option<u64>,
+    pieces: usize,
+    headers: HeaderMap,
+    progress: Option<ProgressFn>,
+}
+
+pub struct ClientBuilder {
+    base: String,
+    retry: RetryPolicy,
+    chunk_size: Option<u64>,
+    pieces: usize,
+    headers: HeaderMap,
+    progress: Option<ProgressFn>,
+    error: Option<Error>,
+}
+
+impl ClientBuilder {
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    // send uploads in pieces of `chunk_size` bytes, each one checksummed and retried on its own,
+    // the pieces of one upload are sent in order and never overlap, unless `parallel_pieces` says otherwise
+    pub fn chunk_size(mut self, chunk_size: u64) -> Self {
+        self.chunk_size = Some(chunk_size.max(1));
+        self
+    }
+
+    // with `chunk_size` set, send up to `pieces` pieces of one upload at once after its first one
+    pub fn parallel_pieces(mut self, pieces: usize) -> Self {
+        self.pieces = pieces.max(1);
+        self
+    }
+
+    // sent with every request, e.g. for a proxy in front of the server
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        match (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+            (Ok(name), Ok(value)) => {
+                self.headers.append(name, value);
+            },
+            _ => self.error = Some(Error::Url(format!("invalid header {}", name))),
+        }
+        self
+    }
+
+    pub fn on_progress(mut self, progress: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    pub fn build(self) -> Result<Client, Error> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let base = self.base.trim_end_matches('/').to_string();
+        base.parse::<Uri>().map_err(|_| Error::Url(base.clone()))?;
+
+        let http = HttpClient::builder(TokioExecutor::new()).build(HttpConnector::new());
+        let inner = Inner { base, http, retry: self.retry, chunk_size: self.chunk_size, pieces: self.pieces, headers: self.headers, progress: self.progress };
+        Ok(Client { inner: Arc::new(inner) })
+    }
+}
+
+impl Client {
+    pub fn new(base_url: impl Into<String>) -> Result<Self, Error> {
+        Self::builder(base_url).build()
+    }
+
+    pub fn builder(base_url: impl Into<String>) -> ClientBuilder {
+        ClientBuilder {
+            base: base_url.into(),
+            retry: RetryPolicy::default(),
+            chunk_size: None,
+            pieces: 1,
+            headers: HeaderMap::new(),
+            progress: None,
+            error: None,
+        }
+    }
+
+    // register an upload of `length` bytes whose content hashes to `hash`, a lowercase hex sha256
+    pub async fn schedule(&self, hash: &str, length: u64) -> Result<Scheduled, Error> {
+        let body = serde_json::to_vec(&serde_json::json!({ "fileHash": hash, "Length": length }))?;
+        let req = self.request(Method::POST, "/schedule_upload")?
+            .header(CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)).map_err(|never| match never {}).boxed_unsync())
+            .map_err(|e| Error::Url(e.to_string()))?;
+
+        let value: serde_json::Value = read_json(self.send(req).await?).await?;
+        if value["status"] == "Denied" {
+            return Err(Error::Denied(value["reason"].as_str().unwrap_or_default().to_string()));
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    pub async fn status(&self, uuid: Uuid) -> Result<UploadState, Error> {
+        let req = self.request(Method::GET, "/status")?
+            .header("uuid", uuid.to_string())
+            .body(empty())
+            .map_err(|e| Error::Url(e.to_string()))?;
+
+        #[derive(Deserialize)]
+        struct Status {
+            status: UploadState,
+        }
+        let status: Status = read_json(self.send(req).await?).await?;
+        Ok(status.status)
+    }
+
+    fn request(&self, method: Method, path: &str) -> Result<Builder, Error> {
+        let uri = format!("{}{}", self.inner.base, path);
+        let uri = uri.parse::<Uri>().map_err(|_| Error::Url(uri))?;
+
+        let mut builder = Request::builder().method(method).uri(uri);
+        for (name, value) in &self.inner.headers {
+            builder = builder.header(name, value);
+        }
+        Ok(builder)
+    }
+
+    // the response as it came, whatever its status
+    async fn send_raw(&self, req: Request<Body>) -> Result<Response<Incoming>, Error> {
+        Ok(self.inner.http.request(req).await?)
+    }
+
+    // the response of a successful request, failures become `Error::Status`
+    async fn send(&self, req: Request<Body>) -> Result<Response<Incoming>, Error> {
+        let resp = self.send_raw(req).await?;
+        if resp.status().is_success() {
+            return Ok(resp);
+        }
+        Err(status_error(resp).await)
+    }
+
+    fn report(&self, progress: Progress) {
+        if let Some(report) = &self.inner.progress {
+            report(progress);
+        }
+    }
+}
+
+async fn status_error(resp: Response<Incoming>) -> Error {
+    let status = resp.status();
+    let retry_after = resp
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+
+    let body = http_body_util::Limited::new(resp.into_body(), MAX_ERROR_BODY).collect().await;
+    let problem = body.ok().and_then(|body| serde_json::from_slice(&body.to_bytes()).ok().map(Box::new));
+    Error::Status { status, problem, retry_after }
+}
+
+async fn read_json<T: serde::de::DeserializeOwned>(resp: Response<Incoming>) -> Result<T, Error> {
+    let body = resp.into_body().collect().await?.to_bytes();
+    Ok(serde_json::from_slice(&body)?)
+}
+
+fn empty() -> Body {
+    Empty::new().map_err(|never| match never {}).boxed_unsync()
+}
@@ -0,0 +1,44 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+
+// How often and how patiently a failed transfer is tried again
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // attempts in a row that may fail, the count starts over whenever the transfer moved forward
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // never retry, the first failure is returned as it is
+    pub fn none() -> Self {
+        Self { max_attempts: 1, ..Self::default() }
+    }
+
+    // exponential backoff before attempt `attempt + 1`, with up to a quarter of jitter on top
+    pub fn backoff(&self, attempt: u32, retry_after: Option<u64>) -> Duration {
+        let exponential = self.initial_backoff.saturating_mul(1 << attempt.min(16)).min(self.max_backoff);
+        let base = match retry_after {
+            Some(seconds) => exponential.max(Duration::from_secs(seconds)),
+            None => exponential,
+        };
+
+        let jitter = RandomState::new().build_hasher().finish() % 1024;
+        base + base / 4 * jitter as u32 / 1024
+    }
+}
@@ -0,0 +1,136 @@
+use std::{io::SeekFrom, path::{Path, PathBuf}};
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt}};
+
+
+// bytes read from a file per chunk of a request body
+const READ_CHUNK: u64 = 256 * 1024;
+
+#[derive(Debug, Clone)]
+enum Content {
+    Path(PathBuf),
+    Bytes(Bytes),
+}
+
+// Content of an upload, read again from any offset whenever a request has to be retried
+#[derive(Debug, Clone)]
+pub struct Source {
+    content: Content,
+    name: Option<String>,
+    content_type: Option<String>,
+}
+
+impl Source {
+    pub fn path(path: impl AsRef<Path>) -> Self {
+        Self { content: Content::Path(path.as_ref().to_path_buf()), name: None, content_type: None }
+    }
+
+    pub fn bytes(bytes: impl Into<Bytes>) -> Self {
+        Self { content: Content::Bytes(bytes.into()), name: None, content_type: None }
+    }
+
+    // sent as `FileName`, the file name of a path by default
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    // drives the server's compression policy
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    pub fn name(&self) -> String {
+        let file_name = match &self.content {
+            Content::Path(path) => path.file_name().map(|name| name.to_string_lossy().into_owned()),
+            Content::Bytes(_) => None,
+        };
+        self.name.clone().or(file_name).unwrap_or_else(|| "upload".to_string())
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+
+    pub async fn len(&self) -> std::io::Result<u64> {
+        match &self.content {
+            Content::Path(path) => Ok(tokio::fs::metadata(path).await?.len()),
+            Content::Bytes(bytes) => Ok(bytes.len() as u64),
+        }
+    }
+
+    // lowercase hex sha256 of the whole content, what `schedule` declares
+    pub async fn sha256(&self) -> std::io::Result<String> {
+        let len = self.len().await?;
+        Ok(hex::encode(self.digest(0, len).await?))
+    }
+
+    // sha256 of the bytes `start..end`
+    pub(crate) async fn digest(&self, start: u64, end: u64) -> std::io::Result<Vec<u8>> {
+        let mut hasher = Sha256::new();
+        let mut chunks = Box::pin(self.read(start, end));
+        while let Some(chunk) = chunks.next().await {
+            hasher.update(chunk?);
+        }
+        Ok(hasher.finalize().to_vec())
+    }
+
+    // the bytes `start..end`, chunk by chunk
+    pub(crate) fn read(&self, start: u64, end: u64) -> impl Stream<Item = std::io::Result<Bytes>> + Send + 'static {
+        let content = self.content.clone();
+
+        futures::stream::try_unfold((None::<File>, start), move |(file, offset)| {
+            let content = content.clone();
+            async move {
+                if offset >= end {
+                    return Ok(None);
+                }
+                let path = match content {
+                    Content::Bytes(bytes) => return Ok(Some((bytes.slice(offset as usize..end as usize), (None, end)))),
+                    Content::Path(path) => path,
+                };
+
+                let mut file = match file {
+                    Some(file) => file,
+                    None => {
+                        let mut file = File::open(&path).await?;
+                        file.seek(SeekFrom::Start(offset)).await?;
+                        file
+                    },
+                };
+                let mut chunk = vec![0u8; (end - offset).min(READ_CHUNK) as usize];
+                file.read_exact(&mut chunk).await?;
+                let read = chunk.len() as u64;
+                Ok(Some((Bytes::from(chunk), (Some(file), offset + read))))
+            }
+        })
+    }
+}
+
+impl From<PathBuf> for Source {
+    fn from(path: PathBuf) -> Self {
+        Self::path(path)
+    }
+}
+
+impl From<&Path> for Source {
+    fn from(path: &Path) -> Self {
+        Self::path(path)
+    }
+}
+
+impl From<Bytes> for Source {
+    fn from(bytes: Bytes) -> Self {
+        Self::bytes(bytes)
+    }
+}
+
+impl From<Vec<u8>> for Source {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::bytes(bytes)
+    }
+}
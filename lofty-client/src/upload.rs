@@ -0,0 +1,194 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::{StreamExt, TryStreamExt};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::{body::Frame, header::{CONTENT_LENGTH, CONTENT_TYPE}, http::request::Builder, Method};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{read_json, Body, Client, Direction, Error, Progress, Source, UploadState};
+
+
+// Answer to an upload request, a piece comes back with the offset the server holds
+#[derive(Debug, Deserialize)]
+struct Acknowledged {
+    offset: Option<u64>,
+}
+
+
+impl Client {
+    // hash `source`, schedule it and send it unless the server already holds the content
+    pub async fn upload_file(&self, source: &Source) -> Result<Uuid, Error> {
+        let length = source.len().await?;
+        let hash = source.sha256().await?;
+
+        let scheduled = self.schedule(&hash, length).await?;
+        if !scheduled.skipped {
+            self.upload(scheduled.uuid, source).await?;
+        }
+        Ok(scheduled.uuid)
+    }
+
+    // upload up to `parallelism` sources at once, the results come back in the order of `sources`
+    // the pieces of each source go side by side as well when the client was built with `parallel_pieces`
+    pub async fn upload_files(&self, sources: Vec<Source>, parallelism: usize) -> Vec<Result<Uuid, Error>> {
+        futures::stream::iter(sources)
+            .map(|source| async move { self.upload_file(&source).await })
+            .buffered(parallelism.max(1))
+            .collect()
+            .await
+    }
+
+    // send a scheduled upload from its first byte
+    pub async fn upload(&self, uuid: Uuid, source: &Source) -> Result<(), Error> {
+        self.transfer(uuid, source, Some(0)).await
+    }
+
+    // continue an upload from wherever the server holds it, e.g. after the process restarted
+    pub async fn resume(&self, uuid: Uuid, source: &Source) -> Result<(), Error> {
+        self.transfer(uuid, source, None).await
+    }
+
+    // send `source` from `offset`, asking the server where to continue whenever it is unknown
+    async fn transfer(&self, uuid: Uuid, source: &Source, mut offset: Option<u64>) -> Result<(), Error> {
+        let total = source.len().await?;
+        let retry = &self.inner.retry;
+        let mut attempt = 0;
+        let mut reached = 0;
+        let mut fanned = self.inner.pieces < 2 || self.inner.chunk_size.is_none();
+
+        loop {
+            let result = match offset {
+                // once the first piece named the upload the rest may go at once, whatever is left after that goes in order
+                Some(start) if start > 0 && !fanned => {
+                    fanned = true;
+                    self.send_pieces(uuid, source, start, total).await
+                },
+                Some(start) => self.send_piece(uuid, source, start, total).await,
+                None => self.held(uuid, total).await,
+            };
+
+            let error = match result {
+                Ok(held) if held >= total => return Ok(()),
+                Ok(held) => {
+                    offset = Some(held);
+                    if held > reached {
+                        reached = held;
+                        attempt = 0;
+                    }
+                    continue;
+                },
+                Err(error) if error.is_retryable() => error,
+                Err(error) => return Err(error),
+            };
+
+            attempt += 1;
+            if attempt >= retry.max_attempts {
+                return Err(Error::RetriesExhausted { attempts: attempt, last: Box::new(error) });
+            }
+            let retry_after = match &error {
+                Error::Status { retry_after, .. } => *retry_after,
+                _ => None,
+            };
+            tokio::time::sleep(retry.backoff(attempt - 1, retry_after)).await;
+            offset = error.offset();
+        }
+    }
+
+    // where the server holds the upload, waiting out a request it is still writing
+    async fn held(&self, uuid: Uuid, total: u64) -> Result<u64, Error> {
+        let retry = &self.inner.retry;
+        for attempt in 0..retry.max_attempts {
+            match self.status(uuid).await? {
+                UploadState::Complete | UploadState::Processing | UploadState::Rejected | UploadState::Clean | UploadState::Quarantined => return Ok(total),
+                UploadState::Broken(offset) => return Ok(offset),
+                UploadState::UnInit | UploadState::Init | UploadState::Failed => return Ok(0),
+                UploadState::Progress(_) | UploadState::Resume(_) => tokio::time::sleep(retry.backoff(attempt, None)).await,
+            }
+        }
+        Err(Error::Status { status: hyper::StatusCode::CONFLICT, problem: None, retry_after: None })
+    }
+
+    // send the piece starting at `start`, answering the offset the server acknowledged
+    async fn send_piece(&self, uuid: Uuid, source: &Source, start: u64, total: u64) -> Result<u64, Error> {
+        let end = match self.inner.chunk_size {
+            Some(chunk_size) => start.saturating_add(chunk_size).min(total),
+            None => total,
+        };
+
+        let mut req = match start {
+            0 => name_headers(self.request(Method::GET, "/upload_file")?, &source.name()),
+            _ => self.request(Method::GET, "/resume_upload")?.header("Content-Pointer", start.to_string()),
+        };
+        req = req.header("uuid", uuid.to_string()).header(CONTENT_LENGTH, (end - start).to_string());
+        if let Some(content_type) = source.content_type() {
+            req = req.header(CONTENT_TYPE, content_type);
+        }
+        if self.inner.chunk_size.is_some() {
+            let digest = source.digest(start, end).await?;
+            req = req.header("Upload-Checksum", format!("sha256 {}", STANDARD.encode(digest)));
+        }
+        // without the marker a body ending before the upload does is refused as incomplete
+        if end < total {
+            req = req.header("Upload-Complete", "?0");
+        }
+        let req = req.body(self.body(uuid, source, start, end, total)).map_err(|e| Error::Url(e.to_string()))?;
+
+        let acknowledged: Acknowledged = read_json(self.send(req).await?).await?;
+        Ok(acknowledged.offset.unwrap_or(end))
+    }
+
+    // send every piece from `start`, `pieces` of them at a time, answering the furthest offset the server acknowledged
+    async fn send_pieces(&self, uuid: Uuid, source: &Source, start: u64, total: u64) -> Result<u64, Error> {
+        let chunk_size = self.inner.chunk_size.unwrap_or(total).max(1);
+        futures::stream::iter((start..total).step_by(chunk_size as usize))
+            .map(|piece| self.stage_piece(uuid, source, piece, piece.saturating_add(chunk_size).min(total), total))
+            .buffer_unordered(self.inner.pieces)
+            .try_fold(start, |held, acknowledged| async move { Ok(held.max(acknowledged)) })
+            .await
+    }
+
+    // the server stages a piece past the offset it holds and writes it once the gap before it is filled
+    async fn stage_piece(&self, uuid: Uuid, source: &Source, start: u64, end: u64, total: u64) -> Result<u64, Error> {
+        let digest = source.digest(start, end).await?;
+        let req = self.request(Method::PUT, "/upload_piece")?
+            .header("uuid", uuid.to_string())
+            .header("Content-Pointer", start.to_string())
+            .header(CONTENT_LENGTH, (end - start).to_string())
+            .header("Upload-Checksum", format!("sha256 {}", STANDARD.encode(digest)))
+            .body(self.body(uuid, source, start, end, total))
+            .map_err(|e| Error::Url(e.to_string()))?;
+
+        let acknowledged: Acknowledged = read_json(self.send(req).await?).await?;
+        Ok(acknowledged.offset.unwrap_or(total))
+    }
+
+    fn body(&self, uuid: Uuid, source: &Source, start: u64, end: u64, total: u64) -> Body {
+        let client = self.clone();
+        let mut transferred = start;
+
+        let frames = source.read(start, end).map_ok(move |chunk| {
+            transferred += chunk.len() as u64;
+            client.report(Progress { uuid, direction: Direction::Upload, transferred, total });
+            Frame::data(chunk)
+        });
+        StreamBody::new(frames).boxed_unsync()
+    }
+}
+
+// a name outside of ASCII goes RFC 5987 encoded in `FileName*`, `FileName` keeps a plain stand-in
+// surrounding spaces would be stripped from a plain header value, they take the encoded route too
+fn name_headers(req: Builder, name: &str) -> Builder {
+    if name.bytes().all(|b| (b' '..=b'~').contains(&b)) && name.trim() == name {
+        return req.header("FileName", name);
+    }
+
+    let plain: String = name.chars().map(|c| if (' '..='~').contains(&c) { c } else { '_' }).collect();
+    let mut encoded = String::from("UTF-8''");
+    for byte in name.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    req.header("FileName", plain).header("FileName*", encoded)
+}
@@ -0,0 +1,467 @@
+use std::{net::{IpAddr, SocketAddr}, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{ConnectInfo, FromRequestParts, Query, Request},
+    http::{request::Parts, Response},
+    middleware::Next,
+    Extension,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{fs::{File, OpenOptions}, io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, sync::mpsc};
+use uuid::Uuid;
+
+use crate::{authorization::Principal, errors::{ErrorCode, ErrorStates}, telemetry, FragmentError};
+
+
+const CURRENT_LOG: &str = "audit.log";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    // `audit` below the data directory by default
+    pub dir: Option<PathBuf>,
+    // rotate the current log once it grows past this many bytes
+    pub max_file_size: u64,
+    // rotated logs kept on disk, the oldest is removed first
+    pub max_files: usize,
+    // link every record to its predecessor with a sha256 digest
+    pub hash_chain: bool,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: None,
+            max_file_size: 64 * 1024 * 1024,
+            max_files: 30,
+            hash_chain: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Schedule,
+    Upload,
+    Resume,
+    Piece,
+    Status,
+    Update,
+    Download,
+    Manifest,
+    Delete,
+    Bundle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub action: AuditAction,
+    pub principal: String,
+    pub client_ip: Option<IpAddr>,
+    pub request_id: Option<String>,
+    pub uuid: Option<Uuid>,
+    pub file_name: Option<String>,
+    pub bytes: u64,
+    pub outcome: AuditOutcome,
+    pub error_code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl AuditRecord {
+    // digest over the record with its own hash blanked, chained onto the previous one
+    fn digest(&self) -> String {
+        let mut unsealed = self.clone();
+        unsealed.hash = None;
+
+        let mut hasher = Sha256::new();
+        hasher.update(self.prev_hash.as_deref().unwrap_or("").as_bytes());
+        hasher.update(serde_json::to_vec(&unsealed).unwrap_or_default());
+        format!("{:x}", hasher.finalize())
+    }
+}
+
+// records go through a channel to the task writing the log
+pub type AuditHandle = Arc<AuditLog>;
+
+#[derive(Debug)]
+pub struct AuditLog {
+    config: AuditConfig,
+    dir: PathBuf,
+    sender: Option<mpsc::UnboundedSender<AuditRecord>>,
+}
+
+impl AuditLog {
+    pub async fn open(config: AuditConfig, data_dir: &Path) -> Result<Self, FragmentError> {
+        let dir = config.dir.clone().unwrap_or_else(|| data_dir.join("audit"));
+        if !config.enabled {
+            return Ok(Self { config, dir, sender: None });
+        }
+
+        tokio::fs::create_dir_all(&dir).await?;
+        let writer = AuditWriter::open(config.clone(), dir.clone()).await?;
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(writer.run(receiver));
+
+        Ok(Self { config, dir, sender: Some(sender) })
+    }
+
+    pub fn record(&self, record: AuditRecord) {
+        if let Some(sender) = &self.sender {
+            if sender.send(record).is_err() {
+                tracing::error!("audit writer stopped, record dropped");
+            }
+        }
+    }
+
+    // every log file, oldest first
+    async fn log_files(&self) -> Result<Vec<PathBuf>, FragmentError> {
+        let mut rotated = rotated_logs(&self.dir).await?;
+        rotated.push(self.dir.join(CURRENT_LOG));
+        Ok(rotated)
+    }
+
+    pub async fn query(&self, filter: &AuditQuery) -> Result<Vec<AuditRecord>, FragmentError> {
+        let mut found = vec![];
+        let limit = filter.limit.unwrap_or(1000);
+
+        for path in self.log_files().await? {
+            let Ok(file) = File::open(&path).await else { continue };
+            let mut lines = BufReader::new(file).lines();
+
+            while let Some(line) = lines.next_line().await? {
+                let Ok(record) = serde_json::from_str::<AuditRecord>(&line) else { continue };
+                if filter.matches(&record) {
+                    found.push(record);
+                }
+            }
+        }
+
+        // keep the most recent matches when the limit cuts in
+        let skip = found.len().saturating_sub(limit);
+        Ok(found.split_off(skip))
+    }
+
+    // walk the chain and report the first record whose digest does not line up
+    pub async fn verify(&self) -> Result<serde_json::Value, FragmentError> {
+        if !self.config.hash_chain {
+            return Ok(serde_json::json!({ "intact": null, "reason": "hash chain disabled" }));
+        }
+
+        let mut previous: Option<String> = None;
+        let mut checked = 0_u64;
+
+        for path in self.log_files().await? {
+            let Ok(file) = File::open(&path).await else { continue };
+            let mut lines = BufReader::new(file).lines();
+            let mut line_no = 0_u64;
+
+            while let Some(line) = lines.next_line().await? {
+                line_no += 1;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let intact = match serde_json::from_str::<AuditRecord>(&line) {
+                    Ok(record) => {
+                        let linked = previous.is_none() || record.prev_hash == previous;
+                        let sealed = record.hash.as_deref() == Some(record.digest().as_str());
+                        previous = record.hash.clone();
+                        linked && sealed
+                    },
+                    Err(_) => false,
+                };
+
+                if !intact {
+                    return Ok(serde_json::json!({
+                        "intact": false,
+                        "checked": checked,
+                        "file": path.display().to_string(),
+                        "line": line_no,
+                    }));
+                }
+                checked += 1;
+            }
+        }
+
+        Ok(serde_json::json!({ "intact": true, "checked": checked }))
+    }
+}
+
+async fn rotated_logs(dir: &Path) -> Result<Vec<PathBuf>, FragmentError> {
+    let mut rotated = vec![];
+    let mut entries = tokio::fs::read_dir(dir).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with("audit-") && name.ends_with(".log") {
+            rotated.push(entry.path());
+        }
+    }
+
+    // rotated names embed a zero padded timestamp, lexical order is chronological
+    rotated.sort();
+    Ok(rotated)
+}
+
+struct AuditWriter {
+    config: AuditConfig,
+    dir: PathBuf,
+    file: File,
+    size: u64,
+    last_hash: Option<String>,
+}
+
+impl AuditWriter {
+    async fn open(config: AuditConfig, dir: PathBuf) -> Result<Self, FragmentError> {
+        let path = dir.join(CURRENT_LOG);
+        let file = OpenOptions::new().create(true).append(true).open(&path).await?;
+        let size = file.metadata().await?.len();
+
+        // pick the chain up where the previous run left it
+        let mut last_hash = last_hash_in(&path).await;
+        if last_hash.is_none() {
+            if let Some(newest) = rotated_logs(&dir).await?.pop() {
+                last_hash = last_hash_in(&newest).await;
+            }
+        }
+
+        Ok(Self { config, dir, file, size, last_hash })
+    }
+
+    async fn run(mut self, mut receiver: mpsc::UnboundedReceiver<AuditRecord>) {
+        while let Some(record) = receiver.recv().await {
+            if let Err(e) = self.append(record).await {
+                tracing::error!(variant = e.state().variant(), "unable to append audit record: {}", e.state());
+                continue;
+            }
+
+            // drain whatever queued up meanwhile before paying for the sync
+            while let Ok(record) = receiver.try_recv() {
+                if let Err(e) = self.append(record).await {
+                    tracing::error!(variant = e.state().variant(), "unable to append audit record: {}", e.state());
+                }
+            }
+
+            if let Err(e) = self.file.sync_data().await {
+                tracing::error!("unable to sync the audit log: {}", e);
+            }
+        }
+    }
+
+    async fn append(&mut self, mut record: AuditRecord) -> Result<(), FragmentError> {
+        if self.config.hash_chain {
+            record.prev_hash = self.last_hash.clone();
+            record.hash = Some(record.digest());
+            self.last_hash = record.hash.clone();
+        }
+
+        let mut line = serde_json::to_vec(&record).map_err(|_| ErrorStates::UndeclaredError)?;
+        line.push(b'\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_file_size {
+            self.rotate().await?;
+        }
+
+        self.file.write_all(&line).await?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    async fn rotate(&mut self) -> Result<(), FragmentError> {
+        self.file.sync_all().await?;
+
+        let current = self.dir.join(CURRENT_LOG);
+        let rotated = self.dir.join(format!("audit-{:020}.log", Utc::now().timestamp_micros()));
+        tokio::fs::rename(&current, &rotated).await?;
+
+        self.file = OpenOptions::new().create(true).append(true).open(&current).await?;
+        self.size = 0;
+
+        let rotated = rotated_logs(&self.dir).await?;
+        let excess = rotated.len().saturating_sub(self.config.max_files);
+        for path in rotated.into_iter().take(excess) {
+            tokio::fs::remove_file(path).await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn last_hash_in(path: &Path) -> Option<String> {
+    let contents = tokio::fs::read_to_string(path).await.ok()?;
+    let line = contents.lines().rev().find(|line| !line.trim().is_empty())?;
+    serde_json::from_str::<AuditRecord>(line).ok()?.hash
+}
+
+#[derive(Debug, Default)]
+struct AuditFields {
+    action: Option<AuditAction>,
+    uuid: Option<Uuid>,
+    file_name: Option<String>,
+    bytes: u64,
+}
+
+// Request scoped slot the handlers fill in, the audit middleware turns it into a record
+#[derive(Debug, Clone, Default)]
+pub struct AuditScope(Arc<Mutex<AuditFields>>);
+
+impl AuditScope {
+    pub fn action(&self, action: AuditAction) {
+        self.0.lock().unwrap().action = Some(action);
+    }
+
+    pub fn file(&self, uuid: Uuid, file_name: Option<&str>) {
+        let mut fields = self.0.lock().unwrap();
+        fields.uuid = Some(uuid);
+        if let Some(name) = file_name {
+            fields.file_name = Some(name.to_string());
+        }
+    }
+
+    pub fn bytes(&self, bytes: usize) {
+        self.0.lock().unwrap().bytes = bytes as u64;
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuditScope
+    where S: Send + Sync
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // handlers served without the audit layer get a detached scope
+        Ok(parts.extensions.get::<AuditScope>().cloned().unwrap_or_default())
+    }
+}
+
+// emits the record when dropped, so an upload abandoned mid stream is still accounted for
+struct PendingRecord {
+    log: AuditHandle,
+    scope: AuditScope,
+    principal: Principal,
+    client_ip: Option<IpAddr>,
+    request_id: Option<String>,
+    outcome: AuditOutcome,
+    error_code: Option<&'static str>,
+}
+
+impl Drop for PendingRecord {
+    fn drop(&mut self) {
+        let fields = std::mem::take(&mut *self.scope.0.lock().unwrap());
+        let Some(action) = fields.action else { return };
+
+        self.log.record(AuditRecord {
+            timestamp: Utc::now(),
+            action,
+            principal: self.principal.to_string(),
+            client_ip: self.client_ip,
+            request_id: self.request_id.take(),
+            uuid: fields.uuid,
+            file_name: fields.file_name,
+            bytes: fields.bytes,
+            outcome: self.outcome,
+            error_code: self.error_code.map(str::to_string),
+            prev_hash: None,
+            hash: None,
+        });
+    }
+}
+
+pub async fn audit_requests(
+    Extension(log): Extension<AuditHandle>,
+    principal: Principal,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    mut req: Request,
+    next: Next,
+) -> Response<Body> {
+
+    let scope = AuditScope::default();
+    req.extensions_mut().insert(scope.clone());
+
+    let mut pending = PendingRecord {
+        log,
+        scope,
+        principal,
+        client_ip: connect_info.map(|ConnectInfo(addr)| addr.ip()),
+        request_id: telemetry::current_request_id(),
+        outcome: AuditOutcome::Cancelled,
+        error_code: None,
+    };
+
+    let resp = next.run(req).await;
+
+    pending.outcome = if resp.status().is_success() { AuditOutcome::Success } else { AuditOutcome::Failure };
+    pending.error_code = resp.extensions().get::<ErrorCode>().map(|ErrorCode(code)| *code);
+
+    resp
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    pub uuid: Option<Uuid>,
+    pub principal: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, record: &AuditRecord) -> bool {
+        self.uuid.is_none_or(|uuid| record.uuid == Some(uuid))
+            && self.principal.as_ref().is_none_or(|principal| &record.principal == principal)
+            && self.since.is_none_or(|since| record.timestamp >= since)
+            && self.until.is_none_or(|until| record.timestamp <= until)
+    }
+}
+
+pub async fn query_audit_log(
+    Extension(log): Extension<AuditHandle>,
+    Query(filter): Query<AuditQuery>,
+) -> Result<Response<Body>, FragmentError> {
+
+    let records = log.query(&filter).await?;
+    let body = serde_json::to_vec(&records).map_err(|_| ErrorStates::UndeclaredError)?;
+
+    let resp = Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))?;
+
+    Ok(resp)
+}
+
+pub async fn verify_audit_log(
+    Extension(log): Extension<AuditHandle>,
+) -> Result<Response<Body>, FragmentError> {
+
+    let report = log.verify().await?;
+    let body = serde_json::to_vec(&report).map_err(|_| ErrorStates::UndeclaredError)?;
+
+    let resp = Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))?;
+
+    Ok(resp)
+}
@@ -1,8 +1,8 @@
-use std::borrow::Cow;
+use std::{borrow::Cow, convert::Infallible, net::{IpAddr, SocketAddr}, sync::Arc};
 
-use axum::http::{HeaderMap, HeaderValue, header::*};
+use axum::{async_trait, extract::{ConnectInfo, FromRequestParts, Request}, http::{HeaderMap, HeaderValue, header::*, request::Parts}, middleware::Next, response::Response, Extension};
 
-use crate::errors::{FragmentError, HeaderErrors};
+use crate::errors::{ErrorStates, FragmentError, HeaderErrors};
 
 
 pub async fn ensure_headers(mut headers: &HeaderMap) -> Result<(), FragmentError> { 
@@ -38,4 +38,105 @@ pub async fn extract_header_fields(headers: &HeaderMap, header_field: HeaderName
 
         return Err(HeaderErrors::HeaderFieldMissing(err_str).into());
     }
-}
\ No newline at end of file
+}
+
+// identity on whose behalf a request is served, used to key per-client limits
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Principal(String);
+
+impl Principal { 
+    pub fn new(name: impl ToString) -> Self { 
+        Self(name.to_string())
+    }
+
+    pub fn anonymous(addr: IpAddr) -> Self { 
+        Self(format!("anonymous@{}", addr))
+    }
+
+    #[inline(always)]
+    pub fn as_str(&self) -> &str { 
+        &self.0
+    }
+
+    // uploads belong to the tenant that scheduled them, those recorded without one to everybody
+    pub fn owns(&self, tenant: Option<&str>) -> bool { 
+        tenant.is_none_or(|tenant| tenant == self.as_str())
+    }
+}
+
+impl std::fmt::Display for Principal { 
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Principal
+    where S: Send + Sync
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // an authentication layer upstream may already have resolved the caller
+        if let Some(principal) = parts.extensions.get::<Principal>() { 
+            return Ok(principal.clone());
+        }
+
+        let principal = match parts.extensions.get::<ConnectInfo<SocketAddr>>() { 
+            Some(ConnectInfo(addr)) => Principal::anonymous(addr.ip()),
+            None => Principal::new("anonymous"),
+        };
+
+        Ok(principal)
+    }
+}
+
+/*
+    Resolves the caller of a request for an embedding application, e.g. from a session cookie
+    or a bearer token. Answering None leaves the caller to the connection, a client certificate
+    or the peer address, an error rejects the request before it reaches a handler.
+ */
+#[async_trait]
+pub trait AuthProvider: Send + Sync + 'static {
+    async fn authenticate(&self, parts: &Parts) -> Result<Option<Principal>, ErrorStates>;
+}
+
+pub type AuthHandle = Arc<Authenticator>;
+
+#[derive(Default)]
+pub struct Authenticator {
+    provider: Option<Box<dyn AuthProvider>>,
+}
+
+impl Authenticator {
+    pub fn new(provider: Option<Box<dyn AuthProvider>>) -> Self {
+        Self { provider }
+    }
+}
+
+impl std::fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Authenticator").field("provider", &self.provider.is_some()).finish()
+    }
+}
+
+// middleware resolving the principal through the configured provider, the extractor picks it up downstream
+pub async fn authenticate(
+    Extension(auth): Extension<AuthHandle>,
+    req: Request,
+    next: Next,
+) -> Result<Response, FragmentError> {
+
+    let Some(provider) = &auth.provider else {
+        return Ok(next.run(req).await);
+    };
+
+    let (mut parts, body) = req.into_parts();
+    if let Some(principal) = provider.authenticate(&parts).await? {
+        // the request span was opened with the caller of the connection
+        tracing::Span::current().record("principal", tracing::field::display(&principal));
+        parts.extensions.insert(principal);
+    }
+
+    Ok(next.run(Request::from_parts(parts, body)).await)
+}
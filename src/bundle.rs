@@ -0,0 +1,625 @@
+use std::{borrow::Cow, collections::HashSet, io, sync::Arc};
+
+use axum::{
+    body::Body,
+    http::{
+        header::{ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_RANGE, RANGE},
+        HeaderMap, Response,
+    },
+    Extension,
+};
+use bytes::{BufMut, Bytes};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use futures::StreamExt;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::{self, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditAction, AuditScope},
+    authorization::Principal,
+    download::{self, ContentAccess},
+    encryption::{Encryption, EncryptionHandle},
+    errors::{BodyErrors, ErrorStates},
+    filename::{self, FilenamePolicy, FilenamePolicyHandle},
+    index::{FileQuery, IndexedFile},
+    merkle,
+    FragmentError,
+};
+
+
+// sizes and offsets from here on need the zip64 fields
+const ZIP64_LIMIT: u64 = 0xFFFF_FFFF;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP64_END: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const END: u32 = 0x0605_4b50;
+const ZIP64_EXTRA: u16 = 0x0001;
+
+// crc and sizes follow the content in a data descriptor, names are UTF-8
+const ZIP_FLAGS: u16 = 1 << 3 | 1 << 11;
+// entries come from a unix host, which makes readers honour the file mode
+const MADE_BY_UNIX: u16 = 3 << 8;
+// a regular file with rw-r--r--
+const FILE_MODE: u32 = 0o100644;
+
+const BLOCK: u64 = 512;
+// largest size the octal field of a ustar header holds
+const USTAR_MAX_SIZE: u64 = 0o777_7777_7777;
+
+static ZEROS: [u8; 2 * BLOCK as usize] = [0; 2 * BLOCK as usize];
+
+/*
+    [bundles]
+    max_files = 10000
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BundleConfig {
+    // uploads a single archive may hold
+    pub max_files: usize,
+}
+
+impl Default for BundleConfig {
+    fn default() -> Self {
+        Self { max_files: 10_000 }
+    }
+}
+
+pub type BundleHandle = Arc<BundleConfig>;
+
+/*
+    POST /bundles
+        {"uuids": ["..."], "filter": {"tags": "nightly"}, "format": "zip" | "tar", "name": "nightly"}
+    `filter` takes the parameters of the `/files` listing, pagination aside, and like it only
+    picks uploads of the caller, among them the ones that can be served. Entries follow `uuids`
+    and then the listing order of `filter`, every upload appears once.
+ */
+#[derive(Debug, Deserialize)]
+pub struct BundleRequest {
+    #[serde(default)]
+    pub uuids: Vec<Uuid>,
+    pub filter: Option<FileQuery>,
+    #[serde(default)]
+    pub format: BundleFormat,
+    // offered as the name of the archive, without its extension
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BundleFormat {
+    // store only zip64, readers find the sizes in the central directory
+    #[default]
+    Zip,
+    // ustar with pax records for long or non ASCII names and sizes past 8 GiB
+    Tar,
+}
+
+impl BundleFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            BundleFormat::Zip => "zip",
+            BundleFormat::Tar => "tar",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            BundleFormat::Zip => "application/zip",
+            BundleFormat::Tar => "application/x-tar",
+        }
+    }
+}
+
+// An upload as it is laid out in the archive
+#[derive(Debug)]
+struct Entry {
+    file: IndexedFile,
+    // path inside the archive
+    name: String,
+    // where the local header of a zip entry starts
+    offset: u64,
+    zip64: bool,
+}
+
+impl Entry {
+    fn size(&self) -> u64 {
+        self.file.file_size as u64
+    }
+}
+
+// A stretch of the archive, the ones carrying a crc are only written once it is known
+#[derive(Debug)]
+enum Segment {
+    // headers and padding
+    Bytes(Bytes),
+    // the logical content of an entry
+    Content(usize),
+    // the zip data descriptor of an entry
+    Descriptor(usize),
+    // the zip central directory with the end records
+    Directory,
+}
+
+/*
+    Every byte of an archive is placed before the first one is sent, so the length is known up
+    front and any range can be served by walking the segments it touches. Nothing is staged,
+    the content of each entry is read from storage as the stream reaches it.
+ */
+#[derive(Debug)]
+struct Layout {
+    format: BundleFormat,
+    entries: Vec<Entry>,
+    // start and end in the archive
+    segments: Vec<(u64, u64, Segment)>,
+    total: u64,
+}
+
+impl Layout {
+    fn new(format: BundleFormat, entries: Vec<Entry>) -> Self {
+        let mut layout = Self { format, entries, segments: Vec::new(), total: 0 };
+        match format {
+            BundleFormat::Zip => layout.lay_zip(),
+            BundleFormat::Tar => layout.lay_tar(),
+        }
+        layout
+    }
+
+    fn push(&mut self, segment: Segment, len: u64) {
+        if len > 0 {
+            self.segments.push((self.total, self.total + len, segment));
+            self.total += len;
+        }
+    }
+
+    fn push_bytes(&mut self, bytes: Bytes) {
+        let len = bytes.len() as u64;
+        self.push(Segment::Bytes(bytes), len);
+    }
+
+    fn lay_zip(&mut self) {
+        for i in 0..self.entries.len() {
+            let offset = self.total;
+            let entry = &mut self.entries[i];
+            entry.offset = offset;
+            entry.zip64 = entry.size() >= ZIP64_LIMIT || offset >= ZIP64_LIMIT;
+
+            let header = zip_local_header(entry);
+            let (size, descriptor) = (entry.size(), zip_descriptor(entry, 0).len() as u64);
+            self.push_bytes(header);
+            self.push(Segment::Content(i), size);
+            self.push(Segment::Descriptor(i), descriptor);
+        }
+
+        // the directory only differs in its crcs once written
+        let directory = zip_directory(&self.entries, &vec![0; self.entries.len()], self.total).len() as u64;
+        self.push(Segment::Directory, directory);
+    }
+
+    fn lay_tar(&mut self) {
+        for i in 0..self.entries.len() {
+            let size = self.entries[i].size();
+            let padding = (BLOCK - size % BLOCK) % BLOCK;
+            self.push_bytes(tar_header(&self.entries[i]));
+            self.push(Segment::Content(i), size);
+            self.push_bytes(Bytes::from_static(&ZEROS[..padding as usize]));
+        }
+        self.push_bytes(Bytes::from_static(&ZEROS));
+    }
+
+    // strong validator of the archive, it changes with any entry
+    fn etag(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.format.extension());
+        for entry in &self.entries {
+            hasher.update(entry.file.uuid.as_bytes());
+            hasher.update(entry.name.as_bytes());
+            hasher.update(entry.size().to_le_bytes());
+            hasher.update(entry.file.hash.as_bytes());
+            hasher.update(entry.file.created_at.timestamp_micros().to_le_bytes());
+        }
+        format!("\"{}\"", hex::encode(&hasher.finalize()[..16]))
+    }
+
+    // bytes `start..end` of the archive, produced by a task that stops once the client is gone
+    fn stream(self: Arc<Self>, encryption: EncryptionHandle, start: u64, end: u64) -> Body {
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            if let Err(e) = self.produce(&encryption, start, end, &tx).await {
+                if !tx.is_closed() {
+                    tracing::warn!(start, end, "bundle stream aborted: {}", e);
+                    let _ = tx.send(Err(e)).await;
+                }
+            }
+        });
+        Body::from_stream(ReceiverStream::new(rx))
+    }
+
+    async fn produce(&self, encryption: &Encryption, start: u64, end: u64, tx: &Sender<io::Result<Bytes>>) -> io::Result<()> {
+        // content streamed from its first to its last byte is checksummed on the way
+        // taken when the uploads were written, only entries from before that are read back
+        let mut crcs: Vec<Option<u32>> = self.entries.iter().map(|entry| entry.file.crc32).collect();
+
+        for (segment_start, segment_end, segment) in &self.segments {
+            if *segment_end <= start || *segment_start >= end {
+                continue;
+            }
+            let from = start.max(*segment_start) - segment_start;
+            let to = end.min(*segment_end) - segment_start;
+
+            let bytes = match segment {
+                Segment::Bytes(bytes) => bytes.slice(from as usize..to as usize),
+                Segment::Content(i) => {
+                    let crc = self.send_content(&self.entries[*i], encryption, from, to, tx).await?;
+                    crcs[*i] = crcs[*i].or(crc);
+                    continue;
+                },
+                Segment::Descriptor(i) => {
+                    let crc = self.crc(*i, encryption, &mut crcs).await?;
+                    zip_descriptor(&self.entries[*i], crc).slice(from as usize..to as usize)
+                },
+                Segment::Directory => {
+                    let mut all = Vec::with_capacity(self.entries.len());
+                    for i in 0..self.entries.len() {
+                        all.push(self.crc(i, encryption, &mut crcs).await?);
+                    }
+                    zip_directory(&self.entries, &all, *segment_start).slice(from as usize..to as usize)
+                },
+            };
+            send(tx, bytes).await?;
+        }
+        Ok(())
+    }
+
+    // the crc of the whole content when `from..to` covered it
+    async fn send_content(&self, entry: &Entry, encryption: &Encryption, from: u64, to: u64, tx: &Sender<io::Result<Bytes>>) -> io::Result<Option<u32>> {
+        let whole = from == 0 && to == entry.size();
+        let mut hasher = crc32fast::Hasher::new();
+        let mut sent = 0;
+
+        let mut content = merkle::read_logical(&entry.file, encryption, from, to).await.map_err(|e| io::Error::other(e.state().to_string()))?;
+        while let Some(chunk) = content.next().await {
+            let chunk = chunk?;
+            sent += chunk.len() as u64;
+            if whole {
+                hasher.update(&chunk);
+            }
+            send(tx, chunk).await?;
+        }
+
+        if sent != to - from {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("upload {} is shorter than listed", entry.file.uuid)));
+        }
+        Ok(whole.then(|| hasher.finalize()))
+    }
+
+    // read back content the stream skipped, a resumed download may start past it
+    async fn crc(&self, i: usize, encryption: &Encryption, crcs: &mut [Option<u32>]) -> io::Result<u32> {
+        if let Some(crc) = crcs[i] {
+            return Ok(crc);
+        }
+
+        let entry = &self.entries[i];
+        let mut hasher = crc32fast::Hasher::new();
+        let mut content = merkle::read_logical(&entry.file, encryption, 0, entry.size()).await.map_err(|e| io::Error::other(e.state().to_string()))?;
+        while let Some(chunk) = content.next().await {
+            hasher.update(&chunk?);
+        }
+
+        let crc = hasher.finalize();
+        crcs[i] = Some(crc);
+        Ok(crc)
+    }
+}
+
+async fn send(tx: &Sender<io::Result<Bytes>>, bytes: Bytes) -> io::Result<()> {
+    tx.send(Ok(bytes)).await.map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+}
+
+// archive paths from the listed names, `report (1).pdf` for the second `report.pdf`
+// names are compared ignoring case, archives get extracted onto case insensitive file systems
+fn entries(files: Vec<IndexedFile>, filenames: &FilenamePolicy) -> Vec<Entry> {
+    let mut taken = HashSet::new();
+    files
+        .into_iter()
+        .map(|file| {
+            let name = filenames.rewrite(&file.name);
+            let name = match taken.insert(name.to_lowercase()) {
+                true => name,
+                false => {
+                    let (stem, extension) = match name.rfind('.') {
+                        Some(dot) if dot > 0 => name.split_at(dot),
+                        _ => (name.as_str(), ""),
+                    };
+                    (1..).map(|n| format!("{} ({}){}", stem, n, extension)).find(|name| taken.insert(name.to_lowercase())).unwrap()
+                },
+            };
+            Entry { file, name, offset: 0, zip64: false }
+        })
+        .collect()
+}
+
+// MS-DOS time and date of `at`, the only timestamps every zip reader understands
+fn dos_time(at: DateTime<Utc>) -> (u16, u16) {
+    let time = at.hour() << 11 | at.minute() << 5 | (at.second() / 2);
+    let date = ((at.year().clamp(1980, 2107) - 1980) as u32) << 9 | at.month() << 5 | at.day();
+    (time as u16, date as u16)
+}
+
+fn zip_version(entry: &Entry) -> u16 {
+    match entry.zip64 {
+        true => 45,
+        false => 20,
+    }
+}
+
+fn zip_local_header(entry: &Entry) -> Bytes {
+    let name = entry.name.as_bytes();
+    let (time, date) = dos_time(entry.file.created_at);
+
+    let mut header = Vec::with_capacity(30 + name.len() + 20);
+    header.put_u32_le(LOCAL_HEADER);
+    header.put_u16_le(zip_version(entry));
+    header.put_u16_le(ZIP_FLAGS);
+    // stored
+    header.put_u16_le(0);
+    header.put_u16_le(time);
+    header.put_u16_le(date);
+    // crc and sizes are left to the descriptor, the zip64 marker tells it has 8 byte sizes
+    header.put_u32_le(0);
+    let sizes = if entry.zip64 { u32::MAX } else { 0 };
+    header.put_u32_le(sizes);
+    header.put_u32_le(sizes);
+    header.put_u16_le(name.len() as u16);
+    header.put_u16_le(if entry.zip64 { 20 } else { 0 });
+    header.put_slice(name);
+    if entry.zip64 {
+        header.put_u16_le(ZIP64_EXTRA);
+        header.put_u16_le(16);
+        header.put_u64_le(0);
+        header.put_u64_le(0);
+    }
+    Bytes::from(header)
+}
+
+fn zip_descriptor(entry: &Entry, crc: u32) -> Bytes {
+    let mut descriptor = Vec::with_capacity(24);
+    descriptor.put_u32_le(DATA_DESCRIPTOR);
+    descriptor.put_u32_le(crc);
+    match entry.zip64 {
+        true => {
+            descriptor.put_u64_le(entry.size());
+            descriptor.put_u64_le(entry.size());
+        },
+        false => {
+            descriptor.put_u32_le(entry.size() as u32);
+            descriptor.put_u32_le(entry.size() as u32);
+        },
+    }
+    Bytes::from(descriptor)
+}
+
+// the central directory starting at `start`, followed by the zip64 end records when anything outgrew the classic ones
+fn zip_directory(entries: &[Entry], crcs: &[u32], start: u64) -> Bytes {
+    let mut directory = Vec::new();
+
+    for (entry, crc) in entries.iter().zip(crcs) {
+        let name = entry.name.as_bytes();
+        let (time, date) = dos_time(entry.file.created_at);
+        let (size, offset) = match entry.zip64 {
+            true => (u32::MAX, u32::MAX),
+            false => (entry.size() as u32, entry.offset as u32),
+        };
+
+        directory.put_u32_le(CENTRAL_HEADER);
+        directory.put_u16_le(MADE_BY_UNIX | zip_version(entry));
+        directory.put_u16_le(zip_version(entry));
+        directory.put_u16_le(ZIP_FLAGS);
+        directory.put_u16_le(0);
+        directory.put_u16_le(time);
+        directory.put_u16_le(date);
+        directory.put_u32_le(*crc);
+        directory.put_u32_le(size);
+        directory.put_u32_le(size);
+        directory.put_u16_le(name.len() as u16);
+        directory.put_u16_le(if entry.zip64 { 28 } else { 0 });
+        // comment, disk and internal attributes
+        directory.put_u16_le(0);
+        directory.put_u16_le(0);
+        directory.put_u16_le(0);
+        directory.put_u32_le(FILE_MODE << 16);
+        directory.put_u32_le(offset);
+        directory.put_slice(name);
+        if entry.zip64 {
+            directory.put_u16_le(ZIP64_EXTRA);
+            directory.put_u16_le(24);
+            directory.put_u64_le(entry.size());
+            directory.put_u64_le(entry.size());
+            directory.put_u64_le(entry.offset);
+        }
+    }
+
+    let count = entries.len() as u64;
+    let size = directory.len() as u64;
+    if count >= 0xFFFF || size >= ZIP64_LIMIT || start >= ZIP64_LIMIT {
+        directory.put_u32_le(ZIP64_END);
+        // the record without its signature and this field
+        directory.put_u64_le(44);
+        directory.put_u16_le(MADE_BY_UNIX | 45);
+        directory.put_u16_le(45);
+        directory.put_u32_le(0);
+        directory.put_u32_le(0);
+        directory.put_u64_le(count);
+        directory.put_u64_le(count);
+        directory.put_u64_le(size);
+        directory.put_u64_le(start);
+
+        directory.put_u32_le(ZIP64_LOCATOR);
+        directory.put_u32_le(0);
+        directory.put_u64_le(start + size);
+        directory.put_u32_le(1);
+    }
+
+    directory.put_u32_le(END);
+    directory.put_u16_le(0);
+    directory.put_u16_le(0);
+    directory.put_u16_le(count.min(0xFFFF) as u16);
+    directory.put_u16_le(count.min(0xFFFF) as u16);
+    directory.put_u32_le(size.min(ZIP64_LIMIT) as u32);
+    directory.put_u32_le(start.min(ZIP64_LIMIT) as u32);
+    directory.put_u16_le(0);
+    Bytes::from(directory)
+}
+
+// the ustar header of an entry, behind a pax header when the name or size do not fit it
+fn tar_header(entry: &Entry) -> Bytes {
+    let mtime = entry.file.created_at.timestamp().max(0) as u64;
+    // ASCII only, so it is cut on a character boundary
+    let plain: String = entry.name.chars().map(|c| if c.is_ascii() && !c.is_ascii_control() { c } else { '_' }).take(100).collect();
+
+    let mut records = String::new();
+    if plain != entry.name {
+        records.push_str(&pax_record("path", &entry.name));
+    }
+    if entry.size() > USTAR_MAX_SIZE {
+        records.push_str(&pax_record("size", &entry.size().to_string()));
+    }
+
+    let mut header = Vec::with_capacity(3 * BLOCK as usize);
+    if !records.is_empty() {
+        let pax_name: String = format!("PaxHeaders/{}", plain).chars().take(100).collect();
+        header.extend_from_slice(&ustar_block(&pax_name, records.len() as u64, mtime, b'x'));
+        header.extend_from_slice(records.as_bytes());
+        header.resize(header.len().next_multiple_of(BLOCK as usize), 0);
+    }
+    header.extend_from_slice(&ustar_block(&plain, entry.size(), mtime, b'0'));
+    Bytes::from(header)
+}
+
+fn ustar_block(name: &str, size: u64, mtime: u64, kind: u8) -> [u8; BLOCK as usize] {
+    let mut block = [0u8; BLOCK as usize];
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut block[100..108], 0o644);
+    octal(&mut block[108..116], 0);
+    octal(&mut block[116..124], 0);
+    match size <= USTAR_MAX_SIZE {
+        true => octal(&mut block[124..136], size),
+        // GNU base-256, readers without it go by the pax size record
+        false => {
+            block[124] = 0x80;
+            block[128..136].copy_from_slice(&size.to_be_bytes());
+        },
+    }
+    octal(&mut block[136..148], mtime);
+    block[156] = kind;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+
+    // summed with the checksum field as spaces
+    block[148..156].fill(b' ');
+    let checksum: u32 = block.iter().map(|b| *b as u32).sum();
+    block[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    block
+}
+
+// zero padded octal closed by a NUL, filling `field`
+fn octal(field: &mut [u8], value: u64) {
+    field.copy_from_slice(format!("{:0width$o}\0", value, width = field.len() - 1).as_bytes());
+}
+
+// `<length> <key>=<value>\n`, the length counts its own digits
+fn pax_record(key: &str, value: &str) -> String {
+    let record = format!(" {}={}\n", key, value);
+    let mut len = record.len() + 1;
+    while len.to_string().len() + record.len() != len {
+        len = len.to_string().len() + record.len();
+    }
+    format!("{}{}", len, record)
+}
+
+// POST /bundles, streams a set of uploads as one zip or tar archive
+pub async fn create_bundle(
+    ContentAccess { index, metrics, encryption, hooks }: ContentAccess,
+    Extension(filenames): Extension<FilenamePolicyHandle>,
+    Extension(config): Extension<BundleHandle>,
+    audit: AuditScope,
+    principal: Principal,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response<Body>, FragmentError> {
+
+    audit.action(AuditAction::Bundle);
+
+    let request: BundleRequest = serde_json::from_slice(&body)
+        .map_err(|_| BodyErrors::InvalidValues(Cow::Borrowed("bundle")))?;
+    if request.uuids.is_empty() && request.filter.is_none() {
+        return Err(BodyErrors::MissingField(Cow::Borrowed("uuids")).into());
+    }
+
+    // only the caller's own uploads go into an archive, whether named or matched
+    let mut files = Vec::with_capacity(request.uuids.len());
+    for uuid in &request.uuids {
+        let file = index.get(uuid).filter(|file| principal.owns(file.tenant.as_deref())).ok_or(ErrorStates::UploadNotFound(*uuid))?;
+        if !hooks.servable(file.state) {
+            return Err(ErrorStates::FileUnavailable(*uuid).into());
+        }
+        files.push(file);
+    }
+    // uploads still running or held back are left out rather than failing the whole bundle
+    if let Some(filter) = request.filter {
+        let filter = FileQuery { owner: Some(principal.to_string()), ..filter };
+        files.extend(index.matching(&filter)?.into_iter().filter(|file| hooks.servable(file.state)));
+    }
+    let mut seen = HashSet::new();
+    files.retain(|file| seen.insert(file.uuid));
+    if files.len() > config.max_files {
+        return Err(ErrorStates::BundleTooLarge(config.max_files).into());
+    }
+
+    let format = request.format;
+    let layout = Arc::new(Layout::new(format, entries(files, &filenames)));
+    let (total, etag) = (layout.total, layout.etag());
+    let name = format!("{}.{}", filenames.rewrite(request.name.as_deref().unwrap_or("bundle")), format.extension());
+
+    let builder = Response::builder()
+        .header(ACCEPT_RANGES, "bytes")
+        .header(CONTENT_TYPE, format.content_type())
+        .header(CONTENT_DISPOSITION, filename::content_disposition(&name))
+        .header(ETAG, &etag);
+
+    // a resumed download only continues the archive it started on
+    let range = headers
+        .get(RANGE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|_| headers.get(IF_RANGE).is_none_or(|validator| validator.as_bytes() == etag.as_bytes()));
+
+    let (start, end, builder) = match range {
+        None => (0, total, builder.status(200)),
+        Some(range) => match download::parse_range(range, total) {
+            Some((start, end)) => (start, end, builder.status(206).header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end - 1, total))),
+            None => {
+                let resp = builder
+                    .status(416)
+                    .header(CONTENT_RANGE, format!("bytes */{}", total))
+                    .body(Body::empty())?;
+                return Ok(resp);
+            },
+        },
+    };
+
+    tracing::info!(entries = layout.entries.len(), total, start, end, format = format.extension(), "bundle streamed");
+    metrics.record_served((end - start) as usize);
+    audit.bytes((end - start) as usize);
+
+    let resp = builder
+        .header(CONTENT_LENGTH, end - start)
+        .body(layout.stream(encryption, start, end))?;
+
+    Ok(resp)
+}
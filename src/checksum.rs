@@ -0,0 +1,166 @@
+use axum::http::HeaderMap;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::{errors::{ErrorStates, HeaderErrors}, FragmentError};
+
+
+/*
+    Digests a client sends along with a single upload request, RFC 9530 and the tus checksum extension.
+
+    Content-Digest covers the body as it went over the wire, Repr-Digest the bytes it decodes to.
+    An upload request only carries a piece of the file, so the representation is that piece and not the whole file.
+    Upload-Checksum covers the body like Content-Digest does.
+*/
+pub const CONTENT_DIGEST: &str = "Content-Digest";
+pub const REPR_DIGEST: &str = "Repr-Digest";
+pub const UPLOAD_CHECKSUM: &str = "Upload-Checksum";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    // both the RFC 9530 registry names and the spelling tus clients use
+    fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "sha-256" | "sha256" => Some(Algorithm::Sha256),
+            "sha-512" | "sha512" => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(Box::new(Sha512::new())),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Box<Sha512>),
+}
+
+impl Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(bytes),
+            Hasher::Sha512(hasher) => hasher.update(bytes),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+// which bytes of the request a digest was taken over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    Wire,
+    Decoded,
+}
+
+#[derive(Debug, Clone)]
+struct Expected {
+    header: &'static str,
+    scope: Scope,
+    digest: Vec<u8>,
+    hasher: Hasher,
+}
+
+// Running digests of one request, checked against what the client declared once the body ended
+#[derive(Debug, Clone, Default)]
+pub struct Checksums {
+    expected: Vec<Expected>,
+}
+
+impl Checksums {
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, FragmentError> {
+        let mut expected = Vec::new();
+
+        for (header, scope) in [(CONTENT_DIGEST, Scope::Wire), (REPR_DIGEST, Scope::Decoded)] {
+            let Some(value) = headers.get(header) else {
+                continue;
+            };
+            let (algorithm, digest) = parse_dictionary(header, value.to_str()?)?;
+            expected.push(Expected { header, scope, digest, hasher: algorithm.hasher() });
+        }
+
+        if let Some(value) = headers.get(UPLOAD_CHECKSUM) {
+            let (algorithm, digest) = parse_tus(value.to_str()?)?;
+            expected.push(Expected { header: UPLOAD_CHECKSUM, scope: Scope::Wire, digest, hasher: algorithm.hasher() });
+        }
+
+        Ok(Self { expected })
+    }
+
+    // without a declared digest every byte is acknowledged as soon as it is durable
+    pub fn is_empty(&self) -> bool {
+        self.expected.is_empty()
+    }
+
+    pub fn update_wire(&mut self, bytes: &[u8]) {
+        self.update(Scope::Wire, bytes);
+    }
+
+    pub fn update_decoded(&mut self, bytes: &[u8]) {
+        self.update(Scope::Decoded, bytes);
+    }
+
+    fn update(&mut self, scope: Scope, bytes: &[u8]) {
+        for expected in self.expected.iter_mut().filter(|expected| expected.scope == scope) {
+            expected.hasher.update(bytes);
+        }
+    }
+
+    // the first declared digest the received bytes don't match fails the request
+    pub fn verify(self) -> Result<(), ErrorStates> {
+        for expected in self.expected {
+            if expected.hasher.finalize() != expected.digest {
+                return Err(ErrorStates::ChecksumMismatch(expected.header));
+            }
+        }
+        Ok(())
+    }
+}
+
+// `sha-256=:<base64>:, sha-512=:<base64>:`, the strongest supported algorithm is the one checked
+fn parse_dictionary(header: &'static str, value: &str) -> Result<(Algorithm, Vec<u8>), FragmentError> {
+    let mut declared = Vec::new();
+
+    for member in value.split(',').filter(|member| !member.trim().is_empty()) {
+        let (name, digest) = member.split_once('=').ok_or(HeaderErrors::InvalidField(header.into()))?;
+        let Some(algorithm) = Algorithm::parse(name) else {
+            continue;
+        };
+        let digest = digest
+            .trim()
+            .strip_prefix(':')
+            .and_then(|digest| digest.strip_suffix(':'))
+            .and_then(|digest| STANDARD.decode(digest).ok())
+            .ok_or(HeaderErrors::InvalidField(header.into()))?;
+        declared.push((algorithm, digest));
+    }
+
+    declared
+        .into_iter()
+        .max_by_key(|(algorithm, _)| *algorithm == Algorithm::Sha512)
+        .ok_or_else(|| ErrorStates::UnsupportedChecksum(header).into())
+}
+
+// `<algorithm> <base64>`
+fn parse_tus(value: &str) -> Result<(Algorithm, Vec<u8>), FragmentError> {
+    let (name, digest) = value.trim().split_once(' ').ok_or(HeaderErrors::InvalidField(UPLOAD_CHECKSUM.into()))?;
+    let algorithm = Algorithm::parse(name).ok_or(ErrorStates::UnsupportedChecksum(UPLOAD_CHECKSUM))?;
+    let digest = STANDARD.decode(digest.trim()).map_err(|_| HeaderErrors::InvalidField(UPLOAD_CHECKSUM.into()))?;
+    Ok((algorithm, digest))
+}
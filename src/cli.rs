@@ -0,0 +1,285 @@
+use std::{path::PathBuf, time::Duration};
+
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use futures::StreamExt;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use lofty_client::{Client, ClientBuilder, Error, ListQuery, Source};
+use uuid::Uuid;
+
+use lofty::{maintenance, rotate_keys, FragmentError, LoadConfig, LocalStorage};
+
+
+/*
+    One binary for the server, the commands that talk to a running one and the upkeep of a
+    stopped one. Without a command it serves, as it always did.
+ */
+#[derive(Debug, Parser)]
+#[command(name = "lofty", version, about = "Resumable upload server and its command line client")]
+pub struct Cli {
+    /// Settings file of the server, used by `serve` and the maintenance commands
+    #[arg(long, global = true, default_value = "./settings.toml")]
+    pub config: String,
+
+    /// Base url of the server the client commands talk to
+    #[arg(long, global = true, env = "LOFTY_URL", default_value = "http://127.0.0.1:2053")]
+    pub server: String,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the server
+    Serve,
+
+    /// Upload files, resuming on their own after a failed request
+    Upload {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Continue an upload scheduled earlier instead of scheduling a new one
+        #[arg(long)]
+        resume: Option<Uuid>,
+        /// Send each file in checksummed pieces of this many bytes
+        #[arg(long)]
+        chunk_size: Option<u64>,
+        /// Files uploaded at the same time
+        #[arg(long, default_value_t = 1)]
+        parallel: usize,
+        /// Failed requests in a row before giving up on a file
+        #[arg(long, default_value_t = 8)]
+        retries: u32,
+        /// No progress bars
+        #[arg(long, short)]
+        quiet: bool,
+    },
+
+    /// Show the state of an upload
+    Status {
+        uuid: Uuid,
+    },
+
+    /// Download an upload, continuing a partial file left by an earlier attempt
+    Download {
+        uuid: Uuid,
+        /// Where to write the content, the uuid in the current directory by default
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        #[arg(long, default_value_t = 8)]
+        retries: u32,
+        #[arg(long, short)]
+        quiet: bool,
+    },
+
+    /// List uploads
+    Ls {
+        /// Comma separated states, e.g. `complete,broken`
+        #[arg(long)]
+        state: Option<String>,
+        #[arg(long)]
+        prefix: Option<String>,
+        #[arg(long)]
+        tenant: Option<String>,
+        /// Comma separated tags, all of them have to be present
+        #[arg(long)]
+        tags: Option<String>,
+        /// Comma separated `key=value` pairs
+        #[arg(long)]
+        metadata: Option<String>,
+        /// Print the listing as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Delete uploads and their content
+    Rm {
+        #[arg(required = true)]
+        uuids: Vec<Uuid>,
+    },
+
+    /// Rewrap the data keys of a stopped server under a new master key
+    RotateKeys,
+
+    /// Remove files and blobs of a stopped server that no upload points at
+    Gc {
+        /// Also drop failed, rejected and quarantined uploads
+        #[arg(long)]
+        failed: bool,
+        /// Only print what would be removed
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Bring the registry of a stopped server in line with the files on disk
+    Reconcile {
+        /// Also hash completed uploads and compare them with their digest
+        #[arg(long)]
+        verify: bool,
+        /// Only print what would change
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+impl Cli {
+    pub fn parse_args() -> Self {
+        let cli = Self::parse();
+        if let Some(Command::Upload { files, resume: Some(_), .. }) = &cli.command {
+            if files.len() > 1 {
+                Self::command().error(ErrorKind::ArgumentConflict, "--resume continues a single file").exit();
+            }
+        }
+        cli
+    }
+
+    pub fn load_config(&self) -> LoadConfig {
+        LoadConfig::new(&self.config)
+    }
+}
+
+impl Command {
+    // works on the settings and data directory of a stopped server rather than talking to one
+    pub fn is_maintenance(&self) -> bool {
+        matches!(self, Command::RotateKeys | Command::Gc { .. } | Command::Reconcile { .. })
+    }
+}
+
+pub async fn maintain(command: Command, config: &LoadConfig) -> Result<(), FragmentError> {
+    // the commands run without an embedding application, uploads are where the settings put them
+    let storage = LocalStorage::new(config.data_dir());
+    match command {
+        Command::RotateKeys => rotate_keys(config, &storage).await,
+        Command::Gc { failed, dry_run } => {
+            let report = maintenance::collect_garbage(config, &storage, failed, dry_run).await?;
+            report.lines.iter().for_each(|line| println!("{}", line));
+            println!("{} removals, {} bytes", report.changes, report.freed);
+            Ok(())
+        },
+        Command::Reconcile { verify, dry_run } => {
+            let report = maintenance::reconcile(&storage, verify, dry_run).await?;
+            report.lines.iter().for_each(|line| println!("{}", line));
+            println!("{} changes", report.changes);
+            Ok(())
+        },
+        _ => Ok(()),
+    }
+}
+
+// run a client command, answering how many of its items failed and were reported on the way
+pub async fn remote(server: &str, command: Command) -> Result<usize, Error> {
+    let client = |retries: u32| Client::builder(server).retry(lofty_client::RetryPolicy { max_attempts: retries.max(1), ..Default::default() });
+
+    match command {
+        Command::Upload { files, resume, chunk_size, parallel, retries, quiet } => {
+            let builder = move || {
+                let builder = client(retries);
+                match chunk_size {
+                    Some(chunk_size) => builder.chunk_size(chunk_size),
+                    None => builder,
+                }
+            };
+            upload(builder, files, resume, parallel, quiet).await
+        },
+        Command::Status { uuid } => {
+            println!("{}", client(1).build()?.status(uuid).await?);
+            Ok(0)
+        },
+        Command::Download { uuid, output, retries, quiet } => {
+            let output = output.unwrap_or_else(|| PathBuf::from(uuid.to_string()));
+            let bar = progress_bar(&uuid.to_string(), quiet);
+            let client = client(retries).on_progress(track(&bar)).build()?;
+
+            let written = client.download(uuid, &output).await;
+            bar.finish_and_clear();
+            println!("{}\t{} bytes", output.display(), written?);
+            Ok(0)
+        },
+        Command::Ls { state, prefix, tenant, tags, metadata, json } => {
+            let query = ListQuery { state, name_prefix: prefix, tenant, tags, metadata, ..Default::default() };
+            let files = client(1).build()?.list_all(&query).await?;
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&files)?);
+                return Ok(0);
+            }
+            println!("{:<36}  {:<24}  {:>14}  {:<20}  NAME", "UUID", "STATE", "SIZE", "CREATED");
+            for file in files {
+                let created = file.created_at.get(..19).unwrap_or(&file.created_at).replace('T', " ");
+                println!("{:<36}  {:<24}  {:>14}  {:<20}  {}", file.uuid, file.state.to_string(), file.file_size, created, file.name);
+            }
+            Ok(0)
+        },
+        Command::Rm { uuids } => {
+            let client = client(1).build()?;
+            let mut failed = 0;
+            for uuid in uuids {
+                match client.delete(uuid).await {
+                    Ok(()) => println!("removed {}", uuid),
+                    Err(e) => {
+                        eprintln!("lofty: {}: {}", uuid, e);
+                        failed += 1;
+                    },
+                }
+            }
+            Ok(failed)
+        },
+        _ => Ok(0),
+    }
+}
+
+async fn upload(builder: impl Fn() -> ClientBuilder, files: Vec<PathBuf>, resume: Option<Uuid>, parallel: usize, quiet: bool) -> Result<usize, Error> {
+    let bars = MultiProgress::new();
+    if quiet {
+        bars.set_draw_target(ProgressDrawTarget::hidden());
+    }
+
+    let uploads = files.into_iter().map(|path| {
+        let bar = bars.add(progress_bar(&path.display().to_string(), quiet));
+        let client = builder().on_progress(track(&bar)).build();
+
+        async move {
+            let source = Source::path(&path);
+            let uploaded = match (client, resume) {
+                (Ok(client), Some(uuid)) => client.resume(uuid, &source).await.map(|_| uuid),
+                (Ok(client), None) => client.upload_file(&source).await,
+                (Err(e), _) => Err(e),
+            };
+            bar.finish_and_clear();
+            (path, uploaded)
+        }
+    });
+
+    let mut failed = 0;
+    let mut uploads = futures::stream::iter(uploads).buffered(parallel.max(1));
+    while let Some((path, uploaded)) = uploads.next().await {
+        match uploaded {
+            Ok(uuid) => println!("{}\t{}", uuid, path.display()),
+            Err(e) => {
+                eprintln!("lofty: {}: {}", path.display(), e);
+                failed += 1;
+            },
+        }
+    }
+    Ok(failed)
+}
+
+fn progress_bar(label: &str, quiet: bool) -> ProgressBar {
+    if quiet {
+        return ProgressBar::hidden();
+    }
+    let style = ProgressStyle::with_template("{msg:30!} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} {eta}")
+        .expect("valid progress template")
+        .progress_chars("=> ");
+    let bar = ProgressBar::new(0).with_style(style).with_message(label.to_string());
+    bar.enable_steady_tick(Duration::from_millis(250));
+    bar
+}
+
+// moves `bar` along with the transfer, a retry may set it back
+fn track(bar: &ProgressBar) -> impl Fn(lofty_client::Progress) + Send + Sync + 'static {
+    let bar = bar.clone();
+    move |progress| {
+        bar.set_length(progress.total);
+        bar.set_position(progress.transferred);
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
+};
+
+use crate::{encryption::SealedSink, utils};
+
+
+/*
+    Compressed uploads are a sequence of independent blocks, each one
+        skippable frame: magic 0x184D2A5E | size 8 | compressed length u32 | logical length u32
+        zstd frame holding at most `block_size` bytes of the upload
+    all little endian. The file stays a valid zstd stream, `zstd -d` restores the upload,
+    while the skippable frames act as the seek table for ranges and resume.
+ */
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A5E;
+const HEADER_LEN: u64 = 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    pub level: i32,
+    // logical bytes per block, the granularity of seeks
+    pub block_size: usize,
+    // with both lists empty every upload is compressed, otherwise only the matching ones
+    pub tenants: Vec<String>,
+    // matched as prefixes, "text/" covers every text type
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            level: 3,
+            block_size: 1024 * 1024,
+            tenants: Vec::new(),
+            content_types: Vec::new(),
+        }
+    }
+}
+
+pub type CompressionHandle = Arc<CompressionConfig>;
+
+impl CompressionConfig {
+    pub fn select(&self, tenant: Option<&str>, content_type: Option<&str>) -> Compression {
+        if !self.enabled {
+            return Compression::None;
+        }
+
+        let everything = self.tenants.is_empty() && self.content_types.is_empty();
+        let tenant_match = tenant.is_some_and(|tenant| self.tenants.iter().any(|t| t == tenant));
+        let type_match = content_type.is_some_and(|ct| {
+            let ct = ct.to_ascii_lowercase();
+            self.content_types.iter().any(|prefix| ct.starts_with(&prefix.to_ascii_lowercase()))
+        });
+
+        if everything || tenant_match || type_match {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+fn invalid_data(reason: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason.to_string())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Block {
+    // where the block header starts in the stored file
+    pub stored_offset: u64,
+    pub stored_len: u64,
+    pub logical_offset: u64,
+    pub logical_len: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BlockIndex {
+    pub blocks: Vec<Block>,
+}
+
+impl BlockIndex {
+    // walk the block headers, a torn block at the tail (crash mid write) ends the index
+    pub async fn scan(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut file = File::open(path).await?;
+        let file_len = file.metadata().await?.len();
+
+        let mut blocks = Vec::new();
+        let mut stored_offset = 0;
+        let mut logical_offset = 0;
+
+        while stored_offset + HEADER_LEN <= file_len {
+            let mut header = [0u8; HEADER_LEN as usize];
+            file.seek(SeekFrom::Start(stored_offset)).await?;
+            file.read_exact(&mut header).await?;
+
+            let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap()) as u64;
+            if field(0) != SKIPPABLE_MAGIC as u64 || field(4) != 8 {
+                break;
+            }
+
+            let stored_len = HEADER_LEN + field(8);
+            let logical_len = field(12);
+            if stored_offset + stored_len > file_len {
+                break;
+            }
+
+            blocks.push(Block { stored_offset, stored_len, logical_offset, logical_len });
+            stored_offset += stored_len;
+            logical_offset += logical_len;
+        }
+
+        Ok(Self { blocks })
+    }
+
+    pub fn logical_len(&self) -> u64 {
+        self.blocks.last().map_or(0, |block| block.logical_offset + block.logical_len)
+    }
+
+    pub fn stored_len(&self) -> u64 {
+        self.blocks.last().map_or(0, |block| block.stored_offset + block.stored_len)
+    }
+
+    // the block holding the logical byte `offset`
+    pub fn locate(&self, offset: u64) -> Option<usize> {
+        let idx = self.blocks.partition_point(|block| block.logical_offset + block.logical_len <= offset);
+        (idx < self.blocks.len()).then_some(idx)
+    }
+}
+
+async fn read_block(file: &mut File, block: &Block) -> std::io::Result<Bytes> {
+    let mut frame = vec![0u8; (block.stored_len - HEADER_LEN) as usize];
+    file.seek(SeekFrom::Start(block.stored_offset + HEADER_LEN)).await?;
+    file.read_exact(&mut frame).await?;
+
+    let capacity = block.logical_len as usize;
+    let logical = tokio::task::spawn_blocking(move || zstd::bulk::decompress(&frame, capacity))
+        .await
+        .map_err(std::io::Error::other)??;
+
+    if logical.len() as u64 != block.logical_len {
+        return Err(invalid_data("compressed block does not match its declared length"));
+    }
+    Ok(Bytes::from(logical))
+}
+
+// logical bytes `start..end` of a compressed upload, decompressing only the blocks involved
+pub fn read_range(path: PathBuf, index: BlockIndex, start: u64, end: u64) -> impl Stream<Item = std::io::Result<Bytes>> {
+    let first = index.locate(start).unwrap_or(index.blocks.len());
+
+    futures::stream::try_unfold((None::<File>, first), move |(file, idx)| {
+        let path = path.clone();
+        let block = index.blocks.get(idx).copied();
+        async move {
+            let Some(block) = block.filter(|block| block.logical_offset < end) else {
+                return Ok(None);
+            };
+            let mut file = match file {
+                Some(file) => file,
+                None => File::open(&path).await?,
+            };
+
+            let logical = read_block(&mut file, &block).await?;
+            let from = start.saturating_sub(block.logical_offset) as usize;
+            let to = (end.min(block.logical_offset + block.logical_len) - block.logical_offset) as usize;
+
+            Ok(Some((logical.slice(from..to), (Some(file), idx + 1))))
+        }
+    })
+}
+
+// bytes of the upload that are safely on disk, as seen by resume
+pub async fn durable_len(path: impl AsRef<Path>, compression: Compression) -> std::io::Result<u64> {
+    match compression {
+        Compression::None => Ok(tokio::fs::metadata(path).await?.len()),
+        Compression::Zstd => Ok(BlockIndex::scan(path).await?.logical_len()),
+    }
+}
+
+// Destination of an upload stream, the plain file, blocks of zstd frames or sealed segments
+#[derive(Debug)]
+pub enum Sink {
+    Plain(BufWriter<File>),
+    Zstd(ZstdSink),
+    Sealed(SealedSink),
+}
+
+#[derive(Debug)]
+pub struct ZstdSink {
+    file: BufWriter<File>,
+    level: i32,
+    block_size: usize,
+    // logical bytes waiting for a full block
+    pending: Vec<u8>,
+    stored: u64,
+}
+
+impl Sink {
+    pub async fn create(path: impl AsRef<Path>, compression: Compression, config: &CompressionConfig, buffer: usize) -> std::io::Result<Self> {
+        let file = BufWriter::with_capacity(buffer, utils::create_fresh(path).await?);
+
+        Ok(match compression {
+            Compression::None => Sink::Plain(file),
+            Compression::Zstd => Sink::Zstd(ZstdSink::new(file, 0, Vec::new(), config)),
+        })
+    }
+
+    // reopen an interrupted upload, everything past the logical `pointer` is discarded
+    pub async fn resume(path: impl AsRef<Path>, pointer: u64, compression: Compression, config: &CompressionConfig, buffer: usize) -> std::io::Result<Self> {
+        let path = path.as_ref();
+
+        match compression {
+            Compression::None => {
+                let mut file = OpenOptions::new().write(true).open(path).await?;
+                file.set_len(pointer).await?;
+                file.seek(SeekFrom::Start(pointer)).await?;
+                Ok(Sink::Plain(BufWriter::with_capacity(buffer, file)))
+            },
+            Compression::Zstd => {
+                let index = BlockIndex::scan(path).await?;
+                let mut file = OpenOptions::new().read(true).write(true).open(path).await?;
+
+                // the block holding the pointer is cut off and its kept prefix goes back to pending
+                let (cut, pending) = match index.locate(pointer) {
+                    Some(idx) => {
+                        let block = index.blocks[idx];
+                        let logical = read_block(&mut file, &block).await?;
+                        let keep = (pointer - block.logical_offset) as usize;
+                        (block.stored_offset, logical[..keep].to_vec())
+                    },
+                    None if pointer == index.logical_len() => (index.stored_len(), Vec::new()),
+                    None => return Err(invalid_data("resume offset is past the compressed data")),
+                };
+
+                file.set_len(cut).await?;
+                file.seek(SeekFrom::Start(cut)).await?;
+                Ok(Sink::Zstd(ZstdSink::new(BufWriter::with_capacity(buffer, file), cut, pending, config)))
+            },
+        }
+    }
+
+    pub async fn write_all(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        match self {
+            Sink::Plain(file) => file.write_all(bytes).await,
+            Sink::Zstd(sink) => sink.write_all(bytes).await,
+            Sink::Sealed(sink) => sink.write_all(bytes).await,
+        }
+    }
+
+    // flush everything written so far and make it durable
+    pub async fn checkpoint(&mut self) -> std::io::Result<()> {
+        let file = match self {
+            Sink::Plain(file) => file,
+            Sink::Zstd(sink) => {
+                sink.write_pending().await?;
+                &mut sink.file
+            },
+            Sink::Sealed(sink) => return sink.checkpoint().await,
+        };
+        file.flush().await?;
+        file.get_ref().sync_data().await
+    }
+
+    // bytes taken on disk, only exact right after a checkpoint
+    pub async fn stored_len(&self) -> std::io::Result<u64> {
+        match self {
+            Sink::Plain(file) => Ok(file.get_ref().metadata().await?.len()),
+            Sink::Zstd(sink) => Ok(sink.stored),
+            Sink::Sealed(sink) => Ok(sink.stored_len()),
+        }
+    }
+}
+
+impl ZstdSink {
+    fn new(file: BufWriter<File>, stored: u64, pending: Vec<u8>, config: &CompressionConfig) -> Self {
+        Self {
+            file,
+            level: config.level,
+            block_size: config.block_size.clamp(4096, u32::MAX as usize),
+            pending,
+            stored,
+        }
+    }
+
+    async fn write_all(&mut self, mut bytes: &[u8]) -> std::io::Result<()> {
+        while !bytes.is_empty() {
+            let room = self.block_size - self.pending.len();
+            let take = room.min(bytes.len());
+            self.pending.extend_from_slice(&bytes[..take]);
+            bytes = &bytes[take..];
+
+            if self.pending.len() == self.block_size {
+                self.write_pending().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_pending(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let logical = std::mem::replace(&mut self.pending, Vec::with_capacity(self.block_size));
+        let logical_len = logical.len() as u32;
+        let level = self.level;
+        // compressing a block takes milliseconds, keep it off the runtime workers
+        let frame = tokio::task::spawn_blocking(move || zstd::bulk::compress(&logical, level))
+            .await
+            .map_err(std::io::Error::other)??;
+
+        let mut header = [0u8; HEADER_LEN as usize];
+        header[0..4].copy_from_slice(&SKIPPABLE_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&8u32.to_le_bytes());
+        header[8..12].copy_from_slice(&(frame.len() as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&logical_len.to_le_bytes());
+
+        self.file.write_all(&header).await?;
+        self.file.write_all(&frame).await?;
+        self.stored += HEADER_LEN + frame.len() as u64;
+        Ok(())
+    }
+}
@@ -1,6 +1,39 @@
-use std::path::PathBuf;
+use std::{net::SocketAddr, path::PathBuf};
 
 use config::Config;
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::listener::ListenerConfig;
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerSettings { 
+    // used when no listeners are configured, serving every route
+    pub bind: SocketAddr,
+    pub listeners: Vec<ListenerConfig>,
+    // static files of the dashboard, served under /dashboard by listeners mounting it
+    pub dashboard: PathBuf,
+}
+
+impl Default for ServerSettings { 
+    fn default() -> Self { 
+        Self { 
+            bind: SocketAddr::from(([0, 0, 0, 0], 2053)),
+            listeners: Vec::new(),
+            dashboard: PathBuf::from("./admin"),
+        }
+    }
+}
+
+impl ServerSettings { 
+    pub fn listeners(&self) -> Vec<ListenerConfig> { 
+        if self.listeners.is_empty() { 
+            return vec![ListenerConfig::tcp(self.bind)];
+        }
+        self.listeners.clone()
+    }
+}
 
 
 #[derive(Debug)]
@@ -28,7 +61,7 @@ impl Default for LoadConfig {
 }
 
 impl LoadConfig { 
-    fn new(source: impl AsRef<str>) -> Self {
+    pub fn new(source: impl AsRef<str>) -> Self {
         
         let path_name = source.as_ref();
         let path = PathBuf::from(path_name);
@@ -43,4 +76,36 @@ impl LoadConfig {
             config_data: config
         }
     }
-}
\ No newline at end of file
+
+    // settings given inline rather than read from a file, an empty string takes every default
+    pub fn from_toml(contents: &str) -> Result<Self, config::ConfigError> {
+        let config = Config::builder()
+            .add_source(config::File::from_str(contents, config::FileFormat::Toml))
+            .build()?;
+
+        Ok(Self {
+            path: PathBuf::new(),
+            config_data: config
+        })
+    }
+
+    // directory the uploaded files are written into
+    pub fn data_dir(&self) -> PathBuf { 
+        self.config_data
+            .get_string("data")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("./data"))
+    }
+
+    // fetch a table from the settings file, falling back to the defaults of the section
+    pub fn section<T: DeserializeOwned + Default>(&self, key: &str) -> T { 
+        match self.config_data.get::<T>(key) { 
+            Ok(section) => section,
+            Err(config::ConfigError::NotFound(_)) => T::default(),
+            Err(e) => panic!("unable to parse the [{}] section of the config file: {}", key, e),
+        }
+    }
+}
+
+
+
@@ -0,0 +1,249 @@
+use std::str::FromStr;
+
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{rejection::ExtensionRejection, FromRequestParts, Path, Request},
+    http::{
+        header::{ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE, VARY},
+        request::Parts,
+        HeaderMap, HeaderName, HeaderValue, Response,
+    },
+    Extension,
+};
+use tower::Service;
+use tower_http::services::ServeFile;
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditAction, AuditScope},
+    authorization::Principal,
+    compression::{self, BlockIndex, Compression},
+    encoding::{self, Coding, EncodingHandle},
+    encryption::{self, EncryptionHandle},
+    errors::ErrorStates,
+    filename,
+    hooks::HooksHandle,
+    index::{FileIndexHandle, IndexedFile},
+    merkle::MerkleTree,
+    metadata::MetadataHandle,
+    metrics::MetricsHandle,
+    FragmentError,
+};
+
+// the handles stored content is looked up, decrypted and accounted through
+pub struct ContentAccess {
+    pub index: FileIndexHandle,
+    pub metrics: MetricsHandle,
+    pub encryption: EncryptionHandle,
+    pub hooks: HooksHandle,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ContentAccess
+    where S: Send + Sync
+{
+    type Rejection = ExtensionRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Extension(index) = Extension::from_request_parts(parts, state).await?;
+        let Extension(metrics) = Extension::from_request_parts(parts, state).await?;
+        let Extension(encryption) = Extension::from_request_parts(parts, state).await?;
+        let Extension(hooks) = Extension::from_request_parts(parts, state).await?;
+
+        Ok(Self { index, metrics, encryption, hooks })
+    }
+}
+
+// GET /files/:uuid/content, serves a completed upload with range support
+pub async fn download_file(
+    ContentAccess { index, metrics, encryption, hooks }: ContentAccess,
+    Extension(metadata): Extension<MetadataHandle>,
+    Extension(encoding): Extension<EncodingHandle>,
+    audit: AuditScope,
+    principal: Principal,
+    Path(uuid): Path<String>,
+    req: Request,
+) -> Result<Response<Body>, FragmentError> {
+
+    audit.action(AuditAction::Download);
+    let uuid = Uuid::from_str(&uuid)?;
+
+    // read from the index, the registry entry stays locked while an upload is running
+    // the content of another tenant's upload is as unknown to the caller as the upload itself
+    let file = index.get(&uuid).filter(|file| principal.owns(file.tenant.as_deref())).ok_or(ErrorStates::UploadNotFound(uuid))?;
+    audit.file(uuid, Some(&file.name));
+
+    // with a scanner configured nothing is handed out before it passed the content
+    if !hooks.servable(file.state) {
+        return Err(ErrorStates::FileUnavailable(uuid).into());
+    }
+
+    // ranges address the original bytes, only whole files are sent encoded
+    let coding = match req.headers().contains_key(RANGE) {
+        true => None,
+        false => encoding.negotiate(req.headers(), Some(file.served_type())),
+    };
+
+    let mut resp = match (coding, &file.envelope, file.compression) {
+        (Some(coding), _, _) => serve_encoded(&file, &encryption, coding).await?,
+        (None, Some(envelope), _) => {
+            let key = encryption.data_key(envelope, &uuid, file.file_size)?;
+            let path = file.output_file_path();
+            let total = encryption::plain_len(&path, &key).await?;
+            serve_ranges(&file, req.headers(), total, |start, end| Body::from_stream(encryption::read_range(path, key, start, end)))?
+        },
+        (None, None, Compression::Zstd) => {
+            let path = file.output_file_path();
+            let index = BlockIndex::scan(&path).await?;
+            let total = index.logical_len();
+            serve_ranges(&file, req.headers(), total, |start, end| Body::from_stream(compression::read_range(path, index, start, end)))?
+        },
+        (None, None, Compression::None) => match ServeFile::new(file.output_file_path()).call(req).await {
+            Ok(mut resp) => {
+                // the file name carries no extension to guess from
+                if let Ok(served) = HeaderValue::from_str(file.served_type()) {
+                    resp.headers_mut().insert(CONTENT_TYPE, served);
+                }
+                resp.map(Body::new)
+            },
+            Err(never) => match never {},
+        },
+    };
+
+    if resp.status().is_success() {
+        let headers = resp.headers_mut();
+
+        headers.insert(CONTENT_DISPOSITION, filename::content_disposition(&file.name));
+        for (name, value) in metadata.exposed_headers(&file.metadata) {
+            headers.insert(name, value);
+        }
+        headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+        if let Some(tree) = &file.merkle { 
+            merkle_headers(tree, headers);
+        }
+
+        // encoded responses are streamed without a length, they carry the whole file
+        let length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(file.file_size);
+        metrics.record_served(length);
+        audit.bytes(length);
+    }
+
+    Ok(resp)
+}
+
+// the whole file in a coding the client accepts, compressed uploads already are a zstd stream and go out as stored
+async fn serve_encoded(file: &IndexedFile, encryption: &EncryptionHandle, coding: Coding) -> Result<Response<Body>, FragmentError> {
+    let path = file.output_file_path();
+
+    let body = match (&file.envelope, file.compression, coding) {
+        (Some(envelope), _, _) => {
+            let key = encryption.data_key(envelope, &file.uuid, file.file_size)?;
+            let total = encryption::plain_len(&path, &key).await?;
+            Body::from_stream(encoding::encode(coding, encryption::read_range(path, key, 0, total)))
+        },
+        (None, Compression::Zstd, Coding::Zstd) if file.file_size > 0 => Body::from_stream(encoding::read_file(path)),
+        (None, Compression::Zstd, _) => {
+            let index = BlockIndex::scan(&path).await?;
+            let total = index.logical_len();
+            Body::from_stream(encoding::encode(coding, compression::read_range(path, index, 0, total)))
+        },
+        (None, Compression::None, _) => Body::from_stream(encoding::encode(coding, encoding::read_file(path))),
+    };
+
+    let resp = Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, file.served_type())
+        .header(CONTENT_ENCODING, coding.as_str())
+        .header(ACCEPT_RANGES, "bytes")
+        .body(body)?;
+
+    Ok(resp)
+}
+
+// ranges of compressed or encrypted uploads address the original bytes, `body` reads back `start..end` of them
+fn serve_ranges(
+    file: &IndexedFile,
+    headers: &HeaderMap,
+    total: u64,
+    body: impl FnOnce(u64, u64) -> Body,
+) -> Result<Response<Body>, FragmentError> {
+    let builder = Response::builder()
+        .header(ACCEPT_RANGES, "bytes")
+        .header(CONTENT_TYPE, file.served_type());
+
+    let (start, end, partial) = match headers.get(RANGE).and_then(|value| value.to_str().ok()) {
+        None => (0, total, false),
+        Some(range) => match parse_range(range, total) {
+            Some((start, end)) => (start, end, true),
+            None => {
+                let resp = builder
+                    .status(416)
+                    .header(CONTENT_RANGE, format!("bytes */{}", total))
+                    .body(Body::empty())?;
+                return Ok(resp);
+            },
+        },
+    };
+
+    let builder = if partial {
+        builder.status(206).header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end - 1, total))
+    } else {
+        builder.status(200)
+    };
+
+    let resp = builder
+        .header(CONTENT_LENGTH, end - start)
+        .body(body(start, end))?;
+
+    Ok(resp)
+}
+
+/*
+    The root of the tree, and on a partial response the leaves it touches with the nodes proving
+    them against the root. Leaves the range only covers in part are sent as `<index>=<hash>`,
+        x-lofty-merkle-edge-leaves: 2=9f86d0...,4=60303a...
+ */
+fn merkle_headers(tree: &MerkleTree, headers: &mut HeaderMap) {
+    let range = headers
+        .get(CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("bytes "))
+        .and_then(|value| value.split_once('/'))
+        .and_then(|(range, _)| range.split_once('-'))
+        .and_then(|(first, last)| Some((first.parse::<u64>().ok()?, last.parse::<u64>().ok()? + 1)));
+    let partial = range.and_then(|(start, end)| Some((start, end, tree.leaf_range(start, end)?)));
+
+    headers.insert(HeaderName::from_static("x-lofty-merkle-root"), tree.root().to_string().parse().unwrap());
+    headers.insert(HeaderName::from_static("x-lofty-merkle-block-size"), HeaderValue::from(tree.block_size));
+
+    if let Some((start, end, (first, last))) = partial {
+        let proof: Vec<_> = tree.proof(first, last).iter().map(ToString::to_string).collect();
+        headers.insert(HeaderName::from_static("x-lofty-merkle-leaves"), format!("{}-{}/{}", first, last, tree.leaves.len()).parse().unwrap());
+        headers.insert(HeaderName::from_static("x-lofty-merkle-proof"), proof.join(",").parse().unwrap());
+
+        let mut edges: Vec<_> = [first, last].into_iter().filter(|leaf| tree.cut_by(*leaf, start, end)).collect();
+        edges.dedup();
+        if !edges.is_empty() {
+            let edges: Vec<_> = edges.into_iter().map(|leaf| format!("{}={}", leaf, tree.leaves[leaf])).collect();
+            headers.insert(HeaderName::from_static("x-lofty-merkle-edge-leaves"), edges.join(",").parse().unwrap());
+        }
+    }
+}
+
+// a single `bytes=` range as the half open [start, end), None if it can't be satisfied
+pub fn parse_range(range: &str, total: u64) -> Option<(u64, u64)> {
+    let (first, last) = range.trim().strip_prefix("bytes=")?.split_once('-')?;
+
+    let (start, end) = match (first.trim(), last.trim()) {
+        ("", suffix) => (total.saturating_sub(suffix.parse().ok()?), total),
+        (first, "") => (first.parse().ok()?, total),
+        (first, last) => (first.parse().ok()?, last.parse::<u64>().ok()?.saturating_add(1).min(total)),
+    };
+
+    (start < end).then_some((start, end))
+}
@@ -0,0 +1,332 @@
+use std::{
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use axum::http::{header::{ACCEPT_ENCODING, CONTENT_ENCODING}, HeaderMap};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use serde::Deserialize;
+use tokio::{fs::File, io::AsyncReadExt};
+
+use crate::{errors::ErrorStates, FragmentError};
+
+
+// bytes read from disk per chunk of an encoded response
+const READ_CHUNK: usize = 64 * 1024;
+
+// Content codings understood on upload bodies and offered on downloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Coding {
+    Gzip,
+    Zstd,
+    Br,
+}
+
+impl Coding {
+    pub fn parse(token: &str) -> Option<Self> {
+        match token.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Coding::Gzip),
+            "zstd" => Some(Coding::Zstd),
+            "br" => Some(Coding::Br),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Coding::Gzip => "gzip",
+            Coding::Zstd => "zstd",
+            Coding::Br => "br",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct EncodingConfig {
+    // Content-Encoding accepted on upload bodies
+    pub requests: Vec<Coding>,
+    // offered on downloads in order of preference, empty serves identity only
+    pub responses: Vec<Coding>,
+    // responses of these content types are already compressed and sent as they are
+    pub skip_content_types: Vec<String>,
+}
+
+impl Default for EncodingConfig {
+    fn default() -> Self {
+        Self {
+            requests: vec![Coding::Gzip, Coding::Zstd, Coding::Br],
+            responses: vec![Coding::Zstd, Coding::Br, Coding::Gzip],
+            skip_content_types: ["image/", "video/", "audio/", "application/zip", "application/gzip", "application/zstd"]
+                .map(str::to_string)
+                .to_vec(),
+        }
+    }
+}
+
+pub type EncodingHandle = Arc<EncodingConfig>;
+
+impl EncodingConfig {
+    // decoder for the body of an upload, None for identity
+    pub fn decoder(&self, headers: &HeaderMap) -> Result<Option<Decoder>, FragmentError> {
+        let Some(value) = headers.get(CONTENT_ENCODING) else {
+            return Ok(None);
+        };
+        let value = value.to_str()?.trim();
+        if value.eq_ignore_ascii_case("identity") {
+            return Ok(None);
+        }
+
+        // stacked codings are not worth the trouble on an upload
+        match Coding::parse(value).filter(|coding| self.requests.contains(coding)) {
+            Some(coding) => Ok(Some(Decoder::new(coding)?)),
+            None => Err(ErrorStates::UnsupportedEncoding(value.to_string()).into()),
+        }
+    }
+
+    // the preferred coding the client accepts for a response of `content_type`
+    pub fn negotiate(&self, headers: &HeaderMap, content_type: Option<&str>) -> Option<Coding> {
+        let content_type = content_type.unwrap_or_default().to_ascii_lowercase();
+        if self.skip_content_types.iter().any(|skip| content_type.starts_with(skip.as_str())) {
+            return None;
+        }
+
+        let accepted: Vec<(String, f32)> = headers
+            .get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|entry| {
+                let mut parts = entry.split(';');
+                let token = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .and_then(|q| q.trim().parse().ok())
+                    .unwrap_or(1.0);
+                (token, quality)
+            })
+            .collect();
+
+        let quality = |coding: Coding| {
+            accepted
+                .iter()
+                .find(|(token, _)| Coding::parse(token) == Some(coding))
+                .or_else(|| accepted.iter().find(|(token, _)| token == "*"))
+                .map_or(0.0, |(_, quality)| *quality)
+        };
+
+        self.responses.iter().copied().find(|coding| quality(*coding) > 0.0)
+    }
+}
+
+// Output of a coder, shared so the bytes it produced can be taken between writes
+#[derive(Debug, Clone, Default)]
+struct Spool(Arc<Mutex<SpoolInner>>);
+
+#[derive(Debug, Default)]
+struct SpoolInner {
+    buf: Vec<u8>,
+    // decoded bytes an upload may still take, caps what a small compressed body can expand to
+    limit: Option<usize>,
+    exceeded: bool,
+}
+
+impl Spool {
+    fn take(&self) -> Bytes {
+        Bytes::from(std::mem::take(&mut self.0.lock().unwrap().buf))
+    }
+
+    fn set_limit(&self, limit: usize) {
+        self.0.lock().unwrap().limit = Some(limit);
+    }
+
+    fn exceeded(&self) -> bool {
+        self.0.lock().unwrap().exceeded
+    }
+}
+
+impl Write for Spool {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let mut inner = self.0.lock().unwrap();
+        if inner.limit.is_some_and(|limit| inner.buf.len() + data.len() > limit) {
+            inner.exceeded = true;
+            return Err(std::io::Error::other("decoded body exceeds the upload size"));
+        }
+        inner.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Decoding {
+    Gzip(flate2::write::MultiGzDecoder<Spool>),
+    Zstd(zstd::stream::write::Decoder<'static, Spool>),
+    Br(Box<brotli::DecompressorWriter<Spool>>),
+}
+
+// Streaming decoder of a request body, fed one wire chunk at a time
+pub struct Decoder {
+    coding: Coding,
+    decoding: Decoding,
+    spool: Spool,
+}
+
+impl std::fmt::Debug for Decoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Decoder").field("coding", &self.coding).finish()
+    }
+}
+
+impl Decoder {
+    fn new(coding: Coding) -> std::io::Result<Self> {
+        let spool = Spool::default();
+        let decoding = match coding {
+            Coding::Gzip => Decoding::Gzip(flate2::write::MultiGzDecoder::new(spool.clone())),
+            Coding::Zstd => Decoding::Zstd(zstd::stream::write::Decoder::new(spool.clone())?),
+            Coding::Br => Decoding::Br(Box::new(brotli::DecompressorWriter::new(spool.clone(), 4096))),
+        };
+        Ok(Self { coding, decoding, spool })
+    }
+
+    pub fn coding(&self) -> Coding {
+        self.coding
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match &mut self.decoding {
+            Decoding::Gzip(decoder) => decoder,
+            Decoding::Zstd(decoder) => decoder,
+            Decoding::Br(decoder) => decoder.as_mut(),
+        }
+    }
+
+    // the bytes `wire` decodes to, at most `limit` of them
+    pub async fn decode(mut self, wire: Bytes, limit: usize) -> (Self, Result<Bytes, FragmentError>) {
+        tokio::task::spawn_blocking(move || {
+            self.spool.set_limit(limit);
+            let written = self.writer().write_all(&wire).and_then(|_| self.writer().flush());
+            let decoded = self.outcome(written);
+            (self, decoded)
+        })
+        .await
+        .expect("decoder task panicked")
+    }
+
+    // the bytes still held by the decoder once the body ended
+    pub fn finish(mut self, limit: usize) -> Result<Bytes, FragmentError> {
+        self.spool.set_limit(limit);
+        let finished = match &mut self.decoding {
+            Decoding::Gzip(decoder) => decoder.try_finish(),
+            Decoding::Zstd(decoder) => decoder.flush(),
+            Decoding::Br(decoder) => decoder.flush(),
+        };
+        self.outcome(finished)
+    }
+
+    fn outcome(&self, result: std::io::Result<()>) -> Result<Bytes, FragmentError> {
+        match result {
+            Ok(()) => Ok(self.spool.take()),
+            Err(_) if self.spool.exceeded() => Err(ErrorStates::UploadSizeExceeded.into()),
+            Err(_) => Err(ErrorStates::MalformedEncoding.into()),
+        }
+    }
+}
+
+enum Encoding {
+    Gzip(flate2::write::GzEncoder<Spool>),
+    Zstd(zstd::stream::write::Encoder<'static, Spool>),
+    Br(Box<brotli::CompressorWriter<Spool>>),
+}
+
+impl Encoding {
+    fn new(coding: Coding, spool: Spool) -> std::io::Result<Self> {
+        Ok(match coding {
+            Coding::Gzip => Encoding::Gzip(flate2::write::GzEncoder::new(spool, flate2::Compression::default())),
+            Coding::Zstd => Encoding::Zstd(zstd::stream::write::Encoder::new(spool, 3)?),
+            Coding::Br => Encoding::Br(Box::new(brotli::CompressorWriter::new(spool, 4096, 5, 22))),
+        })
+    }
+
+    fn write_all(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+        match self {
+            Encoding::Gzip(encoder) => encoder.write_all(chunk),
+            Encoding::Zstd(encoder) => encoder.write_all(chunk),
+            Encoding::Br(encoder) => encoder.write_all(chunk),
+        }
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            Encoding::Gzip(encoder) => encoder.finish().map(drop),
+            Encoding::Zstd(encoder) => encoder.finish().map(drop),
+            Encoding::Br(encoder) => {
+                encoder.into_inner();
+                Ok(())
+            },
+        }
+    }
+}
+
+// the content of a plain file, chunk by chunk
+pub fn read_file(path: PathBuf) -> impl Stream<Item = std::io::Result<Bytes>> {
+    futures::stream::try_unfold(None::<File>, move |file| {
+        let path = path.clone();
+        async move {
+            let mut file = match file {
+                Some(file) => file,
+                None => File::open(&path).await?,
+            };
+            let mut chunk = vec![0u8; READ_CHUNK];
+            let read = file.read(&mut chunk).await?;
+            if read == 0 {
+                return Ok(None);
+            }
+            chunk.truncate(read);
+            Ok(Some((Bytes::from(chunk), Some(file))))
+        }
+    })
+}
+
+// `content` encoded with `coding`, the encoder runs on the blocking pool one chunk at a time
+pub fn encode(coding: Coding, content: impl Stream<Item = std::io::Result<Bytes>> + Send + 'static) -> impl Stream<Item = std::io::Result<Bytes>> {
+    let content = Box::pin(content);
+
+    futures::stream::try_unfold(Some((content, None::<(Encoding, Spool)>)), move |state| async move {
+        let Some((mut content, encoder)) = state else {
+            return Ok(None);
+        };
+        let (mut encoder, spool) = match encoder {
+            Some(encoder) => encoder,
+            None => {
+                let spool = Spool::default();
+                (Encoding::new(coding, spool.clone())?, spool)
+            },
+        };
+
+        match content.next().await {
+            Some(chunk) => {
+                let chunk = chunk?;
+                let (encoder, written) = tokio::task::spawn_blocking(move || {
+                    let written = encoder.write_all(&chunk);
+                    (encoder, written)
+                })
+                .await
+                .map_err(std::io::Error::other)?;
+                written?;
+                Ok(Some((spool.take(), Some((content, Some((encoder, spool)))))))
+            },
+            None => {
+                tokio::task::spawn_blocking(move || encoder.finish()).await.map_err(std::io::Error::other)??;
+                Ok(Some((spool.take(), None)))
+            },
+        }
+    })
+    // encoders hold back output until they have a block worth emitting
+    .filter(|chunk| futures::future::ready(!matches!(chunk, Ok(chunk) if chunk.is_empty())))
+}
@@ -0,0 +1,539 @@
+use std::{
+    collections::HashSet,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use futures::Stream;
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
+};
+use uuid::Uuid;
+
+use crate::{config::LoadConfig, errors::ErrorStates, storage::Storage, utils, FragmentError};
+
+
+/*
+    Encrypted uploads are a sequence of segments, each one
+        nonce (12 bytes) | ciphertext of at most `segment_size` bytes | tag (16 bytes)
+    sealed with the data key of the file. The associated data is the segment index followed by
+    a byte marking the last segment of the upload, so segments can neither be reordered nor
+    dropped from the end with what is left still passing for a complete file.
+    Every segment but the last holds exactly `segment_size` bytes, so a logical offset maps
+    straight to its segment. The plaintext length is the size the upload was declared with,
+    never derived from the file. Data keys are wrapped by the active master key of the keyfile.
+ */
+const TAG_LEN: usize = 16;
+const OVERHEAD: u64 = (NONCE_LEN + TAG_LEN) as u64;
+const KEY_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Cipher {
+    // kebab-case alone would spell it aes256-gcm, which registries written so far carry
+    #[default]
+    #[serde(rename = "aes-256-gcm", alias = "aes256-gcm")]
+    Aes256Gcm,
+    Chacha20Poly1305,
+}
+
+impl Cipher {
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Cipher::Aes256Gcm => &aead::AES_256_GCM,
+            Cipher::Chacha20Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    pub enabled: bool,
+    // master keys, created on first start, keep it away from the data directory and its backups
+    pub keyfile: PathBuf,
+    pub cipher: Cipher,
+    // plaintext bytes per segment, the granularity of seeks
+    pub segment_size: usize,
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            keyfile: PathBuf::from("./keys/master.key"),
+            cipher: Cipher::default(),
+            segment_size: 64 * 1024,
+        }
+    }
+}
+
+// Key material of an encrypted upload, persisted with its `FileObject`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub cipher: Cipher,
+    // master key the data key is wrapped with
+    pub key_id: String,
+    // hex of nonce | wrapped data key | tag
+    pub wrapped_key: String,
+    pub segment_size: usize,
+}
+
+impl Envelope {
+    fn stored_segment(&self) -> u64 {
+        self.segment_size as u64 + OVERHEAD
+    }
+}
+
+fn invalid_data(reason: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason.to_string())
+}
+
+fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    SystemRandom::new().fill(&mut bytes).expect("system random source unavailable");
+    bytes
+}
+
+fn seal(key: &LessSafeKey, aad: &[u8], plain: &[u8]) -> Vec<u8> {
+    let nonce = random::<NONCE_LEN>();
+    let mut sealed = Vec::with_capacity(plain.len() + OVERHEAD as usize);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(plain);
+
+    let tag = key
+        .seal_in_place_separate_tag(Nonce::assume_unique_for_key(nonce), Aad::from(aad), &mut sealed[NONCE_LEN..])
+        .expect("plaintext within the aead limits");
+    sealed.extend_from_slice(tag.as_ref());
+    sealed
+}
+
+fn open(key: &LessSafeKey, aad: &[u8], mut sealed: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    if sealed.len() < OVERHEAD as usize {
+        return Err(invalid_data("sealed data is shorter than its nonce and tag"));
+    }
+    let nonce = Nonce::try_assume_unique_for_key(&sealed[..NONCE_LEN]).map_err(|_| invalid_data("invalid nonce"))?;
+
+    let plain_len = key
+        .open_in_place(nonce, Aad::from(aad), &mut sealed[NONCE_LEN..])
+        .map_err(|_| invalid_data("sealed data failed authentication"))?
+        .len();
+    sealed.copy_within(NONCE_LEN..NONCE_LEN + plain_len, 0);
+    sealed.truncate(plain_len);
+    Ok(sealed)
+}
+
+/*
+    Master keys, one per line as `<id> <hex of 32 bytes>`, the last one wraps new data keys.
+    Older keys stay around until nothing in the registry is wrapped with them.
+ */
+#[derive(Default)]
+pub struct Keyring {
+    keys: Vec<(String, LessSafeKey)>,
+    raw: Vec<(String, [u8; KEY_LEN])>,
+}
+
+impl std::fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ids: Vec<_> = self.raw.iter().map(|(id, _)| id).collect();
+        f.debug_struct("Keyring").field("keys", &ids).finish()
+    }
+}
+
+impl Keyring {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut keyring = Self::default();
+
+        for line in contents.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let (id, key) = line.split_once(' ').ok_or_else(|| invalid_data("keyfile lines are `<id> <hex key>`"))?;
+            let key: [u8; KEY_LEN] = hex::decode(key.trim())
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .ok_or_else(|| invalid_data("master keys are 32 bytes of hex"))?;
+            keyring.push(id.to_string(), key);
+        }
+
+        if keyring.raw.is_empty() {
+            return Err(invalid_data("keyfile holds no master key"));
+        }
+        Ok(keyring)
+    }
+
+    fn push(&mut self, id: String, key: [u8; KEY_LEN]) {
+        let unbound = UnboundKey::new(&aead::AES_256_GCM, &key).expect("32 byte master key");
+        self.keys.push((id.clone(), LessSafeKey::new(unbound)));
+        self.raw.push((id, key));
+    }
+
+    // add a fresh master key and make it the active one
+    pub fn generate(&mut self) -> &str {
+        let id = format!("{}-{}", chrono::Utc::now().format("%Y%m%d"), hex::encode(random::<4>()));
+        self.push(id, random::<KEY_LEN>());
+        self.active_id()
+    }
+
+    pub fn active_id(&self) -> &str {
+        &self.raw.last().expect("keyring holds a master key").0
+    }
+
+    fn master(&self, id: &str) -> Result<&LessSafeKey, ErrorStates> {
+        self.keys
+            .iter()
+            .find(|(key_id, _)| key_id == id)
+            .map(|(_, key)| key)
+            .ok_or_else(|| ErrorStates::KeyUnavailable(id.to_string()))
+    }
+
+    // the file is replaced in one rename and only readable by its owner
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut contents = String::from("# lofty master keys, the last one is active\n");
+        for (id, key) in &self.raw {
+            contents.push_str(&format!("{} {}\n", id, hex::encode(key)));
+        }
+
+        let staging = path.with_extension("tmp");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        std::io::Write::write_all(&mut options.open(&staging)?, contents.as_bytes())?;
+        std::fs::rename(staging, path)
+    }
+
+    // drop the master keys nothing is wrapped with anymore, the active one always stays
+    pub fn retain(&mut self, used: &HashSet<String>) {
+        let active = self.active_id().to_string();
+        let keep = |id: &String| *id == active || used.contains(id);
+        self.keys.retain(|(id, _)| keep(id));
+        self.raw.retain(|(id, _)| keep(id));
+    }
+
+    // a data key for a new upload, wrapped with the active master key and bound to the upload
+    pub fn envelope(&self, uuid: &Uuid, cipher: Cipher, segment_size: usize) -> Envelope {
+        let key_id = self.active_id().to_string();
+        let master = self.master(&key_id).expect("active master key");
+        let wrapped = seal(master, uuid.as_bytes(), &random::<KEY_LEN>());
+
+        Envelope { cipher, key_id, wrapped_key: hex::encode(wrapped), segment_size }
+    }
+
+    // the key of an upload of `total` plaintext bytes, which segment is the last follows from it
+    pub fn data_key(&self, envelope: &Envelope, uuid: &Uuid, total: usize) -> Result<DataKey, FragmentError> {
+        let key = self.unwrap_key(envelope, uuid)?;
+        let unbound = UnboundKey::new(envelope.cipher.algorithm(), &key).map_err(|_| invalid_data("invalid data key"))?;
+
+        Ok(DataKey { key: Arc::new(LessSafeKey::new(unbound)), envelope: envelope.clone(), total: total as u64 })
+    }
+
+    fn unwrap_key(&self, envelope: &Envelope, uuid: &Uuid) -> Result<Vec<u8>, FragmentError> {
+        let master = self.master(&envelope.key_id)?;
+        let wrapped = hex::decode(&envelope.wrapped_key).map_err(|_| invalid_data("wrapped key is not hex"))?;
+        Ok(open(master, uuid.as_bytes(), wrapped)?)
+    }
+
+    // wrap the same data key with the active master key, the file contents are left alone
+    pub fn rewrap(&self, envelope: &Envelope, uuid: &Uuid) -> Result<Envelope, FragmentError> {
+        let key = self.unwrap_key(envelope, uuid)?;
+        let key_id = self.active_id().to_string();
+        let wrapped = seal(self.master(&key_id)?, uuid.as_bytes(), &key);
+
+        Ok(Envelope { key_id, wrapped_key: hex::encode(wrapped), ..envelope.clone() })
+    }
+}
+
+// holds the unwrapped master keys for as long as the server runs
+pub type EncryptionHandle = Arc<Encryption>;
+
+#[derive(Debug)]
+pub struct Encryption {
+    config: EncryptionConfig,
+    keyring: Keyring,
+}
+
+impl Encryption {
+    // a missing keyfile is only created once encryption is enabled, files sealed earlier stay readable either way
+    pub fn open(config: EncryptionConfig) -> Result<EncryptionHandle, FragmentError> {
+        let keyring = match Keyring::load(&config.keyfile) {
+            Ok(keyring) => keyring,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && config.enabled => {
+                let mut keyring = Keyring::default();
+                keyring.generate();
+                keyring.save(&config.keyfile)?;
+                tracing::info!(keyfile = %config.keyfile.display(), "master key created");
+                keyring
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Keyring::default(),
+            Err(e) => return Err(e.into()),
+        };
+        tracing::info!(enabled = config.enabled, keyring = ?keyring, "encryption configured");
+
+        Ok(Arc::new(Self { config, keyring }))
+    }
+
+    #[inline(always)]
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn envelope(&self, uuid: &Uuid) -> Envelope {
+        let segment_size = self.config.segment_size.clamp(1024, 16 * 1024 * 1024);
+        self.keyring.envelope(uuid, self.config.cipher, segment_size)
+    }
+
+    pub fn data_key(&self, envelope: &Envelope, uuid: &Uuid, total: usize) -> Result<DataKey, FragmentError> {
+        self.keyring.data_key(envelope, uuid, total)
+    }
+}
+
+// Unwrapped key of one upload
+#[derive(Clone)]
+pub struct DataKey {
+    key: Arc<LessSafeKey>,
+    envelope: Envelope,
+    // plaintext bytes of the upload
+    total: u64,
+}
+
+impl std::fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DataKey").field("key_id", &self.envelope.key_id).finish()
+    }
+}
+
+impl DataKey {
+    fn segment_size(&self) -> u64 {
+        self.envelope.segment_size as u64
+    }
+
+    fn stored_segment(&self) -> u64 {
+        self.envelope.stored_segment()
+    }
+
+    // segment index and whether it is the last one of the upload
+    fn aad(&self, index: u64) -> [u8; 9] {
+        let mut aad = [0u8; 9];
+        aad[..8].copy_from_slice(&index.to_be_bytes());
+        aad[8] = ((index + 1) * self.segment_size() >= self.total) as u8;
+        aad
+    }
+
+    fn seal_segment(&self, index: u64, plain: &[u8]) -> Vec<u8> {
+        seal(&self.key, &self.aad(index), plain)
+    }
+
+    fn open_segment(&self, index: u64, sealed: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        open(&self.key, &self.aad(index), sealed)
+    }
+}
+
+// plaintext bytes held by an encrypted file of `stored` bytes
+fn logical_len(stored: u64, envelope: &Envelope) -> u64 {
+    let full = stored / envelope.stored_segment();
+    let tail = stored % envelope.stored_segment();
+    full * envelope.segment_size as u64 + tail.saturating_sub(OVERHEAD)
+}
+
+// bytes of the upload that are on disk, as seen by resume, segments are authenticated once they are read back
+pub async fn durable_len(path: impl AsRef<Path>, envelope: &Envelope) -> std::io::Result<u64> {
+    Ok(logical_len(tokio::fs::metadata(path).await?.len(), envelope))
+}
+
+async fn read_segment(file: &mut File, key: &DataKey, index: u64, stored: u64) -> std::io::Result<Vec<u8>> {
+    let offset = index * key.stored_segment();
+    let len = key.stored_segment().min(stored.saturating_sub(offset));
+
+    let mut sealed = vec![0u8; len as usize];
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.read_exact(&mut sealed).await?;
+    key.open_segment(index, sealed)
+}
+
+// plaintext bytes of the encrypted upload, refused when its file holds fewer
+pub async fn plain_len(path: impl AsRef<Path>, key: &DataKey) -> std::io::Result<u64> {
+    if durable_len(path, &key.envelope).await? < key.total {
+        return Err(invalid_data("encrypted file is shorter than its upload"));
+    }
+    Ok(key.total)
+}
+
+// plaintext bytes `start..end` of an encrypted upload, opening only the segments involved
+pub fn read_range(path: PathBuf, key: DataKey, start: u64, end: u64) -> impl Stream<Item = std::io::Result<Bytes>> {
+    let first = start / key.segment_size();
+
+    futures::stream::try_unfold((None::<(File, u64)>, first), move |(file, index)| {
+        let path = path.clone();
+        let key = key.clone();
+        async move {
+            let offset = index * key.segment_size();
+            if offset >= end {
+                return Ok(None);
+            }
+            let (mut file, stored) = match file {
+                Some(file) => file,
+                None => {
+                    let file = File::open(&path).await?;
+                    let stored = file.metadata().await?.len();
+                    (file, stored)
+                },
+            };
+
+            let plain = read_segment(&mut file, &key, index, stored).await?;
+            let from = start.saturating_sub(offset) as usize;
+            let to = ((end - offset) as usize).min(plain.len());
+            if from >= to {
+                return Err(invalid_data("encrypted file ends before the requested range"));
+            }
+
+            Ok(Some((Bytes::from(plain).slice(from..to), (Some((file, stored)), index + 1))))
+        }
+    })
+}
+
+// Upload stream sealed segment by segment
+#[derive(Debug)]
+pub struct SealedSink {
+    file: BufWriter<File>,
+    key: DataKey,
+    // segment the pending bytes belong to
+    index: u64,
+    pending: Vec<u8>,
+    stored: u64,
+}
+
+impl SealedSink {
+    pub async fn create(path: impl AsRef<Path>, key: DataKey, buffer: usize) -> std::io::Result<Self> {
+        let file = BufWriter::with_capacity(buffer, utils::create_fresh(path).await?);
+        Ok(Self::new(file, key, 0, Vec::new()))
+    }
+
+    // reopen an interrupted upload, everything past the plaintext `pointer` is discarded
+    pub async fn resume(path: impl AsRef<Path>, pointer: u64, key: DataKey, buffer: usize) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path).await?;
+        let stored = file.metadata().await?.len();
+
+        let index = pointer / key.segment_size();
+        let cut = index * key.stored_segment();
+        if cut > stored {
+            return Err(invalid_data("resume offset is past the encrypted data"));
+        }
+
+        // the segment holding the pointer is cut off and its kept prefix goes back to pending
+        let keep = (pointer - index * key.segment_size()) as usize;
+        let pending = match keep {
+            0 => Vec::new(),
+            keep => {
+                let mut plain = read_segment(&mut file, &key, index, stored).await?;
+                if plain.len() < keep {
+                    return Err(invalid_data("resume offset is past the encrypted data"));
+                }
+                plain.truncate(keep);
+                plain
+            },
+        };
+
+        file.set_len(cut).await?;
+        file.seek(SeekFrom::Start(cut)).await?;
+        Ok(Self::new(BufWriter::with_capacity(buffer, file), key, index, pending))
+    }
+
+    fn new(file: BufWriter<File>, key: DataKey, index: u64, pending: Vec<u8>) -> Self {
+        let stored = index * key.stored_segment();
+        Self { file, key, index, pending, stored }
+    }
+
+    pub async fn write_all(&mut self, mut bytes: &[u8]) -> std::io::Result<()> {
+        let segment_size = self.key.segment_size() as usize;
+
+        while !bytes.is_empty() {
+            let take = (segment_size - self.pending.len()).min(bytes.len());
+            self.pending.extend_from_slice(&bytes[..take]);
+            bytes = &bytes[take..];
+
+            if self.pending.len() == segment_size {
+                self.write_pending().await?;
+            }
+        }
+        Ok(())
+    }
+
+    // a short segment is only written at a checkpoint, it is sealed again in place once it fills up
+    async fn write_pending(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let sealed = self.key.seal_segment(self.index, &self.pending);
+        self.file.write_all(&sealed).await?;
+        self.stored = self.index * self.key.stored_segment() + sealed.len() as u64;
+
+        if self.pending.len() as u64 == self.key.segment_size() {
+            self.index += 1;
+            self.pending.clear();
+        } else {
+            self.file.seek(SeekFrom::Start(self.index * self.key.stored_segment())).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn checkpoint(&mut self) -> std::io::Result<()> {
+        self.write_pending().await?;
+        self.file.flush().await?;
+        self.file.get_ref().sync_data().await
+    }
+
+    // bytes taken on disk, only exact right after a checkpoint
+    pub fn stored_len(&self) -> u64 {
+        self.stored
+    }
+}
+
+/*
+    `lofty rotate-keys`, to be run while the server is stopped since a running server
+    writes its own registry back on shutdown. A new master key becomes active, every data key
+    in the registry is wrapped with it and the master keys nothing refers to anymore are dropped.
+ */
+pub async fn rotate_keys(config: &LoadConfig, storage: &dyn Storage) -> Result<(), FragmentError> {
+    let _lock = utils::lock_data_dir(storage.data_dir())?;
+    let settings = config.section::<EncryptionConfig>("encryption");
+    let registry_path = storage.registry_path();
+    let handle = utils::load_registry(&registry_path).await?;
+
+    let mut keyring = Keyring::load(&settings.keyfile)?;
+    let active = keyring.generate().to_string();
+    // the old keys are kept until the rewrapped registry is on disk
+    keyring.save(&settings.keyfile)?;
+
+    let mut rewrapped = 0;
+    let jobs: Vec<_> = handle.iter().map(|job| job.clone()).collect();
+    for job in jobs {
+        let mut entry = job.lock().await;
+        let uuid = *entry.get_uuid();
+        if let Some(envelope) = entry.envelope() {
+            let envelope = keyring.rewrap(envelope, &uuid)?;
+            entry.set_envelope(Some(envelope));
+            rewrapped += 1;
+        }
+    }
+    utils::persist_registry(&handle, &registry_path).await?;
+
+    keyring.retain(&HashSet::new());
+    keyring.save(&settings.keyfile)?;
+
+    tracing::info!(key_id = %active, files = rewrapped, "master key rotated");
+    Ok(())
+}
@@ -36,11 +36,23 @@ where
     }   
 }
 
+// Attached to error responses so layers can tell which failure produced them
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorVariant(pub &'static str);
+
+// Stable code of the failure, see `ErrorStates::code`
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorCode(pub &'static str);
+
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
 #[derive(Debug)]
 pub struct ErrorReport { 
     reason: ErrorStates, 
     resp_code: StatusCode, 
-    headers: HeaderMap
+    headers: HeaderMap,
+    offset: Option<u64>,
+    request_id: Option<String>,
 }
 
 impl ErrorReport { 
@@ -48,20 +60,53 @@ impl ErrorReport {
         Self { 
             reason: state, 
             resp_code: StatusCode::BAD_REQUEST, 
-            headers: header
+            headers: header,
+            offset: None,
+            request_id: None,
         }
     }
 
+    // RFC 7807 problem document describing the failure
+    pub fn problem(&self) -> serde_json::Value { 
+        let code = self.reason.code(); 
+        let title = self.reason
+            .title()
+            .or(self.resp_code.canonical_reason())
+            .unwrap_or("Error"); 
+
+        let mut problem = serde_json::json!({
+            "type": format!("urn:lofty:error:{}", code),
+            "title": title,
+            "status": self.resp_code.as_u16(),
+            "detail": self.reason.to_string(),
+            "code": code,
+        });
+
+        if let Some(field) = self.reason.field() { 
+            problem["field"] = serde_json::Value::from(field);
+        }
+        if let Some(request_id) = &self.request_id { 
+            problem["request_id"] = serde_json::Value::from(request_id.as_str());
+        }
+        if let Some(offset) = self.offset { 
+            problem["offset"] = serde_json::Value::from(offset);
+        }
+
+        problem
+    }
+
     pub fn convert_to_response(self) -> Response<Body> { 
         
-        // let mut json_content = axum::Json::from(self.reason.http_message().unwrap());
-        let body = Body::from(self.reason.http_message().unwrap()); 
+        let body = serde_json::to_vec(&self.problem()).unwrap_or_default(); 
+        let body = Body::from(body); 
 
         let mut resp = Response::new(body);
         let mut headers = resp.headers_mut();
         
         headers.extend(self.headers); 
         *resp.status_mut() = self.resp_code; 
+        resp.extensions_mut().insert(ErrorVariant(self.reason.variant()));
+        resp.extensions_mut().insert(ErrorCode(self.reason.code()));
         
         resp
     }
@@ -69,6 +114,7 @@ impl ErrorReport {
 
 impl IntoResponse for FragmentError { 
     fn into_response(self) -> Response<Body> { 
+        self.trace_event(); 
         self.into_report().convert_to_response()
     }
 }
@@ -76,9 +122,11 @@ impl IntoResponse for FragmentError {
 
 #[derive(Debug)]
 pub struct FragmentError { 
-    // location: Location<'a>, 
+    location: &'static Location<'static>, 
     // error: E, 
     error_state: ErrorStates, 
+    // bytes of the upload durably received when the error occured
+    offset: Option<u64>,
     method: String, 
     trace: Backtrace
 }
@@ -92,13 +140,11 @@ impl<'a, E> std::convert::From<E> for FragmentError
     #[track_caller]
     fn from(err: E) -> FragmentError {
 
-        let loc = Location::caller(); 
-
-        println!("loc: {}", loc); 
         Self { 
-            // location: Location::caller().to_owned(), 
+            location: Location::caller(), 
             // error: err, 
             error_state: err.into(), 
+            offset: None,
             method: "".to_string(), //TODO function capture for the location
             trace: std::backtrace::Backtrace::capture()
         }
@@ -107,22 +153,54 @@ impl<'a, E> std::convert::From<E> for FragmentError
 
 
 impl<'a> FragmentError { 
+    #[inline(always)]
+    pub fn state(&self) -> &ErrorStates { 
+        &self.error_state
+    }
+
+    // attach the current upload offset so the client knows where to resume from
+    pub fn with_offset(mut self, offset: usize) -> Self { 
+        self.offset = Some(offset as u64);
+        self
+    }
+
+    // record the failure on the current request span before it is turned into a response
+    pub fn trace_event(&self) { 
+        if self.error_state.status().is_server_error() { 
+            tracing::error!(
+                variant = self.error_state.variant(), 
+                location = %self.location, 
+                backtrace = %self.trace, 
+                "{}", self.error_state
+            );
+        } else { 
+            tracing::warn!(
+                variant = self.error_state.variant(), 
+                location = %self.location, 
+                offset = self.offset, 
+                "{}", self.error_state
+            );
+        }
+    }
+
     pub fn into_report(self) -> ErrorReport { 
         
-        let statuscode = StatusCode::from_u16(
-            self.error_state
-                    .http_code()
-                    .unwrap_or(404)
-                ).unwrap(); 
+        let statuscode = self.error_state.status(); 
 
         let mut headers = vec![
-            (http::header::CONTENT_TYPE, HeaderValue::from_str("application/json").unwrap())
+            (http::header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_CONTENT_TYPE))
         ]; 
 
+        if let ErrorStates::TooManyRequests(retry_after) = self.error_state { 
+            headers.push((http::header::RETRY_AFTER, HeaderValue::from(retry_after)));
+        }
+
         ErrorReport { 
             reason: self.error_state, 
             resp_code: statuscode, 
-            headers: HeaderMap::from_iter(headers)
+            headers: HeaderMap::from_iter(headers),
+            offset: self.offset,
+            request_id: crate::telemetry::current_request_id(),
         }
     }
 }
@@ -145,23 +223,23 @@ pub enum ErrorStates {
     InternalError(#[from] axum::Error),
 
     
-    #[http(code = 404, message = "Missing Header Field")]
+    #[http(code = 400, message = "Invalid request headers")]
     #[error(transparent)]
     RequestError(#[from] HeaderErrors<'static>),
 
     
-    #[http(code = 404, message = "Incorrect body type")]
+    #[http(code = 400, message = "Incorrect body type")]
     #[error(transparent)]
     BodyContentError(#[from] BodyErrors<'static>),
 
     
-    #[http(code = 500, message = "string parsing error")]
-    #[error("internal parsing error")]
+    #[http(code = 400, message = "Header is not valid text")]
+    #[error("header contains non visible ascii characters")]
     ConvertionErr(#[from] ToStrError),
 
     
-    #[http(code = 404, message = "Invalid input recieved")]
-    #[error("invalid string present at")]
+    #[http(code = 400, message = "Invalid input recieved")]
+    #[error("invalid uuid: {0}")]
     UuidConvertionErr(#[from] uuid::Error),
 
     
@@ -175,6 +253,121 @@ pub enum ErrorStates {
     UndeclaredError,
 
     
+    #[http(code = 429, message = "Too many requests")]
+    #[error("rate limit exceeded, retry after {0}s")]
+    TooManyRequests(u64),
+
+    
+    #[http(code = 404, message = "Upload not found")]
+    #[error("no upload is registered under {0}")]
+    UploadNotFound(uuid::Uuid),
+
+    
+    #[http(code = 413, message = "Upload exceeds the declared size")]
+    #[error("received more bytes than the declared file size")]
+    UploadSizeExceeded,
+
+    
+    #[http(code = 400, message = "Upload incomplete")]
+    #[error("the body ended before the declared file size was reached")]
+    UploadIncomplete,
+
+    
+    #[http(code = 416, message = "Offset not satisfiable")]
+    #[error("Content-Pointer does not match a resumable offset")]
+    OffsetOutOfRange,
+
+    
+    #[http(code = 507, message = "Insufficient storage")]
+    #[error("not enough disk space to accept the upload")]
+    InsufficientStorage,
+
+    
+    #[http(code = 500, message = "server went into undesired mode")]
+    #[error("tls configuration error")]
+    TlsError(#[from] rustls::Error),
+
+    
+    #[http(code = 400, message = "Invalid query parameter")]
+    #[error("invalid value for query parameter {0:?}")]
+    InvalidQuery(&'static str),
+
+    
+    #[http(code = 409, message = "Upload is busy")]
+    #[error("upload {0} is being written, retry once it finished")]
+    UploadBusy(uuid::Uuid),
+
+    
+    #[http(code = 409, message = "File not available")]
+    #[error("upload {0} has not completed")]
+    FileUnavailable(uuid::Uuid),
+
+    
+    #[http(code = 500, message = "server went into undesired mode")]
+    #[error("master key {0} is not in the keyfile")]
+    KeyUnavailable(String),
+
+    
+    #[http(code = 415, message = "Unsupported content encoding")]
+    #[error("content encoding {0:?} is not accepted")]
+    UnsupportedEncoding(String),
+
+    
+    #[http(code = 400, message = "Malformed content encoding")]
+    #[error("request body does not decode as its content encoding")]
+    MalformedEncoding,
+
+    
+    #[http(code = 404, message = "Manifest not available")]
+    #[error("no integrity manifest was built for upload {0}")]
+    ManifestUnavailable(uuid::Uuid),
+
+    
+    #[http(code = 409, message = "Stored data is corrupt")]
+    #[error("the stored bytes no longer match their manifest, resume from the reported offset")]
+    StoredPrefixCorrupt,
+
+    
+    #[http(code = 400, message = "Unsupported checksum")]
+    #[error("{0} names no supported algorithm, use sha-256 or sha-512")]
+    UnsupportedChecksum(&'static str),
+
+    
+    #[http(code = 460, message = "Checksum mismatch")]
+    #[error("the request body does not match its {0}, retransmit it from the reported offset")]
+    ChecksumMismatch(&'static str),
+
+    
+    #[http(code = 503, message = "Server is shutting down")]
+    #[error("the server is draining and no longer accepts uploads")]
+    ShuttingDown,
+
+    
+    #[http(code = 401, message = "Unauthorized")]
+    #[error("the request carries no valid credentials")]
+    Unauthorized,
+
+    
+    #[http(code = 415, message = "Content type not allowed")]
+    #[error("uploads of type {0} are not accepted")]
+    ContentTypeNotAllowed(String),
+
+    
+    #[http(code = 422, message = "Invalid file name")]
+    #[error("file name refused: {0}")]
+    InvalidFileName(&'static str),
+
+    
+    #[http(code = 413, message = "Bundle too large")]
+    #[error("a bundle holds at most {0} files")]
+    BundleTooLarge(usize),
+
+    
+    #[http(code = 500, message = "server went into undesired mode")]
+    #[error("the data directory {0} is held by another lofty process")]
+    DataDirLocked(String),
+
+    
     // #[http(code = 500, message = "server went into undesired mode")]
     // #[error("internal socket Error")]
     // SocketError(#[from] ),
@@ -190,18 +383,143 @@ pub enum ErrorStates {
 }
 
 
+impl ErrorStates { 
+    pub fn variant(&self) -> &'static str { 
+        match self { 
+            ErrorStates::TaskError(_) => "TaskError",
+            ErrorStates::BufferError(_) => "BufferError",
+            ErrorStates::InternalError(_) => "InternalError",
+            ErrorStates::RequestError(_) => "RequestError",
+            ErrorStates::BodyContentError(_) => "BodyContentError",
+            ErrorStates::ConvertionErr(_) => "ConvertionErr",
+            ErrorStates::UuidConvertionErr(_) => "UuidConvertionErr",
+            ErrorStates::AxumHttpError(_) => "AxumHttpError",
+            ErrorStates::UndeclaredError => "UndeclaredError",
+            ErrorStates::TooManyRequests(_) => "TooManyRequests",
+            ErrorStates::UploadNotFound(_) => "UploadNotFound",
+            ErrorStates::UploadSizeExceeded => "UploadSizeExceeded",
+            ErrorStates::UploadIncomplete => "UploadIncomplete",
+            ErrorStates::OffsetOutOfRange => "OffsetOutOfRange",
+            ErrorStates::InsufficientStorage => "InsufficientStorage",
+            ErrorStates::TlsError(_) => "TlsError",
+            ErrorStates::InvalidQuery(_) => "InvalidQuery",
+            ErrorStates::UploadBusy(_) => "UploadBusy",
+            ErrorStates::FileUnavailable(_) => "FileUnavailable",
+            ErrorStates::KeyUnavailable(_) => "KeyUnavailable",
+            ErrorStates::UnsupportedEncoding(_) => "UnsupportedEncoding",
+            ErrorStates::MalformedEncoding => "MalformedEncoding",
+            ErrorStates::ManifestUnavailable(_) => "ManifestUnavailable",
+            ErrorStates::StoredPrefixCorrupt => "StoredPrefixCorrupt",
+            ErrorStates::UnsupportedChecksum(_) => "UnsupportedChecksum",
+            ErrorStates::ChecksumMismatch(_) => "ChecksumMismatch",
+            ErrorStates::ShuttingDown => "ShuttingDown",
+            ErrorStates::Unauthorized => "Unauthorized",
+            ErrorStates::ContentTypeNotAllowed(_) => "ContentTypeNotAllowed",
+            ErrorStates::InvalidFileName(_) => "InvalidFileName",
+            ErrorStates::BundleTooLarge(_) => "BundleTooLarge",
+            ErrorStates::DataDirLocked(_) => "DataDirLocked",
+        }
+    }
+
+    // stable machine readable identifier, clients may match on these
+    pub fn code(&self) -> &'static str { 
+        match self { 
+            ErrorStates::TaskError(_) => "task_failed",
+            ErrorStates::BufferError(_) => "io_error",
+            ErrorStates::InternalError(_) => "body_stream_error",
+            ErrorStates::RequestError(e) => e.code(),
+            ErrorStates::BodyContentError(e) => e.code(),
+            ErrorStates::ConvertionErr(_) => "header_encoding",
+            ErrorStates::UuidConvertionErr(_) => "uuid_invalid",
+            ErrorStates::AxumHttpError(_) => "response_build_failed",
+            ErrorStates::UndeclaredError => "internal_error",
+            ErrorStates::TooManyRequests(_) => "rate_limited",
+            ErrorStates::UploadNotFound(_) => "upload_not_found",
+            ErrorStates::UploadSizeExceeded => "upload_size_exceeded",
+            ErrorStates::UploadIncomplete => "upload_incomplete",
+            ErrorStates::OffsetOutOfRange => "offset_out_of_range",
+            ErrorStates::InsufficientStorage => "insufficient_storage",
+            ErrorStates::TlsError(_) => "tls_error",
+            ErrorStates::InvalidQuery(_) => "query_invalid",
+            ErrorStates::UploadBusy(_) => "upload_busy",
+            ErrorStates::FileUnavailable(_) => "file_unavailable",
+            ErrorStates::KeyUnavailable(_) => "key_unavailable",
+            ErrorStates::UnsupportedEncoding(_) => "encoding_unsupported",
+            ErrorStates::MalformedEncoding => "encoding_malformed",
+            ErrorStates::ManifestUnavailable(_) => "manifest_unavailable",
+            ErrorStates::StoredPrefixCorrupt => "stored_prefix_corrupt",
+            ErrorStates::UnsupportedChecksum(_) => "checksum_unsupported",
+            ErrorStates::ChecksumMismatch(_) => "checksum_mismatch",
+            ErrorStates::ShuttingDown => "shutting_down",
+            ErrorStates::Unauthorized => "unauthorized",
+            ErrorStates::ContentTypeNotAllowed(_) => "content_type_not_allowed",
+            ErrorStates::InvalidFileName(_) => "file_name_invalid",
+            ErrorStates::BundleTooLarge(_) => "bundle_too_large",
+            ErrorStates::DataDirLocked(_) => "data_dir_locked",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode { 
+        let code = match self { 
+            ErrorStates::RequestError(e) => e.http_code(),
+            ErrorStates::BodyContentError(e) => e.http_code(),
+            _ => None,
+        }; 
+
+        code.or(self.http_code())
+            .and_then(|code| StatusCode::from_u16(code).ok())
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    pub fn title(&self) -> Option<&'static str> { 
+        let message = match self { 
+            ErrorStates::RequestError(e) => e.http_message(),
+            ErrorStates::BodyContentError(e) => e.http_message(),
+            _ => None,
+        }; 
+
+        message.or(self.http_message())
+    }
+
+    // the request field the error points at, if any
+    pub fn field(&self) -> Option<&str> { 
+        match self { 
+            ErrorStates::RequestError(e) => e.field(),
+            ErrorStates::BodyContentError(e) => e.field(),
+            ErrorStates::UuidConvertionErr(_) 
+            | ErrorStates::UploadNotFound(_) 
+            | ErrorStates::UploadBusy(_) 
+            | ErrorStates::FileUnavailable(_) 
+            | ErrorStates::ManifestUnavailable(_) => Some("uuid"),
+            ErrorStates::UploadSizeExceeded | ErrorStates::UploadIncomplete => Some("Content-Length"),
+            ErrorStates::OffsetOutOfRange | ErrorStates::StoredPrefixCorrupt => Some("Content-Pointer"),
+            ErrorStates::UnsupportedEncoding(_) | ErrorStates::MalformedEncoding => Some("Content-Encoding"),
+            ErrorStates::InvalidFileName(_) => Some("FileName"),
+            ErrorStates::InvalidQuery(field) 
+            | ErrorStates::UnsupportedChecksum(field) 
+            | ErrorStates::ChecksumMismatch(field) => Some(field),
+            _ => None,
+        }
+    }
+}
+
+
 #[derive(Debug, thiserror::Error, HttpError)]
 pub enum HeaderErrors<'a> {
 
+    #[http(code = 400, message = "Missing header field")]
     #[error("Missing header field: {0:?}")]
     HeaderFieldMissing(Cow<'a, str>),
 
+    #[http(code = 409, message = "Header conflicts with the upload state")]
     #[error("Field mismatch: {0:?}")]
     FieldMismatch(Cow<'a, str>),
 
+    #[http(code = 422, message = "Invalid header value")]
     #[error("Invalid field input: {0:?}")]
     InvalidField(Cow<'a, str>),
 
+    #[http(code = 400, message = "Header is not valid text")]
     #[error("Invalid field input: {0:?}")]
     HeaderUnwrapError(#[from] http::header::ToStrError),
 
@@ -211,16 +529,57 @@ pub enum HeaderErrors<'a> {
 
 #[derive(Debug, thiserror::Error, HttpError)]
 pub enum BodyErrors<'a> { 
-    #[error("")]
+    #[http(code = 400, message = "Missing body field")]
+    #[error("Missing body field: {0:?}")]
     MissingField(Cow<'a, str>),
 
+    #[http(code = 422, message = "Missing body value")]
     #[error("Missing header value in field: {0:?}")]
     MissingValueField(Cow<'a, str>),
 
+    #[http(code = 422, message = "Invalid body value")]
     #[error("Invalid values in field: {0:?}")]
     InvalidValues(Cow<'a, str>),
 
 }
+
+impl<'a> HeaderErrors<'a> { 
+    pub fn code(&self) -> &'static str { 
+        match self { 
+            HeaderErrors::HeaderFieldMissing(_) => "header_missing",
+            HeaderErrors::FieldMismatch(_) => "header_mismatch",
+            HeaderErrors::InvalidField(_) => "header_invalid",
+            HeaderErrors::HeaderUnwrapError(_) => "header_encoding",
+        }
+    }
+
+    pub fn field(&self) -> Option<&str> { 
+        match self { 
+            HeaderErrors::HeaderFieldMissing(field) 
+            | HeaderErrors::FieldMismatch(field) 
+            | HeaderErrors::InvalidField(field) => Some(field.as_ref()),
+            HeaderErrors::HeaderUnwrapError(_) => None,
+        }
+    }
+}
+
+impl<'a> BodyErrors<'a> { 
+    pub fn code(&self) -> &'static str { 
+        match self { 
+            BodyErrors::MissingField(_) => "body_field_missing",
+            BodyErrors::MissingValueField(_) => "body_value_missing",
+            BodyErrors::InvalidValues(_) => "body_value_invalid",
+        }
+    }
+
+    pub fn field(&self) -> Option<&str> { 
+        match self { 
+            BodyErrors::MissingField(field) 
+            | BodyErrors::MissingValueField(field) 
+            | BodyErrors::InvalidValues(field) => Some(field.as_ref()),
+        }
+    }
+}
 // impl<'a, T> std::convert::From<T> for HeaderErrors<'a>
 // where T: std::error::Error { 
 
@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::Deserialize;
+
+use crate::{
+    file::UploadState,
+    handlers::JobHandle,
+    index::FileIndexHandle,
+    webhooks::{WebhookEvent, WebhooksHandle},
+};
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExpiryConfig {
+    // seconds an upload may stay incomplete before it is failed, 0 keeps them around forever
+    pub incomplete_after: u64,
+    // seconds between two sweeps
+    pub interval: u64,
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        Self { incomplete_after: 0, interval: 60 }
+    }
+}
+
+// fail uploads that never finished within `incomplete_after`, running transfers hold their entry and are left alone
+pub async fn sweep_periodically(config: ExpiryConfig, ext: JobHandle, index: FileIndexHandle, webhooks: WebhooksHandle) {
+    if config.incomplete_after == 0 {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+    loop {
+        interval.tick().await;
+
+        let cutoff = Utc::now() - chrono::Duration::seconds(config.incomplete_after as i64);
+        let stale = index.created_before(cutoff).into_iter().filter(|file| expires(file.state));
+        for file in stale {
+            let Some(job) = ext.get(&file.uuid).map(|job| job.clone()) else {
+                continue;
+            };
+            let Ok(mut file_obj) = job.try_lock() else {
+                continue;
+            };
+            if !expires(file_obj.get_state()) {
+                continue;
+            }
+
+            file_obj.set_state(UploadState::Failed);
+            tracing::info!(uuid = %file.uuid, created_at = %file.created_at, "incomplete upload expired");
+            webhooks.emit(WebhookEvent::Expired, &file_obj);
+        }
+    }
+}
+
+fn expires(state: UploadState) -> bool {
+    matches!(state, UploadState::UnInit | UploadState::Init | UploadState::Broken(_))
+}
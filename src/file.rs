@@ -1,9 +1,15 @@
-use std::{path::PathBuf, usize, borrow::Cow};
+use std::{path::PathBuf, usize, borrow::Cow, collections::{BTreeMap, BTreeSet}};
+use chrono::{DateTime, Utc};
 use serde::{Serialize, Deserialize}; 
+use sha2::Sha256;
 use tokio::sync::oneshot;
 use uuid::Uuid;
 
 use crate::errors::FragmentError;
+use crate::index::FileIndexHandle;
+use crate::compression::Compression;
+use crate::encryption::Envelope;
+use crate::merkle::MerkleTree;
 
 // use crate::errors::BackendErrors; 
 
@@ -14,8 +20,55 @@ pub struct FileObject {
     state: UploadState, 
     pub file_size: usize, 
     name: String, 
+    // the name as the upload request declared it, `name` is what the filename policy made of it
+    #[serde(default)]
+    raw_name: Option<String>,
     uuid: Uuid, 
     hash: Vec<u8>,
+    // registries written before these existed restore as created now, without owner or tags
+    #[serde(default = "Utc::now")]
+    created_at: DateTime<Utc>,
+    #[serde(default)]
+    tenant: Option<String>,
+    // client supplied key/value provenance and free form labels
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    tags: BTreeSet<String>,
+    // sha256 of the content once it is held by the blob store
+    #[serde(default)]
+    digest: Option<String>,
+    // declared by the upload request, drives the compression policy
+    #[serde(default)]
+    content_type: Option<String>,
+    // sniffed from the first bytes of the content, what it is served as
+    #[serde(default)]
+    detected_type: Option<String>,
+    #[serde(default)]
+    compression: Compression,
+    // bytes taken on disk, `file_size` stays the logical size
+    #[serde(default)]
+    stored_size: usize,
+    // wrapped data key of an encrypted upload
+    #[serde(default)]
+    encryption: Option<Envelope>,
+    // leaves over the logical bytes, the whole upload once complete or the durable prefix of a broken one
+    #[serde(default)]
+    merkle: Option<MerkleTree>,
+    // crc32 of the logical bytes taken as they are written so archives don't read them again,
+    // of the whole upload once complete or the durable prefix of a broken one
+    #[serde(default)]
+    crc32: Option<u32>,
+    // sha256 of the logical bytes taken as they are written so the blob store doesn't read them again,
+    // like the crc but only kept in memory, a restarted server hashes the stored content instead
+    #[serde(skip)]
+    sha256: Option<Sha256>,
+    // why the post upload hooks or the scanner turned the upload down
+    #[serde(default)]
+    rejection: Option<String>,
+    // listing index kept in step with the state of the object
+    #[serde(skip)]
+    index: Option<FileIndexHandle>,
 }
 
 impl FileObject { 
@@ -32,14 +85,154 @@ impl FileObject {
             state: UploadState::UnInit, 
             file_size: size, 
             name: name.to_string(),
+            raw_name: None,
             uuid: Uuid::new_v4(), 
-            hash: vec_hash
+            hash: vec_hash,
+            created_at: Utc::now(),
+            tenant: None,
+            metadata: BTreeMap::new(),
+            tags: BTreeSet::new(),
+            digest: None,
+            content_type: None,
+            detected_type: None,
+            compression: Compression::None,
+            stored_size: 0,
+            encryption: None,
+            merkle: None,
+            crc32: None,
+            sha256: None,
+            rejection: None,
+            index: None,
         }
     }
 
+    pub fn with_tenant(mut self, tenant: impl ToString) -> Self { 
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    pub fn with_labels(mut self, metadata: BTreeMap<String, String>, tags: BTreeSet<String>) -> Self { 
+        self.metadata = metadata;
+        self.tags = tags;
+        self
+    }
+
+    // merge metadata, a `None` value drops the key, and replace the tags when given
+    pub fn update_labels(&mut self, metadata: BTreeMap<String, Option<String>>, tags: Option<BTreeSet<String>>) { 
+        for (key, value) in metadata { 
+            match value { 
+                Some(value) => { self.metadata.insert(key, value); },
+                None => { self.metadata.remove(&key); },
+            }
+        }
+        if let Some(tags) = tags { 
+            self.tags = tags;
+        }
+        self.reindex();
+    }
+
+    // replace metadata and tags as a whole, as a post upload hook left them
+    pub fn set_labels(&mut self, metadata: BTreeMap<String, String>, tags: BTreeSet<String>) { 
+        self.metadata = metadata;
+        self.tags = tags;
+        self.reindex();
+    }
+
+    // a scheduled upload is named by its declared hash until the transfer names it
+    pub fn set_name(&mut self, name: String) { 
+        self.name = name;
+        self.reindex();
+    }
+
+    pub fn set_raw_name(&mut self, raw_name: String) { 
+        self.raw_name = Some(raw_name);
+        self.reindex();
+    }
+
+    pub fn set_digest(&mut self, digest: String) { 
+        self.digest = Some(digest);
+        self.reindex();
+    }
+
+    pub fn take_digest(&mut self) -> Option<String> { 
+        let digest = self.digest.take();
+        self.reindex();
+        digest
+    }
+
+    // how the content is laid out on disk, fixed when the stream is opened
+    pub fn set_storage(&mut self, compression: Compression, content_type: Option<String>) { 
+        self.compression = compression;
+        self.content_type = content_type;
+        self.reindex();
+    }
+
+    pub fn set_detected_type(&mut self, detected_type: &str) { 
+        self.detected_type = Some(detected_type.to_string());
+        self.reindex();
+    }
+
+    // rotation swaps the envelope, the file contents stay as they are
+    pub fn set_envelope(&mut self, envelope: Option<Envelope>) { 
+        self.encryption = envelope;
+        self.reindex();
+    }
+
+    pub fn set_stored_size(&mut self, stored_size: usize) { 
+        self.stored_size = stored_size;
+        if let Some(index) = &self.index { 
+            index.set_stored_size(&self.uuid, stored_size);
+        }
+    }
+
+    pub fn set_merkle(&mut self, merkle: Option<MerkleTree>) { 
+        self.merkle = merkle;
+        self.reindex();
+    }
+
+    pub fn set_crc32(&mut self, crc32: Option<u32>) { 
+        self.crc32 = crc32;
+        self.reindex();
+    }
+
+    pub fn set_sha256(&mut self, sha256: Option<Sha256>) { 
+        self.sha256 = sha256;
+    }
+
+    pub fn take_sha256(&mut self) -> Option<Sha256> { 
+        self.sha256.take()
+    }
+
+    pub fn set_rejection(&mut self, reason: String) { 
+        self.rejection = Some(reason);
+        self.reindex();
+    }
+
+    fn reindex(&self) { 
+        if let Some(index) = &self.index { 
+            index.upsert(self);
+        }
+    }
+
+    // register the object with the listing index, later state changes are forwarded to it
+    pub fn attach_index(&mut self, index: FileIndexHandle) { 
+        index.upsert(self);
+        self.index = Some(index);
+    }
+
     #[inline(always)]
     pub fn set_state(&mut self, state: UploadState) {
+        // progress updates land on every chunk, only transitions between kinds are worth an event
+        if std::mem::discriminant(&self.state) != std::mem::discriminant(&state) { 
+            tracing::info!(uuid = %self.uuid, from = ?self.state, to = ?state, "upload state transition");
+        }
+        let streaming = matches!((self.state, state), (UploadState::Progress(_), UploadState::Progress(_)) | (UploadState::Resume(_), UploadState::Resume(_)));
         self.state = state; 
+
+        // the offset of a running stream stays out of the index, listings see where it started and where it stopped
+        if let (Some(index), false) = (&self.index, streaming) { 
+            index.set_state(&self.uuid, state);
+        }
     }
     
     #[inline(always)]
@@ -47,6 +240,111 @@ impl FileObject {
         self.state 
     }
 
+    // bytes of the file known to be on disk for the current state
+    pub fn received(&self) -> usize { 
+        match self.state { 
+            UploadState::Broken(n) | UploadState::Progress(n) | UploadState::Resume(n) => n,
+            UploadState::Complete | UploadState::Processing | UploadState::Rejected | UploadState::Clean | UploadState::Quarantined => self.file_size,
+            UploadState::UnInit | UploadState::Init | UploadState::Failed => 0,
+        }
+    }
+
+    // an upload caught mid stream by an unclean exit is resumable from what reached the disk
+    pub fn recover(&mut self, on_disk: usize) { 
+        match self.state { 
+            UploadState::Init | UploadState::Progress(_) | UploadState::Resume(_) | UploadState::Broken(_) => { 
+                // the crc was kept for the offset in the registry, a shorter prefix on disk no longer matches it
+                if !matches!(self.state, UploadState::Broken(n) if n <= on_disk) { 
+                    self.crc32 = None;
+                }
+                self.state = UploadState::Broken(on_disk.min(self.received()));
+            },
+            // the hooks of an upload still processing run again on start
+            UploadState::UnInit | UploadState::Complete | UploadState::Processing | UploadState::Rejected 
+                | UploadState::Clean | UploadState::Quarantined | UploadState::Failed => {}
+        }
+    }
+
+    #[inline(always)]
+    pub fn name(&self) -> &str { 
+        &self.name
+    }
+
+    #[inline(always)]
+    pub fn raw_name(&self) -> Option<&str> { 
+        self.raw_name.as_deref()
+    }
+
+    #[inline(always)]
+    pub fn hash(&self) -> &[u8] { 
+        &self.hash
+    }
+
+    #[inline(always)]
+    pub fn created_at(&self) -> DateTime<Utc> { 
+        self.created_at
+    }
+
+    #[inline(always)]
+    pub fn tenant(&self) -> Option<&str> { 
+        self.tenant.as_deref()
+    }
+
+    #[inline(always)]
+    pub fn digest(&self) -> Option<&str> { 
+        self.digest.as_deref()
+    }
+
+    #[inline(always)]
+    pub fn compression(&self) -> Compression { 
+        self.compression
+    }
+
+    #[inline(always)]
+    pub fn content_type(&self) -> Option<&str> { 
+        self.content_type.as_deref()
+    }
+
+    #[inline(always)]
+    pub fn detected_type(&self) -> Option<&str> { 
+        self.detected_type.as_deref()
+    }
+
+    #[inline(always)]
+    pub fn stored_size(&self) -> usize { 
+        self.stored_size
+    }
+
+    #[inline(always)]
+    pub fn envelope(&self) -> Option<&Envelope> { 
+        self.encryption.as_ref()
+    }
+
+    #[inline(always)]
+    pub fn merkle(&self) -> Option<&MerkleTree> { 
+        self.merkle.as_ref()
+    }
+
+    #[inline(always)]
+    pub fn crc32(&self) -> Option<u32> { 
+        self.crc32
+    }
+
+    #[inline(always)]
+    pub fn rejection(&self) -> Option<&str> { 
+        self.rejection.as_deref()
+    }
+
+    #[inline(always)]
+    pub fn metadata(&self) -> &BTreeMap<String, String> { 
+        &self.metadata
+    }
+
+    #[inline(always)]
+    pub fn tags(&self) -> &BTreeSet<String> { 
+        &self.tags
+    }
+
     #[inline(always)]
     pub fn get_uuid(&self) -> Cow<'_, Uuid> { 
         Cow::Borrowed(&(self.uuid))
@@ -87,7 +385,7 @@ pub mod file_drop_handler {
 
 
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum UploadState {
     UnInit, 
     Init, 
@@ -95,9 +393,36 @@ pub enum UploadState {
     Progress(usize),
     Resume(usize), 
     Complete, 
+    // every byte arrived, the post upload hooks are still running
+    Processing,
+    // a post upload hook turned the content down, it is kept but never served
+    Rejected,
+    // the scanner passed the content, the only complete state served while scanning is enabled
+    Clean,
+    // the scanner found something or could not check the content, it is kept but never served
+    Quarantined,
     Failed, 
 }
 
+impl UploadState { 
+    // name of the state without its offset, as used by the listing filters
+    pub fn kind(&self) -> &'static str { 
+        match self { 
+            UploadState::UnInit => "uninit",
+            UploadState::Init => "init",
+            UploadState::Broken(_) => "broken",
+            UploadState::Progress(_) => "progress",
+            UploadState::Resume(_) => "resume",
+            UploadState::Complete => "complete",
+            UploadState::Processing => "processing",
+            UploadState::Rejected => "rejected",
+            UploadState::Clean => "clean",
+            UploadState::Quarantined => "quarantined",
+            UploadState::Failed => "failed",
+        }
+    }
+}
+
 
 
 #[derive(Debug)]
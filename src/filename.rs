@@ -0,0 +1,196 @@
+use std::{borrow::Cow, sync::Arc};
+
+use axum::http::{HeaderMap, HeaderValue};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::errors::{ErrorStates, HeaderErrors};
+
+
+// takes over from `FileName` when present, `UTF-8''<percent encoded name>` as in RFC 5987
+pub const EXTENDED_HEADER: &str = "filename*";
+
+// stands in for a name that sanitizes down to nothing
+const FALLBACK_NAME: &str = "upload";
+
+// extensions up to this many bytes survive a truncation
+const MAX_EXTENSION: usize = 16;
+
+// device names Windows resolves whatever the extension, `con.txt` included
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// attr-char of RFC 5987, everything else is percent encoded
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!').remove(b'#').remove(b'$').remove(b'&').remove(b'+').remove(b'-')
+    .remove(b'.').remove(b'^').remove(b'_').remove(b'`').remove(b'|').remove(b'~');
+
+/*
+    [filenames]
+    max_length = 255
+    on_invalid = "rewrite"      # or "reject"
+    replacement = "_"
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FilenamePolicy {
+    // bytes the stored name may take once encoded as UTF-8
+    pub max_length: usize,
+    pub on_invalid: OnInvalid,
+    // put in place of path separators and in front of reserved names
+    pub replacement: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnInvalid {
+    // store the cleaned up name, the one sent is kept next to it
+    #[default]
+    Rewrite,
+    // refuse the upload with the reason the name is not acceptable
+    Reject,
+}
+
+impl Default for FilenamePolicy {
+    fn default() -> Self {
+        Self { max_length: 255, on_invalid: OnInvalid::Rewrite, replacement: "_".to_string() }
+    }
+}
+
+pub type FilenamePolicyHandle = Arc<FilenamePolicy>;
+
+impl FilenamePolicy {
+    /*
+        The name an upload is stored and served under. Names are NFC normalized, which never
+        counts as a rewrite. Path separators are replaced, control and bidi formatting characters
+        dropped, surrounding spaces and trailing dots trimmed, reserved device names prefixed and
+        overlong names cut back, keeping their extension.
+     */
+    pub fn sanitize(&self, raw: &str) -> Result<String, ErrorStates> {
+        match (self.clean(raw), self.on_invalid) {
+            ((_, Some(reason)), OnInvalid::Reject) => Err(ErrorStates::InvalidFileName(reason)),
+            ((name, _), _) => Ok(name),
+        }
+    }
+
+    // the cleaned up name whatever `on_invalid` says, for names written into archives
+    pub fn rewrite(&self, raw: &str) -> String {
+        self.clean(raw).0
+    }
+
+    // the cleaned up name and the first reason it had to change, if any
+    fn clean(&self, raw: &str) -> (String, Option<&'static str>) {
+        let normalized: String = raw.nfc().collect();
+        let replacement: String = self.replacement.chars().filter(|c| !is_separator(*c) && !is_hidden(*c)).collect();
+        let mut refused = None;
+
+        let mut name = String::with_capacity(normalized.len());
+        for c in normalized.chars() {
+            if is_separator(c) {
+                refused.get_or_insert("contains a path separator");
+                name.push_str(&replacement);
+            } else if is_hidden(c) {
+                refused.get_or_insert("contains control characters");
+            } else {
+                name.push(c);
+            }
+        }
+
+        let trimmed = name.trim().trim_end_matches(['.', ' ']);
+        if trimmed.len() != name.len() {
+            refused.get_or_insert("starts or ends with spaces or dots");
+            name = trimmed.to_string();
+        }
+
+        if name.is_empty() {
+            refused.get_or_insert("is empty");
+            name = FALLBACK_NAME.to_string();
+        }
+
+        let stem = name.split('.').next().unwrap_or_default().trim_end();
+        if RESERVED.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+            refused.get_or_insert("is a reserved device name");
+            name = format!("{}{}", replacement, name);
+        }
+
+        let max_length = self.max_length.max(1);
+        if name.len() > max_length {
+            refused.get_or_insert("is too long");
+            name = truncate(&name, max_length);
+        }
+
+        (name, refused)
+    }
+}
+
+fn is_separator(c: char) -> bool {
+    matches!(c, '/' | '\\')
+}
+
+// characters that render as nothing or reorder what follows them, e.g. `invoice\u{202E}fdp.exe`
+fn is_hidden(c: char) -> bool {
+    c.is_control() || matches!(c, '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}')
+}
+
+// cut `name` back to `max_length` bytes on a character boundary, a short extension is kept
+fn truncate(name: &str, max_length: usize) -> String {
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= MAX_EXTENSION && name.len() - dot < max_length => name.split_at(dot),
+        _ => (name, ""),
+    };
+
+    let mut end = max_length - extension.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], extension)
+}
+
+// the name an upload request declares, `FileName` may carry raw UTF-8 while `FileName*` is encoded
+pub fn requested_name(plain: &HeaderValue, headers: &HeaderMap) -> Result<String, HeaderErrors<'static>> {
+    if let Some(extended) = headers.get(EXTENDED_HEADER) {
+        return extended
+            .to_str()
+            .ok()
+            .and_then(decode_extended)
+            .ok_or(HeaderErrors::InvalidField(Cow::Borrowed("FileName*")));
+    }
+
+    String::from_utf8(plain.as_bytes().to_vec()).map_err(|_| HeaderErrors::InvalidField(Cow::Borrowed("FileName")))
+}
+
+// `charset'language'value` of RFC 5987, both charsets it requires are understood
+fn decode_extended(value: &str) -> Option<String> {
+    let mut parts = value.trim().splitn(3, '\'');
+    let (charset, _language, encoded) = (parts.next()?, parts.next()?, parts.next()?);
+
+    let bytes = percent_decode_str(encoded);
+    match charset {
+        _ if charset.eq_ignore_ascii_case("utf-8") => bytes.decode_utf8().ok().map(Cow::into_owned),
+        _ if charset.eq_ignore_ascii_case("iso-8859-1") => Some(bytes.map(char::from).collect()),
+        _ => None,
+    }
+}
+
+// `attachment` with a plain ASCII `filename` for old clients and the exact name in `filename*` when they differ
+pub fn content_disposition(name: &str) -> HeaderValue {
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            ' '..='~' => c,
+            _ => '_',
+        })
+        .collect();
+
+    let mut disposition = format!("attachment; filename=\"{}\"", fallback);
+    if fallback != name {
+        disposition.push_str("; filename*=UTF-8''");
+        disposition.extend(utf8_percent_encode(name, ATTR_CHAR));
+    }
+    HeaderValue::from_str(&disposition).unwrap_or(HeaderValue::from_static("attachment"))
+}
@@ -3,27 +3,53 @@ use std::{sync::Arc, str::FromStr, borrow::Cow, io::Read, collections::HashMap,
 use axum_core::response::IntoResponse;
 use bytes::Bytes;
 use dashmap::DashMap; 
-use axum::{http::{Request, HeaderValue, header::{*}, Response, request}, body::{Body, HttpBody}, Extension};
+use axum::{http::{Request, HeaderValue, header::{*}, Response, request}, body::{Body, HttpBody}, extract::Path, Extension};
 use serde_json::json;
 use uuid::Uuid;
 use futures::stream::StreamExt;
-use tokio::io::AsyncWriteExt;
-use crate::{errors::{OptionExt, HeaderErrors, ErrorStates}, authorization::extract_header_fields}; 
+use sha2::{Digest, Sha256};
+use crate::{errors::{OptionExt, HeaderErrors, ErrorStates}, authorization::{extract_header_fields, Principal}, limiter::RateLimiter, metrics::{MetricsHandle, UploadTracker}, audit::{AuditAction, AuditScope}, shutdown::ShutdownHandle, index::FileIndexHandle, metadata::MetadataHandle, store::BlobStoreHandle, compression::{Compression, CompressionHandle}, encryption::EncryptionHandle, encoding::{self, Decoder, EncodingHandle}, checksum::Checksums, merkle::{MerkleBuilder, MerkleHandle}, storage::StorageHandle, hooks::HooksHandle, webhooks::{WebhookEvent, WebhooksHandle}, sniff::{self, ContentGate, ContentPolicyHandle}, filename::{self, FilenamePolicyHandle}, index::IndexedFile}; 
 
-use crate::{file::{FileObject, UploadState}, utils::generate_file, FragmentError};
+use crate::{file::{FileObject, UploadState}, FragmentError};
 
 use self::schedule_upload_process::BodyContent;
 
 
 // Task handle for keeping track of all the spawned instance on the runtime
-pub type JobHandle = Arc<DashMap<Uuid, FileObject>>; 
+pub type JobHandle = Arc<DashMap<Uuid, Job>>; 
+
+// an upload holds the lock of its own entry while it streams, the map is only ever held long enough to clone it
+pub type Job = Arc<tokio::sync::Mutex<FileObject>>;
+
+pub fn job(file_obj: FileObject) -> Job { 
+    Arc::new(tokio::sync::Mutex::new(file_obj))
+}
+
+// the entry of `uuid` to write to, a second request for an upload that is being written is turned away
+fn checkout(ext: &JobHandle, uuid: Uuid) -> Result<tokio::sync::OwnedMutexGuard<FileObject>, ErrorStates> { 
+    let job = ext.get(&uuid).map(|job| job.clone()).ok_or(ErrorStates::UploadNotFound(uuid))?;
+    job.try_lock_owned().map_err(|_| ErrorStates::UploadBusy(uuid))
+}
 
 pub async fn schedule_upload_process(
     ext: Extension<JobHandle>,
-    req: Request<HashMap<String, String>>, 
+    Extension(metrics): Extension<MetricsHandle>,
+    Extension(shutdown): Extension<ShutdownHandle>,
+    Extension(index): Extension<FileIndexHandle>,
+    Extension(metadata): Extension<MetadataHandle>,
+    Extension(store): Extension<BlobStoreHandle>,
+    Extension(encryption): Extension<EncryptionHandle>,
+    Extension(storage): Extension<StorageHandle>,
+    Extension(hooks): Extension<HooksHandle>,
+    Extension(webhooks): Extension<WebhooksHandle>,
+    Extension(policy): Extension<ContentPolicyHandle>,
+    principal: Principal,
+    audit: AuditScope,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> Result<Response<axum::body::Body>, FragmentError> {
     /*
-        Request: 
+        Request: POST /schedule_upload
             headers: { 
 
             }
@@ -39,8 +65,10 @@ pub async fn schedule_upload_process(
             body: { status: Denied, Reason: "out of disk space"}
      */
 
-    let (parts, body) = req.into_parts(); 
-    let headers = parts.headers; 
+    audit.action(AuditAction::Schedule);
+    shutdown.ensure_accepting()?;
+
+    let body = schedule_upload_process::parse_body(&body)?;
 
     let headers_names = [
         // "FileName",
@@ -57,14 +85,18 @@ pub async fn schedule_upload_process(
 
     // Check Disk space
     // Check server condition
-    let allocated_disk_space = schedule_upload_process::get_allocated_disk_space(100_000); 
+    let allocated_disk_space = storage.has_room(100_000); 
     let server_condition = schedule_upload_process::get_server_condition(); 
 
     let (allocated_disk_space, server_condition) = tokio::join!(allocated_disk_space, server_condition); 
     
     let mut resp = Response::builder(); 
     
-    let resp = if allocated_disk_space.is_none() || server_condition.is_none() {
+    if !allocated_disk_space { 
+        return Err(ErrorStates::InsufficientStorage.into());
+    }
+
+    let resp = if server_condition.is_none() {
 
         let mut status = "Denied"; 
 
@@ -87,22 +119,72 @@ pub async fn schedule_upload_process(
         let mut extracted_body: BodyContent = schedule_upload_process::process_body(body)?;
 
         // Init file uploader for 
-        let mut uid = uuid::Uuid::new_v4(); 
         let mut file_size = extracted_body.1;
+        // the body only declares the hash, it stands in for the name until the upload carries one
         let mut file_name = extracted_body.0; 
-        let path = "./data"; // TODO: modify this part
+        let path = storage.data_dir();
         
-        let file_obj = FileObject::new(path, file_size as usize, file_name.to_string(), Some(uid)); 
+        let labels = metadata.labels(&headers)?;
+        let mut file_obj = FileObject::new(path, file_size as usize, file_name.to_string(), Some(&file_name))
+            .with_tenant(&principal)
+            .with_labels(labels.metadata, labels.tags); 
+        let uid = *file_obj.get_uuid();
+
+        // the declared hash names a blob already held, nothing has to be transferred
+        // blobs are stored in plaintext, an encrypting server never links uploads to them
+        let declared = file_name.to_ascii_lowercase();
+        let claimed = match encryption.enabled() { 
+            true => None,
+            false => store.claim(&declared, file_size as usize, file_obj.tenant(), &file_obj.output_file_path())?,
+        };
+        let skipped = claimed.is_some();
+        if let Some((compression, stored_size, crc32)) = claimed { 
+            status = "Complete";
+            file_obj.set_storage(compression, None);
+            file_obj.set_stored_size(stored_size);
+            file_obj.set_crc32(crc32);
+            file_obj.set_state(UploadState::Complete);
+            file_obj.set_digest(declared.clone());
+
+            // a linked blob never went through the stream, the policy of this tenant still applies to it
+            let prefix = sniff::stored_prefix(&IndexedFile::from(&file_obj), &encryption, sniff::SNIFF_LEN).await?;
+            if let Some(detected) = sniff::detect(&prefix, true).filter(|_| !prefix.is_empty()) { 
+                if let Err(e) = policy.admit(file_obj.tenant(), detected) { 
+                    tracing::info!(uuid = %uid, detected, "linked content refused by the content policy");
+                    std::fs::remove_file(file_obj.output_file_path())?;
+                    store.release(&declared, compression, file_obj.tenant())?;
+                    return Err(e.into());
+                }
+                file_obj.set_detected_type(detected);
+            }
+            tracing::info!(uuid = %uid, "upload skipped, content already stored");
+        }
+
+        metrics.record_job_inserted();
+        file_obj.attach_index(index.clone());
+        let scheduled = job(file_obj);
+        ext.insert(uid, scheduled.clone());
+        let mut update_handle = scheduled.lock().await;
 
-        // let mut ext_file_obj = guard_with_error( file_obj, |e| { });
-        // return error if stream is already present
-        let mut update_handle = ext
-            .entry(*(file_obj.get_uuid()).to_owned())
-            .or_insert(file_obj); 
+        webhooks.emit(WebhookEvent::Created, &update_handle);
+
+        // known content still goes through the hooks, they judge the upload as much as its bytes
+        if skipped { 
+            hooks.dispatch(&mut update_handle, &ext);
+            match update_handle.get_state() { 
+                UploadState::Processing => status = "Processing",
+                _ => webhooks.emit(WebhookEvent::Completed, &update_handle),
+            }
+        }
+
+        tracing::Span::current().record("uuid", uid.to_string());
+        audit.file(uid, Some(&file_name));
+        tracing::info!(file_size, "upload scheduled");
 
         let mut json_body = serde_json::json!({
             "status" : status, 
             "uuid": uid.to_string(),
+            "skipped": skipped,
         });
         
         let mut json_body = serde_json::to_vec(&json_body).unwrap(); 
@@ -124,10 +206,29 @@ mod schedule_upload_process {
     use crate::errors::BodyErrors;
 
     use super::*; 
-    use sysinfo::{Disks, System, MemoryRefreshKind}; 
+    use sysinfo::{System, MemoryRefreshKind}; 
 
     pub type BodyContent = (String, u64);
 
+    // the JSON object of the request, keys are matched case insensitively and numbers taken as their text
+    pub fn parse_body(body: &[u8]) -> Result<HashMap<String, String>, BodyErrors<'static>> { 
+        let fields: HashMap<String, serde_json::Value> = serde_json::from_slice(body)
+            .map_err(|_| BodyErrors::InvalidValues(Cow::Borrowed("body")))?;
+
+        let fields = fields
+            .into_iter()
+            .map(|(key, value)| { 
+                let value = match value { 
+                    serde_json::Value::String(value) => value,
+                    value => value.to_string(),
+                };
+                (key.to_ascii_lowercase(), value)
+            })
+            .collect();
+
+        Ok(fields)
+    }
+
     pub fn process_body(mut contents: HashMap<String, String>) -> Result<BodyContent, BodyErrors<'static>> { 
         let fileHash = contents.remove("filehash"); 
         let length = contents.remove("length"); 
@@ -159,25 +260,6 @@ mod schedule_upload_process {
     }
 
 
-    pub async fn get_allocated_disk_space(allowed_threshold: u64) -> Option<()> { 
-        /*
-            Obtain the available space on the disk for writing purpose.
-         */
-
-         let mut disks = Disks::new_with_refreshed_list();
-         for disk in disks.iter() { 
-            if disk.name() == "M" { //TODO: modify the storage behaviour
-                if disk.available_space() > allowed_threshold {
-                    return Some(());
-                }
-                return None;
-            }
-         }
-        
-        return None; 
-        // return Err()
-    }
-
     pub async fn get_allocated_memory_space() -> Option<()> {
         /*
             check for allocated memory space within the application 
@@ -204,35 +286,29 @@ mod schedule_upload_process {
     pub async fn get_server_condition() -> Option<()> {
         /*
             get the internal condition of server, such as Contention or hyper fragmentation etc.. 
+            nothing is measured yet, draining and the rate limits are enforced before a request gets here
         */
 
-        let mut networks = sysinfo::Networks::new();
-
-        let instant1 = tokio::time::Instant::now(); 
-
-        let mut summed_avg = 0;
-        let mut counted_loops = 0;  
-
-        loop { 
-
-            if tokio::time::Instant::now() - instant1 > Duration::from_millis(500) {
-                break; 
-            }
-
-            for net in networks.iter() { 
-                summed_avg += net.1.errors_on_received();    
-            }
-
-            counted_loops += 1; 
-        }
-
-
-        todo!()
+        Some(())
     }
 }
 
 pub async fn init_upload_process(
     ext: Extension<JobHandle>,
+    Extension(limiter): Extension<RateLimiter>,
+    Extension(metrics): Extension<MetricsHandle>,
+    Extension(shutdown): Extension<ShutdownHandle>,
+    Extension(store): Extension<BlobStoreHandle>,
+    Extension(compression): Extension<CompressionHandle>,
+    Extension(encryption): Extension<EncryptionHandle>,
+    Extension(encoding): Extension<EncodingHandle>,
+    Extension(merkle): Extension<MerkleHandle>,
+    Extension(hooks): Extension<HooksHandle>,
+    Extension(webhooks): Extension<WebhooksHandle>,
+    Extension(policy): Extension<ContentPolicyHandle>,
+    Extension(filenames): Extension<FilenamePolicyHandle>,
+    principal: Principal,
+    audit: AuditScope,
     req: Request<Body>, 
 ) -> Result<Response<axum::body::Body>, FragmentError> {
     /*
@@ -241,6 +317,7 @@ pub async fn init_upload_process(
                 FileName
                 Content_Length
                 uuid: "xxxx-xxxx-xxxx-xxxx"
+                Upload-Complete: ?0     //optional, the body is a piece ending before the upload does
             Body: 
                 Chunks, 128Mb
                 Chunks, 128Mb
@@ -255,8 +332,12 @@ pub async fn init_upload_process(
             Body:
                 Json { 
                     Status: Complete|Broke|Failed, 
+                    offset: 1024,   //for an acknowledged piece, where the next one starts
                 }
      */
+    audit.action(AuditAction::Upload);
+    shutdown.ensure_accepting()?;
+
     let (parts, body) = req.into_parts(); 
     let headers = parts.headers; 
 
@@ -289,11 +370,10 @@ pub async fn init_upload_process(
         let file_size = file_size.to_str()
             .map_err(|e| HeaderErrors::HeaderUnwrapError(e))?
             .parse::<u64>()
-            .unwrap();  
+            .map_err(|_| HeaderErrors::InvalidField(Cow::Borrowed("Content-Length")))?;  
 
-        let file_name = file_name.to_str()
-            .map_err(|e| HeaderErrors::HeaderUnwrapError(e))?
-            .to_string(); 
+        // names outside of ASCII arrive as raw UTF-8 or RFC 5987 encoded in `FileName*`
+        let file_name = filename::requested_name(&file_name, &headers)?;
 
         
         let uuid = uuid.to_str()
@@ -303,30 +383,61 @@ pub async fn init_upload_process(
 
         (uuid, file_size, file_name)
     };
+
+    let sanitized = filenames.sanitize(&file_name)?;
+    if sanitized != file_name { 
+        tracing::info!(%uuid, raw = ?file_name, name = %sanitized, "file name rewritten");
+    }
     
-    // let mut ext_file_obj = guard_with_error( file_obj, |e| { });
-    // return error if stream is already present
-    let mut update_handle_entry = match ext
-        .entry(uuid) {
-            dashmap::mapref::entry::Entry::Occupied(mut file_obj) => { 
-                // Continue the update process
-                file_obj
-            },  
-            dashmap::mapref::entry::Entry::Vacant(val) => {
-                // The fileobj is missing from the uuid field
-                return Err(HeaderErrors::InvalidField(Cow::Borrowed("uuid")).into());
-            }
-        };
-        
-    let update_handle = update_handle_entry.get_mut();        
+    let mut update_handle_entry = checkout(&ext, uuid)?;
+    let update_handle = &mut *update_handle_entry;
 
-    let _ = init_upload_process::streamer_writer(body, update_handle).await?;
+    // a completed or skipped upload may share its file with others, only one that never finished is written anew
+    if !matches!(update_handle.get_state(), UploadState::UnInit | UploadState::Init | UploadState::Broken(_) | UploadState::Failed) { 
+        return Err(HeaderErrors::FieldMismatch(Cow::Borrowed("uuid")).into());
+    }
+    // a failed upload may still be linked to its blob, the reference goes with the file it replaces
+    if let Some(digest) = update_handle.take_digest() { 
+        store.release(&digest, update_handle.compression(), update_handle.tenant())?;
+    }
+    update_handle.set_name(sanitized);
+    update_handle.set_raw_name(file_name);
+    audit.file(uuid, Some(update_handle.name()));
+
+    // an encoded body is decoded on the way to the sink, `file_size` applies to the decoded bytes
+    let decoder = encoding.decoder(&headers)?;
+    let checksums = Checksums::from_headers(&headers)?;
+    let piece = is_piece(&headers);
+
+    // keep the writer slot reserved until the stream has been flushed
+    let _permit = limiter.acquire_upload(&principal)?;
+
+    let content_type = headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).map(str::to_string);
+    // sealed segments take the place of compressed blocks, the two layouts are not stacked
+    let envelope = encryption.enabled().then(|| encryption.envelope(&uuid));
+    let mode = match envelope { 
+        Some(_) => Compression::None,
+        None => compression.select(update_handle.tenant(), content_type.as_deref()),
+    };
+    update_handle.set_storage(mode, content_type);
+    update_handle.set_envelope(envelope);
+
+    let mut tracker = metrics.track_upload(update_handle.file_size, init_upload_process::BUFFER_SIZE);
+    let written = init_upload_process::streamer_writer(body, decoder, checksums, store.enabled(), update_handle, &mut tracker, &shutdown, &webhooks, &policy, &compression, &encryption, &merkle).await;
+    let written = settle_piece(written, piece, update_handle, tracker);
+    audit.bytes(update_handle.received());
+    finalize_upload(update_handle, &ext, &store, &hooks, &webhooks).await;
+    let _ = written?;
 
     let response = { 
         let status = update_handle.get_state(); 
-        let json = serde_json::json!({ 
+        let mut json = serde_json::json!({ 
             "status": status 
         });
+        // an acknowledged piece tells where the next one starts
+        if let UploadState::Broken(offset) = status { 
+            json["offset"] = offset.into();
+        }
 
         let json = serde_json::to_vec(&json).unwrap(); 
         let json = axum::body::Body::from(json);
@@ -344,53 +455,245 @@ pub async fn init_upload_process(
 }
 
 mod init_upload_process { 
+    use crate::{compression::{CompressionConfig, Sink}, encryption::{Encryption, SealedSink}, merkle::MerkleConfig};
+
     use super::*;
+
+    pub const BUFFER_SIZE: usize = 1_000_0000;  //TODO: allocated buffer_size
+    // emit a progress event every gigabyte written
+    pub const CHECKPOINT_INTERVAL: usize = 1024 * 1024 * 1024;
+
     pub async fn streamer_writer(
         mut body: Body, 
+        decoder: Option<Decoder>,
+        checksums: Checksums,
+        dedup: bool,
         handle: &mut FileObject,
+        tracker: &mut UploadTracker,
+        shutdown: &ShutdownHandle,
+        webhooks: &WebhooksHandle,
+        policy: &ContentPolicyHandle,
+        compression: &CompressionConfig,
+        encryption: &Encryption,
+        merkle: &MerkleConfig,
     ) -> Result<(), FragmentError> {
     
-        let mut stream = body.into_data_stream();
-    
-        let mut buf_size = 1_000_0000;  //TODO: allocated buffer_size
+        let mut buf_size = BUFFER_SIZE;
     
         let file_path = handle.output_file_path(); 
     
         handle.set_state(UploadState::Progress(0));
-        let mut buf_writer = generate_file( file_path, buf_size).await?; 
+        let mut sink = match handle.envelope() { 
+            Some(envelope) => { 
+                let key = encryption.data_key(envelope, &handle.get_uuid(), handle.file_size)?;
+                Sink::Sealed(SealedSink::create(file_path, key, buf_size).await?)
+            },
+            None => Sink::create(file_path, handle.compression(), compression, buf_size).await?,
+        };
         
+        tracing::debug!(
+            file_size = handle.file_size, 
+            compression = ?handle.compression(), 
+            encrypted = handle.envelope().is_some(), 
+            content_encoding = ?decoder.as_ref().map(Decoder::coding),
+            "upload stream opened"
+        );
+
+        // the tree and crc of an earlier attempt no longer describe the file
+        handle.set_merkle(None);
+        handle.set_crc32(None);
+        let tree = merkle.enabled.then(|| MerkleBuilder::new(merkle.block_size));
+        // only content the blob store takes needs its sha256, sealed uploads never share a blob
+        let sha256 = (dedup && handle.envelope().is_none()).then(Sha256::new);
+        let gate = policy.gate(handle.tenant());
+
+        write_stream(body, decoder, checksums, tree, Some(crc32fast::Hasher::new()), sha256, Some(gate), &mut sink, 0, handle, tracker, shutdown, webhooks).await
+    }
+
+    // drain the body into the sink starting at `offset`, the file is left durable up to the reported offset on every exit
+    pub async fn write_stream(
+        body: Body, 
+        mut decoder: Option<Decoder>,
+        mut checksums: Checksums,
+        mut tree: Option<MerkleBuilder>,
+        mut crc: Option<crc32fast::Hasher>,
+        mut sha256: Option<Sha256>,
+        mut gate: Option<ContentGate>,
+        sink: &mut Sink,
+        offset: usize,
+        handle: &mut FileObject,
+        tracker: &mut UploadTracker,
+        shutdown: &ShutdownHandle,
+        webhooks: &WebhooksHandle,
+    ) -> Result<(), FragmentError> {
+
+        let mut stream = body.into_data_stream().fuse();
+
         let mut chunk_counter = 0; 
-        let mut byte_counter = 0; 
-        
-        // println!("we entered the stream");
+        let mut byte_counter = offset; 
+        let mut next_checkpoint = offset + CHECKPOINT_INTERVAL;
+
+        let aborted = shutdown.aborted();
+        tokio::pin!(aborted);
+
+        // bytes covered by a declared digest are only acknowledged once it matched, until then a failure rolls back to `offset`
+        let verified = checksums.is_empty();
+        let acknowledged = |received: usize| if verified { received } else { offset };
+        // a broken upload keeps the crc of the prefix it is resumable from
+        let offset_crc = crc.clone().map(crc32fast::Hasher::finalize);
+        let crc_until = |at: usize, running: &Option<crc32fast::Hasher>| if at == offset { offset_crc } else { running.clone().map(crc32fast::Hasher::finalize) };
+        let offset_sha256 = sha256.clone();
+        let sha256_until = |at: usize, running: &Option<Sha256>| if at == offset { offset_sha256.clone() } else { running.clone() };
         
-        handle.set_state(UploadState::Progress(0));
-        while let Some(chunk) = stream.next().await { 
-            let bytes = chunk?;
-            // tokio::time::sleep(std::time::Duration::from_millis(1)).await;
-            byte_counter += buf_writer.write(&bytes).await?; 
+        handle.set_state(UploadState::Progress(byte_counter));
+        loop { 
+            let chunk = tokio::select! { 
+                chunk = stream.next() => chunk,
+                _ = &mut aborted => { 
+                    let err = FragmentError::from(ErrorStates::ShuttingDown);
+                    return Err(interrupt(sink, handle, tree.as_ref(), crc_until(acknowledged(byte_counter), &crc), sha256_until(acknowledged(byte_counter), &sha256), acknowledged(byte_counter), err).await);
+                }
+            };
+
+            let remaining = handle.file_size.saturating_sub(byte_counter);
+            let bytes = match chunk { 
+                Some(Ok(wire)) => { 
+                    tracker.record_wire(wire.len());
+                    checksums.update_wire(&wire);
+                    match decoder.take() { 
+                        Some(active) => { 
+                            let (active, decoded) = active.decode(wire, remaining).await;
+                            decoder = Some(active);
+                            decoded
+                        },
+                        None => Ok(wire),
+                    }
+                },
+                Some(Err(e)) => Err(e.into()),
+                // the body ended, whatever the decoder still holds makes up the last chunk
+                None => match decoder.take() { 
+                    Some(active) => active.finish(remaining),
+                    None => break,
+                },
+            };
+
+            let bytes = match bytes { 
+                Ok(bytes) => bytes,
+                Err(e) => return Err(interrupt(sink, handle, tree.as_ref(), crc_until(acknowledged(byte_counter), &crc), sha256_until(acknowledged(byte_counter), &sha256), acknowledged(byte_counter), e).await),
+            };
+
+            // we acquired more bytes than nessecary, keep the valid prefix
+            if byte_counter + bytes.len() > handle.file_size { 
+                let err = FragmentError::from(ErrorStates::UploadSizeExceeded);
+                return Err(interrupt(sink, handle, tree.as_ref(), crc_until(acknowledged(byte_counter), &crc), sha256_until(acknowledged(byte_counter), &sha256), acknowledged(byte_counter), err).await);
+            }
+
+            // the type may only be settled once several chunks are written, a refused upload takes what reached the disk with it
+            if let Some(active) = &mut gate { 
+                let last = byte_counter + bytes.len() >= handle.file_size;
+                if let Some(detected) = active.feed(&bytes, last) { 
+                    handle.set_detected_type(detected);
+                    if let Err(e) = active.admit(detected) { 
+                        tracing::info!(detected, offset = byte_counter, "upload refused by the content policy");
+                        match tokio::fs::remove_file(handle.output_file_path()).await { 
+                            Err(removal) if removal.kind() != std::io::ErrorKind::NotFound => { 
+                                tracing::error!("unable to remove the refused upload: {}", removal);
+                            },
+                            _ => handle.set_stored_size(0),
+                        }
+                        handle.set_rejection(e.to_string());
+                        handle.set_state(UploadState::Failed);
+                        return Err(e.into());
+                    }
+                    gate = None;
+                }
+            }
+
+            checksums.update_decoded(&bytes);
+            let started = tokio::time::Instant::now();
+            if let Err(e) = sink.write_all(&bytes).await { 
+                return Err(interrupt(sink, handle, tree.as_ref(), crc_until(acknowledged(byte_counter), &crc), sha256_until(acknowledged(byte_counter), &sha256), acknowledged(byte_counter), e.into()).await);
+            }
+            if let Some(tree) = &mut tree { 
+                tree.update(&bytes);
+            }
+            if let Some(crc) = &mut crc { 
+                crc.update(&bytes);
+            }
+            if let Some(sha256) = &mut sha256 { 
+                sha256.update(&bytes);
+            }
+            tracker.record_chunk(bytes.len(), started.elapsed());
+            byte_counter += bytes.len(); 
             chunk_counter += 1;             
     
             // let p = (file.file_size / byte_counter) * 100;
             handle.set_state(UploadState::Progress(byte_counter));
+            webhooks.progress(handle, byte_counter - bytes.len(), byte_counter);
+
+            if byte_counter >= next_checkpoint { 
+                tracing::info!(offset = byte_counter, chunks = chunk_counter, "upload checkpoint");
+                next_checkpoint += CHECKPOINT_INTERVAL;
+            }
         }
-        // we acquired more bytes than nessecary
-        if byte_counter > handle.file_size { 
-            handle.set_state(UploadState::Broken(byte_counter));
-            return Err(HeaderErrors::FieldMismatch(Cow::Borrowed("Content-Length")).into()); 
+
+        // the body ended, the client retransmits this request's piece only and what was written past `offset` is cut off when it resumes
+        if let Err(e) = checksums.verify() { 
+            return Err(interrupt(sink, handle, tree.as_ref(), offset_crc, offset_sha256.clone(), offset, e.into()).await);
         }
-        // we got less bytes than possible 
+
+        // we got less bytes than possible, a verified piece is kept
         if byte_counter < handle.file_size { 
-            handle.set_state(UploadState::Broken(byte_counter));
-            return Err(HeaderErrors::FieldMismatch(Cow::Borrowed("Content-Length")).into());
+            let err = FragmentError::from(ErrorStates::UploadIncomplete);
+            return Err(interrupt(sink, handle, tree.as_ref(), crc_until(byte_counter, &crc), sha256_until(byte_counter, &sha256), byte_counter, err).await);
         }
         
         drop(stream);
         
-        let _ = buf_writer.shutdown().await?;
+        checkpoint(sink, handle).await.map_err(|e| { 
+            handle.set_state(UploadState::Failed);
+            e
+        })?;
+        handle.set_merkle(tree.map(MerkleBuilder::finish));
+        handle.set_crc32(crc.map(crc32fast::Hasher::finalize));
+        handle.set_sha256(sha256);
         handle.set_state(UploadState::Complete);
+        tracing::info!(bytes = byte_counter, stored = handle.stored_size(), chunks = chunk_counter, "upload stream flushed");
         Ok(())
     }
+
+    // flush the buffered bytes, make them durable and record what they take on disk
+    pub async fn checkpoint(sink: &mut Sink, handle: &mut FileObject) -> Result<(), FragmentError> { 
+        sink.checkpoint().await?;
+        handle.set_stored_size(sink.stored_len().await? as usize);
+        Ok(())
+    }
+
+    // leave the upload resumable from `offset`, or failed when not even that could be persisted
+    async fn interrupt(
+        sink: &mut Sink,
+        handle: &mut FileObject,
+        tree: Option<&MerkleBuilder>,
+        crc: Option<u32>,
+        sha256: Option<Sha256>,
+        offset: usize,
+        err: FragmentError,
+    ) -> FragmentError { 
+        match checkpoint(sink, handle).await { 
+            Ok(_) => { 
+                tracing::info!(offset, "upload interrupted, checkpoint persisted");
+                handle.set_merkle(tree.map(|tree| tree.until(offset as u64)));
+                handle.set_crc32(crc);
+                handle.set_sha256(sha256);
+                handle.set_state(UploadState::Broken(offset));
+            },
+            Err(e) => { 
+                tracing::error!(offset, variant = e.state().variant(), "unable to persist checkpoint");
+                handle.set_state(UploadState::Failed);
+            }
+        }
+        err.with_offset(offset)
+    }
     
     
     //validating the header data of the 
@@ -400,12 +703,7 @@ mod init_upload_process {
             return Err(HeaderErrors::HeaderFieldMissing(Cow::Borrowed("FileName")));
         }
     
-        let str_header = header.to_str()?; 
-        
-        // if str_header.chars().all(|e| !e.is_ascii() ||*/ !e.is_alphanumeric() || e.is_ascii_hexdigit()) {
-        //     return Err(HeaderErrors::InvalidField(Cow::Owned(str_header.to_string())));
-        // }
-    
+        // the characters of `FileName` are up to the filename policy, it may carry UTF-8
         Ok(())
     }
     
@@ -415,14 +713,28 @@ mod init_upload_process {
 //todo: apply the logic for removal of the FileObj from the JobHandle extension if error happens
 pub async fn resume_upload(
     Extension(ext): Extension<JobHandle>,
+    Extension(limiter): Extension<RateLimiter>,
+    Extension(metrics): Extension<MetricsHandle>,
+    Extension(shutdown): Extension<ShutdownHandle>,
+    Extension(store): Extension<BlobStoreHandle>,
+    Extension(compression): Extension<CompressionHandle>,
+    Extension(encryption): Extension<EncryptionHandle>,
+    Extension(encoding): Extension<EncodingHandle>,
+    Extension(merkle): Extension<MerkleHandle>,
+    Extension(hooks): Extension<HooksHandle>,
+    Extension(webhooks): Extension<WebhooksHandle>,
+    Extension(policy): Extension<ContentPolicyHandle>,
+    principal: Principal,
+    audit: AuditScope,
     req: Request<Body>
-) -> Result<Response<String>, FragmentError> {
+) -> Result<Response<axum::body::Body>, FragmentError> {
     /*
             Request: 
                 headers: 
                     uuid: "xxxx-xxxx-xxxx-xxxx" // uuid to start this upload process from
                     Content-Length: "..."   //give content-length of file
                     Content-Pointer: "..."  //some position in the file
+                    Upload-Complete: ?0     //optional, the body is a piece ending before the upload does
                     
                 Body: 
                     remaining_bytes: 128Mb,
@@ -430,6 +742,9 @@ pub async fn resume_upload(
                     remaining_bytes: 128Mb,
                     remaining_bytes: 128Mb,
      */
+    audit.action(AuditAction::Resume);
+    shutdown.ensure_accepting()?;
+
     let (parts, body) = req.into_parts(); 
     let headers = parts.headers; 
 
@@ -449,9 +764,9 @@ pub async fn resume_upload(
     
     let (uuid, content_length, content_pointer) = { 
         
-        let uuid = extracted_headers.pop().unwrap()?; 
-        let content_length = extracted_headers.pop().unwrap()?; 
         let content_pointer = extracted_headers.pop().unwrap()?; 
+        let content_length = extracted_headers.pop().unwrap()?; 
+        let uuid = extracted_headers.pop().unwrap()?; 
         
         let _ = init_upload_process::validate_headers(&uuid)?;
         let _ = init_upload_process::validate_headers(&content_length)?;
@@ -462,12 +777,12 @@ pub async fn resume_upload(
         let content_pointer = content_pointer.to_str()
             .map_err(|e| HeaderErrors::HeaderUnwrapError(e))?
             .parse::<u64>()
-            .map_err(|e| HeaderErrors::InvalidField(Cow::Borrowed("FileSize")))?;  
+            .map_err(|e| HeaderErrors::InvalidField(Cow::Borrowed("Content-Pointer")))?;  
 
         let content_length = content_length.to_str()
             .map_err(|e| HeaderErrors::HeaderUnwrapError(e))?
             .parse::<u64>()
-            .map_err(|e| HeaderErrors::InvalidField(Cow::Borrowed("FileSize")))?; 
+            .map_err(|e| HeaderErrors::InvalidField(Cow::Borrowed("Content-Length")))?; 
 
         
         let uuid = uuid.to_str()
@@ -478,46 +793,67 @@ pub async fn resume_upload(
         (uuid, content_length, content_pointer)
     };
 
-    let mut update_handle_entry = match ext
-        .entry(uuid) {
-            dashmap::mapref::entry::Entry::Occupied(mut file_obj) => { 
-                // Continue the update process
-                file_obj
-            },  
-            dashmap::mapref::entry::Entry::Vacant(val) => {
-                // The fileobj is missing from the uuid field
-                return Err(HeaderErrors::InvalidField(Cow::Borrowed("uuid")).into());
-            }
-    };
+    let mut update_handle_entry = checkout(&ext, uuid)?;
+    let update_handle = &mut *update_handle_entry;
+    audit.file(uuid, Some(update_handle.name()));
 
-    let update_handle = update_handle_entry.get_mut(); 
+    // Content-Length counts wire bytes, only an identity body can be checked against the size up front
+    let decoder = encoding.decoder(&headers)?;
+    let checksums = Checksums::from_headers(&headers)?;
+    let piece = is_piece(&headers);
 
     //verify the logical validity of the content passed
-    resume_upload::validate_header_entries(update_handle, content_length, content_pointer)?;
+    resume_upload::validate_header_entries(update_handle, content_length, content_pointer, decoder.is_some())?;
+
+    let _permit = limiter.acquire_upload(&principal)?;
 
     //resume writing to file from the poitner onwards
+    let remaining = update_handle.file_size - content_pointer as usize;
+    let mut tracker = metrics.track_upload(remaining, init_upload_process::BUFFER_SIZE);
+    let written = resume_upload::streamer_writer(body, decoder, checksums, content_pointer, update_handle, &mut tracker, &shutdown, &webhooks, &policy, &compression, &encryption, &merkle).await;
+    let written = settle_piece(written, piece, update_handle, tracker);
+    audit.bytes(update_handle.received().saturating_sub(content_pointer as usize));
+    finalize_upload(update_handle, &ext, &store, &hooks, &webhooks).await;
+    let _ = written?;
 
+    let response = { 
+        let status = update_handle.get_state(); 
+        let mut json = serde_json::json!({ 
+            "status": status 
+        });
+        // an acknowledged piece tells where the next one starts
+        if let UploadState::Broken(offset) = status { 
+            json["offset"] = offset.into();
+        }
 
+        let json = serde_json::to_vec(&json).unwrap(); 
+        let json = axum::body::Body::from(json);
+        
+        let resp = Response::builder()
+            .status(200)
+            .header("Content-Type", "application/json")
+            .body(json)?; 
 
+        resp        
+    };
 
-    todo!()
+    Ok(response)
 }
 
 mod resume_upload { 
-    use std::path::Path;
-
-    use tokio::{io::{BufWriter, AsyncSeekExt}, fs::File};
+    use crate::{compression::{CompressionConfig, Sink}, encryption::{Encryption, SealedSink}, merkle::{self, MerkleConfig}};
 
     use super::*; 
 
     pub fn validate_header_entries(
         file_obj: &mut FileObject, 
         content_length: u64, 
-        content_pointer: u64
+        content_pointer: u64,
+        encoded: bool,
     ) -> Result<(), FragmentError> { 
         
         if (content_pointer as usize) >= file_obj.file_size { 
-            return Err(HeaderErrors::InvalidField(Cow::Borrowed(("content_pointer"))).into());
+            return Err(FragmentError::from(ErrorStates::OffsetOutOfRange).with_offset(file_obj.received()));
         }
         
         if (content_length as usize) <= 0  {
@@ -528,8 +864,18 @@ mod resume_upload {
             return Err(HeaderErrors::InvalidField(Cow::Borrowed(("Content-Pointer"))).into());
         }
 
-        if (content_pointer as usize) <= 0  {
-            return Err(HeaderErrors::InvalidField(Cow::Borrowed(("Content-Pointer"))).into());
+        // only interrupted uploads can be resumed, and never past the durable offset
+        let UploadState::Broken(durable) = file_obj.get_state() else { 
+            return Err(HeaderErrors::FieldMismatch(Cow::Borrowed("uuid")).into());
+        };
+
+        if (content_pointer as usize) > durable { 
+            return Err(FragmentError::from(ErrorStates::OffsetOutOfRange).with_offset(durable));
+        }
+
+        // a resume may carry a piece of the rest, never more than that
+        if !encoded && (content_pointer + content_length) as usize > file_obj.file_size { 
+            return Err(HeaderErrors::FieldMismatch(Cow::Borrowed("Content-Length")).into());
         }
 
         Ok(())
@@ -537,85 +883,446 @@ mod resume_upload {
 
     pub async fn streamer_writer(
         mut body: Body, 
+        decoder: Option<Decoder>,
+        checksums: Checksums,
         content_pointer: u64,
-        handle: &mut FileObject
+        handle: &mut FileObject,
+        tracker: &mut UploadTracker,
+        shutdown: &ShutdownHandle,
+        webhooks: &WebhooksHandle,
+        policy: &ContentPolicyHandle,
+        compression: &CompressionConfig,
+        encryption: &Encryption,
+        merkle: &MerkleConfig,
     ) -> Result<(), FragmentError> {
-        let mut stream = body.into_data_stream(); 
+
+        // read back before the sink cuts the stored data at the pointer
+        let tree = match merkle.enabled { 
+            true => Some(restore_tree(handle, encryption, merkle, content_pointer).await?),
+            false => None,
+        };
+        // the crc kept with the durable offset carries on, resuming anywhere else leaves it to whoever needs it
+        let crc = match (handle.crc32(), handle.get_state()) { 
+            (Some(crc), UploadState::Broken(durable)) if durable as u64 == content_pointer => Some(crc32fast::Hasher::new_with_initial_len(crc, content_pointer)),
+            _ => None,
+        };
+        let sha256 = match (handle.take_sha256(), handle.get_state()) { 
+            (Some(sha256), UploadState::Broken(durable)) if durable as u64 == content_pointer => Some(sha256),
+            _ => None,
+        };
+
+        // an upload interrupted before its type was settled picks up sniffing from the stored bytes
+        let gate = match handle.detected_type() { 
+            Some(_) => None,
+            None => { 
+                let mut gate = policy.gate(handle.tenant());
+                gate.prefill(&sniff::stored_prefix(&IndexedFile::from(&*handle), encryption, content_pointer as usize).await?);
+                Some(gate)
+            },
+        };
 
         let previous_file_path = handle.output_file_path(); 
 
         handle.set_state(UploadState::Resume((content_pointer) as usize));
 
-        let size = 10_000_000; //todo: modify this part to be fetched from 
+        let size = init_upload_process::BUFFER_SIZE; //todo: modify this part to be fetched from 
 
-        let mut buf_writer = open_file(content_pointer, previous_file_path, size)
-            .await
-            .map_err(|e| {
-                handle.set_state(UploadState::Failed); 
-                e
-            })?; 
+        // anything past the pointer is discarded and written again, in the layout the upload started with
+        let sink = match handle.envelope() { 
+            Some(envelope) => match encryption.data_key(envelope, &handle.get_uuid(), handle.file_size) { 
+                Ok(key) => SealedSink::resume(previous_file_path, content_pointer, key, size).await.map(Sink::Sealed).map_err(FragmentError::from),
+                Err(e) => Err(e),
+            },
+            None => Sink::resume(previous_file_path, content_pointer, handle.compression(), compression, size).await.map_err(FragmentError::from),
+        };
+        let mut sink = sink.map_err(|e| {
+            handle.set_state(UploadState::Failed); 
+            e
+        })?; 
 
-        let mut chunk_counter = 0; 
-        let mut byte_counter = 0; 
-    
-        while let Some(chunk) = stream.next().await { 
-            
-            let bytes = chunk.map_err(|e| { 
-                handle.set_state(UploadState::Broken(((content_pointer as usize) + (byte_counter as usize))));
-                e
-            })?; 
-
-            byte_counter += buf_writer.write(&bytes).await.map_err(|e| { 
-                handle.set_state(UploadState::Broken(((content_pointer as usize) + (byte_counter as usize))));
-                e
-            })?;
-
-            chunk_counter += 1; 
-            handle.set_state(UploadState::Progress(byte_counter as usize));
+        init_upload_process::write_stream(body, decoder, checksums, tree, crc, sha256, gate, &mut sink, content_pointer as usize, handle, tracker, shutdown, webhooks).await
+    }
+
+    // the tree of the stored prefix up to `pointer`, leaves missing from the registry are hashed again from disk
+    async fn restore_tree(
+        handle: &mut FileObject,
+        encryption: &Encryption,
+        merkle: &MerkleConfig,
+        pointer: u64,
+    ) -> Result<MerkleBuilder, FragmentError> { 
+
+        let mut builder = match handle.merkle().cloned() { 
+            Some(tree) => { 
+                let keep = ((pointer / tree.block_size) as usize).min(tree.leaves.len());
+
+                // a torn write would show in the last whole leaf, it has to match the stored bytes before anything is appended
+                if keep > 0 { 
+                    let start = (keep as u64 - 1) * tree.block_size;
+                    let mut stored = MerkleBuilder::new(tree.block_size);
+                    merkle::rehash(&mut stored, handle, encryption, start, start + tree.block_size).await?;
+
+                    if stored.finish().leaves != [tree.leaves[keep - 1]] { 
+                        tracing::warn!(offset = start, "stored leaf does not match the manifest, rolling back");
+                        handle.set_merkle(Some(MerkleBuilder::resume(&tree, keep - 1).until(start)));
+                        handle.set_crc32(None);
+                        handle.set_sha256(None);
+                        handle.set_state(UploadState::Broken(start as usize));
+                        return Err(FragmentError::from(ErrorStates::StoredPrefixCorrupt).with_offset(start as usize));
+                    }
+                }
+                MerkleBuilder::resume(&tree, keep)
+            },
+            None => MerkleBuilder::new(merkle.block_size),
+        };
+
+        let from = builder.position();
+        merkle::rehash(&mut builder, handle, encryption, from, pointer).await?;
+        Ok(builder)
+    }
+
+}
+
+// Handle for a piece of a started upload sent alongside others, it is staged next to the upload until every byte before it is written
+pub async fn upload_piece(
+    Extension(ext): Extension<JobHandle>,
+    Extension(limiter): Extension<RateLimiter>,
+    Extension(metrics): Extension<MetricsHandle>,
+    Extension(shutdown): Extension<ShutdownHandle>,
+    Extension(index): Extension<FileIndexHandle>,
+    Extension(store): Extension<BlobStoreHandle>,
+    Extension(compression): Extension<CompressionHandle>,
+    Extension(encryption): Extension<EncryptionHandle>,
+    Extension(merkle): Extension<MerkleHandle>,
+    Extension(hooks): Extension<HooksHandle>,
+    Extension(webhooks): Extension<WebhooksHandle>,
+    Extension(policy): Extension<ContentPolicyHandle>,
+    principal: Principal,
+    audit: AuditScope,
+    req: Request<Body>
+) -> Result<Response<axum::body::Body>, FragmentError> {
+    /*
+            Request: PUT /upload_piece
+                headers: 
+                    uuid: "xxxx-xxxx-xxxx-xxxx" // upload the piece belongs to, its first piece went through /upload_file
+                    Content-Length: "..."   //bytes in this piece
+                    Content-Pointer: "..."  //where the piece starts in the file
+                    Upload-Checksum: "..."  //optional, checked before the piece is staged
+                    
+                Body: 
+                    piece_bytes: 8Mb, 
+
+            Response: 200
+                Body: 
+                    Json { 
+                        status: Broken|Complete|..., 
+                        offset: 1024, // how far the file is written, staged pieces past a gap wait for it
+                    }
+     */
+    audit.action(AuditAction::Piece);
+    shutdown.ensure_accepting()?;
+
+    let (parts, body) = req.into_parts(); 
+    let headers = parts.headers; 
+
+    let headers_names = [
+        "uuid",
+        "Content-Length",
+        "Content-Pointer"
+    ];
+
+    let mut extracted_headers = futures::future::join_all(headers_names
+        .iter()
+        .map(|field_name| {
+            let field = HeaderName::from_str(field_name).expect("Invalid header name");
+            extract_header_fields(&headers, field)
+        })).await;
+
+    let (uuid, content_length, content_pointer) = { 
+        let content_pointer = extracted_headers.pop().unwrap()?; 
+        let content_length = extracted_headers.pop().unwrap()?; 
+        let uuid = extracted_headers.pop().unwrap()?; 
+
+        let content_pointer = content_pointer.to_str()
+            .map_err(HeaderErrors::HeaderUnwrapError)?
+            .parse::<usize>()
+            .map_err(|_| HeaderErrors::InvalidField(Cow::Borrowed("Content-Pointer")))?;  
+
+        let content_length = content_length.to_str()
+            .map_err(HeaderErrors::HeaderUnwrapError)?
+            .parse::<usize>()
+            .map_err(|_| HeaderErrors::InvalidField(Cow::Borrowed("Content-Length")))?; 
+
+        let uuid = uuid::Uuid::from_str(uuid.to_str().map_err(HeaderErrors::HeaderUnwrapError)?)?;
+
+        (uuid, content_length, content_pointer)
+    };
+
+    // the entry is locked while an earlier piece is written, the index answers without waiting for it
+    let file = index.get(&uuid)
+        .filter(|file| principal.owns(file.tenant.as_deref()))
+        .ok_or(ErrorStates::UploadNotFound(uuid))?;
+    audit.file(uuid, Some(&file.name));
+
+    upload_piece::validate_header_entries(&file, &headers, content_length, content_pointer)?;
+    let checksums = Checksums::from_headers(&headers)?;
+
+    let _permit = limiter.acquire_upload(&principal)?;
+
+    let staged = upload_piece::staged_path(&file.output_file_path(), content_pointer);
+    upload_piece::stage(body, &staged, content_length, checksums).await?;
+    audit.bytes(content_length);
+
+    // whoever holds the entry writes every staged piece it reaches, this request only waits for its turn
+    let job = ext.get(&uuid).map(|job| job.clone()).ok_or(ErrorStates::UploadNotFound(uuid))?;
+    let mut update_handle_entry = job.lock_owned().await;
+    let update_handle = &mut *update_handle_entry;
+
+    // the staged pieces that follow on from the durable offset are written one after another
+    let mut drained = false;
+    let mut written = Ok(());
+    while let UploadState::Broken(durable) = update_handle.get_state() { 
+        let next = upload_piece::staged_path(&update_handle.output_file_path(), durable);
+        let Some(length) = upload_piece::staged_len(&next).await? else { 
+            break;
+        };
+
+        let body = Body::from_stream(encoding::read_file(next.clone()));
+        let mut tracker = metrics.track_upload(length, init_upload_process::BUFFER_SIZE);
+        written = resume_upload::streamer_writer(body, None, Checksums::default(), durable as u64, update_handle, &mut tracker, &shutdown, &webhooks, &policy, &compression, &encryption, &merkle).await;
+        written = settle_piece(written, durable + length < update_handle.file_size, update_handle, tracker);
+        upload_piece::discard(&next).await;
+        drained = true;
+        if written.is_err() { 
+            break;
         }
+    }
 
-        //TODO: ensure & test the logical validity of the post checks performed
+    // the same piece sent twice finds its bytes already written
+    if content_pointer < update_handle.received() { 
+        upload_piece::discard(&staged).await;
+    }
+    if drained { 
+        finalize_upload(update_handle, &ext, &store, &hooks, &webhooks).await;
+    }
+    written?;
 
-        // we acquired more bytes than nessecary
-        if (byte_counter + content_pointer as usize) > handle.file_size { 
-            handle.set_state(UploadState::Broken(byte_counter));
-            return Err(HeaderErrors::FieldMismatch(Cow::Borrowed("Content-Length")).into()); 
+    let response = { 
+        let status = update_handle.get_state(); 
+        let mut json = serde_json::json!({ 
+            "status": status 
+        });
+        if let UploadState::Broken(offset) = status { 
+            json["offset"] = offset.into();
         }
-        // we got less bytes than possible 
-        if (byte_counter + content_pointer as usize) < handle.file_size { 
-            handle.set_state(UploadState::Broken(byte_counter));
+
+        let json = serde_json::to_vec(&json).unwrap(); 
+        let json = axum::body::Body::from(json);
+        
+        Response::builder()
+            .status(200)
+            .header("Content-Type", "application/json")
+            .body(json)?
+    };
+
+    Ok(response)
+}
+
+mod upload_piece { 
+    use std::path::{Path, PathBuf};
+
+    use tokio::io::AsyncWriteExt;
+
+    use crate::utils;
+
+    use super::*; 
+
+    pub fn validate_header_entries(file: &IndexedFile, headers: &HeaderMap, content_length: usize, content_pointer: usize) -> Result<(), FragmentError> { 
+
+        // the first piece names the upload and picks its layout, only /upload_file sends it
+        if content_pointer == 0 { 
+            return Err(HeaderErrors::InvalidField(Cow::Borrowed("Content-Pointer")).into());
+        }
+
+        if content_pointer >= file.file_size { 
+            return Err(ErrorStates::OffsetOutOfRange.into());
+        }
+
+        if content_length == 0 { 
+            return Err(HeaderErrors::InvalidField(Cow::Borrowed("Content-Length")).into());
+        }
+
+        if content_pointer + content_length > file.file_size { 
             return Err(HeaderErrors::FieldMismatch(Cow::Borrowed("Content-Length")).into());
         }
 
-        drop(stream); 
+        // staged bytes are written as they came, a piece is never decoded on the way
+        if headers.contains_key(CONTENT_ENCODING) { 
+            return Err(HeaderErrors::InvalidField(Cow::Borrowed("Content-Encoding")).into());
+        }
 
-        let _ = buf_writer.shutdown().await.map_err(|e| {
-            handle.set_state(UploadState::Failed);
-            e
-        })?; 
+        match file.state { 
+            UploadState::Broken(_) | UploadState::Progress(_) | UploadState::Resume(_) => Ok(()),
+            _ => Err(HeaderErrors::FieldMismatch(Cow::Borrowed("uuid")).into()),
+        }
+    }
 
-        handle.set_state(UploadState::Complete);
-        
+    // where the piece starting at `offset` waits to be written, e.g. `<uuid>.1048576.piece`
+    pub fn staged_path(output: &Path, offset: usize) -> PathBuf { 
+        output.with_extension(format!("{}.piece", offset))
+    }
+
+    // write the body next to the upload, it only takes its staged name once it is whole, checked and durable
+    pub async fn stage(body: Body, staged: &Path, content_length: usize, mut checksums: Checksums) -> Result<(), FragmentError> { 
+        let partial = staged.with_extension("part");
+
+        let written = async { 
+            let mut file = utils::create_fresh(&partial).await?;
+            let mut stream = body.into_data_stream();
+            let mut received = 0;
+
+            while let Some(chunk) = stream.next().await { 
+                let chunk = chunk?;
+                received += chunk.len();
+                if received > content_length { 
+                    return Err(FragmentError::from(ErrorStates::UploadSizeExceeded));
+                }
+                checksums.update_wire(&chunk);
+                checksums.update_decoded(&chunk);
+                file.write_all(&chunk).await?;
+            }
+
+            if received < content_length { 
+                return Err(ErrorStates::UploadIncomplete.into());
+            }
+            checksums.verify()?;
+            file.sync_data().await?;
+            Ok(())
+        }.await;
+
+        if let Err(e) = written { 
+            discard(&partial).await;
+            return Err(e);
+        }
+        tokio::fs::rename(&partial, staged).await?;
         Ok(())
+    }
 
+    // length of the piece staged at `staged`, none while it has not arrived
+    pub async fn staged_len(staged: &Path) -> Result<Option<usize>, FragmentError> { 
+        match tokio::fs::metadata(staged).await { 
+            Ok(meta) => Ok(Some(meta.len() as usize)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
     }
 
-    async fn open_file(pointer: u64, path: impl AsRef<Path>, size: usize) -> Result<BufWriter<File>, FragmentError> {
-        let mut file = File::open(path).await?;
-        file.seek(std::io::SeekFrom::Start(pointer)).await; 
-        let buffered_file = BufWriter::with_capacity(size, file); 
-        
-        Ok(buffered_file)
+    pub async fn discard(path: &Path) { 
+        match tokio::fs::remove_file(path).await { 
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => tracing::warn!(path = %path.display(), "unable to remove staged piece: {}", e),
+            _ => {},
+        }
+    }
+}
+
+// `Upload-Complete: ?0` marks a body that stops short of the upload on purpose
+fn is_piece(headers: &HeaderMap) -> bool { 
+    headers.get("Upload-Complete").is_some_and(|value| value.as_bytes() == b"?0")
+}
+
+// a piece is acknowledged once it is durable, a body ending early without the marker stays an error
+fn settle_piece(written: Result<(), FragmentError>, piece: bool, handle: &FileObject, tracker: UploadTracker) -> Result<(), FragmentError> { 
+    match written { 
+        Err(e) if piece && matches!(e.state(), ErrorStates::UploadIncomplete) && matches!(handle.get_state(), UploadState::Broken(_)) => { 
+            tracker.piece();
+            Ok(())
+        },
+        written => { 
+            tracker.finish(&written);
+            written
+        },
+    }
+}
+
+// hand a completed upload to the blob store and the hooks, the upload itself stands even if deduplication fails
+async fn finalize_upload(file_obj: &mut FileObject, ext: &JobHandle, store: &BlobStoreHandle, hooks: &HooksHandle, webhooks: &WebhooksHandle) { 
+    match file_obj.get_state() { 
+        UploadState::Complete => {},
+        UploadState::Failed => return webhooks.emit(WebhookEvent::Failed, file_obj),
+        // an interrupted upload is resumed rather than reported
+        _ => return,
+    }
+
+    // taken while the upload streamed, unless it was interrupted by a restart
+    let digest = file_obj.take_sha256().map(|sha256| format!("{:x}", sha256.finalize()));
+    if let Err(e) = store.ingest(file_obj, digest).await { 
+        tracing::warn!(uuid = %file_obj.get_uuid(), variant = e.state().variant(), "unable to deduplicate upload: {}", e.state());
+    }
+    hooks.dispatch(file_obj, ext);
+
+    // with hooks to run the upload is only complete once they settled
+    if file_obj.get_state() == UploadState::Complete { 
+        webhooks.emit(WebhookEvent::Completed, file_obj);
     }
+}
+
+// Handle for removing an upload, its content goes with it once no other upload shares the blob
+pub async fn delete_upload(
+    Extension(ext): Extension<JobHandle>,
+    Extension(index): Extension<FileIndexHandle>,
+    Extension(metrics): Extension<MetricsHandle>,
+    Extension(store): Extension<BlobStoreHandle>,
+    Extension(webhooks): Extension<WebhooksHandle>,
+    audit: AuditScope,
+    principal: Principal,
+    Path(uuid): Path<String>,
+) -> Result<Response<axum::body::Body>, FragmentError> { 
+
+    audit.action(AuditAction::Delete);
+    let uuid = uuid::Uuid::from_str(&uuid)?;
 
+    // a running upload holds its entry, it is only removed once the transfer ended
+    let file_obj = checkout(&ext, uuid)?;
+    // the upload and its share of a blob are the owner's to give up, to anyone else it does not exist
+    if !principal.owns(file_obj.tenant()) { 
+        return Err(ErrorStates::UploadNotFound(uuid).into());
+    }
+    ext.remove(&uuid);
+    audit.file(uuid, Some(file_obj.name()));
+
+    index.remove(&uuid);
+    metrics.record_job_removed();
+
+    // removing an upload that never settled abandons it
+    if !matches!(file_obj.get_state(), UploadState::Complete | UploadState::Rejected | UploadState::Clean | UploadState::Quarantined | UploadState::Failed) { 
+        webhooks.emit(WebhookEvent::Cancelled, &file_obj);
+    }
+
+    match tokio::fs::remove_file(file_obj.output_file_path()).await { 
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {},
+    }
+    if let Some(digest) = file_obj.digest() { 
+        store.release(digest, file_obj.compression(), file_obj.tenant())?;
+    }
+    tracing::info!(%uuid, "upload deleted");
+
+    let resp = Response::builder()
+        .status(204)
+        .body(Body::empty())?;
+
+    Ok(resp)
 }
 
 // Handle for acquring the status of the In_progress, discarded or cancelled upload process 
 pub async fn task_progress(
-    mut ext: Extension<JobHandle>,
+    Extension(ext): Extension<JobHandle>,
+    Extension(index): Extension<FileIndexHandle>,
+    audit: AuditScope,
     mut req: Request<Body>, 
 ) -> Result<Response<axum::body::Body>, FragmentError> { 
 
+    audit.action(AuditAction::Status);
+
     let (mut parts, body) = req.into_parts(); 
 
     let headers = parts.headers; 
@@ -638,13 +1345,23 @@ pub async fn task_progress(
         let str_uid = uid.to_str()?;
         uuid::Uuid::from_str(str_uid)?
     };
+    audit.file(uuid, None);
+
+    // an upload that is being written holds its entry, the index answers for it meanwhile with the offset the stream started from
+    let job = ext.get(&uuid).map(|job| job.clone());
+    let listed = job.as_ref().and_then(|job| match job.try_lock() { 
+        Ok(file_obj) => Some(IndexedFile::from(&*file_obj)),
+        Err(_) => index.get(&uuid),
+    });
 
-    let response = if let Some(val) = ext.get(&uuid) {
+    let response = if let Some(val) = listed {
         
-        let uid = val.get_state(); 
+        let uid = val.state; 
 
         let body = serde_json::json!({ 
             "status": uid,
+            "metadata": val.metadata,
+            "tags": val.tags,
         });
 
         let body = serde_json::to_vec(&body).unwrap();
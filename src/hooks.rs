@@ -0,0 +1,538 @@
+use std::{collections::{BTreeMap, BTreeSet}, path::{Path, PathBuf}, process::Stdio, sync::Arc, time::Duration};
+
+use axum::async_trait;
+use futures::StreamExt;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::{
+    compression::{Compression, CompressionConfig, CompressionHandle, Sink},
+    config::LoadConfig,
+    encryption::{EncryptionHandle, SealedSink},
+    file::{FileObject, UploadState},
+    handlers::JobHandle,
+    index::IndexedFile,
+    merkle::{self, MerkleBuilder, MerkleConfig, MerkleHandle},
+    metadata::MetadataConfig,
+    scanning::Scanning,
+    store::BlobStoreHandle,
+    utils,
+    webhooks::{WebhookEvent, WebhooksHandle},
+    FragmentError,
+};
+
+
+// bytes moved at once while content is written back
+const WRITE_BUFFER: usize = 1024 * 1024;
+
+// What a hook reports when it could not handle an upload, the upload is rejected with it
+pub type HookError = Box<dyn std::error::Error + Send + Sync>;
+
+// An upload that just completed, as the hooks see it
+#[derive(Debug, Clone)]
+pub struct CompletedUpload {
+    pub uuid: Uuid,
+    pub name: String,
+    // the content as it was uploaded, a staged copy when it is stored compressed or sealed, read only
+    pub path: PathBuf,
+    pub size: usize,
+    pub content_type: Option<String>,
+    pub tenant: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+    pub tags: BTreeSet<String>,
+    pub digest: Option<String>,
+    // a file with new content, taken over by the pipeline and written back in place of `path`
+    pub replacement: Option<PathBuf>,
+}
+
+impl From<&FileObject> for CompletedUpload {
+    fn from(file_obj: &FileObject) -> Self {
+        Self {
+            uuid: *file_obj.get_uuid(),
+            name: file_obj.name().to_string(),
+            path: file_obj.output_file_path(),
+            size: file_obj.file_size,
+            content_type: file_obj.content_type().map(str::to_string),
+            tenant: file_obj.tenant().map(str::to_string),
+            metadata: file_obj.metadata().clone(),
+            tags: file_obj.tags().clone(),
+            digest: file_obj.digest().map(str::to_string),
+            replacement: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    // stop the pipeline, the upload is kept as `Rejected` with the reason
+    Reject(String),
+}
+
+/*
+    One stage of the post upload pipeline, e.g. validating the format or notifying another
+    system. A stage may change the name, content type, metadata and tags of the upload and
+    replace its content through `replacement`, later stages read the new content. Everything
+    is written back once every stage accepted the upload, new content in the layout of the
+    upload with its digest and manifest taken anew.
+ */
+#[async_trait]
+pub trait PostUploadHook: Send + Sync + 'static {
+    // used in the logs and rejection reasons
+    fn name(&self) -> &str;
+
+    async fn run(&self, upload: &mut CompletedUpload) -> Result<Verdict, HookError>;
+}
+
+// the post upload pipeline, with the scanner ahead of the hooks when one is configured
+pub type HooksHandle = Arc<Hooks>;
+
+pub struct Hooks {
+    hooks: Vec<Arc<dyn PostUploadHook>>,
+    // labels left by the hooks are held to the limits of the upload requests
+    limits: Arc<MetadataConfig>,
+    // told about every upload the pipeline settled
+    webhooks: WebhooksHandle,
+    // checks the content ahead of the hooks, only clean uploads are served while it is set
+    scanning: Option<Arc<Scanning>>,
+    // stages the content for the hooks and writes back what they replaced
+    ingest: Arc<Ingest>,
+}
+
+impl Hooks {
+    pub fn new(hooks: Vec<Arc<dyn PostUploadHook>>, limits: MetadataConfig, webhooks: WebhooksHandle, ingest: Ingest) -> Self {
+        Self { hooks, limits: Arc::new(limits), webhooks, scanning: None, ingest: Arc::new(ingest) }
+    }
+
+    pub fn with_scanning(mut self, scanning: Option<Scanning>) -> Self {
+        self.scanning = scanning.map(Arc::new);
+        self
+    }
+
+    // whether the content of an upload in `state` may be handed out
+    pub fn servable(&self, state: UploadState) -> bool {
+        match self.scanning {
+            Some(_) => state == UploadState::Clean,
+            None => matches!(state, UploadState::Complete | UploadState::Clean),
+        }
+    }
+
+    fn idle(&self) -> bool {
+        self.hooks.is_empty() && self.scanning.is_none()
+    }
+
+    /*
+        Move a completed upload to `Processing` and run the pipeline on it, detached from the
+        request. The scanner goes first and leaves the upload `Quarantined` when it objects,
+        the hooks run on what it passed. Once the last stage is done the entry in `ext` ends up
+        `Clean` with a scanner and `Complete` without, or `Rejected` when a hook turned it down
+        or failed. Without hooks or scanner it stays complete.
+     */
+    pub fn dispatch(&self, file_obj: &mut FileObject, ext: &JobHandle) {
+        if self.idle() || file_obj.get_state() != UploadState::Complete {
+            return;
+        }
+
+        file_obj.set_state(UploadState::Processing);
+        self.spawn(file_obj, ext.clone());
+    }
+
+    // run the pipeline again on uploads a previous run left processing
+    pub async fn resume(&self, ext: &JobHandle) {
+        let jobs: Vec<_> = ext.iter().map(|job| job.clone()).collect();
+        for job in jobs {
+            let mut entry = job.lock().await;
+            if entry.get_state() != UploadState::Processing {
+                continue;
+            }
+            match self.idle() {
+                true => {
+                    entry.set_state(UploadState::Complete);
+                    self.webhooks.emit(WebhookEvent::Completed, &entry);
+                },
+                false => self.spawn(&entry, ext.clone()),
+            }
+        }
+    }
+
+    fn spawn(&self, file_obj: &FileObject, ext: JobHandle) {
+        let file = IndexedFile::from(file_obj);
+        let upload = CompletedUpload::from(file_obj);
+        let hooks = self.hooks.clone();
+        let limits = self.limits.clone();
+        let webhooks = self.webhooks.clone();
+        let scanning = self.scanning.clone();
+        let ingest = self.ingest.clone();
+        tokio::spawn(async move {
+            let uuid = upload.uuid;
+            if let Some(scanning) = &scanning {
+                if let Err(reason) = scanning.check(&file, &upload).await {
+                    return settle(&ext, &webhooks, &ingest, uuid, Outcome::Quarantined(reason)).await;
+                }
+            }
+
+            let processed = run_stages(hooks, upload, &file, &ingest).await.and_then(|upload| {
+                match limits.within_limits(&upload.metadata, &upload.tags) {
+                    true => Ok(upload),
+                    false => Err("the labels left by the hooks exceed the metadata limits".to_string()),
+                }
+            });
+            let outcome = match processed {
+                Ok(upload) if scanning.is_some() => Outcome::Accepted(Box::new(upload), UploadState::Clean),
+                Ok(upload) => Outcome::Accepted(Box::new(upload), UploadState::Complete),
+                Err(reason) => Outcome::Rejected(reason),
+            };
+            settle(&ext, &webhooks, &ingest, uuid, outcome).await;
+        });
+    }
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("hooks", &self.hooks.iter().map(|hook| hook.name()).collect::<Vec<_>>())
+            .field("scanning", &self.scanning)
+            .finish()
+    }
+}
+
+// Stages the content for the hooks and writes back what they replaced, in the layout of the upload
+pub struct Ingest {
+    compression: CompressionHandle,
+    encryption: EncryptionHandle,
+    merkle: MerkleHandle,
+    store: BlobStoreHandle,
+}
+
+impl Ingest {
+    pub fn new(config: &LoadConfig, encryption: EncryptionHandle, store: BlobStoreHandle) -> Self {
+        Self {
+            compression: Arc::new(config.section::<CompressionConfig>("compression")),
+            encryption,
+            merkle: Arc::new(config.section::<MerkleConfig>("merkle")),
+            store,
+        }
+    }
+
+    // a plaintext copy next to the upload, None when the stored file already holds the content as it came
+    async fn stage(&self, file: &IndexedFile) -> Result<Option<PathBuf>, FragmentError> {
+        if file.envelope.is_none() && file.compression == Compression::None {
+            return Ok(None);
+        }
+
+        let staged = file.output_file_path().with_extension("hook");
+        let mut content = merkle::read_logical(file, &self.encryption, 0, file.file_size as u64).await?;
+        let mut out = utils::create_fresh(&staged).await?;
+        while let Some(chunk) = content.next().await {
+            out.write_all(&chunk?).await?;
+        }
+        out.flush().await?;
+        Ok(Some(staged))
+    }
+
+    // write the upload anew from `content`, its digest, manifest and crc are taken over the new bytes
+    async fn write_back(&self, file_obj: &mut FileObject, content: &Path) -> Result<(), FragmentError> {
+        let size = tokio::fs::metadata(content).await?.len() as usize;
+        // the old content may be linked to a blob, the upload gives up its reference and gets a file of its own
+        if let Some(digest) = file_obj.take_digest() {
+            self.store.release(&digest, file_obj.compression(), file_obj.tenant())?;
+        }
+
+        let path = file_obj.output_file_path();
+        let mut sink = match file_obj.envelope() {
+            Some(envelope) => {
+                let key = self.encryption.data_key(envelope, &file_obj.get_uuid(), size)?;
+                Sink::Sealed(SealedSink::create(path, key, WRITE_BUFFER).await?)
+            },
+            None => Sink::create(path, file_obj.compression(), &self.compression, WRITE_BUFFER).await?,
+        };
+        let mut tree = self.merkle.enabled.then(|| MerkleBuilder::new(self.merkle.block_size));
+        let mut crc = crc32fast::Hasher::new();
+        let mut sha256 = Sha256::new();
+
+        let mut reader = tokio::fs::File::open(content).await?;
+        let mut buf = vec![0; WRITE_BUFFER];
+        loop {
+            let read = reader.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            sink.write_all(&buf[..read]).await?;
+            if let Some(tree) = &mut tree {
+                tree.update(&buf[..read]);
+            }
+            crc.update(&buf[..read]);
+            sha256.update(&buf[..read]);
+        }
+        sink.checkpoint().await?;
+
+        file_obj.file_size = size;
+        file_obj.set_stored_size(sink.stored_len().await? as usize);
+        file_obj.set_merkle(tree.map(MerkleBuilder::finish));
+        file_obj.set_crc32(Some(crc.finalize()));
+        self.store.ingest(file_obj, Some(format!("{:x}", sha256.finalize()))).await
+    }
+}
+
+// the hooks on the plaintext of the upload, staged next to it unless it is stored as it came
+async fn run_stages(hooks: Vec<Arc<dyn PostUploadHook>>, mut upload: CompletedUpload, file: &IndexedFile, ingest: &Ingest) -> Result<CompletedUpload, String> {
+    if hooks.is_empty() {
+        return Ok(upload);
+    }
+
+    let staged = ingest.stage(file).await.map_err(|e| format!("unable to stage the content: {}", e.state()))?;
+    if let Some(staged) = &staged {
+        upload.path = staged.clone();
+    }
+    let processed = process(hooks, upload).await;
+    if let Some(staged) = staged {
+        discard(&staged).await;
+    }
+    processed
+}
+
+// the stages in order, each on its own task so a panicking hook rejects the upload instead of leaving it processing
+async fn process(hooks: Vec<Arc<dyn PostUploadHook>>, mut upload: CompletedUpload) -> Result<CompletedUpload, String> {
+    // the content the latest replacement left, it goes again when a later stage turns the upload down
+    let mut replaced: Option<PathBuf> = None;
+
+    for hook in hooks {
+        let name = hook.name().to_string();
+        let stage = tokio::spawn(async move {
+            let verdict = hook.run(&mut upload).await;
+            (upload, verdict)
+        });
+
+        let (next, verdict) = match stage.await {
+            Ok(stage) => stage,
+            Err(_) => {
+                if let Some(replaced) = replaced {
+                    discard(&replaced).await;
+                }
+                return Err(format!("{} panicked", name));
+            },
+        };
+        let mut reason = match verdict {
+            Ok(Verdict::Accept) => None,
+            Ok(Verdict::Reject(reason)) => Some(format!("{}: {}", name, reason)),
+            Err(e) => Some(format!("{} failed: {}", name, e)),
+        };
+        upload = next;
+
+        // later stages read the content this one handed over
+        if let Some(replacement) = upload.replacement.take() {
+            if let Some(previous) = replaced.replace(replacement.clone()) {
+                discard(&previous).await;
+            }
+            match tokio::fs::metadata(&replacement).await {
+                Ok(metadata) => {
+                    upload.size = metadata.len() as usize;
+                    upload.path = replacement;
+                    upload.digest = None;
+                },
+                Err(e) => reason = reason.or(Some(format!("{} failed: its replacement can't be read: {}", name, e))),
+            }
+        }
+
+        if let Some(reason) = reason {
+            if let Some(replaced) = replaced {
+                discard(&replaced).await;
+            }
+            return Err(reason);
+        }
+        tracing::debug!(uuid = %upload.uuid, hook = name, "post upload hook accepted");
+    }
+
+    upload.replacement = replaced;
+    Ok(upload)
+}
+
+async fn discard(path: &Path) {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => tracing::warn!(path = %path.display(), "unable to remove hook content: {}", e),
+        _ => {},
+    }
+}
+
+// where the pipeline leaves an upload
+enum Outcome {
+    // with the labels the hooks left and the state it is served in
+    Accepted(Box<CompletedUpload>, UploadState),
+    Rejected(String),
+    Quarantined(String),
+}
+
+// write the outcome back, unless the upload was deleted in the meantime
+async fn settle(ext: &JobHandle, webhooks: &WebhooksHandle, ingest: &Ingest, uuid: Uuid, outcome: Outcome) {
+    let replacement = match &outcome {
+        Outcome::Accepted(upload, _) => upload.replacement.clone(),
+        _ => None,
+    };
+    write_outcome(ext, webhooks, ingest, uuid, outcome).await;
+    if let Some(replacement) = replacement {
+        discard(&replacement).await;
+    }
+}
+
+async fn write_outcome(ext: &JobHandle, webhooks: &WebhooksHandle, ingest: &Ingest, uuid: Uuid, outcome: Outcome) {
+    let Some(job) = ext.get(&uuid).map(|job| job.clone()) else {
+        tracing::debug!(%uuid, "upload removed while processing");
+        return;
+    };
+    let mut file_obj = job.lock().await;
+    if file_obj.get_state() != UploadState::Processing {
+        return;
+    }
+
+    // content the hooks replaced is written back first, an upload it can't be written for is turned down
+    let outcome = match outcome {
+        Outcome::Accepted(upload, state) => match upload.replacement.clone() {
+            Some(content) => match ingest.write_back(&mut file_obj, &content).await {
+                Ok(()) => {
+                    tracing::info!(%uuid, size = file_obj.file_size, "upload content replaced by the hooks");
+                    Outcome::Accepted(upload, state)
+                },
+                Err(e) => Outcome::Rejected(format!("unable to write back the replaced content: {}", e.state())),
+            },
+            None => Outcome::Accepted(upload, state),
+        },
+        outcome => outcome,
+    };
+
+    match outcome {
+        Outcome::Accepted(upload, state) => {
+            let compression = file_obj.compression();
+            file_obj.set_name(upload.name);
+            file_obj.set_storage(compression, upload.content_type);
+            file_obj.set_labels(upload.metadata, upload.tags);
+            file_obj.set_state(state);
+            webhooks.emit(WebhookEvent::Completed, &file_obj);
+        },
+        Outcome::Rejected(reason) => {
+            tracing::info!(%uuid, reason, "upload rejected");
+            file_obj.set_rejection(reason);
+            file_obj.set_state(UploadState::Rejected);
+            webhooks.emit(WebhookEvent::Rejected, &file_obj);
+        },
+        Outcome::Quarantined(reason) => {
+            tracing::warn!(%uuid, reason, "upload quarantined");
+            file_obj.set_rejection(reason);
+            file_obj.set_state(UploadState::Quarantined);
+            webhooks.emit(WebhookEvent::Quarantined, &file_obj);
+        },
+    }
+}
+
+/*
+    One entry of `[[hooks]]`, a local program run on every completed upload
+        name = "validate"
+        exec = "/usr/local/bin/validate-upload"
+        args = ["--strict"]
+        timeout = 60
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct HookConfig {
+    pub name: String,
+    pub exec: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    // seconds the program may run before the upload is rejected
+    #[serde(default = "HookConfig::default_timeout")]
+    pub timeout: u64,
+}
+
+impl HookConfig {
+    fn default_timeout() -> u64 {
+        60
+    }
+}
+
+/*
+    Runs a program with the upload described in its environment:
+        LOFTY_UUID, LOFTY_FILE, LOFTY_NAME, LOFTY_SIZE, LOFTY_CONTENT_TYPE, LOFTY_TENANT,
+        LOFTY_DIGEST, LOFTY_TAGS (comma separated) and LOFTY_META_<KEY>
+    A zero exit accepts the upload, `key=value` lines it prints are merged into the metadata
+    and a file it wrote at LOFTY_OUTPUT replaces the content. Any other exit rejects it with
+    the last line written to stderr.
+ */
+#[derive(Debug)]
+pub struct ExecHook {
+    config: HookConfig,
+}
+
+impl ExecHook {
+    pub fn new(config: HookConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[async_trait]
+impl PostUploadHook for ExecHook {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    async fn run(&self, upload: &mut CompletedUpload) -> Result<Verdict, HookError> {
+        // next to the content, a name of its own for every run
+        let replacement = upload.path.with_file_name(format!("{}.{}.out", upload.uuid, Uuid::new_v4().simple()));
+        let mut command = tokio::process::Command::new(&self.config.exec);
+        command
+            .args(&self.config.args)
+            .envs(environment(upload))
+            .env("LOFTY_OUTPUT", &replacement)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        let timeout = Duration::from_secs(self.config.timeout);
+        let output = tokio::time::timeout(timeout, command.output()).await;
+        let output = match output {
+            Ok(Ok(output)) if output.status.success() => output,
+            output => {
+                discard(&replacement).await;
+                output.map_err(|_| format!("timed out after {}s", self.config.timeout))??
+            },
+        };
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let reason = match stderr.lines().map(str::trim).rfind(|line| !line.is_empty()) {
+                Some(line) => line.chars().take(512).collect(),
+                None => format!("exited with {}", output.status),
+            };
+            return Ok(Verdict::Reject(reason));
+        }
+
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            if let Some((key, value)) = line.split_once('=') {
+                upload.metadata.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+        if tokio::fs::try_exists(&replacement).await? {
+            upload.replacement = Some(replacement);
+        }
+        Ok(Verdict::Accept)
+    }
+}
+
+fn environment(upload: &CompletedUpload) -> Vec<(String, String)> {
+    let mut env = vec![
+        ("LOFTY_UUID".to_string(), upload.uuid.to_string()),
+        ("LOFTY_FILE".to_string(), upload.path.display().to_string()),
+        ("LOFTY_NAME".to_string(), upload.name.clone()),
+        ("LOFTY_SIZE".to_string(), upload.size.to_string()),
+        ("LOFTY_CONTENT_TYPE".to_string(), upload.content_type.clone().unwrap_or_default()),
+        ("LOFTY_TENANT".to_string(), upload.tenant.clone().unwrap_or_default()),
+        ("LOFTY_DIGEST".to_string(), upload.digest.clone().unwrap_or_default()),
+        ("LOFTY_TAGS".to_string(), upload.tags.iter().cloned().collect::<Vec<_>>().join(",")),
+    ];
+
+    for (key, value) in &upload.metadata {
+        let key: String = key.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect();
+        env.push((format!("LOFTY_META_{}", key), value.clone()));
+    }
+    env
+}
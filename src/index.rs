@@ -0,0 +1,642 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ops::Bound,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use axum::{body::Body, extract::Query, http::Response, Extension};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{authorization::Principal, compression::Compression, encryption::Envelope, errors::ErrorStates, file::{FileObject, UploadState}, handlers::JobHandle, merkle::MerkleTree, sniff, FragmentError};
+
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+// in memory view of the registry, built at startup and kept in step with the uploads
+pub type FileIndexHandle = Arc<FileIndex>;
+
+// Snapshot of a `FileObject` as it is listed
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexedFile {
+    pub uuid: Uuid,
+    pub name: String,
+    pub raw_name: Option<String>,
+    #[serde(skip)]
+    pub path: PathBuf,
+    pub file_size: usize,
+    pub hash: String,
+    pub state: UploadState,
+    pub created_at: DateTime<Utc>,
+    pub tenant: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+    pub tags: BTreeSet<String>,
+    pub rejection: Option<String>,
+    pub digest: Option<String>,
+    pub content_type: Option<String>,
+    pub detected_type: Option<String>,
+    pub compression: Compression,
+    pub stored_size: usize,
+    #[serde(skip)]
+    pub envelope: Option<Envelope>,
+    pub encrypted: bool,
+    #[serde(skip)]
+    pub merkle: Option<Arc<MerkleTree>>,
+    #[serde(skip)]
+    pub crc32: Option<u32>,
+}
+
+impl IndexedFile {
+    pub fn output_file_path(&self) -> PathBuf {
+        self.path.join(self.uuid.as_hyphenated().to_string())
+    }
+
+    pub fn served_type(&self) -> &str {
+        sniff::served_type(self.detected_type.as_deref(), self.content_type.as_deref()).unwrap_or("application/octet-stream")
+    }
+}
+
+impl From<&FileObject> for IndexedFile {
+    fn from(file_obj: &FileObject) -> Self {
+        Self {
+            uuid: *file_obj.get_uuid(),
+            name: file_obj.name().to_string(),
+            raw_name: file_obj.raw_name().map(str::to_string),
+            path: file_obj.path.clone(),
+            file_size: file_obj.file_size,
+            hash: String::from_utf8_lossy(file_obj.hash()).into_owned(),
+            state: file_obj.get_state(),
+            created_at: file_obj.created_at(),
+            tenant: file_obj.tenant().map(str::to_string),
+            metadata: file_obj.metadata().clone(),
+            tags: file_obj.tags().clone(),
+            rejection: file_obj.rejection().map(str::to_string),
+            digest: file_obj.digest().map(str::to_string),
+            content_type: file_obj.content_type().map(str::to_string),
+            detected_type: file_obj.detected_type().map(str::to_string),
+            compression: file_obj.compression(),
+            stored_size: file_obj.stored_size(),
+            envelope: file_obj.envelope().cloned(),
+            encrypted: file_obj.envelope().is_some(),
+            merkle: file_obj.merkle().cloned().map(Arc::new),
+            crc32: file_obj.crc32(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    #[default]
+    Created,
+    Name,
+    Size,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/*
+    GET /files query string, every filter is optional and they all have to match
+        state=complete,broken
+        name_prefix=report-
+        min_size=1024&max_size=1048576
+        created_after=2024-01-01T00:00:00Z&created_before=...
+        tenant=ingest-bot
+        metadata=source=ci,build_id=1234
+        tags=nightly,signed
+        sort=created|name|size&order=asc|desc
+        limit=100&cursor=<next_cursor of the previous page>
+    Callers only ever see their own uploads, asking for the tenant of someone else finds nothing.
+ */
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FileQuery {
+    pub state: Option<String>,
+    pub name_prefix: Option<String>,
+    pub min_size: Option<usize>,
+    pub max_size: Option<usize>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub tenant: Option<String>,
+    pub metadata: Option<String>,
+    pub tags: Option<String>,
+    #[serde(default)]
+    pub sort: SortKey,
+    #[serde(default)]
+    pub order: SortOrder,
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    // the principal the listing is for, set by the handler and never taken from the query
+    #[serde(skip)]
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FilePage {
+    pub files: Vec<IndexedFile>,
+    // pass back as `cursor` to fetch the following page, absent on the last one
+    pub next_cursor: Option<String>,
+}
+
+/*
+    Position of the last entry of a page in the order it was listed by, the sort key and uuid
+    rather than the entry itself, so a page still follows when that entry was deleted meanwhile.
+    Handed out opaque: `created.<uuid>.<rfc3339>`, `name.<uuid>.<name>` or `size.<uuid>.<bytes>`
+    in url safe base64.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Cursor {
+    Created(DateTime<Utc>, Uuid),
+    Name(String, Uuid),
+    Size(usize, Uuid),
+}
+
+impl Cursor {
+    fn of(sort: SortKey, file: &IndexedFile) -> Self {
+        match sort {
+            SortKey::Created => Cursor::Created(file.created_at, file.uuid),
+            SortKey::Name => Cursor::Name(file.name.clone(), file.uuid),
+            SortKey::Size => Cursor::Size(file.file_size, file.uuid),
+        }
+    }
+
+    fn sort(&self) -> SortKey {
+        match self {
+            Cursor::Created(..) => SortKey::Created,
+            Cursor::Name(..) => SortKey::Name,
+            Cursor::Size(..) => SortKey::Size,
+        }
+    }
+
+    fn encode(&self) -> String {
+        let position = match self {
+            Cursor::Created(at, uuid) => format!("created.{}.{}", uuid, at.to_rfc3339()),
+            Cursor::Name(name, uuid) => format!("name.{}.{}", uuid, name),
+            Cursor::Size(size, uuid) => format!("size.{}.{}", uuid, size),
+        };
+        URL_SAFE_NO_PAD.encode(position)
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let position = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+        let (sort, rest) = position.split_once('.')?;
+        let (uuid, value) = rest.split_once('.')?;
+        let uuid = Uuid::parse_str(uuid).ok()?;
+
+        match sort {
+            "created" => Some(Cursor::Created(DateTime::parse_from_rfc3339(value).ok()?.with_timezone(&Utc), uuid)),
+            "name" => Some(Cursor::Name(value.to_string(), uuid)),
+            "size" => Some(Cursor::Size(value.parse().ok()?, uuid)),
+            _ => None,
+        }
+    }
+
+    fn created(&self) -> Option<(DateTime<Utc>, Uuid)> {
+        match self {
+            Cursor::Created(at, uuid) => Some((*at, *uuid)),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> Option<(String, Uuid)> {
+        match self {
+            Cursor::Name(name, uuid) => Some((name.clone(), *uuid)),
+            _ => None,
+        }
+    }
+
+    fn size(&self) -> Option<(usize, Uuid)> {
+        match self {
+            Cursor::Size(size, uuid) => Some((*size, *uuid)),
+            _ => None,
+        }
+    }
+
+    // where `file` stands relative to the cursor in ascending order
+    fn cmp_file(&self, file: &IndexedFile) -> std::cmp::Ordering {
+        match self {
+            Cursor::Created(at, uuid) => (*at, *uuid).cmp(&(file.created_at, file.uuid)),
+            Cursor::Name(name, uuid) => (name.as_str(), *uuid).cmp(&(file.name.as_str(), file.uuid)),
+            Cursor::Size(size, uuid) => (*size, *uuid).cmp(&(file.file_size, file.uuid)),
+        }
+    }
+}
+
+// a parsed `FileQuery`, checked once before walking the index
+struct Filter<'a> {
+    states: Option<HashSet<&'a str>>,
+    name_prefix: Option<&'a str>,
+    min_size: Option<usize>,
+    max_size: Option<usize>,
+    created_after: Option<DateTime<Utc>>,
+    created_before: Option<DateTime<Utc>>,
+    tenant: Option<&'a str>,
+    owner: Option<&'a str>,
+    metadata: Vec<(&'a str, &'a str)>,
+    tags: Vec<&'a str>,
+}
+
+impl<'a> Filter<'a> {
+    fn parse(query: &'a FileQuery) -> Result<Self, ErrorStates> {
+        let states = match &query.state {
+            Some(states) => {
+                let states: HashSet<&str> = states.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+                if states.iter().any(|state| !STATE_KINDS.contains(state)) {
+                    return Err(ErrorStates::InvalidQuery("state"));
+                }
+                Some(states)
+            },
+            None => None,
+        };
+
+        let metadata = match &query.metadata {
+            Some(metadata) => metadata
+                .split(',')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| pair.split_once('=').ok_or(ErrorStates::InvalidQuery("metadata")))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+
+        let tags = match &query.tags {
+            Some(tags) => tags.split(',').map(str::trim).filter(|tag| !tag.is_empty()).collect(),
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            states,
+            name_prefix: query.name_prefix.as_deref(),
+            min_size: query.min_size,
+            max_size: query.max_size,
+            created_after: query.created_after,
+            created_before: query.created_before,
+            tenant: query.tenant.as_deref(),
+            owner: query.owner.as_deref(),
+            metadata,
+            tags,
+        })
+    }
+
+    fn matches(&self, file: &IndexedFile) -> bool {
+        self.states.as_ref().is_none_or(|states| states.contains(file.state.kind()))
+            && self.name_prefix.is_none_or(|prefix| file.name.starts_with(prefix))
+            && self.min_size.is_none_or(|min| file.file_size >= min)
+            && self.max_size.is_none_or(|max| file.file_size <= max)
+            && self.created_after.is_none_or(|after| file.created_at >= after)
+            && self.created_before.is_none_or(|before| file.created_at <= before)
+            && self.tenant.is_none_or(|tenant| file.tenant.as_deref() == Some(tenant))
+            && self.owner.is_none_or(|owner| file.tenant.as_deref().is_none_or(|tenant| tenant == owner))
+            && self.metadata.iter().all(|(key, value)| file.metadata.get(*key).map(String::as_str) == Some(*value))
+            && self.tags.iter().all(|tag| file.tags.contains(*tag))
+    }
+}
+
+const STATE_KINDS: [&str; 11] = ["uninit", "init", "broken", "progress", "resume", "complete", "processing", "rejected", "clean", "quarantined", "failed"];
+
+#[derive(Debug, Default)]
+struct IndexInner {
+    files: HashMap<Uuid, IndexedFile>,
+    by_created: BTreeSet<(DateTime<Utc>, Uuid)>,
+    by_name: BTreeSet<(String, Uuid)>,
+    by_size: BTreeSet<(usize, Uuid)>,
+    by_tenant: HashMap<String, HashSet<Uuid>>,
+    by_metadata: HashMap<(String, String), HashSet<Uuid>>,
+    by_tag: HashMap<String, HashSet<Uuid>>,
+}
+
+/*
+    Secondary indexes over the registry, so listings never lock the `JobHandle` shards
+    (active uploads hold on to their entry for the whole transfer).
+    Queries walk the index of the sort key and only touch the entries they return,
+    tenant, metadata and tag filters start from their own sets instead.
+ */
+#[derive(Debug, Default)]
+pub struct FileIndex {
+    inner: RwLock<IndexInner>,
+}
+
+impl FileIndex {
+    // index every object of the registry, meant to run before any upload is accepted
+    pub async fn build(handle: &JobHandle) -> FileIndexHandle {
+        let index: FileIndexHandle = Arc::new(FileIndex::default());
+        let jobs: Vec<_> = handle.iter().map(|job| job.clone()).collect();
+        for job in jobs {
+            job.lock().await.attach_index(index.clone());
+        }
+        index
+    }
+
+    pub fn get(&self, uuid: &Uuid) -> Option<IndexedFile> {
+        self.inner.read().unwrap().files.get(uuid).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().files.len()
+    }
+
+    pub fn upsert(&self, file_obj: &FileObject) {
+        let file = IndexedFile::from(file_obj);
+        let mut inner = self.inner.write().unwrap();
+        inner.remove(&file.uuid);
+        inner.insert(file);
+    }
+
+    pub fn remove(&self, uuid: &Uuid) {
+        self.inner.write().unwrap().remove(uuid);
+    }
+
+    // files created before `cutoff`, oldest first
+    pub fn created_before(&self, cutoff: DateTime<Utc>) -> Vec<IndexedFile> {
+        let inner = self.inner.read().unwrap();
+        inner.by_created
+            .range(..(cutoff, Uuid::nil()))
+            .filter_map(|(_, uuid)| inner.files.get(uuid).cloned())
+            .collect()
+    }
+
+    // the state is not part of any ordered index, it is updated in place on every change of kind
+    pub fn set_state(&self, uuid: &Uuid, state: UploadState) {
+        if let Some(file) = self.inner.write().unwrap().files.get_mut(uuid) {
+            file.state = state;
+        }
+    }
+
+    pub fn set_stored_size(&self, uuid: &Uuid, stored_size: usize) {
+        if let Some(file) = self.inner.write().unwrap().files.get_mut(uuid) {
+            file.stored_size = stored_size;
+        }
+    }
+
+    // every file the query matches in its order, page after page
+    pub fn matching(&self, query: &FileQuery) -> Result<Vec<IndexedFile>, ErrorStates> {
+        let mut query = FileQuery { limit: Some(MAX_PAGE_SIZE), cursor: None, ..query.clone() };
+        let mut files = Vec::new();
+        loop {
+            let page = self.query(&query)?;
+            files.extend(page.files);
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => return Ok(files),
+            }
+        }
+    }
+
+    pub fn query(&self, query: &FileQuery) -> Result<FilePage, ErrorStates> {
+        let filter = Filter::parse(query)?;
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+        let inner = self.inner.read().unwrap();
+
+        // a cursor only continues the order it was handed out for
+        let after = match &query.cursor {
+            Some(cursor) => Some(Cursor::decode(cursor).filter(|cursor| cursor.sort() == query.sort).ok_or(ErrorStates::InvalidQuery("cursor"))?),
+            None => None,
+        };
+
+        // one extra entry tells whether another page follows
+        let mut matched: Vec<&IndexedFile> = match inner.narrowed(&filter) {
+            Some(candidates) => {
+                let mut candidates: Vec<&IndexedFile> = candidates
+                    .into_iter()
+                    .filter_map(|uuid| inner.files.get(&uuid))
+                    .filter(|file| filter.matches(file))
+                    .collect();
+                candidates.sort_by(|a, b| compare(query.sort, a, b));
+                if query.order == SortOrder::Desc {
+                    candidates.reverse();
+                }
+
+                candidates
+                    .into_iter()
+                    .filter(|file| after.as_ref().is_none_or(|after| follows(query, after, file)))
+                    .take(limit + 1)
+                    .collect()
+            },
+            None => inner.walk(query, &filter, after.as_ref(), limit + 1),
+        };
+
+        let next_cursor = if matched.len() > limit {
+            matched.truncate(limit);
+            matched.last().map(|file| Cursor::of(query.sort, file).encode())
+        } else {
+            None
+        };
+
+        Ok(FilePage {
+            files: matched.into_iter().cloned().collect(),
+            next_cursor,
+        })
+    }
+}
+
+impl IndexInner {
+    fn insert(&mut self, file: IndexedFile) {
+        let uuid = file.uuid;
+        self.by_created.insert((file.created_at, uuid));
+        self.by_name.insert((file.name.clone(), uuid));
+        self.by_size.insert((file.file_size, uuid));
+        if let Some(tenant) = &file.tenant {
+            self.by_tenant.entry(tenant.clone()).or_default().insert(uuid);
+        }
+        for (key, value) in &file.metadata {
+            self.by_metadata.entry((key.clone(), value.clone())).or_default().insert(uuid);
+        }
+        for tag in &file.tags {
+            self.by_tag.entry(tag.clone()).or_default().insert(uuid);
+        }
+        self.files.insert(uuid, file);
+    }
+
+    fn remove(&mut self, uuid: &Uuid) {
+        let Some(file) = self.files.remove(uuid) else {
+            return;
+        };
+
+        self.by_created.remove(&(file.created_at, file.uuid));
+        self.by_name.remove(&(file.name, file.uuid));
+        self.by_size.remove(&(file.file_size, file.uuid));
+        if let Some(tenant) = file.tenant {
+            remove_from_set(&mut self.by_tenant, tenant, uuid);
+        }
+        for pair in file.metadata {
+            remove_from_set(&mut self.by_metadata, pair, uuid);
+        }
+        for tag in file.tags {
+            remove_from_set(&mut self.by_tag, tag, uuid);
+        }
+    }
+
+    // the smallest candidate set the tenant, metadata and tag filters allow, `None` when none is given
+    fn narrowed(&self, filter: &Filter<'_>) -> Option<HashSet<Uuid>> {
+        let empty = HashSet::new();
+        let mut sets: Vec<&HashSet<Uuid>> = Vec::new();
+
+        if let Some(tenant) = filter.tenant {
+            sets.push(self.by_tenant.get(tenant).unwrap_or(&empty));
+        }
+        for (key, value) in &filter.metadata {
+            sets.push(self.by_metadata.get(&(key.to_string(), value.to_string())).unwrap_or(&empty));
+        }
+        for tag in &filter.tags {
+            sets.push(self.by_tag.get(*tag).unwrap_or(&empty));
+        }
+
+        sets.sort_by_key(|set| set.len());
+        let (smallest, rest) = sets.split_first()?;
+
+        Some(smallest.iter().filter(|uuid| rest.iter().all(|set| set.contains(uuid))).copied().collect())
+    }
+
+    // walk the ordered index of the sort key, bounded by the filter on that same key
+    fn walk<'a>(&'a self, query: &FileQuery, filter: &Filter<'_>, after: Option<&Cursor>, take: usize) -> Vec<&'a IndexedFile> {
+        let desc = query.order == SortOrder::Desc;
+        let lookup = |uuid: &Uuid| self.files.get(uuid);
+        let keep = |file: &&IndexedFile| filter.matches(file);
+        let (lower_cursor, upper_cursor) = match after { 
+            Some(after) if desc => (None, Some(after)),
+            Some(after) => (Some(after), None),
+            None => (None, None),
+        };
+
+        match query.sort {
+            SortKey::Created => {
+                let lower = tighter_lower(filter.created_after.map_or(Bound::Unbounded, |at| Bound::Included((at, Uuid::nil()))), lower_cursor.and_then(Cursor::created));
+                let upper = tighter_upper(filter.created_before.map_or(Bound::Unbounded, |at| Bound::Included((at, Uuid::max()))), upper_cursor.and_then(Cursor::created));
+                walk_range(&self.by_created, lower, upper, desc, |(_, uuid)| lookup(uuid), keep, take)
+            },
+            SortKey::Name => {
+                let prefix = filter.name_prefix.unwrap_or("");
+                let lower = tighter_lower(Bound::Included((prefix.to_string(), Uuid::nil())), lower_cursor.and_then(Cursor::name));
+                let upper = tighter_upper(prefix_end(prefix).map_or(Bound::Unbounded, |end| Bound::Excluded((end, Uuid::nil()))), upper_cursor.and_then(Cursor::name));
+                walk_range(&self.by_name, lower, upper, desc, |(_, uuid)| lookup(uuid), keep, take)
+            },
+            SortKey::Size => {
+                let lower = tighter_lower(filter.min_size.map_or(Bound::Unbounded, |size| Bound::Included((size, Uuid::nil()))), lower_cursor.and_then(Cursor::size));
+                let upper = tighter_upper(filter.max_size.map_or(Bound::Unbounded, |size| Bound::Included((size, Uuid::max()))), upper_cursor.and_then(Cursor::size));
+                walk_range(&self.by_size, lower, upper, desc, |(_, uuid)| lookup(uuid), keep, take)
+            },
+        }
+    }
+}
+
+fn remove_from_set<K: std::hash::Hash + Eq>(sets: &mut HashMap<K, HashSet<Uuid>>, key: K, uuid: &Uuid) {
+    if let Some(set) = sets.get_mut(&key) {
+        set.remove(uuid);
+        if set.is_empty() {
+            sets.remove(&key);
+        }
+    }
+}
+
+// smallest string ordered after every string starting with `prefix`, `None` if there is no such bound
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+// the cursor position is exclusive and replaces the filter bound once it is past it
+fn tighter_lower<K: Ord>(filter: Bound<K>, cursor: Option<K>) -> Bound<K> {
+    match (filter, cursor) {
+        (Bound::Included(bound) | Bound::Excluded(bound), Some(cursor)) if cursor < bound => Bound::Included(bound),
+        (_, Some(cursor)) => Bound::Excluded(cursor),
+        (filter, None) => filter,
+    }
+}
+
+fn tighter_upper<K: Ord>(filter: Bound<K>, cursor: Option<K>) -> Bound<K> {
+    match (filter, cursor) {
+        (Bound::Included(bound), Some(cursor)) if cursor > bound => Bound::Included(bound),
+        (Bound::Excluded(bound), Some(cursor)) if cursor > bound => Bound::Excluded(bound),
+        (_, Some(cursor)) => Bound::Excluded(cursor),
+        (filter, None) => filter,
+    }
+}
+
+fn walk_range<'a, K: Ord + 'a>(
+    index: &'a BTreeSet<K>,
+    lower: Bound<K>,
+    upper: Bound<K>,
+    desc: bool,
+    lookup: impl Fn(&'a K) -> Option<&'a IndexedFile>,
+    keep: impl Fn(&&'a IndexedFile) -> bool,
+    take: usize,
+) -> Vec<&'a IndexedFile> {
+    // `BTreeSet::range` panics on inverted bounds, which a stale cursor can produce
+    let empty = match (&lower, &upper) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) => start >= end,
+        _ => false,
+    };
+    if empty {
+        return Vec::new();
+    }
+
+    let range = index.range((lower, upper));
+    if desc {
+        range.rev().filter_map(lookup).filter(keep).take(take).collect()
+    } else {
+        range.filter_map(lookup).filter(keep).take(take).collect()
+    }
+}
+
+fn compare(sort: SortKey, a: &IndexedFile, b: &IndexedFile) -> std::cmp::Ordering {
+    match sort {
+        SortKey::Created => (a.created_at, a.uuid).cmp(&(b.created_at, b.uuid)),
+        SortKey::Name => (&a.name, a.uuid).cmp(&(&b.name, b.uuid)),
+        SortKey::Size => (a.file_size, a.uuid).cmp(&(b.file_size, b.uuid)),
+    }
+}
+
+// whether `file` comes after the cursor in the requested order
+fn follows(query: &FileQuery, after: &Cursor, file: &IndexedFile) -> bool {
+    match query.order {
+        SortOrder::Asc => after.cmp_file(file).is_lt(),
+        SortOrder::Desc => after.cmp_file(file).is_gt(),
+    }
+}
+
+pub async fn list_files(
+    Extension(index): Extension<FileIndexHandle>,
+    principal: Principal,
+    Query(query): Query<FileQuery>,
+) -> Result<Response<Body>, FragmentError> {
+
+    let query = FileQuery { owner: Some(principal.to_string()), ..query };
+    respond(&index, &query)
+}
+
+// GET /admin/files, the uploads of every tenant for listeners that mount the admin routes
+pub async fn list_all_files(
+    Extension(index): Extension<FileIndexHandle>,
+    Query(query): Query<FileQuery>,
+) -> Result<Response<Body>, FragmentError> {
+
+    respond(&index, &query)
+}
+
+fn respond(index: &FileIndex, query: &FileQuery) -> Result<Response<Body>, FragmentError> {
+    let page = index.query(query)?;
+    let body = serde_json::to_vec(&page).map_err(|_| ErrorStates::UndeclaredError)?;
+
+    let resp = Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))?;
+
+    Ok(resp)
+}
@@ -0,0 +1,49 @@
+/*
+    lofty as a library: `LoftyServer::builder()` assembles a server from its settings, storage,
+    authentication and post upload hooks, which either runs on its own listeners or hands out
+    an axum `Router` to nest into another service. The `lofty` binary is a thin wrapper around it.
+ */
+
+mod file;
+mod errors;
+mod utils;
+mod handlers;
+mod authorization;
+mod limiter;
+mod metrics;
+mod telemetry;
+mod audit;
+mod shutdown;
+mod tls;
+mod listener;
+mod index;
+mod metadata;
+mod download;
+mod store;
+mod compression;
+mod encryption;
+mod encoding;
+mod checksum;
+mod merkle;
+mod storage;
+mod hooks;
+mod scanning;
+mod sniff;
+mod filename;
+mod bundle;
+mod webhooks;
+mod expiry;
+mod server;
+pub mod config;
+pub mod maintenance;
+
+pub use errors::{ErrorStates, FragmentError};
+pub use authorization::{AuthProvider, Principal};
+pub use hooks::{CompletedUpload, ExecHook, HookConfig, HookError, PostUploadHook, Verdict};
+pub use scanning::{ClamdScanner, ScanError, ScanVerdict, Scanner};
+pub use config::LoadConfig;
+pub use listener::Mount;
+pub use storage::{LocalStorage, Storage};
+pub use server::{LoftyServer, LoftyServerBuilder};
+pub use telemetry::{init_tracing, LogConfig};
+pub use encryption::rotate_keys;
@@ -0,0 +1,158 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{extract::Request, middleware::Next, response::Response, Extension};
+use dashmap::DashMap;
+use serde::Deserialize;
+use tokio::{sync::{OwnedSemaphorePermit, Semaphore}, time::Instant};
+
+use crate::{authorization::Principal, errors::ErrorStates, FragmentError};
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LimitConfig {
+    // sustained request rate allowed for a single principal
+    pub requests_per_second: f64,
+    // requests a principal may fire in a burst before being throttled
+    pub burst: u32,
+    // simultaneously active `streamer_writer` instances across the server
+    pub max_active_uploads: usize,
+    pub max_active_uploads_per_principal: usize,
+    // seconds advertised in `Retry-After` when an upload slot is unavailable
+    pub retry_after: u64,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_second: 50.0,
+            burst: 100,
+            max_active_uploads: 256,
+            max_active_uploads_per_principal: 8,
+            retry_after: 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// token buckets and upload permits, kept per principal across requests
+pub type RateLimiter = Arc<Limiter>;
+
+#[derive(Debug)]
+pub struct Limiter {
+    config: LimitConfig,
+    buckets: DashMap<Principal, TokenBucket>,
+    uploads: Arc<Semaphore>,
+    principal_uploads: DashMap<Principal, Arc<Semaphore>>,
+}
+
+// Held for as long as an upload stream is being written to disk
+#[derive(Debug)]
+pub struct UploadPermit {
+    _global: OwnedSemaphorePermit,
+    _principal: OwnedSemaphorePermit,
+}
+
+impl Limiter {
+    pub fn new(config: LimitConfig) -> Self {
+        Self {
+            uploads: Arc::new(Semaphore::new(config.max_active_uploads)),
+            buckets: DashMap::new(),
+            principal_uploads: DashMap::new(),
+            config,
+        }
+    }
+
+    // take a token from the principal's bucket, or report how long until one is available
+    pub fn check_rate(&self, principal: &Principal) -> Result<(), ErrorStates> {
+        let now = Instant::now();
+        let capacity = self.config.burst.max(1) as f64;
+        let rate = self.config.requests_per_second;
+
+        let mut bucket = self.buckets
+            .entry(principal.clone())
+            .or_insert_with(|| TokenBucket { tokens: capacity, last_refill: now });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let wait = if rate > 0.0 {
+            ((1.0 - bucket.tokens) / rate).ceil() as u64
+        } else {
+            self.config.retry_after
+        };
+
+        Err(ErrorStates::TooManyRequests(wait.max(1)))
+    }
+
+    // reserve a writer slot both globally and for the principal
+    pub fn acquire_upload(&self, principal: &Principal) -> Result<UploadPermit, ErrorStates> {
+        let retry_after = self.config.retry_after.max(1);
+
+        let global = self.uploads
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| ErrorStates::TooManyRequests(retry_after))?;
+
+        let semaphore = self.principal_uploads
+            .entry(principal.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.config.max_active_uploads_per_principal)))
+            .clone();
+
+        let principal = semaphore
+            .try_acquire_owned()
+            .map_err(|_| ErrorStates::TooManyRequests(retry_after))?;
+
+        Ok(UploadPermit {
+            _global: global,
+            _principal: principal,
+        })
+    }
+
+    // drop state for principals that went quiet, so the maps don't grow with every client seen
+    pub fn sweep(&self) {
+        let now = Instant::now();
+        let capacity = self.config.burst.max(1) as f64;
+        let rate = self.config.requests_per_second;
+
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens + elapsed * rate < capacity
+        });
+
+        // the map holds the only reference once every permit has been released
+        self.principal_uploads.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+    }
+}
+
+pub async fn sweep_periodically(limiter: RateLimiter, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        limiter.sweep();
+    }
+}
+
+// middleware applying the per-principal request rate to every route
+pub async fn rate_limit(
+    Extension(limiter): Extension<RateLimiter>,
+    principal: Principal,
+    req: Request,
+    next: Next,
+) -> Result<Response, FragmentError> {
+
+    limiter.check_rate(&principal)?;
+
+    Ok(next.run(req).await)
+}
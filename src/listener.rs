@@ -0,0 +1,171 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{authorization::Principal, FragmentError};
+
+
+// groups of routes a listener can expose
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mount {
+    // /schedule_upload, /upload_file, /status, /resume_upload and /upload_piece
+    Uploads,
+    // /files listing, metadata updates, downloads and deletes
+    Files,
+    Metrics,
+    // /admin/files across tenants, /admin/audit and friends
+    Admin,
+    Dashboard,
+}
+
+impl Mount {
+    pub fn all() -> Vec<Mount> {
+        vec![Mount::Uploads, Mount::Files, Mount::Metrics, Mount::Admin, Mount::Dashboard]
+    }
+}
+
+/*
+    One entry of `[[server.listeners]]`, exactly one of `tcp` or `unix` has to be set
+        tcp = "[::1]:2054"
+        unix = "/run/lofty/lofty.sock"
+        mode = 0o660
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListenerConfig {
+    pub name: Option<String>,
+    pub tcp: Option<SocketAddr>,
+    pub unix: Option<PathBuf>,
+    // permissions applied to the socket file
+    pub mode: Option<u32>,
+    // terminate TLS with the `[tls]` certificates, defaults to `tls.enabled`
+    pub tls: Option<bool>,
+    #[serde(default = "Mount::all")]
+    pub mounts: Vec<Mount>,
+}
+
+impl ListenerConfig {
+    pub fn tcp(addr: SocketAddr) -> Self {
+        Self { name: None, tcp: Some(addr), unix: None, mode: None, tls: None, mounts: Mount::all() }
+    }
+
+    pub fn label(&self) -> String {
+        match (&self.name, &self.tcp, &self.unix) {
+            (Some(name), _, _) => name.clone(),
+            (None, Some(addr), _) => addr.to_string(),
+            (None, None, Some(path)) => format!("unix:{}", path.display()),
+            (None, None, None) => "unnamed".to_string(),
+        }
+    }
+
+    pub fn tls(&self, default: bool) -> bool {
+        self.tls.unwrap_or(default)
+    }
+}
+
+// A bound listener, accepting either TCP connections or connections on a unix domain socket
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+// who is on the other side of an accepted connection
+#[derive(Debug, Clone, Default)]
+pub struct Peer {
+    pub addr: Option<SocketAddr>,
+    pub principal: Option<Principal>,
+}
+
+impl std::fmt::Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.addr, &self.principal) {
+            (Some(addr), _) => write!(f, "{}", addr),
+            (None, Some(principal)) => write!(f, "{}", principal),
+            (None, None) => write!(f, "unknown"),
+        }
+    }
+}
+
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Connection for T {}
+
+impl Listener {
+    pub async fn bind(config: &ListenerConfig) -> Result<Self, FragmentError> {
+        match (&config.tcp, &config.unix) {
+            (Some(addr), None) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            (None, Some(path)) => Self::bind_unix(path, config.mode),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("listener {} needs exactly one of `tcp` or `unix`", config.label()),
+            ).into()),
+        }
+    }
+
+    #[cfg(unix)]
+    fn bind_unix(path: &std::path::Path, mode: Option<u32>) -> Result<Self, FragmentError> {
+        use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+        // a socket file left behind by a previous run would make the bind fail
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            if meta.file_type().is_socket() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // bound inside a directory only we can enter and moved into place once its mode is set,
+        // nobody gets to connect through the permissions the umask handed out in between
+        let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(std::path::Path::new("."));
+        let staging = parent.join(format!(".lofty-{}", uuid::Uuid::new_v4()));
+        std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+
+        let staged = staging.join("socket");
+        let bound = tokio::net::UnixListener::bind(&staged).and_then(|listener| {
+            if let Some(mode) = mode {
+                std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+            }
+            std::fs::rename(&staged, path)?;
+            Ok(listener)
+        });
+        let _ = std::fs::remove_file(&staged);
+        let _ = std::fs::remove_dir(&staging);
+
+        Ok(Listener::Unix(bound?, path.to_path_buf()))
+    }
+
+    pub async fn accept(&self) -> std::io::Result<(Box<dyn Connection>, Peer)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr): (TcpStream, _) = listener.accept().await?;
+                let _ = stream.set_nodelay(true);
+                Ok((Box::new(stream), Peer { addr: Some(addr), principal: None }))
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                // local clients are told apart by their user id
+                let principal = stream.peer_cred().ok().map(|cred| Principal::new(format!("uid:{}", cred.uid())));
+                Ok((Box::new(stream), Peer { addr: None, principal }))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -1,42 +1,62 @@
-use std::{sync::Arc, time::Duration};
-
-// use errors::BackendErrors;
-use dashmap::DashMap;
-use errors::FragmentError;
-use handlers::JobHandle; 
+use lofty::{init_tracing, FragmentError, LoadConfig, LoftyServer};
+use cli::Command;
 
 // extern crate scopeguard;
 
 
-mod file; 
-mod errors;
-mod utils; 
-mod handlers;
-mod authorization;
-mod config;
+mod cli;
 
-async fn tokio_main() -> Result<(), FragmentError> { 
+async fn tokio_main(config: LoadConfig) -> Result<(), FragmentError> {
 
-    let addr = [0_u8; 4].into(); 
-    let port = 2053; 
+    init_tracing(&config.section("logging"));
 
-    let handle: JobHandle = Arc::new(DashMap::new());
+    let server = LoftyServer::builder()
+        .config(config)
+        .build()
+        .await?;
 
-    let router = utils::create_router(handle).await?;
-    let _app = utils::start_server((addr, port), router).await?;
-    
-    Ok(())
+    server.serve().await
 }
 
 fn main() {
-    
+    let mut cli = cli::Cli::parse_args();
+
     let mut runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .global_queue_interval(40)
         .build()
         .unwrap();
 
-    let tokio_main_process = tokio_main(); 
-    
-    runtime.block_on(tokio_main_process);
-}
\ No newline at end of file
+    let command = cli.command.take().unwrap_or(Command::Serve);
+
+    // `lofty rotate-keys`, `gc` and `reconcile` work on the data of a stopped server instead of serving
+    if command.is_maintenance() {
+        let config = cli.load_config();
+        init_tracing(&config.section("logging"));
+
+        if let Err(e) = runtime.block_on(cli::maintain(command, &config)) {
+            tracing::error!(variant = e.state().variant(), "maintenance failed: {}", e.state());
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // the client commands talk to a running server and report on the terminal, not through the logs
+    if !matches!(command, Command::Serve) {
+        match runtime.block_on(cli::remote(&cli.server, command)) {
+            Ok(0) => {},
+            Ok(_) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("lofty: {}", e);
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
+    let tokio_main_process = tokio_main(cli.load_config());
+
+    if let Err(e) = runtime.block_on(tokio_main_process) {
+        tracing::error!(variant = e.state().variant(), "server exited: {}", e.state());
+    }
+}
@@ -0,0 +1,216 @@
+use std::{collections::HashSet, path::{Path, PathBuf}, str::FromStr, sync::Arc};
+
+use crate::{
+    compression::Compression,
+    config::LoadConfig,
+    file::{FileObject, UploadState},
+    handlers::{self, JobHandle},
+    index::IndexedFile,
+    storage::Storage,
+    store::{self, StoreConfig},
+    utils,
+    FragmentError,
+};
+
+
+/*
+    Offline upkeep of the data directory, run like `rotate-keys` against a stopped server.
+    The server holds the registry in memory and writes it back on exit, changes made next to
+    a running instance would be overwritten. Both sides lock the data directory, a run is
+    refused while a server holds it.
+ */
+
+// what a run found, every change is listed whether or not it was applied
+#[derive(Debug, Default)]
+pub struct Report {
+    pub changes: usize,
+    pub freed: u64,
+    pub lines: Vec<String>,
+}
+
+impl Report {
+    fn record(&mut self, dry_run: bool, line: String, freed: u64) {
+        let prefix = if dry_run { "would " } else { "" };
+        self.lines.push(format!("{}{}", prefix, line));
+        self.changes += 1;
+        self.freed += freed;
+    }
+}
+
+/*
+    Remove what no registry entry points at: upload files without an entry, staging files of the
+    store and the hooks a crash left behind and blobs no upload is linked to. `failed` also drops
+    failed, rejected and quarantined uploads.
+ */
+pub async fn collect_garbage(config: &LoadConfig, storage: &dyn Storage, failed: bool, dry_run: bool) -> Result<Report, FragmentError> {
+    let _lock = utils::lock_data_dir(storage.data_dir())?;
+    let registry_path = storage.registry_path();
+    let handle = utils::load_registry(&registry_path).await?;
+    let mut files = Vec::with_capacity(handle.len());
+    for job in handle.iter().map(|job| job.clone()).collect::<Vec<_>>() {
+        files.push(IndexedFile::from(&*job.lock().await));
+    }
+    let mut report = Report::default();
+
+    if failed {
+        let (dropped, kept): (Vec<_>, Vec<_>) = files.into_iter().partition(|file| matches!(file.state, UploadState::Failed | UploadState::Rejected | UploadState::Quarantined));
+        for file in dropped {
+            let freed = remove(&file.output_file_path(), dry_run)?;
+            report.record(dry_run, format!("drop {} upload {} ({} bytes)", file.state.kind(), file.uuid, freed), freed);
+            if !dry_run {
+                handle.remove(&file.uuid);
+            }
+        }
+        files = kept;
+    }
+
+    // registry paths are relative to where the server runs, they are compared by what they point at
+    let known: HashSet<PathBuf> = files.iter().map(|file| canonical(file.output_file_path())).collect();
+    let mut dirs: HashSet<PathBuf> = files.iter().map(|file| canonical(file.path.clone())).collect();
+    dirs.insert(canonical(storage.data_dir().to_path_buf()));
+
+    for dir in dirs {
+        for path in list(&dir)?.into_iter().filter(|path| path.is_file()) {
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            let orphan = uuid::Uuid::from_str(name).is_ok() && !known.contains(&canonical(path.clone()));
+            let staging = path.extension().is_some_and(|ext| ext == "dedup" || ext == "hook" || ext == "out" || ext == "piece" || ext == "part");
+            if orphan || staging {
+                let freed = remove(&path, dry_run)?;
+                report.record(dry_run, format!("remove {} ({} bytes)", path.display(), freed), freed);
+            }
+        }
+    }
+
+    // blobs are only linked once dedup is on, the directory may not exist otherwise
+    let store = config.section::<StoreConfig>("store");
+    let linked: HashSet<String> = files
+        .iter()
+        .filter_map(|file| file.digest.as_deref().map(|digest| store::blob_key(digest, file.compression)))
+        .collect();
+    for shard in list(&store.dir)?.into_iter().filter(|shard| shard.is_dir()) {
+        for path in list(&shard)? {
+            let key = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            // an upload the registry lost after a crash may still be linked to it
+            if !linked.contains(key) && store::links(&path)? == 0 {
+                let freed = remove(&path, dry_run)?;
+                report.record(dry_run, format!("remove blob {} ({} bytes)", key, freed), freed);
+            }
+        }
+    }
+
+    if !dry_run && failed {
+        utils::persist_registry(&handle, &registry_path).await?;
+    }
+    tracing::info!(changes = report.changes, freed = report.freed, dry_run, "garbage collected");
+    Ok(report)
+}
+
+/*
+    Bring the registry in line with the files on disk. Interrupted uploads are set back to what
+    reached the disk, completed uploads whose file is gone or cut short are marked failed and,
+    with `verify`, so are those whose content no longer hashes to their digest.
+ */
+pub async fn reconcile(storage: &dyn Storage, verify: bool, dry_run: bool) -> Result<Report, FragmentError> {
+    let _lock = utils::lock_data_dir(storage.data_dir())?;
+    let registry_path = storage.registry_path();
+    let contents = match tokio::fs::read(&registry_path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Report::default()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut objects: Vec<FileObject> = serde_json::from_slice(&contents)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let mut report = Report::default();
+
+    for file_obj in objects.iter_mut() {
+        let uuid = *file_obj.get_uuid();
+        let state = file_obj.get_state();
+
+        let problem = match state {
+            UploadState::Complete | UploadState::Clean => check_complete(file_obj, verify).await?,
+            _ => {
+                file_obj.recover(utils::durable_len(file_obj).await);
+                if file_obj.get_state() != state {
+                    report.record(dry_run, format!("set {} from {:?} to {:?}", uuid, state, file_obj.get_state()), 0);
+                }
+                None
+            },
+        };
+
+        if let Some(problem) = problem {
+            file_obj.set_state(UploadState::Failed);
+            report.record(dry_run, format!("mark {} failed, {}", uuid, problem), 0);
+        }
+    }
+
+    if !dry_run && report.changes > 0 {
+        let handle: JobHandle = Arc::new(objects.into_iter().map(|file_obj| (*file_obj.get_uuid(), handlers::job(file_obj))).collect());
+        utils::persist_registry(&handle, &registry_path).await?;
+    }
+    tracing::info!(changes = report.changes, dry_run, "registry reconciled");
+    Ok(report)
+}
+
+// why a completed upload can't be served anymore, None if it still can
+async fn check_complete(file_obj: &FileObject, verify: bool) -> Result<Option<String>, FragmentError> {
+    let path = file_obj.output_file_path();
+    let len = match tokio::fs::metadata(&path).await {
+        Ok(metadata) => metadata.len() as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Some("its file is missing".to_string())),
+        Err(e) => return Err(e.into()),
+    };
+
+    // uploads from before stored sizes were recorded report zero
+    let expected = file_obj.stored_size();
+    if expected > 0 && len != expected {
+        return Ok(Some(format!("its file holds {} of {} bytes", len, expected)));
+    }
+
+    // sealed segments authenticate themselves on every read, there is no plaintext digest to compare
+    let digest = match (verify, file_obj.digest(), file_obj.envelope()) {
+        (true, Some(digest), None) => digest,
+        _ => return Ok(None),
+    };
+    let actual = match file_obj.compression() {
+        Compression::None => store::sha256_file(path).await,
+        Compression::Zstd => store::sha256_blocks(path).await,
+    };
+    match actual {
+        Ok(actual) if actual == digest => Ok(None),
+        Ok(actual) => Ok(Some(format!("its content hashes to {}", actual))),
+        Err(e) => Ok(Some(format!("its content can't be read: {}", e.state()))),
+    }
+}
+
+// the entries of `dir`, nothing if it does not exist
+fn list(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut paths = Vec::new();
+    for entry in entries {
+        paths.push(entry?.path());
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+// delete `path` unless this is a dry run, answering the bytes it took
+fn remove(path: &Path, dry_run: bool) -> std::io::Result<u64> {
+    let len = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    if !dry_run {
+        std::fs::remove_file(path)?;
+    }
+    Ok(len)
+}
+
+fn canonical(path: PathBuf) -> PathBuf {
+    std::fs::canonicalize(&path).unwrap_or(path)
+}
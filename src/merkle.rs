@@ -0,0 +1,331 @@
+use std::{fmt, io::SeekFrom, path::PathBuf, str::FromStr, sync::Arc};
+
+use axum::{body::Body, extract::Path, http::Response, Extension};
+use bytes::Bytes;
+use futures::{stream::BoxStream, Stream, StreamExt};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt}};
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditAction, AuditScope},
+    authorization::Principal,
+    compression::{self, BlockIndex, Compression},
+    encryption::{self, Encryption},
+    errors::ErrorStates,
+    file::{FileObject, UploadState},
+    index::{FileIndexHandle, IndexedFile},
+    FragmentError,
+};
+
+
+/*
+    Merkle tree over the logical bytes of an upload, built while it streams in.
+
+    Leaves are fixed size blocks, the last one may be shorter.
+        leaf = sha256(0x00 || block)
+        node = sha256(0x01 || left || right)
+    A node without a right sibling is carried up to the next level as it is.
+    An empty upload has a single leaf over no bytes.
+
+    A range proof lists, level by level from the leaves up, the left sibling of the first node
+    and then the right sibling of the last node, whenever the covered nodes have one.
+
+    Ranges are served as requested, also when they start or end inside a leaf. Such edge
+    leaves can't be hashed from the bytes sent, their hashes go out next to the proof so the
+    leaves in between still check against the root. The bytes of an edge leaf are not proven,
+    a client that wants every byte verified asks for ranges aligned to `block_size`.
+*/
+
+// bytes of plaintext read back per chunk when leaves are rebuilt from disk
+const READ_CHUNK: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MerkleConfig {
+    pub enabled: bool,
+    // bytes per leaf, trees keep the size they were built with
+    pub block_size: u64,
+}
+
+impl Default for MerkleConfig {
+    fn default() -> Self {
+        Self { enabled: true, block_size: 4 * 1024 * 1024 }
+    }
+}
+
+pub type MerkleHandle = Arc<MerkleConfig>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Node([u8; 32]);
+
+impl Node {
+    pub fn leaf(block: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update([0x00]);
+        hasher.update(block);
+        Self(hasher.finalize().into())
+    }
+
+    fn parent(left: &Node, right: &Node) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update([0x01]);
+        hasher.update(left.0);
+        hasher.update(right.0);
+        Self(hasher.finalize().into())
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Node({})", self)
+    }
+}
+
+impl Serialize for Node {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Node {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let mut node = [0u8; 32];
+        hex::decode_to_slice(&encoded, &mut node).map_err(serde::de::Error::custom)?;
+        Ok(Self(node))
+    }
+}
+
+// Leaves of an upload, stored with its `FileObject`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MerkleTree {
+    pub block_size: u64,
+    // logical bytes the leaves cover
+    pub length: u64,
+    pub leaves: Vec<Node>,
+}
+
+impl MerkleTree {
+    pub fn root(&self) -> Node {
+        let mut level = match self.leaves.is_empty() {
+            true => vec![Node::leaf(&[])],
+            false => self.leaves.clone(),
+        };
+        while level.len() > 1 {
+            level = parents(&level);
+        }
+        level[0]
+    }
+
+    // leaves holding any of the bytes `start..end`, inclusive on both ends
+    pub fn leaf_range(&self, start: u64, end: u64) -> Option<(usize, usize)> {
+        if start >= end || end > self.length {
+            return None;
+        }
+        Some(((start / self.block_size) as usize, ((end - 1) / self.block_size) as usize))
+    }
+
+    // whether leaf `index` holds bytes outside of `start..end`
+    pub fn cut_by(&self, index: usize, start: u64, end: u64) -> bool {
+        let from = index as u64 * self.block_size;
+        let to = (from + self.block_size).min(self.length);
+        start > from || end < to
+    }
+
+    // the nodes needed next to leaves `first..=last` to recompute the root
+    pub fn proof(&self, first: usize, last: usize) -> Vec<Node> {
+        let mut proof = Vec::new();
+        let mut level = self.leaves.clone();
+        let (mut first, mut last) = (first, last);
+
+        while level.len() > 1 {
+            if first % 2 == 1 {
+                proof.push(level[first - 1]);
+            }
+            if last % 2 == 0 && last + 1 < level.len() {
+                proof.push(level[last + 1]);
+            }
+            level = parents(&level);
+            first /= 2;
+            last /= 2;
+        }
+        proof
+    }
+}
+
+fn parents(level: &[Node]) -> Vec<Node> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => Node::parent(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+// Tree of an upload in flight, fed the logical bytes in the order they are written
+#[derive(Debug, Clone)]
+pub struct MerkleBuilder {
+    block_size: u64,
+    leaves: Vec<Node>,
+    // the leaf being filled, already prefixed
+    current: Sha256,
+    filled: u64,
+}
+
+impl MerkleBuilder {
+    pub fn new(block_size: u64) -> Self {
+        Self { block_size, leaves: Vec::new(), current: leaf_hasher(), filled: 0 }
+    }
+
+    // continue from the whole leaves of `tree`, at most `keep` of them
+    pub fn resume(tree: &MerkleTree, keep: usize) -> Self {
+        let mut builder = Self::new(tree.block_size);
+        builder.leaves = tree.leaves[..keep.min(tree.leaves.len())].to_vec();
+        builder
+    }
+
+    // logical bytes fed so far
+    pub fn position(&self) -> u64 {
+        self.leaves.len() as u64 * self.block_size + self.filled
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let take = bytes.len().min((self.block_size - self.filled) as usize);
+            self.current.update(&bytes[..take]);
+            self.filled += take as u64;
+            bytes = &bytes[take..];
+
+            if self.filled == self.block_size {
+                let current = std::mem::replace(&mut self.current, leaf_hasher());
+                self.leaves.push(Node(current.finalize().into()));
+                self.filled = 0;
+            }
+        }
+    }
+
+    // the whole leaves below `offset`, what an interrupted upload keeps
+    pub fn until(&self, offset: u64) -> MerkleTree {
+        let leaves = ((offset / self.block_size) as usize).min(self.leaves.len());
+        MerkleTree {
+            block_size: self.block_size,
+            length: leaves as u64 * self.block_size,
+            leaves: self.leaves[..leaves].to_vec(),
+        }
+    }
+
+    pub fn finish(mut self) -> MerkleTree {
+        let length = self.position();
+        if self.filled > 0 {
+            self.leaves.push(Node(self.current.finalize().into()));
+        }
+        MerkleTree { block_size: self.block_size, length, leaves: self.leaves }
+    }
+}
+
+fn leaf_hasher() -> Sha256 {
+    let mut hasher = Sha256::new();
+    hasher.update([0x00]);
+    hasher
+}
+
+// logical bytes `start..end` of an upload, in whichever layout it is stored
+pub async fn read_logical(
+    file: &IndexedFile,
+    encryption: &Encryption,
+    start: u64,
+    end: u64,
+) -> Result<BoxStream<'static, std::io::Result<Bytes>>, FragmentError> {
+    let path = file.output_file_path();
+
+    let stream = match (&file.envelope, file.compression) {
+        (Some(envelope), _) => {
+            let key = encryption.data_key(envelope, &file.uuid, file.file_size)?;
+            encryption::read_range(path, key, start, end).boxed()
+        },
+        (None, Compression::Zstd) => {
+            let index = BlockIndex::scan(&path).await?;
+            compression::read_range(path, index, start, end).boxed()
+        },
+        (None, Compression::None) => read_plain(path, start, end).boxed(),
+    };
+    Ok(stream)
+}
+
+fn read_plain(path: PathBuf, start: u64, end: u64) -> impl Stream<Item = std::io::Result<Bytes>> {
+    futures::stream::try_unfold((None::<File>, start), move |(file, offset)| {
+        let path = path.clone();
+        async move {
+            if offset >= end {
+                return Ok(None);
+            }
+            let mut file = match file {
+                Some(file) => file,
+                None => {
+                    let mut file = File::open(&path).await?;
+                    file.seek(SeekFrom::Start(offset)).await?;
+                    file
+                },
+            };
+
+            let mut chunk = vec![0u8; (end - offset).min(READ_CHUNK) as usize];
+            file.read_exact(&mut chunk).await?;
+            let read = chunk.len() as u64;
+            Ok(Some((Bytes::from(chunk), (Some(file), offset + read))))
+        }
+    })
+}
+
+// feed the stored bytes `start..end` of `file` into `builder`
+pub async fn rehash(builder: &mut MerkleBuilder, file: &FileObject, encryption: &Encryption, start: u64, end: u64) -> Result<(), FragmentError> {
+    let mut stream = read_logical(&IndexedFile::from(file), encryption, start, end).await?;
+    while let Some(chunk) = stream.next().await {
+        builder.update(&chunk?);
+    }
+    Ok(())
+}
+
+// GET /files/:uuid/manifest, the leaves of an upload so any block can be checked on its own
+pub async fn serve_manifest(
+    Extension(index): Extension<FileIndexHandle>,
+    audit: AuditScope,
+    principal: Principal,
+    Path(uuid): Path<String>,
+) -> Result<Response<Body>, FragmentError> {
+
+    audit.action(AuditAction::Manifest);
+    let uuid = Uuid::from_str(&uuid)?;
+
+    // the leaves describe the content, they are the owner's as much as the content itself
+    let file = index.get(&uuid).filter(|file| principal.owns(file.tenant.as_deref())).ok_or(ErrorStates::UploadNotFound(uuid))?;
+    audit.file(uuid, Some(&file.name));
+    let tree = file.merkle.ok_or(ErrorStates::ManifestUnavailable(uuid))?;
+
+    // an unfinished upload lists the whole leaves of its durable prefix
+    let body = serde_json::json!({
+        "uuid": uuid,
+        "algorithm": "sha-256",
+        "complete": matches!(file.state, UploadState::Complete | UploadState::Clean),
+        "block_size": tree.block_size,
+        "length": tree.length,
+        "root": tree.root(),
+        "leaves": tree.leaves,
+    });
+
+    let resp = Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(&body).unwrap()))?;
+
+    Ok(resp)
+}
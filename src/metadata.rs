@@ -0,0 +1,196 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+    sync::Arc,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::Path,
+    http::{HeaderMap, HeaderName, HeaderValue, Response},
+    Extension,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    audit::{AuditAction, AuditScope},
+    authorization::Principal,
+    errors::{BodyErrors, ErrorStates, HeaderErrors},
+    handlers::JobHandle,
+    index::FileIndexHandle,
+    FragmentError,
+};
+
+
+/*
+    Schedule time headers. Upload-Metadata follows tus, comma separated keys each followed by
+    their base64 encoded value, the value may be left out. Decoded values are plain visible ascii:
+        Upload-Metadata: source Y2k=, build_id MTIzNA==, reviewed
+        Upload-Tags: nightly, signed
+ */
+pub const METADATA_HEADER: &str = "upload-metadata";
+pub const TAGS_HEADER: &str = "upload-tags";
+
+// prefix of the response headers metadata is exposed under on download
+pub const EXPOSED_PREFIX: &str = "x-lofty-meta-";
+
+const MAX_KEY_LEN: usize = 64;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetadataConfig {
+    // keys and tags an upload may carry
+    pub max_entries: usize,
+    pub max_value_len: usize,
+    // metadata keys served as `x-lofty-meta-*` headers on download, "*" exposes all of them
+    pub expose: Vec<String>,
+}
+
+impl Default for MetadataConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 32,
+            max_value_len: 1024,
+            expose: Vec::new(),
+        }
+    }
+}
+
+pub type MetadataHandle = Arc<MetadataConfig>;
+
+#[derive(Debug, Default)]
+pub struct Labels {
+    pub metadata: BTreeMap<String, String>,
+    pub tags: BTreeSet<String>,
+}
+
+impl MetadataConfig {
+    pub fn labels(&self, headers: &HeaderMap) -> Result<Labels, FragmentError> {
+        let mut labels = Labels::default();
+
+        if let Some(value) = headers.get(METADATA_HEADER) {
+            for pair in value.to_str()?.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+                let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+                let value = STANDARD
+                    .decode(value.trim())
+                    .ok()
+                    .and_then(|value| String::from_utf8(value).ok())
+                    .ok_or(HeaderErrors::InvalidField(Cow::Borrowed("Upload-Metadata")))?;
+                labels.metadata.insert(key.to_string(), value);
+            }
+        }
+
+        if let Some(value) = headers.get(TAGS_HEADER) {
+            labels.tags = value.to_str()?
+                .split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+
+        if !self.within_limits(&labels.metadata, &labels.tags) {
+            return Err(HeaderErrors::InvalidField(Cow::Borrowed("Upload-Metadata")).into());
+        }
+        Ok(labels)
+    }
+
+    // keys end up in header names and values in header values, both are kept to what those allow
+    pub fn within_limits(&self, metadata: &BTreeMap<String, String>, tags: &BTreeSet<String>) -> bool {
+        metadata.len() <= self.max_entries
+            && tags.len() <= self.max_entries
+            && metadata.keys().all(|key| valid_key(key))
+            && metadata.values().all(|value| self.valid_value(value))
+            && tags.iter().all(|tag| self.valid_value(tag) && !tag.contains(','))
+    }
+
+    fn valid_value(&self, value: &str) -> bool {
+        value.len() <= self.max_value_len && value.bytes().all(|b| (0x20..0x7f).contains(&b))
+    }
+
+    pub fn exposed_headers<'a>(&'a self, metadata: &'a BTreeMap<String, String>) -> impl Iterator<Item = (HeaderName, HeaderValue)> + 'a {
+        let all = self.expose.iter().any(|key| key == "*");
+
+        metadata
+            .iter()
+            .filter(move |(key, _)| all || self.expose.iter().any(|exposed| exposed == *key))
+            .filter_map(|(key, value)| {
+                let name = HeaderName::from_str(&format!("{}{}", EXPOSED_PREFIX, key)).ok()?;
+                let value = HeaderValue::from_str(value).ok()?;
+                Some((name, value))
+            })
+    }
+}
+
+fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_KEY_LEN
+        && key.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_' || b == b'-')
+}
+
+/*
+    PATCH /files/:uuid body, metadata is merged with `null` dropping a key, tags are replaced
+        { "metadata": { "build_id": "1235", "stale": null }, "tags": ["nightly"] }
+ */
+#[derive(Debug, Default, Deserialize)]
+pub struct LabelPatch {
+    #[serde(default)]
+    pub metadata: BTreeMap<String, Option<String>>,
+    pub tags: Option<BTreeSet<String>>,
+}
+
+pub async fn update_labels(
+    Extension(ext): Extension<JobHandle>,
+    Extension(index): Extension<FileIndexHandle>,
+    Extension(config): Extension<MetadataHandle>,
+    audit: AuditScope,
+    principal: Principal,
+    Path(uuid): Path<String>,
+    body: Bytes,
+) -> Result<Response<Body>, FragmentError> {
+
+    audit.action(AuditAction::Update);
+    let uuid = Uuid::from_str(&uuid)?;
+
+    let patch: LabelPatch = serde_json::from_slice(&body)
+        .map_err(|_| BodyErrors::InvalidValues(Cow::Borrowed("metadata")))?;
+
+    // uploads keep their entry locked while streaming, labels are changed once they finished
+    let job = ext.get(&uuid).map(|job| job.clone()).ok_or(ErrorStates::UploadNotFound(uuid))?;
+    let mut file_obj = job.try_lock().map_err(|_| ErrorStates::UploadBusy(uuid))?;
+
+    // labels belong to the tenant that scheduled the upload, to anyone else it does not exist
+    if !principal.owns(file_obj.tenant()) {
+        return Err(ErrorStates::UploadNotFound(uuid).into());
+    }
+    audit.file(uuid, Some(file_obj.name()));
+
+    let mut metadata = file_obj.metadata().clone();
+    for (key, value) in &patch.metadata {
+        match value {
+            Some(value) => { metadata.insert(key.clone(), value.clone()); },
+            None => { metadata.remove(key); },
+        }
+    }
+    let tags = patch.tags.clone().unwrap_or_else(|| file_obj.tags().clone());
+
+    if !config.within_limits(&metadata, &tags) {
+        return Err(BodyErrors::InvalidValues(Cow::Borrowed("metadata")).into());
+    }
+
+    file_obj.update_labels(patch.metadata, patch.tags);
+    drop(file_obj);
+
+    let listed = index.get(&uuid).ok_or(ErrorStates::UploadNotFound(uuid))?;
+    let body = serde_json::to_vec(&listed).map_err(|_| ErrorStates::UndeclaredError)?;
+
+    let resp = Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))?;
+
+    Ok(resp)
+}
@@ -0,0 +1,256 @@
+use std::{path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use axum::{body::Body, extract::Request, http::{header::CONTENT_TYPE, Response}, middleware::Next, Extension};
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use sysinfo::Disks;
+use tokio::time::Instant;
+
+use crate::{errors::{ErrorStates, ErrorVariant}, FragmentError};
+
+
+// registry the handlers record into, rendered by GET /metrics
+pub type MetricsHandle = Arc<Metrics>;
+
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    data_dir: PathBuf,
+    volume: String,
+    uploads: IntCounterVec,
+    bytes_received: IntCounter,
+    wire_bytes_received: IntCounter,
+    bytes_served: IntCounter,
+    upload_duration: Histogram,
+    chunk_write_latency: Histogram,
+    active_uploads: IntGauge,
+    job_handle_entries: IntGauge,
+    disk_reserved: IntGaugeVec,
+    disk_free: IntGaugeVec,
+    buffer_memory: IntGauge,
+    errors: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new(data_dir: impl Into<PathBuf>) -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("lofty".to_string()), None)?;
+
+        let uploads = IntCounterVec::new(
+            Opts::new("uploads_total", "Upload state transitions by outcome"),
+            &["event"],
+        )?;
+        let bytes_received = IntCounter::new("bytes_received_total", "Upload bytes written to disk")?;
+        let wire_bytes_received = IntCounter::new("wire_bytes_received_total", "Upload body bytes as sent, before their content encoding is removed")?;
+        let bytes_served = IntCounter::new("bytes_served_total", "File bytes sent to clients")?;
+        let upload_duration = Histogram::with_opts(
+            HistogramOpts::new("upload_duration_seconds", "Wall time of completed upload streams")
+                .buckets(exponential_buckets(1.0, 2.0, 14)?),
+        )?;
+        let chunk_write_latency = Histogram::with_opts(
+            HistogramOpts::new("chunk_write_seconds", "Latency of writing a single body chunk")
+                .buckets(exponential_buckets(0.00001, 4.0, 10)?),
+        )?;
+        let active_uploads = IntGauge::new("active_uploads", "Upload streams currently being written")?;
+        let job_handle_entries = IntGauge::new("job_handle_entries", "File objects tracked in the JobHandle")?;
+        let disk_reserved = IntGaugeVec::new(
+            Opts::new("disk_reserved_bytes", "Bytes still expected by in-flight uploads"),
+            &["volume"],
+        )?;
+        let disk_free = IntGaugeVec::new(
+            Opts::new("disk_free_bytes", "Free space on the volume holding the data directory"),
+            &["volume"],
+        )?;
+        let buffer_memory = IntGauge::new("buffer_memory_bytes", "Write buffer capacity held by active uploads")?;
+        let errors = IntCounterVec::new(
+            Opts::new("errors_total", "Error responses by ErrorStates variant"),
+            &["variant"],
+        )?;
+
+        registry.register(Box::new(uploads.clone()))?;
+        registry.register(Box::new(bytes_received.clone()))?;
+        registry.register(Box::new(wire_bytes_received.clone()))?;
+        registry.register(Box::new(bytes_served.clone()))?;
+        registry.register(Box::new(upload_duration.clone()))?;
+        registry.register(Box::new(chunk_write_latency.clone()))?;
+        registry.register(Box::new(active_uploads.clone()))?;
+        registry.register(Box::new(job_handle_entries.clone()))?;
+        registry.register(Box::new(disk_reserved.clone()))?;
+        registry.register(Box::new(disk_free.clone()))?;
+        registry.register(Box::new(buffer_memory.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+
+        let data_dir = data_dir.into();
+        let (volume, _) = volume_stats(&data_dir);
+
+        Ok(Self {
+            registry,
+            data_dir,
+            volume,
+            uploads,
+            bytes_received,
+            wire_bytes_received,
+            bytes_served,
+            upload_duration,
+            chunk_write_latency,
+            active_uploads,
+            job_handle_entries,
+            disk_reserved,
+            disk_free,
+            buffer_memory,
+            errors,
+        })
+    }
+
+    // start accounting for an upload stream, `expected` being the bytes still to be written
+    pub fn track_upload(self: &Arc<Self>, expected: usize, buffer_size: usize) -> UploadTracker {
+        let volume = self.volume.clone();
+
+        self.uploads.with_label_values(&["started"]).inc();
+        self.active_uploads.inc();
+        self.buffer_memory.add(buffer_size as i64);
+        self.disk_reserved.with_label_values(&[&volume]).add(expected as i64);
+
+        UploadTracker {
+            metrics: self.clone(),
+            volume,
+            started: Instant::now(),
+            reserved: expected as i64,
+            buffer_size: buffer_size as i64,
+            outcome: None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn record_served(&self, bytes: usize) {
+        self.bytes_served.inc_by(bytes as u64);
+    }
+
+    pub fn set_job_entries(&self, entries: usize) {
+        self.job_handle_entries.set(entries as i64);
+    }
+
+    #[inline(always)]
+    pub fn record_job_inserted(&self) {
+        self.job_handle_entries.inc();
+    }
+
+    #[inline(always)]
+    pub fn record_job_removed(&self) {
+        self.job_handle_entries.dec();
+    }
+
+    pub fn record_error(&self, variant: &str) {
+        self.errors.with_label_values(&[variant]).inc();
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, prometheus::Error> {
+        let (_, available) = volume_stats(&self.data_dir);
+        self.disk_free.with_label_values(&[&self.volume]).set(available as i64);
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+// mount point of the disk holding `path` and its available space
+fn volume_stats(path: &Path) -> (String, u64) {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let disks = Disks::new_with_refreshed_list();
+
+    disks.iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| (disk.mount_point().display().to_string(), disk.available_space()))
+        .unwrap_or_else(|| (path.display().to_string(), 0))
+}
+
+// Keeps the gauges of a single upload honest, an upload dropped without an outcome counts as cancelled
+#[derive(Debug)]
+pub struct UploadTracker {
+    metrics: MetricsHandle,
+    volume: String,
+    started: Instant,
+    reserved: i64,
+    buffer_size: i64,
+    outcome: Option<&'static str>,
+}
+
+impl UploadTracker {
+    pub fn record_chunk(&mut self, written: usize, latency: Duration) {
+        // oversized bodies still count as received, but never release more than was reserved
+        let released = (written as i64).min(self.reserved);
+
+        self.metrics.bytes_received.inc_by(written as u64);
+        self.metrics.chunk_write_latency.observe(latency.as_secs_f64());
+        self.metrics.disk_reserved.with_label_values(&[&self.volume]).sub(released);
+        self.reserved -= released;
+    }
+
+    // body bytes as they came off the connection, `record_chunk` counts them once decoded
+    pub fn record_wire(&mut self, received: usize) {
+        self.metrics.wire_bytes_received.inc_by(received as u64);
+    }
+
+    // classify the result of a streamer_writer run
+    pub fn finish<T>(mut self, result: &Result<T, FragmentError>) {
+        let outcome = match result {
+            Ok(_) => "completed",
+            // the body stream broke, the client went away mid upload, or the server cut it short
+            Err(e) if matches!(e.state(), ErrorStates::InternalError(_) | ErrorStates::ShuttingDown) => "cancelled",
+            Err(_) => "failed",
+        };
+
+        if result.is_ok() {
+            self.metrics.upload_duration.observe(self.started.elapsed().as_secs_f64());
+        }
+        self.outcome = Some(outcome);
+    }
+
+    // a piece of the upload is durable, the upload itself goes on with the next request
+    pub fn piece(mut self) {
+        self.outcome = Some("piece");
+    }
+}
+
+impl Drop for UploadTracker {
+    fn drop(&mut self) {
+        let outcome = self.outcome.unwrap_or("cancelled");
+
+        self.metrics.uploads.with_label_values(&[outcome]).inc();
+        self.metrics.active_uploads.dec();
+        self.metrics.buffer_memory.sub(self.buffer_size);
+        self.metrics.disk_reserved.with_label_values(&[&self.volume]).sub(self.reserved);
+    }
+}
+
+// middleware counting error responses by the variant that produced them
+pub async fn count_errors(
+    Extension(metrics): Extension<MetricsHandle>,
+    req: Request,
+    next: Next,
+) -> Response<Body> {
+    let resp = next.run(req).await;
+
+    if let Some(ErrorVariant(variant)) = resp.extensions().get::<ErrorVariant>() {
+        metrics.record_error(variant);
+    }
+
+    resp
+}
+
+pub async fn serve_metrics(
+    Extension(metrics): Extension<MetricsHandle>,
+) -> Result<Response<Body>, FragmentError> {
+
+    let body = metrics.encode().map_err(|_| ErrorStates::UndeclaredError)?;
+
+    let resp = Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, TextEncoder::new().format_type())
+        .body(Body::from(body))?;
+
+    Ok(resp)
+}
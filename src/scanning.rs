@@ -0,0 +1,214 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use axum::async_trait;
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt};
+use serde::Deserialize;
+use tokio::{io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader}, net::TcpStream};
+
+use crate::{encryption::EncryptionHandle, hooks::CompletedUpload, index::IndexedFile, merkle};
+
+
+// What a scanner reports when it could not check an upload, the upload is quarantined once the attempts ran out
+pub type ScanError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    // the name of what was found, the upload is kept `Quarantined` with it
+    Infected(String),
+}
+
+/*
+    Checks the content of completed uploads before anything else sees it, e.g. an antivirus
+    daemon. `content` yields the bytes as they were uploaded, whatever layout they are stored in.
+ */
+#[async_trait]
+pub trait Scanner: Send + Sync + 'static {
+    // used in the logs and quarantine reasons
+    fn name(&self) -> &str;
+
+    async fn scan(&self, upload: &CompletedUpload, content: BoxStream<'static, std::io::Result<Bytes>>) -> Result<ScanVerdict, ScanError>;
+}
+
+/*
+    [scanning]
+    tcp = "127.0.0.1:3310"          # or unix = "/run/clamav/clamd.ctl"
+    chunk_size = 65536
+    timeout = 300
+    attempts = 3
+    retry_delay = 5
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ScanConfig {
+    // address of a clamd compatible daemon, scanning is off without one
+    pub tcp: Option<String>,
+    pub unix: Option<PathBuf>,
+    // bytes per INSTREAM chunk, has to stay below the StreamMaxLength of the daemon
+    pub chunk_size: usize,
+    // seconds a single scan may take
+    pub timeout: u64,
+    // scans of an upload before it is quarantined as unchecked
+    pub attempts: u32,
+    // seconds between two attempts
+    pub retry_delay: u64,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self { tcp: None, unix: None, chunk_size: 64 * 1024, timeout: 300, attempts: 3, retry_delay: 5 }
+    }
+}
+
+impl ScanConfig {
+    // the daemon of the settings, if any
+    pub fn clamd(&self) -> Option<ClamdScanner> {
+        let scanner = match (&self.tcp, &self.unix) {
+            (Some(addr), _) => ClamdScanner::tcp(addr.clone()),
+            (None, Some(path)) => ClamdScanner::unix(path.clone()),
+            (None, None) => return None,
+        };
+        Some(scanner.with_chunk_size(self.chunk_size))
+    }
+}
+
+// The scanner of the post upload pipeline with the attempts it gets per upload
+pub struct Scanning {
+    scanner: Arc<dyn Scanner>,
+    encryption: EncryptionHandle,
+    timeout: Duration,
+    attempts: u32,
+    retry_delay: Duration,
+}
+
+impl Scanning {
+    pub fn new(scanner: Arc<dyn Scanner>, encryption: EncryptionHandle, config: &ScanConfig) -> Self {
+        Self {
+            scanner,
+            encryption,
+            timeout: Duration::from_secs(config.timeout),
+            attempts: config.attempts.max(1),
+            retry_delay: Duration::from_secs(config.retry_delay),
+        }
+    }
+
+    // scan an upload until the scanner has a verdict, the reason to quarantine it otherwise
+    pub async fn check(&self, file: &IndexedFile, upload: &CompletedUpload) -> Result<(), String> {
+        let name = self.scanner.name();
+
+        for attempt in 1..=self.attempts {
+            let scanned = match merkle::read_logical(file, &self.encryption, 0, file.file_size as u64).await {
+                Ok(content) => match tokio::time::timeout(self.timeout, self.scanner.scan(upload, content)).await {
+                    Ok(scanned) => scanned,
+                    Err(_) => Err(format!("timed out after {}s", self.timeout.as_secs()).into()),
+                },
+                Err(e) => Err(e.state().to_string().into()),
+            };
+
+            match scanned {
+                Ok(ScanVerdict::Clean) => {
+                    tracing::debug!(uuid = %upload.uuid, scanner = name, "upload scanned clean");
+                    return Ok(());
+                },
+                Ok(ScanVerdict::Infected(found)) => return Err(format!("{}: {} found", name, found)),
+                Err(e) if attempt == self.attempts => return Err(format!("{} failed: {}", name, e)),
+                Err(e) => {
+                    tracing::warn!(uuid = %upload.uuid, scanner = name, attempt, "scan failed, retrying: {}", e);
+                    tokio::time::sleep(self.retry_delay).await;
+                },
+            }
+        }
+        unreachable!("at least one attempt is made")
+    }
+}
+
+impl std::fmt::Debug for Scanning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Scanning").field("scanner", &self.scanner.name()).field("attempts", &self.attempts).finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ClamdAddress {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+/*
+    Streams uploads to clamd with the INSTREAM command:
+        zINSTREAM\0, then <u32 big endian length><bytes> per chunk, closed by a zero length
+    and reads back `stream: OK`, `stream: <signature> FOUND` or `<message> ERROR`.
+ */
+#[derive(Debug, Clone)]
+pub struct ClamdScanner {
+    address: ClamdAddress,
+    chunk_size: usize,
+}
+
+impl ClamdScanner {
+    pub fn tcp(addr: impl Into<String>) -> Self {
+        Self { address: ClamdAddress::Tcp(addr.into()), chunk_size: ScanConfig::default().chunk_size }
+    }
+
+    pub fn unix(path: impl Into<PathBuf>) -> Self {
+        Self { address: ClamdAddress::Unix(path.into()), chunk_size: ScanConfig::default().chunk_size }
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+}
+
+#[async_trait]
+impl Scanner for ClamdScanner {
+    fn name(&self) -> &str {
+        "clamd"
+    }
+
+    async fn scan(&self, _upload: &CompletedUpload, content: BoxStream<'static, std::io::Result<Bytes>>) -> Result<ScanVerdict, ScanError> {
+        match &self.address {
+            ClamdAddress::Tcp(addr) => instream(TcpStream::connect(addr).await?, content, self.chunk_size).await,
+            #[cfg(unix)]
+            ClamdAddress::Unix(path) => instream(tokio::net::UnixStream::connect(path).await?, content, self.chunk_size).await,
+            #[cfg(not(unix))]
+            ClamdAddress::Unix(_) => Err("unix sockets are not supported on this platform".into()),
+        }
+    }
+}
+
+async fn instream(
+    stream: impl AsyncRead + AsyncWrite + Unpin,
+    mut content: BoxStream<'static, std::io::Result<Bytes>>,
+    chunk_size: usize,
+) -> Result<ScanVerdict, ScanError> {
+    let mut stream = BufReader::new(stream);
+    stream.write_all(b"zINSTREAM\0").await?;
+
+    while let Some(bytes) = content.next().await {
+        for chunk in bytes?.chunks(chunk_size) {
+            stream.write_all(&(chunk.len() as u32).to_be_bytes()).await?;
+            stream.write_all(chunk).await?;
+        }
+    }
+    stream.write_all(&0u32.to_be_bytes()).await?;
+    stream.flush().await?;
+
+    // replies to `z` commands end with a NUL
+    let mut reply = Vec::new();
+    stream.read_until(0, &mut reply).await?;
+    parse_reply(String::from_utf8_lossy(&reply).trim_end_matches('\0').trim())
+}
+
+fn parse_reply(reply: &str) -> Result<ScanVerdict, ScanError> {
+    let result = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+    if result == "OK" {
+        return Ok(ScanVerdict::Clean);
+    }
+    match result.strip_suffix("FOUND") {
+        Some(found) => Ok(ScanVerdict::Infected(found.trim().to_string())),
+        None if reply.is_empty() => Err("clamd closed the connection without a reply".into()),
+        None => Err(format!("clamd replied `{}`", reply).into()),
+    }
+}
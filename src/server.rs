@@ -0,0 +1,209 @@
+use std::{path::Path, sync::Arc, time::Duration};
+
+use axum::Router;
+use futures::FutureExt;
+
+use crate::{
+    authorization::{AuthProvider, Authenticator},
+    config::{LoadConfig, ServerSettings},
+    encryption::{Encryption, EncryptionConfig},
+    handlers::JobHandle,
+    hooks::{ExecHook, HookConfig, Hooks, Ingest, PostUploadHook},
+    listener::{Listener, Mount},
+    scanning::{ScanConfig, Scanner, Scanning},
+    shutdown::{Shutdown, ShutdownHandle},
+    storage::{LocalStorage, Storage, StorageHandle},
+    store::{BlobStore, StoreConfig},
+    tls::{TlsConfig, TlsReloader},
+    utils::{self, Services},
+    webhooks::{WebhookConfig, Webhooks},
+    FragmentError,
+};
+
+
+/*
+    A lofty instance, either nested into another application through `router` or run on the
+    listeners of its settings through `serve`.
+
+        let server = LoftyServer::builder()
+            .config(LoadConfig::new("./settings.toml"))
+            .auth(SessionAuth::new(sessions))
+            .hook(Thumbnailer::default())
+            .build()
+            .await?;
+        let app = Router::new().nest("/uploads", server.router());
+ */
+#[derive(Debug)]
+pub struct LoftyServer {
+    config: LoadConfig,
+    handle: JobHandle,
+    storage: StorageHandle,
+    services: Services,
+    shutdown: ShutdownHandle,
+    // held for as long as the server lives, `gc`, `reconcile` and `rotate-keys` refuse to run meanwhile
+    _lock: std::fs::File,
+}
+
+#[derive(Default)]
+pub struct LoftyServerBuilder {
+    config: Option<LoadConfig>,
+    storage: Option<StorageHandle>,
+    auth: Option<Box<dyn AuthProvider>>,
+    hooks: Vec<Arc<dyn PostUploadHook>>,
+    scanner: Option<Arc<dyn Scanner>>,
+}
+
+impl LoftyServerBuilder {
+    // settings of the server, every section falls back to its defaults without them
+    pub fn config(mut self, config: LoadConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    // where uploads are written, the `data` setting by default
+    pub fn storage(mut self, storage: impl Storage) -> Self {
+        self.storage = Some(Arc::new(storage));
+        self
+    }
+
+    // resolve callers through the embedding application instead of the connection
+    pub fn auth(mut self, provider: impl AuthProvider) -> Self {
+        self.auth = Some(Box::new(provider));
+        self
+    }
+
+    // a stage of the post upload pipeline, run after the `[[hooks]]` of the settings in the order added
+    pub fn hook(mut self, hook: impl PostUploadHook) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    // check completed uploads with `scanner` instead of the clamd daemon of `[scanning]`
+    pub fn scanner(mut self, scanner: impl Scanner) -> Self {
+        self.scanner = Some(Arc::new(scanner));
+        self
+    }
+
+    // restore the registry and open everything the routes share, needs a running tokio runtime
+    pub async fn build(self) -> Result<LoftyServer, FragmentError> {
+        let config = match self.config {
+            Some(config) => config,
+            None => LoadConfig::from_toml("").expect("empty settings are valid"),
+        };
+        let storage = self.storage.unwrap_or_else(|| Arc::new(LocalStorage::new(config.data_dir())));
+        tokio::fs::create_dir_all(storage.data_dir()).await?;
+        let lock = utils::lock_data_dir(storage.data_dir())?;
+
+        let handle: JobHandle = utils::load_registry(storage.registry_path()).await?;
+        let shutdown = Arc::new(Shutdown::new(config.section("shutdown")));
+
+        let auth = Arc::new(Authenticator::new(self.auth));
+        let encryption = Encryption::open(config.section::<EncryptionConfig>("encryption"))?;
+        let webhooks = Webhooks::open(config.section::<WebhookConfig>("webhooks"), storage.data_dir()).await?;
+        let mut pipeline: Vec<Arc<dyn PostUploadHook>> = config
+            .section::<Vec<HookConfig>>("hooks")
+            .into_iter()
+            .map(|hook| Arc::new(ExecHook::new(hook)) as Arc<dyn PostUploadHook>)
+            .collect();
+        pipeline.extend(self.hooks);
+
+        let scan_config = config.section::<ScanConfig>("scanning");
+        let scanner = self.scanner.or_else(|| scan_config.clamd().map(|clamd| Arc::new(clamd) as Arc<dyn Scanner>));
+        let scanning = scanner.map(|scanner| Scanning::new(scanner, encryption.clone(), &scan_config));
+        tracing::info!(hooks = pipeline.len(), scanning = ?scanning, "post upload pipeline configured");
+
+        // hooks may replace the content, it is written back through the same store as the uploads
+        let store = BlobStore::open(config.section::<StoreConfig>("store"), &handle).await?;
+        let ingest = Ingest::new(&config, encryption.clone(), store.clone());
+        let hooks = Arc::new(Hooks::new(pipeline, config.section("metadata"), webhooks.clone(), ingest).with_scanning(scanning));
+        hooks.resume(&handle).await;
+
+        let services = Services::new(handle.clone(), &config, storage.clone(), encryption, store, auth, hooks, webhooks, shutdown.clone()).await?;
+
+        Ok(LoftyServer { config, handle, storage, services, shutdown, _lock: lock })
+    }
+}
+
+impl LoftyServer {
+    pub fn builder() -> LoftyServerBuilder {
+        LoftyServerBuilder::default()
+    }
+
+    // every route, ready to be nested or merged into another router
+    pub fn router(&self) -> Router {
+        self.services.router(&Mount::all())
+    }
+
+    pub fn router_for(&self, mounts: &[Mount]) -> Router {
+        self.services.router(mounts)
+    }
+
+    #[inline(always)]
+    pub fn data_dir(&self) -> &Path {
+        self.storage.data_dir()
+    }
+
+    /*
+        Refuse new uploads and give running ones the grace period to finish. An embedding
+        application calls this on its own shutdown, then `persist` once its server stopped.
+     */
+    pub fn shutdown(&self) {
+        self.shutdown.begin();
+    }
+
+    // write the registry, uploads interrupted by the exit are resumed from it on the next start
+    pub async fn persist(&self) -> Result<(), FragmentError> {
+        utils::persist_registry(&self.handle, self.storage.registry_path()).await
+    }
+
+    // serve the listeners of `[server]` until SIGINT or SIGTERM, then drain and persist the registry
+    pub async fn serve(self) -> Result<(), FragmentError> {
+        let listeners = self.config.section::<ServerSettings>("server").listeners();
+        let tls_config = self.config.section::<TlsConfig>("tls");
+
+        tokio::spawn(self.shutdown.clone().listen());
+
+        // certificates are only loaded when at least one listener terminates TLS
+        let tls_default = tls_config.enabled;
+        let tls = if listeners.iter().any(|listener| listener.tls(tls_default)) {
+            let tls = Arc::new(TlsReloader::load(tls_config)?);
+            tokio::spawn(tls.clone().watch());
+            Some(tls)
+        } else {
+            None
+        };
+
+        let mut servers = Vec::with_capacity(listeners.len());
+        for listener_config in &listeners {
+            let listener = Listener::bind(listener_config).await?;
+            let tls = tls.clone().filter(|_| listener_config.tls(tls_default));
+            tracing::info!(
+                listener = %listener_config.label(),
+                tls = tls.is_some(),
+                mounts = ?listener_config.mounts,
+                "lofty listening"
+            );
+
+            let router = self.services.router(&listener_config.mounts);
+            let draining = {
+                let shutdown = self.shutdown.clone();
+                async move { shutdown.draining().await }
+            };
+            servers.push(utils::start_server(listener, router, tls, draining));
+        }
+        let server = futures::future::try_join_all(servers).map(|served| served.map(|_| ()));
+
+        // the server future completes once in-flight requests are done, the writers give up after the grace period
+        let deadline = self.shutdown.grace_period() + Duration::from_secs(10);
+        let served = tokio::select! {
+            served = server => served,
+            _ = async { self.shutdown.draining().await; tokio::time::sleep(deadline).await } => {
+                tracing::warn!("connections still open after the grace period, exiting anyway");
+                Ok(())
+            }
+        };
+
+        self.persist().await?;
+        served
+    }
+}
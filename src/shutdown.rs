@@ -0,0 +1,109 @@
+use std::{sync::Arc, time::Duration};
+
+use serde::Deserialize;
+use tokio::sync::watch;
+
+use crate::errors::ErrorStates;
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    // seconds active uploads may keep streaming once a shutdown signal arrived
+    pub grace_period: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self { grace_period: 30 }
+    }
+}
+
+pub type ShutdownHandle = Arc<Shutdown>;
+
+/*
+    Shutdown happens in two phases:
+        draining: new uploads are refused, running ones keep streaming
+        aborting: the grace period ran out, writers checkpoint what they have and bail out
+ */
+#[derive(Debug)]
+pub struct Shutdown {
+    draining: watch::Sender<bool>,
+    aborting: watch::Sender<bool>,
+    grace_period: Duration,
+}
+
+impl Shutdown {
+    pub fn new(config: ShutdownConfig) -> Self {
+        Self {
+            draining: watch::channel(false).0,
+            aborting: watch::channel(false).0,
+            grace_period: Duration::from_secs(config.grace_period),
+        }
+    }
+
+    #[inline(always)]
+    pub fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    pub fn ensure_accepting(&self) -> Result<(), ErrorStates> {
+        if *self.draining.borrow() {
+            return Err(ErrorStates::ShuttingDown);
+        }
+        Ok(())
+    }
+
+    // resolves once the server started draining
+    pub async fn draining(&self) {
+        let mut receiver = self.draining.subscribe();
+        let _ = receiver.wait_for(|draining| *draining).await;
+    }
+
+    // resolves once the grace period for active uploads is over
+    pub async fn aborted(&self) {
+        let mut receiver = self.aborting.subscribe();
+        let _ = receiver.wait_for(|aborting| *aborting).await;
+    }
+
+    pub fn begin(self: &Arc<Self>) {
+        if self.draining.send_replace(true) {
+            return;
+        }
+
+        tracing::info!(grace_period = self.grace_period.as_secs(), "shutdown requested, draining uploads");
+
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(shutdown.grace_period).await;
+            tracing::warn!("grace period elapsed, interrupting active uploads");
+            shutdown.aborting.send_replace(true);
+        });
+    }
+
+    // wait for SIGINT or SIGTERM and start draining, meant to be handed to the server as its shutdown future
+    pub async fn listen(self: Arc<Self>) {
+        let ctrl_c = async {
+            let _ = tokio::signal::ctrl_c().await;
+        };
+
+        #[cfg(unix)]
+        let terminate = async {
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(mut signal) => { signal.recv().await; },
+                Err(_) => std::future::pending::<()>().await,
+            }
+        };
+
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = ctrl_c => {},
+            _ = terminate => {},
+            _ = self.draining() => {},
+        }
+
+        self.begin();
+    }
+}
@@ -0,0 +1,184 @@
+use std::{collections::HashMap, sync::Arc};
+
+use futures::StreamExt;
+use serde::Deserialize;
+
+use crate::{encryption::Encryption, errors::ErrorStates, index::IndexedFile, merkle, FragmentError};
+
+
+// leading bytes looked at, enough to reach the volume descriptor of an ISO 9660 image
+pub const SNIFF_LEN: usize = 32 * 1024 + 6;
+
+// a type and the bytes it has at the given offsets, earlier entries win
+type Signature = (&'static str, &'static [(usize, &'static [u8])]);
+
+const SIGNATURES: &[Signature] = &[
+    // executables
+    ("application/x-executable", &[(0, b"\x7fELF")]),
+    ("application/vnd.microsoft.portable-executable", &[(0, b"MZ")]),
+    ("application/x-mach-binary", &[(0, b"\xfe\xed\xfa\xce")]),
+    ("application/x-mach-binary", &[(0, b"\xfe\xed\xfa\xcf")]),
+    ("application/x-mach-binary", &[(0, b"\xce\xfa\xed\xfe")]),
+    ("application/x-mach-binary", &[(0, b"\xcf\xfa\xed\xfe")]),
+    ("application/x-mach-binary", &[(0, b"\xca\xfe\xba\xbe")]),
+    ("application/wasm", &[(0, b"\0asm")]),
+    ("text/x-shellscript", &[(0, b"#!")]),
+    // archives and compressed streams
+    ("application/zip", &[(0, b"PK\x03\x04")]),
+    ("application/zip", &[(0, b"PK\x05\x06")]),
+    ("application/gzip", &[(0, b"\x1f\x8b")]),
+    ("application/zstd", &[(0, b"\x28\xb5\x2f\xfd")]),
+    ("application/x-bzip2", &[(0, b"BZh")]),
+    ("application/x-xz", &[(0, b"\xfd7zXZ\0")]),
+    ("application/x-7z-compressed", &[(0, b"7z\xbc\xaf\x27\x1c")]),
+    ("application/vnd.rar", &[(0, b"Rar!\x1a\x07")]),
+    // images
+    ("image/png", &[(0, b"\x89PNG\r\n\x1a\n")]),
+    ("image/jpeg", &[(0, b"\xff\xd8\xff")]),
+    ("image/gif", &[(0, b"GIF87a")]),
+    ("image/gif", &[(0, b"GIF89a")]),
+    ("image/webp", &[(0, b"RIFF"), (8, b"WEBP")]),
+    ("image/tiff", &[(0, b"II*\0")]),
+    ("image/tiff", &[(0, b"MM\0*")]),
+    // documents and data
+    ("application/pdf", &[(0, b"%PDF-")]),
+    ("application/vnd.apache.parquet", &[(0, b"PAR1")]),
+    ("application/vnd.sqlite3", &[(0, b"SQLite format 3\0")]),
+    // audio and video
+    ("audio/ogg", &[(0, b"OggS")]),
+    ("audio/flac", &[(0, b"fLaC")]),
+    ("audio/mpeg", &[(0, b"ID3")]),
+    ("audio/wav", &[(0, b"RIFF"), (8, b"WAVE")]),
+    ("video/x-msvideo", &[(0, b"RIFF"), (8, b"AVI ")]),
+    ("video/mp4", &[(4, b"ftyp")]),
+    ("video/x-matroska", &[(0, b"\x1a\x45\xdf\xa3")]),
+    // signatures past the start, only decided once enough of the stream arrived
+    ("application/x-tar", &[(257, b"ustar")]),
+    ("application/x-iso9660-image", &[(32769, b"CD001")]),
+];
+
+/*
+    The type of content starting with `prefix`, None while more bytes could still change the
+    answer. `complete` says no more bytes are coming. Content without a known signature is
+    `text/plain` when it reads as text and `application/octet-stream` otherwise.
+ */
+pub fn detect(prefix: &[u8], complete: bool) -> Option<&'static str> {
+    let complete = complete || prefix.len() >= SNIFF_LEN;
+
+    for (mime, parts) in SIGNATURES {
+        let decidable = parts.iter().all(|(offset, magic)| offset + magic.len() <= prefix.len());
+        if !decidable && !complete {
+            return None;
+        }
+        if decidable && parts.iter().all(|(offset, magic)| &prefix[*offset..offset + magic.len()] == *magic) {
+            return Some(mime);
+        }
+    }
+
+    match looks_like_text(prefix) {
+        true => Some("text/plain"),
+        false => Some("application/octet-stream"),
+    }
+}
+
+fn looks_like_text(prefix: &[u8]) -> bool {
+    // the prefix may end in the middle of a character
+    let text = match std::str::from_utf8(prefix) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&prefix[..e.valid_up_to()]).unwrap_or_default(),
+        Err(_) => return false,
+    };
+    text.chars().all(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r' | '\x0c'))
+}
+
+// the type a download is served as, the declared one only refines what the content does not tell apart
+pub fn served_type<'a>(detected: Option<&'a str>, declared: Option<&'a str>) -> Option<&'a str> {
+    match (detected, declared) {
+        (Some("text/plain" | "application/octet-stream"), Some(declared)) => Some(declared),
+        (Some(detected), _) => Some(detected),
+        (None, declared) => declared,
+    }
+}
+
+/*
+    [content]
+    deny = ["application/x-executable", "application/vnd.microsoft.portable-executable"]
+
+    [content.tenants.partner-a]
+    allow = ["image/", "application/pdf"]
+ */
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ContentPolicy {
+    // matched as prefixes of the detected type, "image/" covers every image
+    // an empty allow list accepts everything not denied
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    // tenants with lists of their own, they replace the ones above
+    pub tenants: HashMap<String, TypeRules>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TypeRules {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+pub type ContentPolicyHandle = Arc<ContentPolicy>;
+
+impl ContentPolicy {
+    pub fn admit(&self, tenant: Option<&str>, mime: &str) -> Result<(), ErrorStates> {
+        let (allow, deny) = match tenant.and_then(|tenant| self.tenants.get(tenant)) {
+            Some(rules) => (&rules.allow, &rules.deny),
+            None => (&self.allow, &self.deny),
+        };
+
+        let matches = |prefix: &String| mime.starts_with(&prefix.to_ascii_lowercase());
+        if deny.iter().any(matches) || (!allow.is_empty() && !allow.iter().any(matches)) {
+            return Err(ErrorStates::ContentTypeNotAllowed(mime.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn gate(self: &Arc<Self>, tenant: Option<&str>) -> ContentGate {
+        ContentGate { policy: self.clone(), tenant: tenant.map(str::to_string), prefix: Vec::new() }
+    }
+}
+
+// Collects the start of an upload stream until its type is known and checks it against the policy
+#[derive(Debug)]
+pub struct ContentGate {
+    policy: ContentPolicyHandle,
+    tenant: Option<String>,
+    prefix: Vec<u8>,
+}
+
+impl ContentGate {
+    // bytes that reached the disk in an earlier request
+    pub fn prefill(&mut self, bytes: &[u8]) {
+        self.prefix.extend_from_slice(&bytes[..bytes.len().min(SNIFF_LEN - self.prefix.len())]);
+    }
+
+    // the detected type once `bytes` settled it, `complete` when they are the last of the upload
+    pub fn feed(&mut self, bytes: &[u8], complete: bool) -> Option<&'static str> {
+        self.prefill(bytes);
+        detect(&self.prefix, complete)
+    }
+
+    pub fn admit(&self, mime: &str) -> Result<(), ErrorStates> {
+        self.policy.admit(self.tenant.as_deref(), mime)
+    }
+}
+
+// up to `len` leading logical bytes of an upload already on disk, never more than a sniff needs
+pub async fn stored_prefix(file: &IndexedFile, encryption: &Encryption, len: usize) -> Result<Vec<u8>, FragmentError> {
+    let len = len.min(SNIFF_LEN).min(file.file_size);
+    let mut stream = merkle::read_logical(file, encryption, 0, len as u64).await?;
+
+    let mut prefix = Vec::with_capacity(len);
+    while let Some(chunk) = stream.next().await {
+        prefix.extend_from_slice(&chunk?);
+    }
+    Ok(prefix)
+}
@@ -0,0 +1,60 @@
+use std::{path::{Path, PathBuf}, sync::Arc};
+
+use axum::async_trait;
+use sysinfo::Disks;
+
+
+// Where a server keeps its uploads and registry
+pub type StorageHandle = Arc<dyn Storage>;
+
+/*
+    Placement of a server's uploads. The upload path streams into, and reads back from, one
+    local file per upload below `data_dir` and links deduplicated blobs from `[store] dir`. A
+    storage only decides where that directory and the registry live, e.g. on a mounted
+    volume, and whether it takes new uploads, it does not do the reads and writes itself.
+ */
+#[async_trait]
+pub trait Storage: std::fmt::Debug + Send + Sync + 'static {
+    fn data_dir(&self) -> &Path;
+
+    // the file objects of the server, written back on exit
+    fn registry_path(&self) -> PathBuf {
+        self.data_dir().join("registry.json")
+    }
+
+    // asked before an upload is scheduled, false refuses it as out of storage
+    async fn has_room(&self, reserve: u64) -> bool;
+}
+
+// Uploads on the local disk below `data_dir`
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    data_dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(data_dir: impl Into<PathBuf>) -> Self {
+        Self { data_dir: data_dir.into() }
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    #[inline(always)]
+    fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    // the disk holding the data directory is the one mounted closest to it
+    async fn has_room(&self, reserve: u64) -> bool {
+        let Ok(path) = std::fs::canonicalize(&self.data_dir) else {
+            return false;
+        };
+        let disks = Disks::new_with_refreshed_list();
+        disks
+            .iter()
+            .filter(|disk| path.starts_with(disk.mount_point()))
+            .max_by_key(|disk| disk.mount_point().as_os_str().len())
+            .is_some_and(|disk| disk.available_space() > reserve)
+    }
+}
@@ -0,0 +1,303 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use futures::StreamExt;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{compression::{self, BlockIndex, Compression}, file::FileObject, handlers::JobHandle, FragmentError};
+
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StoreConfig {
+    pub dedup: bool,
+    // has to live on the same filesystem as the data directory, uploads are hard linked to their blob
+    pub dir: PathBuf,
+    // let a declared hash skip the transfer even when only another tenant holds the blob,
+    // off by default since knowing a digest is then enough to obtain the content
+    pub share_across_tenants: bool,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            dedup: false,
+            dir: PathBuf::from("./data/blobs"),
+            share_across_tenants: false,
+        }
+    }
+}
+
+// blobs by digest, with the references each tenant holds on them
+pub type BlobStoreHandle = Arc<BlobStore>;
+
+#[derive(Debug, Default)]
+struct Blob {
+    size: usize,
+    stored_size: usize,
+    // of the logical content, handed on to the uploads linked to it
+    crc32: Option<u32>,
+    // references held per tenant, the blob goes once all of them are released
+    owners: HashMap<String, usize>,
+    // links on disk the registry has no digest for, uploads ingested after it was last written
+    untracked: usize,
+}
+
+impl Blob {
+    fn refs(&self) -> usize {
+        self.owners.values().sum::<usize>() + self.untracked
+    }
+}
+
+/*
+    Completed uploads are moved under their sha256 and the upload path becomes a hard link to it.
+    The digest is taken over the logical content, compressed and plain copies of it are separate blobs
+    (`<digest>.zst` next to `<digest>`) since a link has to keep the layout its upload was written in.
+    Reference counts are not persisted, they are rebuilt from the digests in the registry on startup.
+    A registry that is older than the blobs, as after a crash, misses some of their uploads. The
+    link counts on disk cover those, such a blob stays until the store is opened without them.
+ */
+#[derive(Debug)]
+pub struct BlobStore {
+    config: StoreConfig,
+    blobs: Mutex<HashMap<String, Blob>>,
+}
+
+impl BlobStore {
+    pub async fn open(config: StoreConfig, handle: &JobHandle) -> Result<BlobStoreHandle, FragmentError> {
+        if config.dedup {
+            std::fs::create_dir_all(&config.dir)?;
+        }
+
+        let mut blobs: HashMap<String, Blob> = HashMap::new();
+        let jobs: Vec<_> = handle.iter().map(|job| job.clone()).collect();
+        for job in jobs {
+            let entry = job.lock().await;
+            if let Some(digest) = entry.digest() {
+                let blob = blobs.entry(blob_key(digest, entry.compression())).or_default();
+                blob.size = entry.file_size;
+                blob.stored_size = entry.stored_size();
+                blob.crc32 = blob.crc32.or(entry.crc32());
+                *blob.owners.entry(owner(entry.tenant())).or_default() += 1;
+            }
+        }
+        if config.dedup {
+            for (key, links) in linked_blobs(&config.dir)? {
+                let blob = blobs.entry(key).or_default();
+                blob.untracked = links.saturating_sub(blob.refs());
+            }
+        }
+        tracing::info!(blobs = blobs.len(), dedup = config.dedup, "blob store opened");
+
+        Ok(Arc::new(Self { config, blobs: Mutex::new(blobs) }))
+    }
+
+    #[inline(always)]
+    pub fn enabled(&self) -> bool {
+        self.config.dedup
+    }
+
+    fn blob_path(&self, key: &str) -> PathBuf {
+        self.config.dir.join(&key[..2]).join(key)
+    }
+
+    // swap the completed upload for a link to its blob, a known blob drops the fresh copy
+    // `digest` is the sha256 taken while the content was written, without it the stored content is hashed
+    pub async fn ingest(&self, file_obj: &mut FileObject, digest: Option<String>) -> Result<(), FragmentError> {
+        // sealed with a key of their own, encrypted uploads never share content
+        if !self.enabled() || file_obj.envelope().is_some() {
+            return Ok(());
+        }
+
+        let path = file_obj.output_file_path();
+        let digest = match (digest, file_obj.compression()) {
+            (Some(digest), _) => digest,
+            (None, Compression::None) => sha256_file(path.clone()).await?,
+            (None, Compression::Zstd) => sha256_blocks(path.clone()).await?,
+        };
+        let key = blob_key(&digest, file_obj.compression());
+        let blob_path = self.blob_path(&key);
+
+        {
+            let mut blobs = self.blobs.lock().unwrap();
+            let blob = blobs.entry(key).or_default();
+
+            if blob.refs() > 0 {
+                // link next to the upload and rename over it, the fresh copy survives a missing blob
+                let staging = path.with_extension("dedup");
+                std::fs::hard_link(&blob_path, &staging)?;
+                std::fs::rename(&staging, &path)?;
+                blob.crc32 = blob.crc32.or(file_obj.crc32());
+                tracing::info!(uuid = %file_obj.get_uuid(), %digest, refs = blob.refs() + 1, "upload deduplicated");
+            } else {
+                if let Some(parent) = blob_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                // a blob left behind without references is stale, the fresh upload replaces it
+                match std::fs::remove_file(&blob_path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {},
+                }
+                std::fs::hard_link(&path, &blob_path)?;
+                blob.size = file_obj.file_size;
+                blob.stored_size = file_obj.stored_size();
+                blob.crc32 = file_obj.crc32();
+            }
+
+            *blob.owners.entry(owner(file_obj.tenant())).or_default() += 1;
+        }
+
+        file_obj.set_digest(digest);
+        Ok(())
+    }
+
+    /*
+        Upload skip, link an existing blob of the declared digest and size to `target`.
+        Returns the layout, stored size and crc32 of the linked blob, None if there is no such blob
+        or the tenant may not reuse it. A plain copy is preferred over a compressed one.
+     */
+    pub fn claim(&self, digest: &str, size: usize, tenant: Option<&str>, target: &Path) -> Result<Option<(Compression, usize, Option<u32>)>, FragmentError> {
+        if !self.enabled() || !is_digest(digest) {
+            return Ok(None);
+        }
+
+        let owner = owner(tenant);
+        let mut blobs = self.blobs.lock().unwrap();
+
+        for compression in [Compression::None, Compression::Zstd] {
+            let key = blob_key(digest, compression);
+            let Some(blob) = blobs.get_mut(&key) else {
+                continue;
+            };
+
+            let allowed = self.config.share_across_tenants || blob.owners.contains_key(&owner);
+            if !allowed || blob.size != size || blob.refs() == 0 {
+                continue;
+            }
+
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::hard_link(self.blob_path(&key), target)?;
+            *blob.owners.entry(owner).or_default() += 1;
+
+            return Ok(Some((compression, blob.stored_size, blob.crc32)));
+        }
+
+        Ok(None)
+    }
+
+    // drop one reference, the blob is collected once nothing points at it anymore
+    pub fn release(&self, digest: &str, compression: Compression, tenant: Option<&str>) -> Result<(), FragmentError> {
+        let key = blob_key(digest, compression);
+        let mut blobs = self.blobs.lock().unwrap();
+        let Some(blob) = blobs.get_mut(&key) else {
+            return Ok(());
+        };
+
+        let owner = owner(tenant);
+        if let Some(refs) = blob.owners.get_mut(&owner) {
+            *refs -= 1;
+            if *refs == 0 {
+                blob.owners.remove(&owner);
+            }
+        }
+
+        if blob.refs() == 0 {
+            blobs.remove(&key);
+            match std::fs::remove_file(self.blob_path(&key)) {
+                Ok(()) => tracing::info!(%digest, "blob collected"),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn blob_key(digest: &str, compression: Compression) -> String {
+    match compression {
+        Compression::None => digest.to_string(),
+        Compression::Zstd => format!("{}.zst", digest),
+    }
+}
+
+// the blobs below `dir` with the uploads linked to each of them
+fn linked_blobs(dir: &Path) -> std::io::Result<Vec<(String, usize)>> {
+    let mut blobs = Vec::new();
+    for shard in std::fs::read_dir(dir)? {
+        let shard = shard?.path();
+        if !shard.is_dir() {
+            continue;
+        }
+        for blob in std::fs::read_dir(&shard)? {
+            let blob = blob?.path();
+            let links = links(&blob)?;
+            if let (Some(key), true) = (blob.file_name().and_then(|name| name.to_str()), links > 0) {
+                blobs.push((key.to_string(), links));
+            }
+        }
+    }
+    Ok(blobs)
+}
+
+// uploads hard linked to the blob at `path`, every name besides its own
+#[cfg(unix)]
+pub fn links(path: &Path) -> std::io::Result<usize> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(std::fs::metadata(path)?.nlink().saturating_sub(1) as usize)
+}
+
+// without link counts only the registry tells
+#[cfg(not(unix))]
+pub fn links(_path: &Path) -> std::io::Result<usize> {
+    Ok(0)
+}
+
+fn owner(tenant: Option<&str>) -> String {
+    tenant.unwrap_or_default().to_string()
+}
+
+// a lowercase hex sha256, anything else can't name a blob
+pub fn is_digest(digest: &str) -> bool {
+    digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+pub async fn sha256_file(path: PathBuf) -> Result<String, FragmentError> {
+    let digest = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 1024 * 1024];
+
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await??;
+
+    Ok(digest)
+}
+
+// digest of the logical content of a compressed upload, so it matches the plain copy and the declared hash
+pub async fn sha256_blocks(path: PathBuf) -> Result<String, FragmentError> {
+    let index = BlockIndex::scan(&path).await?;
+    let end = index.logical_len();
+    let mut blocks = std::pin::pin!(compression::read_range(path, index, 0, end));
+    let mut hasher = Sha256::new();
+
+    while let Some(block) = blocks.next().await {
+        hasher.update(&block?);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -0,0 +1,143 @@
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+use tracing::{field, Instrument};
+use tracing_subscriber::EnvFilter;
+
+use crate::authorization::Principal;
+
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static CURRENT_REQUEST_ID: String;
+}
+
+// id of the request being served by the current task, if any
+pub fn current_request_id() -> Option<String> {
+    CURRENT_REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Compact,
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    // filter directives such as `info` or `lofty=debug,tower_http=warn`, `RUST_LOG` takes precedence
+    pub level: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Pretty,
+            level: "info".to_string(),
+        }
+    }
+}
+
+pub fn init_tracing(config: &LogConfig) {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.level))
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_target(false);
+
+    let _ = match config.format {
+        LogFormat::Pretty => builder.pretty().try_init(),
+        LogFormat::Compact => builder.compact().try_init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .try_init(),
+    };
+}
+
+// Identifier of a single request, echoed back in `x-request-id`
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    fn from_request(req: &Request) -> Self {
+        let supplied = req.headers()
+            .get(&REQUEST_ID_HEADER)
+            .and_then(|val| val.to_str().ok())
+            .filter(|val| !val.is_empty() && val.len() <= 128);
+
+        match supplied {
+            Some(val) => RequestId(val.to_string()),
+            None => RequestId(uuid::Uuid::new_v4().to_string()),
+        }
+    }
+}
+
+// middleware opening a span per request, every event of the handler is tagged with its fields
+pub async fn trace_request(
+    principal: Principal,
+    matched_path: Option<MatchedPath>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+
+    let request_id = RequestId::from_request(&req);
+    let route = matched_path
+        .as_ref()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+
+    let upload_uuid = req.headers()
+        .get("uuid")
+        .and_then(|val| val.to_str().ok())
+        .map(|val| val.to_string());
+
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id.0,
+        method = %req.method(),
+        route = %route,
+        // the caller of the connection until `authenticate` resolved the request's own
+        principal = %principal,
+        uuid = field::Empty,
+        status = field::Empty,
+    );
+
+    if let Some(uuid) = upload_uuid {
+        span.record("uuid", uuid.as_str());
+    }
+
+    req.extensions_mut().insert(request_id.clone());
+
+    let scoped_id = request_id.0.clone();
+    let handler = async move {
+        let started = tokio::time::Instant::now();
+        tracing::debug!("request received");
+
+        let mut resp = next.run(req).await;
+
+        tracing::Span::current().record("status", resp.status().as_u16());
+        tracing::info!(elapsed_ms = started.elapsed().as_millis() as u64, "request finished");
+
+        if let Ok(val) = HeaderValue::from_str(&request_id.0) {
+            resp.headers_mut().insert(REQUEST_ID_HEADER, val);
+        }
+
+        resp
+    }
+    .instrument(span);
+
+    CURRENT_REQUEST_ID.scope(scoped_id, handler).await
+}
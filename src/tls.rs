@@ -0,0 +1,195 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{ServerConnection, WebPkiClientVerifier},
+    RootCertStore, ServerConfig,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{authorization::Principal, FragmentError};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    // client certificates are verified when presented, anonymous clients are still served
+    Optional,
+    Required,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    // PEM encoded certificate chain and private key
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // PEM bundle of the CAs client certificates are checked against, enables mTLS
+    pub client_ca: Option<PathBuf>,
+    pub client_auth: ClientAuth,
+    // seconds between checks of the PEM files for changes
+    pub reload_interval: u64,
+    // sha256 fingerprint of a client certificate to the principal it authenticates
+    pub principals: HashMap<String, String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert: PathBuf::from("./certs/server.pem"),
+            key: PathBuf::from("./certs/server.key"),
+            client_ca: None,
+            client_auth: ClientAuth::Optional,
+            reload_interval: 30,
+            principals: HashMap::new(),
+        }
+    }
+}
+
+// Holds the live rustls config, swapped in place whenever the PEM files change on disk
+#[derive(Debug)]
+pub struct TlsReloader {
+    config: TlsConfig,
+    current: RwLock<Arc<ServerConfig>>,
+    stamp: RwLock<Vec<Option<SystemTime>>>,
+}
+
+impl TlsReloader {
+    pub fn load(config: TlsConfig) -> Result<Self, FragmentError> {
+        let server_config = build_server_config(&config)?;
+        let stamp = modified_times(&config);
+
+        Ok(Self {
+            config,
+            current: RwLock::new(Arc::new(server_config)),
+            stamp: RwLock::new(stamp),
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().unwrap().clone())
+    }
+
+    // rebuild the config if any of the files changed, a broken update keeps the previous certificates
+    pub fn reload_if_changed(&self) {
+        let stamp = modified_times(&self.config);
+        if *self.stamp.read().unwrap() == stamp {
+            return;
+        }
+
+        match build_server_config(&self.config) {
+            Ok(server_config) => {
+                *self.current.write().unwrap() = Arc::new(server_config);
+                *self.stamp.write().unwrap() = stamp;
+                tracing::info!(cert = %self.config.cert.display(), "tls certificates reloaded");
+            },
+            Err(e) => {
+                tracing::error!(variant = e.state().variant(), "unable to reload tls certificates: {}", e.state());
+            }
+        }
+    }
+
+    pub async fn watch(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.reload_interval.max(1)));
+        loop {
+            interval.tick().await;
+            self.reload_if_changed();
+        }
+    }
+
+    // map the verified client certificate of a connection onto a principal
+    pub fn principal_for(&self, conn: &ServerConnection) -> Option<Principal> {
+        let leaf = conn.peer_certificates()?.first()?;
+        let fingerprint = format!("{:x}", Sha256::digest(leaf.as_ref()));
+
+        if let Some(name) = self.config.principals.get(&fingerprint) {
+            return Some(Principal::new(name));
+        }
+
+        match common_name(leaf) {
+            Some(cn) => Some(Principal::new(format!("cn:{}", cn))),
+            None => Some(Principal::new(format!("cert:{}", fingerprint))),
+        }
+    }
+}
+
+fn modified_times(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    [Some(&config.cert), Some(&config.key), config.client_ca.as_ref()]
+        .into_iter()
+        .flatten()
+        .map(|path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+fn invalid_data(reason: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, FragmentError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+
+    if certs.is_empty() {
+        return Err(invalid_data(format!("no certificate found in {}", path.display())).into());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, FragmentError> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    match rustls_pemfile::private_key(&mut reader)? {
+        Some(key) => Ok(key),
+        None => Err(invalid_data(format!("no private key found in {}", path.display())).into()),
+    }
+}
+
+pub fn build_server_config(config: &TlsConfig) -> Result<ServerConfig, FragmentError> {
+    let certs = load_certs(&config.cert)?;
+    let key = load_key(&config.key)?;
+
+    // pin the provider explicitly rather than relying on a process wide default
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions()?;
+
+    let builder = match &config.client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca)? {
+                roots.add(cert)?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match config.client_auth {
+                ClientAuth::Optional => verifier.allow_unauthenticated().build(),
+                ClientAuth::Required => verifier.build(),
+            }
+            .map_err(|e| invalid_data(e.to_string()))?;
+
+            builder.with_client_cert_verifier(verifier)
+        },
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder.with_single_cert(certs, key)?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(server_config)
+}
+
+fn common_name(cert: &CertificateDer<'_>) -> Option<String> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+    let cn = parsed.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(str::to_string)
+}
@@ -1,42 +1,366 @@
-use std::{net::Ipv4Addr, path::Path};
+use std::{future::Future, path::{Path, PathBuf}, sync::Arc, time::Duration};
 
-use axum::routing::{get, post};
-use axum::{Extension, Router};
+use axum::extract::{ConnectInfo, Request};
+use axum::routing::{get, patch, post, put};
+use axum::{middleware, Extension, Router};
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use tower::Service;
+use dashmap::DashMap;
 use tokio::fs::File;
-use tokio::io::BufWriter;  
+use tokio::io::{AsyncWriteExt, BufWriter};  
 
-use crate::FragmentError;
-use crate::config::LoadConfig;
-use crate::handlers::{JobHandle, init_upload_process, task_progress, resume_upload,};
+use crate::{ErrorStates, FragmentError};
+use crate::config::{LoadConfig, ServerSettings};
+use crate::handlers::{self, JobHandle, schedule_upload_process, init_upload_process, task_progress, resume_upload, upload_piece, delete_upload};
+use crate::limiter::{self, LimitConfig, Limiter, RateLimiter};
+use crate::metrics::{self, Metrics, MetricsHandle};
+use crate::telemetry;
+use crate::audit::{self, AuditConfig, AuditHandle, AuditLog};
+use crate::shutdown::ShutdownHandle;
+use crate::file::FileObject;
+use crate::tls::TlsReloader;
+use crate::index::{self, FileIndex, FileIndexHandle};
+use crate::metadata::{self, MetadataConfig, MetadataHandle};
+use crate::download;
+use crate::store::BlobStoreHandle;
+use crate::compression::{self, CompressionConfig, CompressionHandle};
+use crate::encryption::{self, EncryptionHandle};
+use crate::encoding::{EncodingConfig, EncodingHandle};
+use crate::merkle::{self, MerkleConfig, MerkleHandle};
+use crate::listener::{Connection, Listener, Mount, Peer};
+use crate::storage::StorageHandle;
+use crate::authorization::{self, AuthHandle};
+use crate::hooks::HooksHandle;
+use crate::webhooks::{self, WebhooksHandle};
+use crate::expiry::{self, ExpiryConfig};
+use crate::sniff::{ContentPolicy, ContentPolicyHandle};
+use crate::filename::{FilenamePolicy, FilenamePolicyHandle};
+use crate::bundle::{self, BundleConfig, BundleHandle};
 
 
-pub async fn create_router( 
+// State shared by every listener, each one mounts its own subset of the routes on top of it
+#[derive(Debug, Clone)]
+pub struct Services { 
     ext: JobHandle,
-) -> Result<Router, FragmentError> { 
-    
-    let serve_dir = tower_http::services::fs::ServeDir::new("./admin");
+    limiter: RateLimiter,
+    metrics: MetricsHandle,
+    audit_log: AuditHandle,
+    index: FileIndexHandle,
+    metadata: MetadataHandle,
+    store: BlobStoreHandle,
+    compression: CompressionHandle,
+    encryption: EncryptionHandle,
+    encoding: EncodingHandle,
+    merkle: MerkleHandle,
+    storage: StorageHandle,
+    auth: AuthHandle,
+    hooks: HooksHandle,
+    webhooks: WebhooksHandle,
+    policy: ContentPolicyHandle,
+    filenames: FilenamePolicyHandle,
+    bundles: BundleHandle,
+    shutdown: ShutdownHandle,
+    dashboard: PathBuf,
+}
+
+impl Services { 
+    pub async fn new(
+        ext: JobHandle,
+        config: &LoadConfig,
+        storage: StorageHandle,
+        encryption: EncryptionHandle,
+        store: BlobStoreHandle,
+        auth: AuthHandle,
+        hooks: HooksHandle,
+        webhooks: WebhooksHandle,
+        shutdown: ShutdownHandle,
+    ) -> Result<Self, FragmentError> { 
+
+        let limiter: RateLimiter = Arc::new(Limiter::new(config.section::<LimitConfig>("limits")));
+        tokio::spawn(limiter::sweep_periodically(limiter.clone(), Duration::from_secs(60)));
+
+        let metrics: MetricsHandle = Arc::new(Metrics::new(storage.data_dir())
+            .expect("unable to register the prometheus metrics"));
+        metrics.set_job_entries(ext.len());
+
+        let audit_log: AuditHandle = Arc::new(AuditLog::open(config.section::<AuditConfig>("audit"), storage.data_dir()).await?);
+
+        let index = FileIndex::build(&ext).await;
+        tracing::info!(entries = index.len(), "file index built");
+
+        let metadata: MetadataHandle = Arc::new(config.section::<MetadataConfig>("metadata"));
+
+        let compression: CompressionHandle = Arc::new(config.section::<CompressionConfig>("compression"));
+
+        let encoding: EncodingHandle = Arc::new(config.section::<EncodingConfig>("encoding"));
+
+        let merkle: MerkleHandle = Arc::new(config.section::<MerkleConfig>("merkle"));
+
+        let policy: ContentPolicyHandle = Arc::new(config.section::<ContentPolicy>("content"));
+
+        let filenames: FilenamePolicyHandle = Arc::new(config.section::<FilenamePolicy>("filenames"));
+
+        let bundles: BundleHandle = Arc::new(config.section::<BundleConfig>("bundles"));
+
+        let dashboard = config.section::<ServerSettings>("server").dashboard;
+
+        tokio::spawn(expiry::sweep_periodically(config.section::<ExpiryConfig>("expiry"), ext.clone(), index.clone(), webhooks.clone()));
+
+        Ok(Self { ext, limiter, metrics, audit_log, index, metadata, store, compression, encryption, encoding, merkle, storage, auth, hooks, webhooks, policy, filenames, bundles, shutdown, dashboard })
+    }
+
+    pub fn router(&self, mounts: &[Mount]) -> Router { 
+        let mut router = Router::new();
+
+        for mount in mounts { 
+            router = match mount { 
+                Mount::Uploads => router
+                    .route("/schedule_upload", post(schedule_upload_process))
+                    .route("/upload_file", get(init_upload_process))
+                    .route("/status", get(task_progress))
+                    .route("/resume_upload", get(resume_upload))
+                    .route("/upload_piece", put(upload_piece)),
+                Mount::Files => router
+                    .route("/files", get(index::list_files))
+                    .route("/files/:uuid", patch(metadata::update_labels).delete(delete_upload))
+                    .route("/files/:uuid/content", get(download::download_file))
+                    .route("/files/:uuid/manifest", get(merkle::serve_manifest))
+                    .route("/bundles", post(bundle::create_bundle)),
+                Mount::Metrics => router.route("/metrics", get(metrics::serve_metrics)),
+                Mount::Admin => router
+                    .route("/admin/files", get(index::list_all_files))
+                    .route("/admin/audit", get(audit::query_audit_log))
+                    .route("/admin/audit/verify", get(audit::verify_audit_log))
+                    .route("/admin/webhooks/deliveries", get(webhooks::query_deliveries)),
+                Mount::Dashboard => router,
+            };
+        }
+
+        let router = router
+            .layer(middleware::from_fn(limiter::rate_limit))
+            .layer(middleware::from_fn(audit::audit_requests))
+            .layer(middleware::from_fn(authorization::authenticate))
+            .layer(middleware::from_fn(metrics::count_errors))
+            .layer(middleware::from_fn(telemetry::trace_request))
+            .layer(Extension(self.limiter.clone()))
+            .layer(Extension(self.metrics.clone()))
+            .layer(Extension(self.audit_log.clone()))
+            .layer(Extension(self.index.clone()))
+            .layer(Extension(self.metadata.clone()))
+            .layer(Extension(self.store.clone()))
+            .layer(Extension(self.compression.clone()))
+            .layer(Extension(self.encryption.clone()))
+            .layer(Extension(self.encoding.clone()))
+            .layer(Extension(self.merkle.clone()))
+            .layer(Extension(self.storage.clone()))
+            .layer(Extension(self.auth.clone()))
+            .layer(Extension(self.hooks.clone()))
+            .layer(Extension(self.webhooks.clone()))
+            .layer(Extension(self.policy.clone()))
+            .layer(Extension(self.filenames.clone()))
+            .layer(Extension(self.bundles.clone()))
+            .layer(Extension(self.shutdown.clone()))
+            .layer(Extension(self.ext.clone()));  
+
+        if mounts.contains(&Mount::Dashboard) { 
+            let serve_dir = tower_http::services::fs::ServeDir::new(&self.dashboard);
+            return router.nest_service("/dashboard", serve_dir);
+        }
+
+        router
+    }
+}
+
+// serve `app` on `listener` until `signal` fires, terminating TLS when a reloader is given
+// every connection picks up the certificates current at accept time
+pub async fn start_server(
+    listener: Listener, 
+    app: Router, 
+    tls: Option<Arc<TlsReloader>>,
+    signal: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), FragmentError>  {
+
+    // connections are told to finish their in-flight requests once the signal fires
+    let (closing, _) = tokio::sync::watch::channel(false);
+    let mut connections = tokio::task::JoinSet::new();
 
-    // let server_router = Router::new()
-    //     .route("/foo", get(|| async { "Hi from /foo" }))
-    //     .nest_service("/assets", serve_dir.clone())
-    //     .fallback_service(serve_dir);
+    tokio::pin!(signal);
+    loop { 
+        let (stream, peer) = tokio::select! { 
+            accepted = listener.accept() => match accepted { 
+                Ok(accepted) => accepted,
+                Err(e) => { 
+                    // out of descriptors and the like, accepting right away would fail the same way
+                    tracing::warn!("unable to accept connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            // reap finished connections as we go, the set would otherwise hold every one until shutdown
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            _ = &mut signal => break,
+        };
 
-    let mut router = Router::new()
-        .route("/upload_file", get(init_upload_process))
-        .route("/status", get(task_progress))
-        .route("/resume_upload", get(resume_upload))
-        .layer(Extension(ext));  
+        let tls = tls.clone();
+        let app = app.clone();
+        let closing = closing.subscribe();
 
-    let final_router = router.nest_service("/dashboard", serve_dir); 
+        connections.spawn(async move { 
+            let Some(tls) = tls else { 
+                return serve_connection(stream, peer, app, closing).await;
+            };
 
-    Ok(final_router)
+            let handshake = tokio::time::timeout(Duration::from_secs(10), tls.acceptor().accept(stream)).await;
+            let stream = match handshake { 
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => { 
+                    tracing::debug!(%peer, "tls handshake failed: {}", e);
+                    return;
+                },
+                Err(_) => { 
+                    tracing::debug!(%peer, "tls handshake timed out");
+                    return;
+                }
+            };
+
+            let peer = Peer { 
+                principal: tls.principal_for(stream.get_ref().1).or(peer.principal),
+                ..peer
+            };
+            serve_connection(stream, peer, app, closing).await
+        });
+    }
+
+    drop(listener);
+    closing.send_replace(true);
+    while connections.join_next().await.is_some() {}
+
+    Ok(())
+}
+
+async fn serve_connection(
+    stream: impl Connection,
+    peer: Peer,
+    app: Router,
+    mut closing: tokio::sync::watch::Receiver<bool>,
+) { 
+    let remote = peer.clone();
+    let service = hyper::service::service_fn(move |mut req: Request<Incoming>| { 
+        if let Some(addr) = peer.addr { 
+            req.extensions_mut().insert(ConnectInfo(addr));
+        }
+        if let Some(principal) = &peer.principal { 
+            req.extensions_mut().insert(principal.clone());
+        }
+        // the router is always ready, no need to poll it first
+        app.clone().call(req)
+    });
+
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+    tokio::pin!(conn);
+
+    tokio::select! { 
+        served = conn.as_mut() => { 
+            if let Err(e) = served { 
+                tracing::debug!(peer = %remote, "connection closed with error: {}", e);
+            }
+        },
+        _ = closing.changed() => { 
+            conn.as_mut().graceful_shutdown();
+            let _ = conn.await;
+        }
+    }
+}
+
+// restore the file objects of a previous run, uploads it interrupted become resumable
+pub async fn load_registry(path: impl AsRef<Path>) -> Result<JobHandle, FragmentError> { 
+    let handle: JobHandle = Arc::new(DashMap::new());
+
+    let contents = match tokio::fs::read(path.as_ref()).await { 
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(handle),
+        Err(e) => return Err(e.into()),
+    };
+
+    let objects: Vec<FileObject> = serde_json::from_slice(&contents)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    for mut file_obj in objects { 
+        let on_disk = durable_len(&file_obj).await;
+        file_obj.recover(on_disk);
+        handle.insert(*file_obj.get_uuid(), handlers::job(file_obj));
+    }
+
+    tracing::info!(entries = handle.len(), "registry restored");
+    Ok(handle)
+}
+
+// bytes of the upload that made it to disk intact, zero when its file is gone
+pub async fn durable_len(file_obj: &FileObject) -> usize { 
+    // compressed and encrypted uploads count the bytes they hold, not the size of the file
+    let on_disk = match file_obj.envelope() { 
+        Some(envelope) => encryption::durable_len(file_obj.output_file_path(), envelope).await,
+        None => compression::durable_len(file_obj.output_file_path(), file_obj.compression()).await,
+    };
+    on_disk.map(|len| len as usize).unwrap_or(0)
+}
+
+// a new file at `path`, whatever was there is unlinked rather than truncated since it may be shared with a blob
+pub async fn create_fresh(path: impl AsRef<Path>) -> std::io::Result<File> { 
+    let path = path.as_ref();
+    match tokio::fs::remove_file(path).await { 
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {},
+    }
+    tokio::fs::OpenOptions::new().write(true).create_new(true).open(path).await
 }
 
-pub async fn start_server(addr: (Ipv4Addr, u16), app: Router) -> Result<(), FragmentError>  {
-    let listener = tokio::net::TcpListener::bind(addr).await?;
+/*
+    Claim `dir` for this process until the returned file is dropped. A serving instance and the
+    offline commands rewrite the same registry, whichever comes second is refused instead of
+    overwriting the other's changes. The lock is released by the OS if the process dies.
+ */
+pub fn lock_data_dir(dir: &Path) -> Result<std::fs::File, FragmentError> { 
+    std::fs::create_dir_all(dir)?;
+    let file = std::fs::OpenOptions::new().create(true).write(true).truncate(false).open(dir.join("lofty.lock"))?;
+
+    match file.try_lock() { 
+        Ok(()) => Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) => Err(ErrorStates::DataDirLocked(dir.display().to_string()).into()),
+        Err(std::fs::TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+// write the registry next to the data, through a temporary file so a crash never leaves half of it
+pub async fn persist_registry(handle: &JobHandle, path: impl AsRef<Path>) -> Result<(), FragmentError> { 
+    let path = path.as_ref();
+
+    // writers still streaming are given up by the drain, their entries are written once they let go
+    let jobs: Vec<_> = handle.iter().map(|job| job.clone()).collect();
+    let mut objects = Vec::with_capacity(jobs.len());
+    for job in jobs { 
+        let value = serde_json::to_value(&*job.lock().await)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        objects.push(value);
+    }
+
+    let contents = serde_json::to_vec_pretty(&objects)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    if let Some(parent) = path.parent() { 
+        tokio::fs::create_dir_all(parent).await?;
+    }
 
-    axum::serve(listener, app).await?; 
+    let staging = path.with_extension("json.tmp");
+    let mut file = File::create(&staging).await?;
+    file.write_all(&contents).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&staging, path).await?;
 
+    tracing::info!(entries = objects.len(), path = %path.display(), "registry persisted");
     Ok(())
 }
 
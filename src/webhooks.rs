@@ -0,0 +1,474 @@
+use std::{collections::VecDeque, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration};
+
+use axum::{body::Body, extract::Query, http::{header::*, Request, Response, StatusCode, Uri}, Extension};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use http_body_util::Full;
+use hyper_util::rt::TokioIo;
+use ring::hmac;
+use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+use serde::{Deserialize, Serialize};
+use tokio::{io::{AsyncRead, AsyncWrite, AsyncWriteExt}, net::TcpStream, sync::mpsc};
+use tokio_rustls::TlsConnector;
+use uuid::Uuid;
+
+use crate::{errors::ErrorStates, file::FileObject, index::IndexedFile, FragmentError};
+
+
+const DELIVERY_LOG: &str = "deliveries.log";
+
+/*
+    [webhooks]
+    secret = "shared signing key"
+    milestones = [25, 50, 75]
+
+    [[webhooks.endpoints]]
+    url = "https://hooks.example.com/lofty"
+    events = ["completed", "failed"]
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub endpoints: Vec<EndpointConfig>,
+    // HMAC-SHA256 key of the `Lofty-Signature` header, an endpoint may bring its own
+    pub secret: Option<String>,
+    // percentages of an upload reported as `progress` events
+    pub milestones: Vec<u8>,
+    // attempts per delivery before it is given up
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    // seconds a single attempt may take
+    pub timeout: u64,
+    // trusted roots of https endpoints
+    pub ca_file: PathBuf,
+    // outbox and delivery log, `webhooks` below the data directory by default
+    pub dir: Option<PathBuf>,
+    // deliveries kept in the log
+    pub log_entries: usize,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            secret: None,
+            milestones: vec![25, 50, 75],
+            max_attempts: 10,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 5 * 60 * 1000,
+            timeout: 10,
+            ca_file: PathBuf::from("/etc/ssl/certs/ca-certificates.crt"),
+            dir: None,
+            log_entries: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EndpointConfig {
+    pub url: String,
+    #[serde(default = "WebhookEvent::all")]
+    pub events: Vec<WebhookEvent>,
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Created,
+    Progress,
+    Completed,
+    Rejected,
+    Quarantined,
+    Failed,
+    Cancelled,
+    Expired,
+}
+
+impl WebhookEvent {
+    pub fn all() -> Vec<WebhookEvent> {
+        use WebhookEvent::*;
+        vec![Created, Progress, Completed, Rejected, Quarantined, Failed, Cancelled, Expired]
+    }
+}
+
+// One event bound for one endpoint, kept in the outbox until it was delivered or given up
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Delivery {
+    id: Uuid,
+    url: String,
+    event: WebhookEvent,
+    upload: Uuid,
+    // the payload exactly as it is signed and sent
+    body: String,
+    attempts: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryOutcome {
+    Delivered,
+    Retrying,
+    Abandoned,
+}
+
+// An attempt as the delivery log shows it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryRecord {
+    pub timestamp: DateTime<Utc>,
+    pub delivery: Uuid,
+    pub event: WebhookEvent,
+    pub upload: Uuid,
+    pub url: String,
+    pub attempt: u32,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub outcome: DeliveryOutcome,
+}
+
+// queues deliveries on the outbox and keeps the recent ones for the admin listing
+pub type WebhooksHandle = Arc<Webhooks>;
+
+/*
+    Deliveries are written to the outbox before the first attempt and removed once they
+    succeeded or ran out of attempts, a restart picks up whatever is left. Every delivery
+    is retried on its own, receivers order events by `occurred_at` rather than arrival.
+ */
+#[derive(Debug)]
+pub struct Webhooks {
+    config: WebhookConfig,
+    outbox: PathBuf,
+    tls: Option<Arc<ClientConfig>>,
+    recent: Mutex<VecDeque<DeliveryRecord>>,
+    log: Option<mpsc::UnboundedSender<DeliveryRecord>>,
+}
+
+impl Webhooks {
+    pub async fn open(config: WebhookConfig, data_dir: &Path) -> Result<WebhooksHandle, FragmentError> {
+        let dir = config.dir.clone().unwrap_or_else(|| data_dir.join("webhooks"));
+        let outbox = dir.join("outbox");
+
+        if config.endpoints.is_empty() {
+            return Ok(Arc::new(Self { config, outbox, tls: None, recent: Mutex::default(), log: None }));
+        }
+        tokio::fs::create_dir_all(&outbox).await?;
+
+        // roots are only read when an endpoint needs them
+        let tls = match config.endpoints.iter().any(|endpoint| endpoint.url.starts_with("https:")) {
+            true => Some(client_config(&config.ca_file)?),
+            false => None,
+        };
+
+        let log_path = dir.join(DELIVERY_LOG);
+        let recent = read_log(&log_path, config.log_entries).await?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(write_log(log_path, recent.clone(), receiver));
+
+        let webhooks = Arc::new(Self { config, outbox, tls, recent: Mutex::new(recent), log: Some(sender) });
+
+        let pending = webhooks.pending().await?;
+        if !pending.is_empty() {
+            tracing::info!(deliveries = pending.len(), "resuming webhook deliveries");
+        }
+        for delivery in pending {
+            tokio::spawn(webhooks.clone().deliver(delivery));
+        }
+        Ok(webhooks)
+    }
+
+    pub fn emit(self: &Arc<Self>, event: WebhookEvent, file_obj: &FileObject) {
+        self.send_event(event, file_obj, None);
+    }
+
+    // report the milestones an upload passed going from `before` to `after` bytes
+    pub fn progress(self: &Arc<Self>, file_obj: &FileObject, before: usize, after: usize) {
+        if file_obj.file_size == 0 {
+            return;
+        }
+        for &milestone in &self.config.milestones {
+            let threshold = file_obj.file_size * milestone as usize / 100;
+            if before < threshold && threshold <= after {
+                self.send_event(WebhookEvent::Progress, file_obj, Some(milestone));
+            }
+        }
+    }
+
+    fn send_event(self: &Arc<Self>, event: WebhookEvent, file_obj: &FileObject, progress: Option<u8>) {
+        let mut endpoints = self.config.endpoints.iter().filter(|endpoint| endpoint.events.contains(&event)).peekable();
+        if endpoints.peek().is_none() {
+            return;
+        }
+
+        let payload = serde_json::json!({
+            "id": Uuid::new_v4(),
+            "event": event,
+            "occurred_at": Utc::now(),
+            "progress": progress,
+            "file": IndexedFile::from(file_obj),
+        });
+        let body = payload.to_string();
+
+        for endpoint in endpoints {
+            let delivery = Delivery {
+                id: Uuid::new_v4(),
+                url: endpoint.url.clone(),
+                event,
+                upload: *file_obj.get_uuid(),
+                body: body.clone(),
+                attempts: 0,
+            };
+            tokio::spawn(self.clone().enqueue(delivery));
+        }
+    }
+
+    // the delivery is in the outbox before the first attempt, a restart picks it up from there
+    async fn enqueue(self: Arc<Self>, delivery: Delivery) {
+        if let Err(e) = self.store(&delivery).await {
+            tracing::error!(delivery = %delivery.id, "unable to write webhook delivery to the outbox: {}", e);
+        }
+        self.deliver(delivery).await
+    }
+
+    async fn deliver(self: Arc<Self>, mut delivery: Delivery) {
+        let Some(endpoint) = self.config.endpoints.iter().find(|endpoint| endpoint.url == delivery.url) else {
+            tracing::warn!(delivery = %delivery.id, url = delivery.url, "webhook endpoint no longer configured, delivery dropped");
+            self.finish(&delivery).await;
+            return;
+        };
+        let secret = endpoint.secret.as_ref().or(self.config.secret.as_ref());
+
+        loop {
+            delivery.attempts += 1;
+            let timeout = Duration::from_secs(self.config.timeout);
+            let sent = match tokio::time::timeout(timeout, self.send(&delivery, secret)).await {
+                Ok(sent) => sent,
+                Err(_) => Err(format!("timed out after {}s", self.config.timeout)),
+            };
+
+            let outcome = match &sent {
+                Ok(status) if status.is_success() => DeliveryOutcome::Delivered,
+                _ if delivery.attempts >= self.config.max_attempts => DeliveryOutcome::Abandoned,
+                _ => DeliveryOutcome::Retrying,
+            };
+            self.record(&delivery, &sent, outcome);
+
+            if outcome != DeliveryOutcome::Retrying {
+                self.finish(&delivery).await;
+                return;
+            }
+            if let Err(e) = self.store(&delivery).await {
+                tracing::error!(delivery = %delivery.id, "unable to update webhook delivery in the outbox: {}", e);
+            }
+            tokio::time::sleep(self.backoff(delivery.attempts)).await;
+        }
+    }
+
+    async fn send(&self, delivery: &Delivery, secret: Option<&String>) -> Result<StatusCode, String> {
+        let uri: Uri = delivery.url.parse().map_err(|_| format!("invalid url {}", delivery.url))?;
+        let host = uri.host().ok_or_else(|| format!("no host in {}", delivery.url))?.to_string();
+        let https = uri.scheme_str() == Some("https");
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+        let mut req = Request::post(uri.path_and_query().map_or("/", |path| path.as_str()))
+            .header(HOST, uri.authority().map_or(host.as_str(), |authority| authority.as_str()))
+            .header(CONTENT_TYPE, "application/json")
+            .header("Lofty-Event", serde_json::to_value(delivery.event).unwrap_or_default().as_str().unwrap_or_default())
+            .header("Lofty-Delivery", delivery.id.to_string());
+        if let Some(secret) = secret {
+            req = req.header("Lofty-Signature", sign(secret, Utc::now().timestamp(), &delivery.body));
+        }
+        let req = req.body(Full::new(Bytes::from(delivery.body.clone()))).map_err(|e| e.to_string())?;
+
+        let stream = TcpStream::connect((host.as_str(), port)).await.map_err(|e| e.to_string())?;
+        match (https, &self.tls) {
+            (false, _) => exchange(stream, req).await,
+            (true, Some(tls)) => {
+                let name = ServerName::try_from(host).map_err(|e| e.to_string())?;
+                let stream = TlsConnector::from(tls.clone()).connect(name, stream).await.map_err(|e| e.to_string())?;
+                exchange(stream, req).await
+            },
+            (true, None) => Err("https is not available".to_string()),
+        }
+    }
+
+    fn backoff(&self, attempts: u32) -> Duration {
+        let backoff = self.config.initial_backoff_ms.saturating_mul(1 << attempts.saturating_sub(1).min(20));
+        Duration::from_millis(backoff.min(self.config.max_backoff_ms))
+    }
+
+    fn record(&self, delivery: &Delivery, sent: &Result<StatusCode, String>, outcome: DeliveryOutcome) {
+        let record = DeliveryRecord {
+            timestamp: Utc::now(),
+            delivery: delivery.id,
+            event: delivery.event,
+            upload: delivery.upload,
+            url: delivery.url.clone(),
+            attempt: delivery.attempts,
+            status: sent.as_ref().ok().map(StatusCode::as_u16),
+            error: sent.as_ref().err().cloned(),
+            outcome,
+        };
+        if outcome != DeliveryOutcome::Delivered {
+            tracing::warn!(delivery = %delivery.id, url = delivery.url, attempt = delivery.attempts, ?outcome, status = ?record.status, error = ?record.error, "webhook delivery failed");
+        }
+
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= self.config.log_entries.max(1) {
+            recent.pop_front();
+        }
+        recent.push_back(record.clone());
+        if let Some(log) = &self.log {
+            let _ = log.send(record);
+        }
+    }
+
+    pub fn deliveries(&self, filter: &DeliveryQuery) -> Vec<DeliveryRecord> {
+        let recent = self.recent.lock().unwrap();
+        let mut found: Vec<_> = recent.iter().filter(|record| filter.matches(record)).cloned().collect();
+
+        // keep the most recent matches when the limit cuts in
+        let skip = found.len().saturating_sub(filter.limit.unwrap_or(100));
+        found.split_off(skip)
+    }
+
+    // replace the outbox entry, only once both the file and its directory entry are on disk does it count
+    async fn store(&self, delivery: &Delivery) -> std::io::Result<()> {
+        let path = self.outbox.join(format!("{}.json", delivery.id));
+        let staging = path.with_extension("json.tmp");
+
+        let mut file = tokio::fs::File::create(&staging).await?;
+        file.write_all(&serde_json::to_vec(delivery)?).await?;
+        file.sync_data().await?;
+        tokio::fs::rename(staging, path).await?;
+        tokio::fs::File::open(&self.outbox).await?.sync_data().await
+    }
+
+    async fn finish(&self, delivery: &Delivery) {
+        match tokio::fs::remove_file(self.outbox.join(format!("{}.json", delivery.id))).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                tracing::error!(delivery = %delivery.id, "unable to remove webhook delivery from the outbox: {}", e);
+            },
+            _ => {},
+        }
+    }
+
+    async fn pending(&self) -> Result<Vec<Delivery>, FragmentError> {
+        let mut pending = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.outbox).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.path().extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match serde_json::from_slice::<Delivery>(&tokio::fs::read(entry.path()).await?) {
+                Ok(delivery) => pending.push(delivery),
+                Err(e) => tracing::warn!(path = %entry.path().display(), "skipping unreadable webhook delivery: {}", e),
+            }
+        }
+        Ok(pending)
+    }
+}
+
+// `t=<unix seconds>,v1=<hex hmac-sha256 of "<t>.<body>">`, the timestamp lets receivers refuse replays
+fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, format!("{}.{}", timestamp, body).as_bytes());
+    format!("t={},v1={}", timestamp, hex::encode(tag.as_ref()))
+}
+
+async fn exchange(stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static, req: Request<Full<Bytes>>) -> Result<StatusCode, String> {
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.map_err(|e| e.to_string())?;
+    tokio::spawn(conn);
+
+    let resp = sender.send_request(req).await.map_err(|e| e.to_string())?;
+    Ok(resp.status())
+}
+
+fn client_config(ca_file: &Path) -> Result<Arc<ClientConfig>, FragmentError> {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(ca_file)?);
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut reader) {
+        roots.add(cert?)?;
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+// the tail of the log, compacted to it so the file does not grow past what is served
+async fn read_log(path: &Path, keep: usize) -> Result<VecDeque<DeliveryRecord>, FragmentError> {
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(VecDeque::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut recent: VecDeque<DeliveryRecord> = contents.lines().filter_map(|line| serde_json::from_str(line).ok()).collect();
+    while recent.len() > keep {
+        recent.pop_front();
+    }
+    Ok(recent)
+}
+
+async fn write_log(path: PathBuf, recent: VecDeque<DeliveryRecord>, mut receiver: mpsc::UnboundedReceiver<DeliveryRecord>) {
+    let mut lines = Vec::new();
+    for record in &recent {
+        lines.extend(serde_json::to_vec(record).unwrap_or_default());
+        lines.push(b'\n');
+    }
+    if let Err(e) = tokio::fs::write(&path, lines).await {
+        tracing::error!("unable to compact the webhook delivery log: {}", e);
+    }
+
+    let mut file = match tokio::fs::OpenOptions::new().create(true).append(true).open(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            tracing::error!("unable to open the webhook delivery log: {}", e);
+            return;
+        },
+    };
+    while let Some(record) = receiver.recv().await {
+        let mut line = serde_json::to_vec(&record).unwrap_or_default();
+        line.push(b'\n');
+        if let Err(e) = file.write_all(&line).await {
+            tracing::error!("unable to append to the webhook delivery log: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DeliveryQuery {
+    pub upload: Option<Uuid>,
+    pub event: Option<WebhookEvent>,
+    pub outcome: Option<DeliveryOutcome>,
+    pub limit: Option<usize>,
+}
+
+impl DeliveryQuery {
+    fn matches(&self, record: &DeliveryRecord) -> bool {
+        self.upload.is_none_or(|upload| record.upload == upload)
+            && self.event.is_none_or(|event| record.event == event)
+            && self.outcome.is_none_or(|outcome| record.outcome == outcome)
+    }
+}
+
+pub async fn query_deliveries(
+    Extension(webhooks): Extension<WebhooksHandle>,
+    Query(filter): Query<DeliveryQuery>,
+) -> Result<Response<Body>, FragmentError> {
+
+    let records = webhooks.deliveries(&filter);
+    let body = serde_json::to_vec(&records).map_err(|_| ErrorStates::UndeclaredError)?;
+
+    let resp = Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))?;
+
+    Ok(resp)
+}
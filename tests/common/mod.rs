@@ -0,0 +1,188 @@
+#![allow(dead_code)]
+
+use std::{
+    net::TcpListener as StdListener,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, net::TcpStream};
+
+
+// A lofty binary running out of its own scratch directory, killed and cleaned up on drop
+pub struct Server {
+    child: Child,
+    pub dir: PathBuf,
+}
+
+impl Server {
+    // SIGTERM and wait, the server drains and writes its registry back before exiting
+    #[cfg(unix)]
+    pub fn stop(&mut self) {
+        let status = Command::new("kill").arg("-TERM").arg(self.child.id().to_string()).status().unwrap();
+        assert!(status.success());
+        self.child.wait().unwrap();
+    }
+
+    // SIGKILL, nothing is written back
+    pub fn crash(&mut self) {
+        self.child.kill().unwrap();
+        self.child.wait().unwrap();
+    }
+
+    // start again on the settings and data left in the scratch directory
+    pub fn restart(&mut self) {
+        self.child = lofty(&self.dir).spawn().unwrap();
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("lofty-{}-{}", name, uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn free_port() -> u16 {
+    StdListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+// numbered lines, compressible and easy to tell apart at any offset
+pub fn content(len: usize) -> Vec<u8> {
+    (0..).flat_map(|i: u32| format!("line {:>10}\n", i).into_bytes()).take(len).collect()
+}
+
+// a registry entry in `dir` waiting for its upload, next to the ones seeded before it
+pub fn seed_pending(dir: &Path, name: &str, size: usize) -> uuid::Uuid {
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    let path = dir.join("data/registry.json");
+    let mut registry: Vec<serde_json::Value> = match std::fs::read(&path) {
+        Ok(contents) => serde_json::from_slice(&contents).unwrap(),
+        Err(_) => Vec::new(),
+    };
+
+    let uuid = uuid::Uuid::new_v4();
+    registry.push(serde_json::json!({
+        "path": "./data",
+        "state": "UnInit",
+        "file_size": size,
+        "name": name,
+        "uuid": uuid,
+        "hash": [],
+    }));
+    std::fs::write(&path, serde_json::to_vec(&registry).unwrap()).unwrap();
+    uuid
+}
+
+// a server on a free port with `settings` below its `[server]` section, once it accepts connections
+pub async fn start(name: &str, settings: &str) -> (Server, lofty_client::Client, u16) {
+    let dir = scratch_dir(name);
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+
+    let port = free_port();
+    let server = spawn_server(dir, &format!("[server]\nbind = \"127.0.0.1:{}\"\n\n{}", port, settings));
+    drop(tcp(port).await);
+
+    (server, lofty_client::Client::new(format!("http://127.0.0.1:{}", port)).unwrap(), port)
+}
+
+// start the server in `dir` with `settings` appended to a minimal settings.toml
+pub fn spawn_server(dir: PathBuf, settings: &str) -> Server {
+    let settings = format!("data = \"./data\"\n\n[logging]\nlevel = \"warn\"\n\n{}\n", settings);
+    std::fs::write(dir.join("settings.toml"), settings).unwrap();
+
+    let child = lofty(&dir).spawn().unwrap();
+    Server { child, dir }
+}
+
+// the lofty binary set up to run out of `dir`
+pub fn lofty(dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_lofty"));
+    command.current_dir(dir).stdout(Stdio::null());
+    command
+}
+
+pub async fn request(mut stream: impl AsyncRead + AsyncWrite + Unpin, method: &str, path: &str, headers: &str, body: &[u8]) -> String {
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n{}\r\n",
+        method, path, body.len(), headers
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(body).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+// like `request`, for binary bodies, a chunked body is joined back together
+pub async fn request_bytes(mut stream: impl AsyncRead + AsyncWrite + Unpin, method: &str, path: &str, headers: &str, body: &[u8]) -> (String, Vec<u8>) {
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n{}\r\n",
+        method, path, body.len(), headers
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(body).await.unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+
+    let split = response.windows(4).position(|window| window == b"\r\n\r\n").expect("response without head");
+    let head = String::from_utf8(response[..split].to_vec()).unwrap();
+    let mut body = response[split + 4..].to_vec();
+
+    if head.to_ascii_lowercase().contains("transfer-encoding: chunked") {
+        let mut joined = Vec::new();
+        let mut rest = &body[..];
+        loop {
+            let line = rest.windows(2).position(|window| window == b"\r\n").unwrap();
+            let size = usize::from_str_radix(std::str::from_utf8(&rest[..line]).unwrap().trim(), 16).unwrap();
+            if size == 0 {
+                break;
+            }
+            joined.extend_from_slice(&rest[line + 2..line + 2 + size]);
+            rest = &rest[line + 2 + size + 2..];
+        }
+        body = joined;
+    }
+    (head, body)
+}
+
+pub async fn get(stream: impl AsyncRead + AsyncWrite + Unpin, path: &str) -> String {
+    request(stream, "GET", path, "", b"").await
+}
+
+// the JSON body of a response read by `request`
+pub fn json_body(response: &str) -> serde_json::Value {
+    let (_, body) = response.split_once("\r\n\r\n").expect("response without body");
+    serde_json::from_str(body).unwrap_or_else(|_| panic!("not a json body: {}", body))
+}
+
+pub async fn tcp(port: u16) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("server never listened on port {}", port);
+}
+
+#[cfg(unix)]
+pub async fn unix(path: &Path) -> tokio::net::UnixStream {
+    for _ in 0..100 {
+        if let Ok(stream) = tokio::net::UnixStream::connect(path).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("server never listened on {}", path.display());
+}
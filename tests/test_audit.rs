@@ -0,0 +1,55 @@
+mod common;
+
+use std::{path::Path, time::Duration};
+
+use common::{free_port, get, json_body, request, scratch_dir, spawn_server, tcp};
+use serde_json::json;
+
+
+// the records of the current log, once the writer caught up with `count` of them
+async fn records(log: &Path, count: usize) -> Vec<serde_json::Value> {
+    for _ in 0..100 {
+        let contents = std::fs::read_to_string(log).unwrap_or_default();
+        let lines: Vec<_> = contents.lines().filter(|line| !line.trim().is_empty()).map(|line| serde_json::from_str(line).unwrap()).collect();
+        if lines.len() >= count {
+            return lines;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("the audit log never reached {} records", count);
+}
+
+#[tokio::test]
+async fn verify_reports_an_edited_record() {
+    let dir = scratch_dir("audit");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    let port = free_port();
+    let _server = spawn_server(dir.clone(), &format!("[server]\nbind = \"127.0.0.1:{}\"\n", port));
+    drop(tcp(port).await);
+
+    for length in [10, 20, 30] {
+        let body = json!({ "fileHash": "a".repeat(64), "Length": length });
+        let response = request(tcp(port).await, "POST", "/schedule_upload", "", body.to_string().as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+
+    let log = dir.join("data/audit/audit.log");
+    let written = records(&log, 3).await;
+    assert!(written.iter().all(|record| record["action"] == "schedule" && record["hash"].is_string()), "{:?}", written);
+    let report = json_body(&get(tcp(port).await, "/admin/audit/verify").await);
+    assert_eq!(report, json!({ "intact": true, "checked": 3 }));
+
+    // the second record claims more bytes than it was written with, its digest no longer matches
+    let contents = std::fs::read_to_string(&log).unwrap();
+    let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+    let mut edited: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+    edited["bytes"] = json!(4096);
+    lines[1] = edited.to_string();
+    std::fs::write(&log, lines.join("\n") + "\n").unwrap();
+
+    let report = json_body(&get(tcp(port).await, "/admin/audit/verify").await);
+    assert_eq!(report["intact"], false, "{}", report);
+    assert_eq!(report["checked"], 1);
+    assert_eq!(report["line"], 2);
+    assert!(report["file"].as_str().unwrap().ends_with("audit.log"), "{}", report);
+}
@@ -0,0 +1,226 @@
+mod common;
+
+use std::io::{Cursor, Read};
+
+use common::{free_port, request_bytes, scratch_dir, spawn_server, start, tcp};
+use lofty_client::{Client, Source};
+use serde_json::json;
+use uuid::Uuid;
+
+
+async fn bundle(port: u16, body: serde_json::Value, headers: &str) -> (String, Vec<u8>) {
+    let headers = format!("Content-Type: application/json\r\n{}", headers);
+    request_bytes(tcp(port).await, "POST", "/bundles", &headers, &serde_json::to_vec(&body).unwrap()).await
+}
+
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+fn lines(prefix: &str, count: usize) -> Vec<u8> {
+    (0..count).flat_map(|i| format!("{} line {:>6}\n", prefix, i).into_bytes()).collect()
+}
+
+#[tokio::test]
+async fn streams_uploads_as_a_zip_of_known_length() {
+    // compressed uploads go into the archive as they were sent
+    let (_server, client, port) = start("bundles", "[compression]\nenabled = true\n").await;
+
+    let contents = [lines("first", 20_000), lines("second", 3), b"\x89PNG\r\n\x1a\n".repeat(1000), Vec::new()];
+    let mut uuids = Vec::new();
+    for (name, content) in ["report.txt", "REPORT.txt", "日本語.png", "empty"].into_iter().zip(&contents) {
+        uuids.push(client.upload_file(&Source::bytes(content.clone()).with_name(name).with_content_type("text/plain")).await.unwrap());
+    }
+
+    let (head, body) = bundle(port, json!({ "uuids": uuids, "name": "nightly" }), "").await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert_eq!(header(&head, "content-length"), Some(body.len().to_string().as_str()));
+    assert_eq!(header(&head, "content-type"), Some("application/zip"));
+    assert_eq!(header(&head, "content-disposition"), Some("attachment; filename=\"nightly.zip\""));
+
+    // the reader checks every crc as it goes
+    let mut archive = zip::ZipArchive::new(Cursor::new(body)).unwrap();
+    let names: Vec<_> = archive.file_names().map(str::to_string).collect();
+    assert_eq!(names.len(), 4);
+    for (i, (name, content)) in ["report.txt", "REPORT (1).txt", "日本語.png", "empty"].into_iter().zip(&contents).enumerate() {
+        let mut entry = archive.by_index(i).unwrap();
+        assert_eq!(entry.name(), name);
+        assert_eq!(entry.size(), content.len() as u64);
+        assert_eq!(entry.unix_mode(), Some(0o100644));
+        let mut read = Vec::new();
+        entry.read_to_end(&mut read).unwrap();
+        assert_eq!(&read, content, "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn bundles_what_a_listing_filter_matches_as_tar() {
+    let (_server, client, port) = start("bundles", "[bundles]\nmax_files = 3\n").await;
+
+    let long_name = format!("log-{}.txt", "x".repeat(150));
+    let mut expected = Vec::new();
+    for (name, content) in [("log-b.txt", lines("b", 100)), ("log-a.txt", lines("a", 1)), (long_name.as_str(), lines("long", 40))] {
+        client.upload_file(&Source::bytes(content.clone()).with_name(name)).await.unwrap();
+        expected.push((name.to_string(), content));
+    }
+    client.upload_file(&Source::bytes(b"not a log".to_vec()).with_name("other.txt")).await.unwrap();
+    // scheduled but not uploaded, a filter passes over it
+    let pending = client.schedule(&"0".repeat(64), 10).await.unwrap().uuid;
+
+    let (head, body) = bundle(port, json!({ "filter": { "name_prefix": "log-", "sort": "name" }, "format": "tar" }), "").await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert_eq!(header(&head, "content-length"), Some(body.len().to_string().as_str()));
+    assert_eq!(body.len() % 512, 0);
+
+    let mut archive = tar::Archive::new(Cursor::new(body));
+    let mut read = Vec::new();
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let name = entry.path().unwrap().to_string_lossy().into_owned();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).unwrap();
+        read.push((name, content));
+    }
+    expected.sort();
+    assert_eq!(read, expected);
+
+    let (head, _) = bundle(port, json!({ "uuids": [pending] }), "").await;
+    assert!(head.starts_with("HTTP/1.1 409"), "{}", head);
+    let (head, _) = bundle(port, json!({ "uuids": [Uuid::new_v4()] }), "").await;
+    assert!(head.starts_with("HTTP/1.1 404"), "{}", head);
+    let (head, _) = bundle(port, json!({ "filter": {} }), "").await;
+    assert!(head.starts_with("HTTP/1.1 413"), "{}", head);
+    let (head, _) = bundle(port, json!({ "format": "zip" }), "").await;
+    assert!(head.starts_with("HTTP/1.1 400"), "{}", head);
+}
+
+#[tokio::test]
+async fn resumes_an_interrupted_bundle_download() {
+    let (server, client, port) = start("bundles", "").await;
+
+    let first = lines("first", 50_000);
+    let second = lines("second", 10_000);
+    let uuids = [
+        client.upload_file(&Source::bytes(first.clone()).with_name("first.txt")).await.unwrap(),
+        client.upload_file(&Source::bytes(second.clone()).with_name("second.txt")).await.unwrap(),
+    ];
+    let request = json!({ "uuids": uuids });
+
+    let (head, whole) = bundle(port, request.clone(), "").await;
+    let etag = header(&head, "etag").unwrap().to_string();
+    assert!(head.contains("accept-ranges: bytes"), "{}", head);
+
+    // picking up in the middle of the first entry, its crc was taken when it was uploaded
+    let cut = first.len() / 2;
+    let (head, rest) = bundle(port, request.clone(), &format!("Range: bytes={}-\r\nIf-Range: {}\r\n", cut, etag)).await;
+    assert!(head.starts_with("HTTP/1.1 206"), "{}", head);
+    assert_eq!(header(&head, "content-range"), Some(format!("bytes {}-{}/{}", cut, whole.len() - 1, whole.len()).as_str()));
+    assert_eq!(&whole[cut..], &rest[..]);
+
+    let (head, tail) = bundle(port, request.clone(), "Range: bytes=-100\r\n").await;
+    assert!(head.starts_with("HTTP/1.1 206"), "{}", head);
+    assert_eq!(&whole[whole.len() - 100..], &tail[..]);
+
+    // another archive by now, the whole of it is sent
+    let (head, again) = bundle(port, request.clone(), "Range: bytes=10-\r\nIf-Range: \"stale\"\r\n").await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert_eq!(again, whole);
+
+    let (head, _) = bundle(port, request.clone(), &format!("Range: bytes={}-\r\n", whole.len())).await;
+    assert!(head.starts_with("HTTP/1.1 416"), "{}", head);
+
+    // the directory carries the crcs of the uploads as written, nothing of them is read back for it
+    std::fs::write(server.dir.join("data").join(uuids[0].to_string()), vec![b'x'; first.len()]).unwrap();
+    let (_, tail) = bundle(port, request, "Range: bytes=-100\r\n").await;
+    assert_eq!(&whole[whole.len() - 100..], &tail[..]);
+}
+
+#[tokio::test]
+async fn carries_the_crc_of_uploads_sent_in_pieces() {
+    let (server, _, port) = start("bundles", "").await;
+    let client = Client::builder(format!("http://127.0.0.1:{}", port)).chunk_size(10_000).build().unwrap();
+
+    let content = lines("piece", 5_000);
+    let uuid = client.upload_file(&Source::bytes(content.clone()).with_name("pieces.txt")).await.unwrap();
+
+    // every piece went on from the crc of the ones before it, the directory doesn't read the stored bytes
+    std::fs::write(server.dir.join("data").join(uuid.to_string()), vec![b'x'; content.len()]).unwrap();
+    let (head, tail) = bundle(port, json!({ "uuids": [uuid] }), "Range: bytes=-100\r\n").await;
+    assert!(head.starts_with("HTTP/1.1 206"), "{}", head);
+    assert!(tail.windows(4).any(|window| window == crc32(&content).to_le_bytes()), "{:?}", tail);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn switches_to_zip64_past_4_gib() {
+    let dir = scratch_dir("bundles-zip64");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+
+    // a sparse upload just over the 32 bit limit, and a small one stored behind it
+    let (large, small) = (Uuid::new_v4(), Uuid::new_v4());
+    let large_size = (1u64 << 32) + 10;
+    std::fs::File::create(dir.join("data").join(large.to_string())).unwrap().set_len(large_size).unwrap();
+    std::fs::write(dir.join("data").join(small.to_string()), b"tail").unwrap();
+    let registry: Vec<_> = [(large, "large.bin", large_size), (small, "small.txt", 4)]
+        .iter()
+        .map(|(uuid, name, size)| json!({ "path": "./data", "state": "Complete", "file_size": size, "name": name, "uuid": uuid, "hash": [] }))
+        .collect();
+    std::fs::write(dir.join("data/registry.json"), serde_json::to_vec(&registry).unwrap()).unwrap();
+
+    let port = free_port();
+    let _server = spawn_server(dir, &format!("[server]\nbind = \"127.0.0.1:{}\"\n", port));
+
+    let (head, tail) = bundle(port, json!({ "uuids": [large, small] }), "Range: bytes=-300\r\n").await;
+    assert!(head.starts_with("HTTP/1.1 206"), "{}", head);
+    let total: u64 = header(&head, "content-range").unwrap().rsplit('/').next().unwrap().parse().unwrap();
+
+    // local headers with their zip64 extra, the content and 24 byte descriptors
+    let large_entry = 30 + 9 + 20 + large_size + 24;
+    let small_entry = 30 + 9 + 20 + 4 + 24;
+    let directory = 2 * (46 + 9 + 28);
+    assert_eq!(total, large_entry + small_entry + directory + 56 + 20 + 22);
+
+    let u16_at = |at: usize| u16::from_le_bytes(tail[at..at + 2].try_into().unwrap());
+    let u32_at = |at: usize| u32::from_le_bytes(tail[at..at + 4].try_into().unwrap());
+    let u64_at = |at: usize| u64::from_le_bytes(tail[at..at + 8].try_into().unwrap());
+
+    // end of central directory points at the zip64 records
+    let end = tail.len() - 22;
+    assert_eq!(u32_at(end), 0x0605_4b50);
+    assert_eq!(u32_at(end + 16), u32::MAX);
+    let locator = end - 20;
+    assert_eq!(u32_at(locator), 0x0706_4b50);
+    let record = locator - 56;
+    assert_eq!(u32_at(record), 0x0606_4b50);
+    assert_eq!(u64_at(locator + 8), total - 22 - 20 - 56);
+    assert_eq!(u64_at(record + 32), 2);
+    assert_eq!(u64_at(record + 48), large_entry + small_entry);
+
+    // the small entry starts past 4 GiB, its offset lives in the zip64 extra
+    let small_header = record - (46 + 9 + 28);
+    assert_eq!(u32_at(small_header), 0x0201_4b50);
+    assert_eq!(u32_at(small_header + 16), crc32(b"tail"));
+    assert_eq!(u16_at(small_header + 30), 28);
+    assert_eq!(&tail[small_header + 46..small_header + 55], b"small.txt");
+    assert_eq!(u64_at(small_header + 55 + 4), 4);
+    assert_eq!(u64_at(small_header + 55 + 20), large_entry);
+
+    let large_header = small_header - (46 + 9 + 28);
+    let mut zeros = crc32fast::Hasher::new();
+    let block = vec![0u8; 1 << 20];
+    for _ in 0..large_size >> 20 {
+        zeros.update(&block);
+    }
+    zeros.update(&block[..(large_size % (1 << 20)) as usize]);
+    assert_eq!(u32_at(large_header + 16), zeros.finalize());
+    assert_eq!(u64_at(large_header + 55 + 4), large_size);
+}
+
+fn crc32(content: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(content);
+    hasher.finalize()
+}
@@ -0,0 +1,98 @@
+mod common;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::{content, free_port, json_body, request, scratch_dir, seed_pending, spawn_server, tcp, Server};
+use sha2::{Digest, Sha256, Sha512};
+
+
+fn sha256(data: &[u8]) -> String {
+    STANDARD.encode(Sha256::digest(data))
+}
+
+fn start(size: usize) -> (Server, u16, uuid::Uuid) {
+    let dir = scratch_dir("checksum");
+    let uuid = seed_pending(&dir, "pieces.txt", size);
+
+    let port = free_port();
+    let server = spawn_server(dir, &format!("[server]\nbind = \"127.0.0.1:{}\"\n", port));
+    (server, port, uuid)
+}
+
+async fn upload(port: u16, uuid: uuid::Uuid, headers: &str, body: &[u8]) -> String {
+    let headers = format!("FileName: pieces.txt\r\nuuid: {}\r\n{}", uuid, headers);
+    request(tcp(port).await, "GET", "/upload_file", &headers, body).await
+}
+
+async fn resume(port: u16, uuid: uuid::Uuid, pointer: usize, headers: &str, body: &[u8]) -> String {
+    let headers = format!("uuid: {}\r\nContent-Pointer: {}\r\n{}", uuid, pointer, headers);
+    request(tcp(port).await, "GET", "/resume_upload", &headers, body).await
+}
+
+async fn download(port: u16, uuid: uuid::Uuid) -> String {
+    request(tcp(port).await, "GET", &format!("/files/{}/content", uuid), "", b"").await
+}
+
+fn body(response: &str) -> &str {
+    response.split_once("\r\n\r\n").unwrap().1
+}
+
+#[tokio::test]
+async fn verifies_declared_digests() {
+    let content = content(20_000);
+    let (_server, port, uuid) = start(content.len());
+
+    // the digest of a different body, nothing of this request is kept
+    let digest = format!("Repr-Digest: sha-256=:{}:\r\n", sha256(b"something else"));
+    let response = upload(port, uuid, &digest, &content).await;
+    assert!(response.starts_with("HTTP/1.1 460"), "{}", response);
+    let problem = json_body(&response);
+    assert_eq!(problem["code"], "checksum_mismatch");
+    assert_eq!(problem["field"], "Repr-Digest");
+    assert_eq!(problem["offset"], 0);
+
+    // the strongest digest of the dictionary is the one checked
+    let digest = format!(
+        "Content-Digest: sha-256=:{}:, sha-512=:{}:\r\n",
+        sha256(b"ignored"),
+        STANDARD.encode(Sha512::digest(&content))
+    );
+    let response = upload(port, uuid, &digest, &content).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    assert_eq!(body(&download(port, uuid).await).as_bytes(), &content[..]);
+}
+
+#[tokio::test]
+async fn mismatched_pieces_roll_back_to_their_offset() {
+    let content = content(10_000);
+    let (_server, port, uuid) = start(content.len());
+
+    let digest = format!("Upload-Checksum: sha256 {}\r\n", sha256(&content[..4_000]));
+    let response = upload(port, uuid, &digest, &content[..4_000]).await;
+    assert_eq!(json_body(&response)["offset"], 4_000, "{}", response);
+
+    // a corrupted remainder is refused, the upload stays resumable where the piece started
+    let mut corrupted = content[4_000..].to_vec();
+    corrupted[1_234] ^= 0xff;
+    let digest = format!("Upload-Checksum: sha256 {}\r\n", sha256(&content[4_000..]));
+    let response = resume(port, uuid, 4_000, &digest, &corrupted).await;
+    assert!(response.starts_with("HTTP/1.1 460"), "{}", response);
+    assert_eq!(json_body(&response)["offset"], 4_000);
+
+    let response = resume(port, uuid, 4_000, &digest, &content[4_000..]).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    assert_eq!(body(&download(port, uuid).await).as_bytes(), &content[..]);
+}
+
+#[tokio::test]
+async fn rejects_unsupported_algorithms() {
+    let (_server, port, uuid) = start(1_000);
+
+    let response = upload(port, uuid, "Content-Digest: md5=:1B2M2Y8AsgTpgAmY7PhCfg==:\r\n", &content(1_000)).await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    assert_eq!(json_body(&response)["code"], "checksum_unsupported");
+
+    let response = upload(port, uuid, "Upload-Checksum: sha-256\r\n", &content(1_000)).await;
+    assert!(response.starts_with("HTTP/1.1 422"), "{}", response);
+}
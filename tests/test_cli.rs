@@ -0,0 +1,124 @@
+mod common;
+
+use std::{path::Path, process::{Output, Stdio}};
+
+use common::{content, free_port, lofty, scratch_dir, spawn_server, tcp, Server};
+
+
+async fn start() -> (Server, String) {
+    let dir = scratch_dir("cli");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+
+    let port = free_port();
+    let settings = format!("[server]\nbind = \"127.0.0.1:{}\"\n\n[store]\ndedup = true\n", port);
+    let server = spawn_server(dir, &settings);
+    drop(tcp(port).await);
+    (server, format!("http://127.0.0.1:{}", port))
+}
+
+// run a client or maintenance command in `dir` and wait for it
+async fn run(dir: &Path, url: &str, args: &[&str]) -> Output {
+    let mut command = lofty(dir);
+    command.args(args).env("LOFTY_URL", url).stdout(Stdio::piped()).stderr(Stdio::piped());
+    tokio::task::spawn_blocking(move || command.output().unwrap()).await.unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+#[tokio::test]
+async fn moves_files_through_the_server() {
+    let (server, url) = start().await;
+    let dir = &server.dir;
+    std::fs::write(dir.join("one.txt"), content(10_000)).unwrap();
+    std::fs::write(dir.join("two.txt"), content(25_000)).unwrap();
+
+    let uploaded = stdout(&run(dir, &url, &["upload", "-q", "--chunk-size", "4096", "--parallel", "2", "one.txt", "two.txt"]).await);
+    let uuids: Vec<&str> = uploaded.lines().map(|line| line.split('\t').next().unwrap()).collect();
+    assert_eq!(uuids.len(), 2, "{}", uploaded);
+    assert!(uploaded.lines().nth(1).unwrap().ends_with("two.txt"));
+
+    assert_eq!(stdout(&run(dir, &url, &["status", uuids[0]]).await).trim(), "complete");
+
+    // a partial copy is continued, not fetched again
+    std::fs::write(dir.join("copy.txt"), &content(25_000)[..9_000]).unwrap();
+    let downloaded = stdout(&run(dir, &url, &["download", "-q", uuids[1], "-o", "copy.txt"]).await);
+    assert!(downloaded.contains("25000 bytes"), "{}", downloaded);
+    assert_eq!(std::fs::read(dir.join("copy.txt")).unwrap(), content(25_000));
+
+    let listed: serde_json::Value = serde_json::from_str(&stdout(&run(dir, &url, &["ls", "--json", "--state", "complete"]).await)).unwrap();
+    assert_eq!(listed.as_array().unwrap().len(), 2);
+    let table = stdout(&run(dir, &url, &["ls", "--prefix", "one"]).await);
+    assert_eq!(table.lines().count(), 2, "{}", table);
+    assert!(table.contains(uuids[0]));
+
+    assert_eq!(stdout(&run(dir, &url, &["rm", uuids[0]]).await).trim(), format!("removed {}", uuids[0]));
+    let status = run(dir, &url, &["rm", uuids[0]]).await;
+    assert!(!status.status.success());
+    assert!(String::from_utf8_lossy(&status.stderr).contains(uuids[0]));
+}
+
+#[tokio::test]
+async fn resumes_a_scheduled_upload() {
+    let (server, url) = start().await;
+    let dir = &server.dir;
+    std::fs::write(dir.join("lines.txt"), content(10_000)).unwrap();
+
+    let client = lofty_client::Client::new(&url).unwrap();
+    let scheduled = client.schedule(&"0".repeat(64), 10_000).await.unwrap();
+    let uuid = scheduled.uuid.to_string();
+
+    let uploaded = stdout(&run(dir, &url, &["upload", "-q", "--resume", &uuid, "lines.txt"]).await);
+    assert!(uploaded.starts_with(&uuid), "{}", uploaded);
+    assert_eq!(stdout(&run(dir, &url, &["status", &uuid]).await).trim(), "complete");
+
+    let refused = run(dir, &url, &["upload", "--resume", &uuid, "lines.txt", "lines.txt"]).await;
+    assert!(!refused.status.success());
+}
+
+#[tokio::test]
+async fn maintenance_repairs_a_stopped_server() {
+    let (mut server, url) = start().await;
+    let dir = server.dir.clone();
+    std::fs::write(dir.join("kept.txt"), content(10_000)).unwrap();
+    std::fs::write(dir.join("lost.txt"), content(12_000)).unwrap();
+
+    let uploaded = stdout(&run(&dir, &url, &["upload", "-q", "kept.txt", "lost.txt"]).await);
+    let uuids: Vec<String> = uploaded.lines().map(|line| line.split('\t').next().unwrap().to_string()).collect();
+
+    // the running server holds the data directory, its registry would overwrite the repairs
+    for command in [&["gc", "--dry-run"][..], &["reconcile", "--dry-run"], &["rotate-keys"]] {
+        let refused = run(&dir, &url, command).await;
+        let logged = String::from_utf8_lossy(&refused.stdout).to_string() + &String::from_utf8_lossy(&refused.stderr);
+        assert!(!refused.status.success() && logged.contains("held by another lofty process"), "{:?}: {}", command, logged);
+    }
+    server.stop();
+
+    // the content of one upload goes missing, a stray file shows up next to the rest
+    std::fs::remove_file(dir.join("data").join(&uuids[1])).unwrap();
+    let stray = dir.join("data").join(uuid::Uuid::new_v4().to_string());
+    std::fs::write(&stray, b"left over").unwrap();
+
+    let dry_run = stdout(&run(&dir, &url, &["reconcile", "--verify", "--dry-run"]).await);
+    assert!(dry_run.contains(&format!("would mark {} failed", uuids[1])), "{}", dry_run);
+    let reconciled = stdout(&run(&dir, &url, &["reconcile", "--verify"]).await);
+    assert!(reconciled.contains(&format!("mark {} failed, its file is missing", uuids[1])), "{}", reconciled);
+
+    let collected = stdout(&run(&dir, &url, &["gc", "--failed"]).await);
+    assert!(collected.contains(&format!("drop failed upload {}", uuids[1])), "{}", collected);
+    assert!(!stray.exists());
+    // the blob of the dropped upload has nothing left pointing at it
+    assert!(collected.contains("remove blob"), "{}", collected);
+
+    server.restart();
+    drop(tcp(url.rsplit(':').next().unwrap().parse().unwrap()).await);
+    let listed: serde_json::Value = serde_json::from_str(&stdout(&run(&dir, &url, &["ls", "--json"]).await)).unwrap();
+    let listed: Vec<_> = listed.as_array().unwrap().iter().map(|file| file["uuid"].as_str().unwrap().to_string()).collect();
+    assert_eq!(listed, vec![uuids[0].clone()]);
+
+    std::fs::remove_file(dir.join("copy")).ok();
+    stdout(&run(&dir, &url, &["download", "-q", &uuids[0], "-o", "copy"]).await);
+    assert_eq!(std::fs::read(dir.join("copy")).unwrap(), content(10_000));
+}
@@ -0,0 +1,201 @@
+mod common;
+
+use std::{sync::{Arc, Mutex}, time::Duration};
+
+use common::{content, free_port, json_body, request, scratch_dir, spawn_server, tcp, Server};
+use lofty_client::{Client, Direction, RetryPolicy, Source, UploadState};
+use sha2::{Digest, Sha256};
+
+
+async fn start() -> (Server, u16) {
+    let dir = scratch_dir("client");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+
+    let port = free_port();
+    let settings = format!("[server]\nbind = \"127.0.0.1:{}\"\n\n[store]\ndedup = true\n", port);
+    let server = spawn_server(dir, &settings);
+    // waits until the server accepts connections
+    drop(tcp(port).await);
+    (server, port)
+}
+
+fn client(port: u16) -> lofty_client::ClientBuilder {
+    let retry = RetryPolicy { initial_backoff: Duration::from_millis(20), ..RetryPolicy::default() };
+    Client::builder(format!("http://127.0.0.1:{}", port)).retry(retry)
+}
+
+async fn downloaded(client: &Client, uuid: uuid::Uuid) -> Vec<u8> {
+    let dest = scratch_dir("client-download").join("copy");
+    client.download(uuid, &dest).await.unwrap();
+    std::fs::read(dest).unwrap()
+}
+
+#[tokio::test]
+async fn uploads_and_skips_known_content() {
+    let (_server, port) = start().await;
+    let client = client(port).build().unwrap();
+    let content = content(20_000);
+
+    let uuid = client.upload_file(&Source::bytes(content.clone()).with_name("lines.txt")).await.unwrap();
+    assert_eq!(client.status(uuid).await.unwrap(), UploadState::Complete);
+    assert_eq!(downloaded(&client, uuid).await, content);
+
+    let hash = hex::encode(Sha256::digest(&content));
+    let scheduled = client.schedule(&hash, content.len() as u64).await.unwrap();
+    assert!(scheduled.skipped);
+    assert_eq!(scheduled.status, "Complete");
+    assert_eq!(downloaded(&client, scheduled.uuid).await, content);
+}
+
+#[tokio::test]
+async fn chunked_uploads_report_progress() {
+    let (_server, port) = start().await;
+    let content = content(10_000);
+
+    let reported = Arc::new(Mutex::new(Vec::new()));
+    let client = client(port)
+        .chunk_size(3_000)
+        .on_progress({
+            let reported = reported.clone();
+            move |progress| reported.lock().unwrap().push(progress)
+        })
+        .build()
+        .unwrap();
+
+    let path = scratch_dir("client-source").join("lines.txt");
+    std::fs::write(&path, &content).unwrap();
+
+    let uuid = client.upload_file(&Source::path(&path)).await.unwrap();
+    assert_eq!(downloaded(&client, uuid).await, content);
+
+    let reported = reported.lock().unwrap();
+    let uploaded: Vec<_> = reported.iter().filter(|progress| progress.direction == Direction::Upload).collect();
+    assert!(uploaded.windows(2).all(|pair| pair[0].transferred <= pair[1].transferred));
+    assert!(uploaded.iter().any(|progress| progress.transferred == 3_000));
+    assert_eq!(uploaded.last().unwrap().transferred, 10_000);
+    assert!(reported.iter().all(|progress| progress.total == 10_000));
+}
+
+#[tokio::test]
+async fn resumes_where_the_server_stopped() {
+    let (_server, port) = start().await;
+    let client = client(port).build().unwrap();
+    let content = content(10_000);
+
+    let hash = hex::encode(Sha256::digest(&content));
+    let scheduled = client.schedule(&hash, content.len() as u64).await.unwrap();
+
+    // an earlier run got part of the way
+    let headers = format!("FileName: lines.txt\r\nuuid: {}\r\n", scheduled.uuid);
+    request(tcp(port).await, "GET", "/upload_file", &headers, &content[..4_500]).await;
+    assert_eq!(client.status(scheduled.uuid).await.unwrap(), UploadState::Broken(4_500));
+
+    client.resume(scheduled.uuid, &Source::bytes(content.clone())).await.unwrap();
+    assert_eq!(downloaded(&client, scheduled.uuid).await, content);
+}
+
+#[tokio::test]
+async fn pieces_are_acknowledged_with_their_offset() {
+    let (_server, port) = start().await;
+    let client = client(port).build().unwrap();
+    let content = content(10_000);
+
+    let hash = hex::encode(Sha256::digest(&content));
+    let scheduled = client.schedule(&hash, content.len() as u64).await.unwrap();
+
+    let headers = format!("FileName: lines.txt\r\nuuid: {}\r\nUpload-Complete: ?0\r\n", scheduled.uuid);
+    let response = request(tcp(port).await, "GET", "/upload_file", &headers, &content[..4_500]).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert_eq!(json_body(&response)["offset"], 4_500, "{}", response);
+
+    let headers = format!("uuid: {}\r\nContent-Pointer: 4500\r\nUpload-Complete: ?0\r\n", scheduled.uuid);
+    let response = request(tcp(port).await, "GET", "/resume_upload", &headers, &content[4_500..8_000]).await;
+    assert_eq!(json_body(&response)["offset"], 8_000, "{}", response);
+
+    let headers = format!("uuid: {}\r\nContent-Pointer: 8000\r\n", scheduled.uuid);
+    let response = request(tcp(port).await, "GET", "/resume_upload", &headers, &content[8_000..]).await;
+    assert_eq!(json_body(&response)["status"], "Complete", "{}", response);
+    assert_eq!(downloaded(&client, scheduled.uuid).await, content);
+}
+
+#[tokio::test]
+async fn stages_pieces_sent_ahead_of_the_offset() {
+    let (_server, port) = start().await;
+    let client = client(port).build().unwrap();
+    let content = content(9_000);
+
+    let hash = hex::encode(Sha256::digest(&content));
+    let scheduled = client.schedule(&hash, content.len() as u64).await.unwrap();
+
+    let headers = format!("FileName: lines.txt\r\nuuid: {}\r\nUpload-Complete: ?0\r\n", scheduled.uuid);
+    request(tcp(port).await, "GET", "/upload_file", &headers, &content[..3_000]).await;
+
+    // the last piece arrives first and waits for the one before it
+    let headers = format!("uuid: {}\r\nContent-Pointer: 6000\r\n", scheduled.uuid);
+    let response = request(tcp(port).await, "PUT", "/upload_piece", &headers, &content[6_000..]).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert_eq!(json_body(&response)["offset"], 3_000, "{}", response);
+
+    let headers = format!("uuid: {}\r\nContent-Pointer: 3000\r\n", scheduled.uuid);
+    let response = request(tcp(port).await, "PUT", "/upload_piece", &headers, &content[3_000..6_000]).await;
+    assert_eq!(json_body(&response)["status"], "Complete", "{}", response);
+    assert_eq!(downloaded(&client, scheduled.uuid).await, content);
+
+    // the first piece names the upload, it never goes through the staging route
+    let headers = format!("uuid: {}\r\nContent-Pointer: 0\r\n", scheduled.uuid);
+    let response = request(tcp(port).await, "PUT", "/upload_piece", &headers, &content[..3_000]).await;
+    assert!(response.starts_with("HTTP/1.1 422"), "{}", response);
+}
+
+#[tokio::test]
+async fn uploads_pieces_of_one_file_in_parallel() {
+    let (_server, port) = start().await;
+    let client = client(port).chunk_size(1_000).parallel_pieces(4).build().unwrap();
+    let content = content(20_500);
+
+    let uuid = client.upload_file(&Source::bytes(content.clone())).await.unwrap();
+    assert_eq!(client.status(uuid).await.unwrap(), UploadState::Complete);
+    assert_eq!(downloaded(&client, uuid).await, content);
+}
+
+#[tokio::test]
+async fn uploads_files_in_parallel() {
+    let (_server, port) = start().await;
+    let client = client(port).build().unwrap();
+
+    let contents: Vec<_> = (1..=6).map(|i| content(i * 3_000)).collect();
+    let sources = contents.iter().map(|content| Source::bytes(content.clone())).collect();
+
+    let uuids = client.upload_files(sources, 3).await;
+    for (uuid, content) in uuids.into_iter().zip(&contents) {
+        assert_eq!(&downloaded(&client, uuid.unwrap()).await, content);
+    }
+}
+
+#[tokio::test]
+async fn downloads_continue_a_partial_file() {
+    let (_server, port) = start().await;
+    let client = client(port).build().unwrap();
+    let content = content(10_000);
+
+    let uuid = client.upload_file(&Source::bytes(content.clone())).await.unwrap();
+
+    let dest = scratch_dir("client-download").join("copy");
+    std::fs::write(&dest, &content[..6_000]).unwrap();
+    assert_eq!(client.download(uuid, &dest).await.unwrap(), 10_000);
+    assert_eq!(std::fs::read(&dest).unwrap(), content);
+
+    // already complete, nothing left to fetch
+    assert_eq!(client.download(uuid, &dest).await.unwrap(), 10_000);
+    assert_eq!(std::fs::read(&dest).unwrap(), content);
+}
+
+#[tokio::test]
+async fn unknown_uploads_fail_without_retrying() {
+    let (_server, port) = start().await;
+    let client = client(port).build().unwrap();
+
+    let error = client.upload(uuid::Uuid::new_v4(), &Source::bytes(content(100))).await.unwrap_err();
+    assert!(!error.is_retryable(), "{}", error);
+    assert!(error.code().is_some(), "{}", error);
+}
@@ -0,0 +1,90 @@
+mod common;
+
+use common::{content, free_port, get, json_body, request, scratch_dir, seed_pending, spawn_server, tcp, Server};
+use serde_json::json;
+
+
+fn start(size: usize) -> (Server, u16, uuid::Uuid) {
+    let dir = scratch_dir("compression");
+    let uuid = seed_pending(&dir, "log.txt", size);
+
+    let port = free_port();
+    let settings = format!(
+        "[server]\nbind = \"127.0.0.1:{}\"\n\n[compression]\nenabled = true\nblock_size = 4096\ncontent_types = [\"text/\"]\n",
+        port
+    );
+    (spawn_server(dir, &settings), port, uuid)
+}
+
+async fn upload(port: u16, uuid: uuid::Uuid, content: &[u8]) -> String {
+    let headers = format!("FileName: log.txt\r\nuuid: {}\r\nContent-Type: text/plain\r\n", uuid);
+    request(tcp(port).await, "GET", "/upload_file", &headers, content).await
+}
+
+fn body(response: &str) -> &str {
+    response.split_once("\r\n\r\n").unwrap().1
+}
+
+async fn listed(port: u16, uuid: uuid::Uuid) -> serde_json::Value {
+    let response = get(tcp(port).await, "/files").await;
+    json_body(&response)["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|file| file["uuid"] == json!(uuid))
+        .cloned()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn compressed_uploads_serve_ranges() {
+    let content = content(20_000);
+    let (server, port, uuid) = start(content.len());
+
+    let response = upload(port, uuid, &content).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let file = listed(port, uuid).await;
+    assert_eq!(file["compression"], "zstd");
+    assert_eq!(file["file_size"], content.len());
+    let stored = std::fs::metadata(server.dir.join("data").join(uuid.to_string())).unwrap().len();
+    assert_eq!(file["stored_size"], stored);
+    assert!((stored as usize) < content.len(), "stored {} bytes of {}", stored, content.len());
+
+    let path = format!("/files/{}/content", uuid);
+    let response = get(tcp(port).await, &path).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert_eq!(body(&response).as_bytes(), &content[..]);
+
+    // spans the boundary between the first two blocks
+    let headers = "Range: bytes=4000-4199\r\n";
+    let response = request(tcp(port).await, "GET", &path, headers, b"").await;
+    assert!(response.starts_with("HTTP/1.1 206"), "{}", response);
+    assert!(response.to_ascii_lowercase().contains("content-range: bytes 4000-4199/20000"), "{}", response);
+    assert_eq!(body(&response).as_bytes(), &content[4000..4200]);
+
+    let response = request(tcp(port).await, "GET", &path, "Range: bytes=-100\r\n", b"").await;
+    assert_eq!(body(&response).as_bytes(), &content[19_900..]);
+
+    let response = request(tcp(port).await, "GET", &path, "Range: bytes=20000-\r\n", b"").await;
+    assert!(response.starts_with("HTTP/1.1 416"), "{}", response);
+}
+
+#[tokio::test]
+async fn compressed_uploads_resume_mid_block() {
+    let content = content(10_000);
+    let (_server, port, uuid) = start(content.len());
+
+    // the stream ends early, the upload is left resumable at what reached the disk
+    let response = upload(port, uuid, &content[..6_000]).await;
+    assert!(!response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert_eq!(listed(port, uuid).await["state"], json!({ "Broken": 6_000 }));
+
+    // resuming inside the second block rewrites it from the pointer on
+    let headers = format!("uuid: {}\r\nContent-Pointer: 5000\r\n", uuid);
+    let response = request(tcp(port).await, "GET", "/resume_upload", &headers, &content[5_000..]).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let response = get(tcp(port).await, &format!("/files/{}/content", uuid)).await;
+    assert_eq!(body(&response).as_bytes(), &content[..]);
+}
@@ -0,0 +1,167 @@
+mod common;
+
+use std::{net::SocketAddr, time::Duration};
+
+use axum::{async_trait, http::request::Parts};
+use common::{request_bytes, scratch_dir, start, tcp};
+use lofty::{AuthProvider, ErrorStates, LoadConfig, LocalStorage, LoftyServer, Principal};
+use lofty_client::{Client, ListQuery, Source, UploadState};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+
+fn png() -> Vec<u8> {
+    let mut content = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+    content.extend((0..4000u32).map(|i| (i % 251) as u8));
+    content
+}
+
+fn elf(len: usize) -> Vec<u8> {
+    let mut content = b"\x7fELF\x02\x01\x01\0".to_vec();
+    content.resize(len, 0x90);
+    content
+}
+
+// a single member archive, the magic sits past the first block of a stream
+fn tar() -> Vec<u8> {
+    let mut header = vec![0u8; 512];
+    header[..9].copy_from_slice(b"notes.txt");
+    header[257..263].copy_from_slice(b"ustar\0");
+    header.extend(vec![b'a'; 512]);
+    header.extend(vec![0u8; 1024]);
+    header
+}
+
+#[tokio::test]
+async fn detects_the_type_of_uploads_and_serves_them_as_it() {
+    let (_server, client, port) = start("content-types", "[compression]\ncontent_types = [\"application/x-tar\"]\nenabled = true\n").await;
+
+    let png = client.upload_file(&Source::bytes(png()).with_name("picture.bin")).await.unwrap();
+    let tar = client.upload_file(&Source::bytes(tar()).with_name("archive").with_content_type("application/x-tar")).await.unwrap();
+    let gzip = client.upload_file(&Source::bytes(b"\x1f\x8b\x08\0\0\0\0\0\0\x03".to_vec())).await.unwrap();
+    let json = client.upload_file(&Source::bytes(b"{\"declared\": true}\n".to_vec()).with_content_type("application/json")).await.unwrap();
+
+    let files = client.list_all(&ListQuery::default()).await.unwrap();
+    let detected = |uuid| files.iter().find(|file| file.uuid == uuid).unwrap().detected_type.clone().unwrap();
+    assert_eq!(detected(png), "image/png");
+    assert_eq!(detected(tar), "application/x-tar");
+    assert_eq!(detected(gzip), "application/gzip");
+    assert_eq!(detected(json), "text/plain");
+
+    // the name says nothing, the content does
+    let (head, body) = request_bytes(tcp(port).await, "GET", &format!("/files/{}/content", png), "", b"").await;
+    assert!(head.to_ascii_lowercase().contains("content-type: image/png"), "{}", head);
+    assert_eq!(body, self::png());
+
+    // compressed uploads are served from their blocks with the detected type as well
+    let (head, body) = request_bytes(tcp(port).await, "GET", &format!("/files/{}/content", tar), "", b"").await;
+    assert!(head.to_ascii_lowercase().contains("content-type: application/x-tar"), "{}", head);
+    assert_eq!(body, self::tar());
+
+    // generic text keeps the more precise declared type
+    let (head, _) = request_bytes(tcp(port).await, "GET", &format!("/files/{}/content", json), "", b"").await;
+    assert!(head.to_ascii_lowercase().contains("content-type: application/json"), "{}", head);
+}
+
+#[tokio::test]
+async fn refuses_denied_types_before_writing_them() {
+    let (server, client, _) = start("content-types", "[content]\ndeny = [\"application/x-executable\"]\n").await;
+
+    let source = Source::bytes(elf(4 * 1024 * 1024)).with_name("tool");
+    let scheduled = client.schedule(&source.sha256().await.unwrap(), source.len().await.unwrap()).await.unwrap();
+    let error = client.upload(scheduled.uuid, &source).await.unwrap_err();
+    assert_eq!(error.code(), Some("content_type_not_allowed"), "{}", error);
+
+    assert_eq!(client.status(scheduled.uuid).await.unwrap(), UploadState::Failed);
+    let files = client.list_all(&ListQuery::default()).await.unwrap();
+    assert_eq!(files[0].detected_type.as_deref(), Some("application/x-executable"));
+    assert_eq!(files[0].rejection.as_deref(), Some("uploads of type application/x-executable are not accepted"));
+
+    // nothing of the refused stream reached the disk
+    let stored = std::fs::metadata(server.dir.join("data").join(scheduled.uuid.to_string())).map(|meta| meta.len()).unwrap_or(0);
+    assert_eq!(stored, 0);
+
+    // other types are not affected
+    let text = client.upload_file(&Source::bytes(b"plain notes\n".to_vec())).await.unwrap();
+    assert_eq!(client.status(text).await.unwrap(), UploadState::Complete);
+}
+
+#[tokio::test]
+async fn removes_what_reached_the_disk_before_the_type_was_settled() {
+    let (server, client, port) = start("content-types", "[content]\ndeny = [\"application/x-tar\"]\n").await;
+
+    let content = tar();
+    let source = Source::bytes(content.clone()).with_name("archive");
+    let scheduled = client.schedule(&source.sha256().await.unwrap(), source.len().await.unwrap()).await.unwrap();
+
+    // the first chunk ends before the magic, it is written before the gate decides
+    let mut stream = tcp(port).await;
+    let head = format!(
+        "GET /upload_file HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\nFileName: archive\r\nuuid: {}\r\n\r\n",
+        content.len(),
+        scheduled.uuid
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(&content[..200]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    stream.write_all(&content[200..]).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.contains("content_type_not_allowed"), "{}", response);
+
+    assert_eq!(client.status(scheduled.uuid).await.unwrap(), UploadState::Failed);
+    assert!(!server.dir.join("data").join(scheduled.uuid.to_string()).exists());
+}
+
+// callers name themselves in `x-session`
+struct SessionAuth;
+
+#[async_trait]
+impl AuthProvider for SessionAuth {
+    async fn authenticate(&self, parts: &Parts) -> Result<Option<Principal>, ErrorStates> {
+        match parts.headers.get("x-session").and_then(|value| value.to_str().ok()) {
+            Some(session) => Ok(Some(Principal::new(session))),
+            None => Err(ErrorStates::Unauthorized),
+        }
+    }
+}
+
+#[tokio::test]
+async fn applies_the_policy_of_each_tenant_to_linked_content_too() {
+    let dir = scratch_dir("content-types-tenants");
+    let settings = format!(
+        "[audit]\ndir = \"{}\"\n\n[store]\ndedup = true\ndir = \"{}\"\nshare_across_tenants = true\n\n[content.tenants.partner]\nallow = [\"image/\"]\n",
+        dir.join("audit").display(),
+        dir.join("data/blobs").display(),
+    );
+    let server = LoftyServer::builder()
+        .config(LoadConfig::from_toml(&settings).unwrap())
+        .storage(LocalStorage::new(dir.join("data")))
+        .auth(SessionAuth)
+        .build()
+        .await
+        .unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let app = server.router();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    });
+    let url = format!("http://127.0.0.1:{}", port);
+    let internal = Client::builder(&url).header("x-session", "internal").build().unwrap();
+    let partner = Client::builder(&url).header("x-session", "partner").build().unwrap();
+
+    // tenants without lists of their own accept anything
+    let source = Source::bytes(elf(64 * 1024));
+    internal.upload_file(&source).await.unwrap();
+
+    let error = partner.upload_file(&Source::bytes(b"just text\n".to_vec())).await.unwrap_err();
+    assert_eq!(error.code(), Some("content_type_not_allowed"), "{}", error);
+    partner.upload_file(&Source::bytes(png())).await.unwrap();
+
+    // knowing the digest of stored content does not get around the policy
+    let error = partner.schedule(&source.sha256().await.unwrap(), source.len().await.unwrap()).await.unwrap_err();
+    assert_eq!(error.code(), Some("content_type_not_allowed"), "{}", error);
+    let held = partner.list_all(&ListQuery::default()).await.unwrap();
+    assert_eq!(held.iter().filter(|file| file.tenant.as_deref() == Some("partner")).count(), 2);
+}
@@ -0,0 +1,217 @@
+mod common;
+
+use std::{io::Write, net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+
+use axum::{async_trait, http::request::Parts, routing, Router};
+use common::{content, get, scratch_dir, tcp};
+use lofty::{AuthProvider, CompletedUpload, ErrorStates, HookError, LoadConfig, LocalStorage, LoftyServer, PostUploadHook, Principal, Verdict};
+use lofty_client::{Client, Error, ListQuery, Source, UploadState};
+
+
+// callers name themselves in `x-session`, requests without one are turned away
+struct SessionAuth;
+
+#[async_trait]
+impl AuthProvider for SessionAuth {
+    async fn authenticate(&self, parts: &Parts) -> Result<Option<Principal>, ErrorStates> {
+        match parts.headers.get("x-session").and_then(|value| value.to_str().ok()) {
+            Some(session) => Ok(Some(Principal::new(session))),
+            None => Err(ErrorStates::Unauthorized),
+        }
+    }
+}
+
+// log output of the test, json lines as the subscriber writes them
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<CompletedUpload>>>);
+
+#[async_trait]
+impl PostUploadHook for Recorder {
+    fn name(&self) -> &str {
+        "recorder"
+    }
+
+    async fn run(&self, upload: &mut CompletedUpload) -> Result<Verdict, HookError> {
+        self.0.lock().unwrap().push(upload.clone());
+        upload.tags.insert("recorded".to_string());
+        Ok(Verdict::Accept)
+    }
+}
+
+// the lofty routes nested under `/lofty` of a host application
+async fn start(recorder: Recorder) -> (LoftyServer, u16) {
+    let dir = scratch_dir("embed");
+    let settings = format!("[audit]\ndir = \"{}\"\n", dir.join("audit").display());
+
+    let server = LoftyServer::builder()
+        .config(LoadConfig::from_toml(&settings).unwrap())
+        .storage(LocalStorage::new(dir.join("data")))
+        .auth(SessionAuth)
+        .hook(recorder)
+        .build()
+        .await
+        .unwrap();
+
+    let app = Router::new()
+        .route("/health", routing::get(|| async { "ok" }))
+        .nest("/lofty", server.router());
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    });
+
+    (server, port)
+}
+
+#[tokio::test]
+async fn serves_uploads_nested_in_another_router() {
+    let recorder = Recorder::default();
+    let (server, port) = start(recorder.clone()).await;
+    let client = Client::builder(format!("http://127.0.0.1:{}/lofty", port)).header("x-session", "alice").build().unwrap();
+    let content = content(12_000);
+
+    let uuid = client.upload_file(&Source::bytes(content.clone()).with_name("lines.txt")).await.unwrap();
+    // hooks run detached from the request
+    for _ in 0..50 {
+        if client.status(uuid).await.unwrap() != UploadState::Processing {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(client.status(uuid).await.unwrap(), UploadState::Complete);
+    assert!(server.data_dir().join(uuid.to_string()).exists());
+
+    let dest = scratch_dir("embed-download").join("copy");
+    client.download(uuid, &dest).await.unwrap();
+    assert_eq!(std::fs::read(&dest).unwrap(), content);
+
+    // the provider resolved the caller, uploads are owned by it
+    let files = client.list_all(&ListQuery { tenant: Some("alice".to_string()), ..Default::default() }).await.unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].uuid, uuid);
+    assert!(files[0].tags.contains("recorded"));
+
+    let completed = recorder.0.lock().unwrap().clone();
+    assert_eq!(completed.len(), 1);
+    assert_eq!(completed[0].uuid, uuid);
+    assert_eq!(completed[0].name, "lines.txt");
+    assert_eq!(completed[0].size, 12_000);
+    assert_eq!(completed[0].tenant.as_deref(), Some("alice"));
+
+    // the registry is written where the storage keeps its uploads
+    server.persist().await.unwrap();
+    assert!(server.data_dir().join("registry.json").exists());
+}
+
+#[tokio::test]
+async fn rejects_callers_the_provider_does_not_know() {
+    let (_server, port) = start(Recorder::default()).await;
+    let client = Client::new(format!("http://127.0.0.1:{}/lofty", port)).unwrap();
+
+    match client.schedule(&"0".repeat(64), 100).await.unwrap_err() {
+        Error::Status { status, .. } => assert_eq!(status, 401),
+        e => panic!("unexpected error {}", e),
+    }
+
+    // routes of the host application are left alone
+    let health = get(tcp(port).await, "/health").await;
+    assert!(health.starts_with("HTTP/1.1 200"), "{}", health);
+}
+
+#[tokio::test]
+async fn keeps_uploads_to_the_tenant_that_made_them() {
+    let (server, port) = start(Recorder::default()).await;
+    let url = format!("http://127.0.0.1:{}/lofty", port);
+    let alice = Client::builder(&url).header("x-session", "alice").build().unwrap();
+    let bob = Client::builder(&url).header("x-session", "bob").build().unwrap();
+
+    let uuid = alice.upload_file(&Source::bytes(content(6_000)).with_name("private.txt")).await.unwrap();
+
+    // to anyone but its owner the upload does not exist
+    let error = bob.delete(uuid).await.unwrap_err();
+    assert_eq!(error.code(), Some("upload_not_found"), "{}", error);
+    assert!(server.data_dir().join(uuid.to_string()).exists());
+    let dest = scratch_dir("embed-tenants").join("copy");
+    let error = bob.download(uuid, &dest).await.unwrap_err();
+    assert_eq!(error.code(), Some("upload_not_found"), "{}", error);
+    alice.download(uuid, &dest).await.unwrap();
+    let session = |name: &str| format!("x-session: {}\r\n", name);
+    let response = common::request(tcp(port).await, "GET", &format!("/lofty/files/{}/manifest", uuid), &session("bob"), b"").await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    let response = common::request(tcp(port).await, "GET", &format!("/lofty/files/{}/manifest", uuid), &session("alice"), b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    // nor does it go into an archive of anyone else, named or matched by a filter
+    let bundle = |body: serde_json::Value, name: &str| {
+        let headers = format!("{}Content-Type: application/json\r\n", session(name));
+        async move { common::request_bytes(tcp(port).await, "POST", "/lofty/bundles", &headers, body.to_string().as_bytes()).await }
+    };
+    let (head, _) = bundle(serde_json::json!({ "uuids": [uuid] }), "bob").await;
+    assert!(head.starts_with("HTTP/1.1 404"), "{}", head);
+    let (head, body) = bundle(serde_json::json!({ "filter": { "tenant": "alice" } }), "bob").await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert_eq!(zip::ZipArchive::new(std::io::Cursor::new(body)).unwrap().len(), 0);
+    let (head, body) = bundle(serde_json::json!({ "filter": { "tenant": "alice" } }), "alice").await;
+    assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    assert_eq!(zip::ZipArchive::new(std::io::Cursor::new(body)).unwrap().len(), 1);
+
+    alice.delete(uuid).await.unwrap();
+    assert!(!server.data_dir().join(uuid.to_string()).exists());
+}
+
+// the host application and lofty share a single thread, nothing on the upload path may block it
+#[tokio::test(flavor = "current_thread")]
+async fn runs_on_a_current_thread_runtime() {
+    let captured = Captured::default();
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .with_current_span(true)
+        .with_writer({
+            let captured = captured.clone();
+            move || captured.clone()
+        })
+        .finish();
+    // every task runs on this thread, the default subscriber sees all of them
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let recorder = Recorder::default();
+    let (_server, port) = start(recorder.clone()).await;
+    let client = Client::builder(format!("http://127.0.0.1:{}/lofty", port)).header("x-session", "bob").chunk_size(4_000).build().unwrap();
+
+    let sources = (1..=3).map(|i| Source::bytes(content(i * 5_000))).collect();
+    let uploaded = tokio::time::timeout(Duration::from_secs(30), client.upload_files(sources, 3)).await.expect("the uploads stalled the runtime");
+    assert!(uploaded.iter().all(Result::is_ok), "{:?}", uploaded);
+    for _ in 0..50 {
+        if recorder.0.lock().unwrap().len() == 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(recorder.0.lock().unwrap().len(), 3);
+
+    // the request span carries the caller the provider resolved, not the one of the connection
+    let logged = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    let finished: Vec<serde_json::Value> = logged
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .filter(|line: &serde_json::Value| line["fields"]["message"] == "request finished")
+        .collect();
+    assert!(!finished.is_empty(), "{}", logged);
+    assert!(finished.iter().all(|line| line["span"]["principal"] == "bob"), "{:?}", finished);
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use std::{collections::HashMap, io::{Read, Write}};
+
+use common::{content, free_port, json_body, request, request_bytes, scratch_dir, seed_pending, spawn_server, tcp, Server};
+
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn gunzip(data: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::new();
+    flate2::read::GzDecoder::new(data).read_to_end(&mut decoded).unwrap();
+    decoded
+}
+
+fn brotli(data: &[u8]) -> Vec<u8> {
+    let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+    encoder.write_all(data).unwrap();
+    encoder.into_inner()
+}
+
+fn start(files: &[(&'static str, usize)], settings: &str) -> (Server, u16, HashMap<&'static str, uuid::Uuid>) {
+    let dir = scratch_dir("encoding");
+    let uuids = files.iter().map(|(name, size)| (*name, seed_pending(&dir, name, *size))).collect();
+
+    let port = free_port();
+    let server = spawn_server(dir, &format!("[server]\nbind = \"127.0.0.1:{}\"\n\n{}", port, settings));
+    (server, port, uuids)
+}
+
+async fn upload(port: u16, uuid: uuid::Uuid, headers: &str, body: &[u8]) -> String {
+    let headers = format!("FileName: upload\r\nuuid: {}\r\n{}", uuid, headers);
+    request(tcp(port).await, "GET", "/upload_file", &headers, body).await
+}
+
+async fn download(port: u16, uuid: uuid::Uuid, headers: &str) -> (String, Vec<u8>) {
+    request_bytes(tcp(port).await, "GET", &format!("/files/{}/content", uuid), headers, b"").await
+}
+
+#[tokio::test]
+async fn decodes_compressed_upload_bodies() {
+    let content = content(50_000);
+    let files = [("gzip", content.len()), ("zstd", content.len()), ("br", content.len())];
+    let (_server, port, uuids) = start(&files, "");
+
+    let bodies = [
+        ("gzip", gzip(&content)),
+        ("zstd", zstd::encode_all(&content[..], 3).unwrap()),
+        ("br", brotli(&content)),
+    ];
+    for (coding, body) in bodies {
+        assert!(body.len() < content.len());
+        let response = upload(port, uuids[coding], &format!("Content-Encoding: {}\r\n", coding), &body).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}: {}", coding, response);
+
+        let (head, downloaded) = download(port, uuids[coding], "").await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert_eq!(downloaded, content, "{}", coding);
+    }
+}
+
+#[tokio::test]
+async fn rejects_oversized_and_unknown_encodings() {
+    let (_server, port, uuids) = start(&[("bomb", 1_000), ("unknown", 1_000)], "");
+
+    // a few kilobytes on the wire, a megabyte once decoded
+    let bomb = gzip(&vec![0u8; 1024 * 1024]);
+    let response = upload(port, uuids["bomb"], "Content-Encoding: gzip\r\n", &bomb).await;
+    assert_eq!(json_body(&response)["code"], "upload_size_exceeded", "{}", response);
+
+    let response = upload(port, uuids["unknown"], "Content-Encoding: compress\r\n", b"whatever").await;
+    assert!(response.starts_with("HTTP/1.1 415"), "{}", response);
+}
+
+#[tokio::test]
+async fn resumes_with_an_encoded_remainder() {
+    let content = content(10_000);
+    let (_server, port, uuids) = start(&[("resumed", content.len())], "");
+    let uuid = uuids["resumed"];
+
+    let response = upload(port, uuid, "", &content[..4_000]).await;
+    assert!(!response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    // Content-Length counts the compressed remainder, not the 6000 bytes it decodes to
+    let headers = format!("uuid: {}\r\nContent-Pointer: 4000\r\nContent-Encoding: gzip\r\n", uuid);
+    let response = request(tcp(port).await, "GET", "/resume_upload", &headers, &gzip(&content[4_000..])).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    assert_eq!(download(port, uuid, "").await.1, content);
+}
+
+#[tokio::test]
+async fn encodes_downloads_the_client_accepts() {
+    let content = content(50_000);
+    let settings = "[compression]\nenabled = true\ncontent_types = [\"text/\"]\n";
+    let (_server, port, uuids) = start(&[("plain", content.len()), ("stored", content.len())], settings);
+
+    let response = upload(port, uuids["plain"], "Content-Type: application/octet-stream\r\n", &content).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let response = upload(port, uuids["stored"], "Content-Type: text/plain\r\n", &content).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let (head, body) = download(port, uuids["plain"], "Accept-Encoding: gzip, br;q=0\r\n").await;
+    assert!(head.to_ascii_lowercase().contains("content-encoding: gzip"), "{}", head);
+    assert_eq!(gunzip(&body), content);
+
+    // compressed at rest, the stored blocks go out as they are
+    let (head, body) = download(port, uuids["stored"], "Accept-Encoding: zstd\r\n").await;
+    assert!(head.to_ascii_lowercase().contains("content-encoding: zstd"), "{}", head);
+    assert_eq!(zstd::decode_all(&body[..]).unwrap(), content);
+
+    // ranges are always served as identity
+    let (head, body) = download(port, uuids["plain"], "Accept-Encoding: gzip\r\nRange: bytes=100-199\r\n").await;
+    assert!(head.starts_with("HTTP/1.1 206"), "{}", head);
+    assert!(!head.to_ascii_lowercase().contains("content-encoding"), "{}", head);
+    assert_eq!(body, &content[100..200]);
+}
@@ -0,0 +1,130 @@
+mod common;
+
+use std::path::Path;
+
+use common::{content, free_port, get, json_body, lofty, request, scratch_dir, seed_pending, spawn_server, tcp, Server};
+use serde_json::json;
+
+
+fn start(size: usize) -> (Server, u16, uuid::Uuid) {
+    let dir = scratch_dir("encryption");
+    let uuid = seed_pending(&dir, "secrets.txt", size);
+
+    let port = free_port();
+    let settings = format!(
+        "[server]\nbind = \"127.0.0.1:{}\"\n\n[encryption]\nenabled = true\nsegment_size = 1024\n",
+        port
+    );
+    (spawn_server(dir, &settings), port, uuid)
+}
+
+async fn upload(port: u16, uuid: uuid::Uuid, content: &[u8]) -> String {
+    let headers = format!("FileName: secrets.txt\r\nuuid: {}\r\n", uuid);
+    request(tcp(port).await, "GET", "/upload_file", &headers, content).await
+}
+
+async fn download(port: u16, uuid: uuid::Uuid, headers: &str) -> String {
+    request(tcp(port).await, "GET", &format!("/files/{}/content", uuid), headers, b"").await
+}
+
+fn body(response: &str) -> &str {
+    response.split_once("\r\n\r\n").unwrap().1
+}
+
+fn key_ids(dir: &Path) -> Vec<String> {
+    std::fs::read_to_string(dir.join("keys/master.key"))
+        .unwrap()
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| line.split(' ').next().unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn encrypted_uploads_serve_ranges() {
+    let content = content(5_000);
+    let (server, port, uuid) = start(content.len());
+
+    let response = upload(port, uuid, &content).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let stored = std::fs::read(server.dir.join("data").join(uuid.to_string())).unwrap();
+    assert!(!stored.windows(11).any(|window| window == b"secret line"), "plaintext on disk");
+
+    let listed = json_body(&get(tcp(port).await, "/files").await)["files"][0].clone();
+    assert_eq!(listed["encrypted"], true);
+    assert_eq!(listed["stored_size"], stored.len());
+
+    let response = download(port, uuid, "").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert_eq!(body(&response).as_bytes(), &content[..]);
+
+    // spans three segments
+    let response = download(port, uuid, "Range: bytes=1000-3099\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 206"), "{}", response);
+    assert_eq!(body(&response).as_bytes(), &content[1000..3100]);
+}
+
+#[tokio::test]
+async fn encrypted_uploads_resume_mid_segment() {
+    let content = content(5_000);
+    let (_server, port, uuid) = start(content.len());
+
+    let response = upload(port, uuid, &content[..3_000]).await;
+    assert!(!response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let headers = format!("uuid: {}\r\nContent-Pointer: 2500\r\n", uuid);
+    let response = request(tcp(port).await, "GET", "/resume_upload", &headers, &content[2_500..]).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    assert_eq!(body(&download(port, uuid, "").await).as_bytes(), &content[..]);
+}
+
+#[tokio::test]
+async fn truncated_files_are_not_served_short() {
+    let content = content(5_000);
+    let (server, port, uuid) = start(content.len());
+
+    let response = upload(port, uuid, &content).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    // four whole segments of 1024 bytes, nonce and tag each, the last one gone
+    let file = server.dir.join("data").join(uuid.to_string());
+    let sealed = std::fs::read(&file).unwrap();
+    std::fs::write(&file, &sealed[..4 * (1024 + 28)]).unwrap();
+
+    for range in ["", "Range: bytes=0-99\r\n"] {
+        let response = download(port, uuid, range).await;
+        assert!(response.starts_with("HTTP/1.1 5"), "{}", response);
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn rotation_rewraps_without_rewriting_files() {
+    let content = content(3_000);
+    let (mut server, port, uuid) = start(content.len());
+
+    let response = upload(port, uuid, &content).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    server.stop();
+
+    let file = server.dir.join("data").join(uuid.to_string());
+    let sealed = std::fs::read(&file).unwrap();
+    let before = key_ids(&server.dir);
+    assert_eq!(before.len(), 1);
+
+    let status = lofty(&server.dir).arg("rotate-keys").status().unwrap();
+    assert!(status.success());
+
+    let after = key_ids(&server.dir);
+    assert_eq!(after.len(), 1);
+    assert_ne!(after, before);
+
+    let registry: serde_json::Value = serde_json::from_slice(&std::fs::read(server.dir.join("data/registry.json")).unwrap()).unwrap();
+    assert_eq!(registry[0]["encryption"]["key_id"], json!(after[0]));
+    assert_eq!(std::fs::read(&file).unwrap(), sealed, "file contents rewritten");
+
+    server.restart();
+    assert_eq!(body(&download(port, uuid, "").await).as_bytes(), &content[..]);
+}
@@ -0,0 +1,92 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{free_port, json_body, request, scratch_dir, spawn_server, tcp, Server};
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+
+async fn start() -> (Server, u16) {
+    let dir = scratch_dir("errors");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    let port = free_port();
+    let server = spawn_server(dir, &format!("[server]\nbind = \"127.0.0.1:{}\"\n", port));
+    drop(tcp(port).await);
+    (server, port)
+}
+
+async fn schedule(port: u16, length: usize) -> String {
+    let body = json!({ "fileHash": "a".repeat(64), "Length": length });
+    let response = request(tcp(port).await, "POST", "/schedule_upload", "", body.to_string().as_bytes()).await;
+    json_body(&response)["uuid"].as_str().unwrap().to_string()
+}
+
+// the status line, content type and problem document of an error response
+fn problem(response: &str) -> (u16, String, serde_json::Value) {
+    let status = response[9..12].parse().unwrap();
+    let content_type = response
+        .lines()
+        .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-type:").map(|value| value.trim().to_string()))
+        .unwrap_or_default();
+    (status, content_type, json_body(response))
+}
+
+#[tokio::test]
+async fn describes_failures_as_problem_documents() {
+    let (_server, port) = start().await;
+    let uuid = schedule(port, 10).await;
+
+    let cases = [
+        // a header the route needs is missing
+        ("GET", "/upload_file".to_string(), "FileName: a.bin\r\n".to_string(), b"".to_vec(), 400, "header_missing", Some("uuid")),
+        // the header is there, the upload is in no state to take it
+        ("GET", "/resume_upload".to_string(), format!("uuid: {}\r\nContent-Pointer: 4\r\n", uuid), b"rest".to_vec(), 409, "header_mismatch", Some("uuid")),
+        // well formed, but not a value the field accepts
+        ("POST", "/schedule_upload".to_string(), String::new(), json!({ "fileHash": "a", "Length": "12x" }).to_string().into_bytes(), 422, "body_value_missing", Some("FilHash or Length")),
+        ("GET", "/files?state=sideways".to_string(), String::new(), b"".to_vec(), 400, "query_invalid", Some("state")),
+        ("GET", "/files/not-a-uuid/content".to_string(), String::new(), b"".to_vec(), 400, "uuid_invalid", Some("uuid")),
+    ];
+
+    for (method, path, headers, body, status, code, field) in cases {
+        let response = request(tcp(port).await, method, &path, &headers, &body).await;
+        let (actual, content_type, problem) = problem(&response);
+        assert_eq!(actual, status, "{}", response);
+        assert_eq!(content_type, "application/problem+json");
+        assert_eq!(problem["status"], status);
+        assert_eq!(problem["code"], code);
+        assert_eq!(problem["type"], format!("urn:lofty:error:{}", code));
+        assert!(problem["title"].is_string() && problem["detail"].is_string(), "{}", problem);
+        assert_eq!(problem["field"].as_str(), field, "{}", problem);
+        assert!(problem.get("offset").is_none(), "{}", problem);
+    }
+}
+
+#[tokio::test]
+async fn reports_the_offset_a_failed_stream_can_resume_from() {
+    let (_server, port) = start().await;
+    let uuid = schedule(port, 100).await;
+
+    // sixty bytes land before the body runs past the declared size
+    let mut stream = tcp(port).await;
+    let head = format!(
+        "GET /upload_file HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: 150\r\nFileName: over.bin\r\nuuid: {}\r\n\r\n",
+        uuid
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(&[b'o'; 60]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    stream.write_all(&[b'o'; 90]).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (status, content_type, problem) = problem(&response);
+    assert_eq!(status, 413, "{}", response);
+    assert_eq!(content_type, "application/problem+json");
+    assert_eq!(problem["type"], "urn:lofty:error:upload_size_exceeded");
+    assert_eq!(problem["field"], "Content-Length");
+    assert_eq!(problem["offset"], 60);
+
+    let status = request(tcp(port).await, "GET", "/status", &format!("uuid: {}\r\n", uuid), b"").await;
+    assert_eq!(json_body(&status)["status"], json!({ "Broken": 60 }));
+}
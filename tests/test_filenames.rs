@@ -0,0 +1,100 @@
+mod common;
+
+use common::{request, request_bytes, start, tcp};
+use lofty_client::{Client, Error, FileInfo, ListQuery, Source};
+use uuid::Uuid;
+
+
+async fn upload_named(client: &Client, name: &str) -> Result<FileInfo, Error> {
+    let uuid = client.upload_file(&Source::bytes(name.as_bytes().to_vec()).with_name(name)).await?;
+    Ok(listed(client, uuid).await)
+}
+
+async fn listed(client: &Client, uuid: Uuid) -> FileInfo {
+    client.list_all(&ListQuery::default()).await.unwrap().into_iter().find(|file| file.uuid == uuid).unwrap()
+}
+
+#[tokio::test]
+async fn rewrites_unsafe_names_and_keeps_what_was_sent() {
+    let (_server, client, port) = start("filenames", "").await;
+
+    let cases = [
+        ("../../etc/passwd", ".._.._etc_passwd"),
+        ("C:\\Windows\\notes.txt", "C:_Windows_notes.txt"),
+        ("invoice\u{202E}fdp.exe", "invoicefdp.exe"),
+        ("tab\tand\nnewline.txt", "tabandnewline.txt"),
+        ("  spaced out. . ", "spaced out"),
+        ("con.txt", "_con.txt"),
+        ("LPT1", "_LPT1"),
+        ("...", "upload"),
+    ];
+    for (raw, expected) in cases {
+        let file = upload_named(&client, raw).await.unwrap();
+        assert_eq!(file.name, expected, "{:?}", raw);
+        assert_eq!(file.raw_name.as_deref(), Some(raw));
+    }
+
+    // overlong names are cut on a character boundary and keep their extension
+    let long = format!("{}.tar.gz", "é".repeat(200));
+    let file = upload_named(&client, &long).await.unwrap();
+    assert_eq!(file.name.len(), 255);
+    assert!(file.name.ends_with("é.gz"), "{}", file.name);
+
+    // decomposed input is stored composed
+    let file = upload_named(&client, "nai\u{308}ve re\u{301}sume\u{301}.txt").await.unwrap();
+    assert_eq!(file.name, "na\u{ef}ve r\u{e9}sum\u{e9}.txt");
+
+    let (head, body) = request_bytes(tcp(port).await, "GET", &format!("/files/{}/content", file.uuid), "", b"").await;
+    assert!(
+        head.contains("content-disposition: attachment; filename=\"na_ve r_sum_.txt\"; filename*=UTF-8''na%C3%AFve%20r%C3%A9sum%C3%A9.txt"),
+        "{}",
+        head
+    );
+    assert_eq!(body, "nai\u{308}ve re\u{301}sume\u{301}.txt".as_bytes());
+}
+
+#[tokio::test]
+async fn accepts_raw_utf8_and_extended_name_headers() {
+    let (_server, client, port) = start("filenames", "").await;
+
+    for (headers, expected) in [
+        ("FileName: 報告書.pdf\r\n", "報告書.pdf"),
+        ("FileName: fallback.txt\r\nFileName*: UTF-8''%E2%82%AC%20rates.txt\r\n", "€ rates.txt"),
+        ("FileName: fallback.txt\r\nFileName*: iso-8859-1'en'%A3%20rates.txt\r\n", "£ rates.txt"),
+    ] {
+        let scheduled = client.schedule(&"0".repeat(64), 4).await.unwrap();
+        let headers = format!("{}uuid: {}\r\n", headers, scheduled.uuid);
+        let response = request(tcp(port).await, "GET", "/upload_file", &headers, b"data").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert_eq!(listed(&client, scheduled.uuid).await.name, expected);
+    }
+
+    let scheduled = client.schedule(&"0".repeat(64), 4).await.unwrap();
+    let headers = format!("FileName: fallback.txt\r\nFileName*: UTF-8''%FF%FE\r\nuuid: {}\r\n", scheduled.uuid);
+    let response = request(tcp(port).await, "GET", "/upload_file", &headers, b"data").await;
+    assert!(response.starts_with("HTTP/1.1 422"), "{}", response);
+    assert!(response.contains("\"field\":\"FileName*\""), "{}", response);
+}
+
+#[tokio::test]
+async fn rejects_names_it_would_have_to_rewrite() {
+    let (_server, client, _) = start("filenames", "[filenames]\non_invalid = \"reject\"\nmax_length = 32\n").await;
+
+    for (raw, detail) in [
+        ("reports/2024.csv", "file name refused: contains a path separator"),
+        ("aux.log", "file name refused: is a reserved device name"),
+        ("trailing.", "file name refused: starts or ends with spaces or dots"),
+        ("a-name-well-beyond-the-limit-set.txt", "file name refused: is too long"),
+    ] {
+        let error = upload_named(&client, raw).await.unwrap_err();
+        assert_eq!(error.code(), Some("file_name_invalid"), "{}", error);
+        let Error::Status { problem: Some(problem), .. } = error else { panic!("no problem document for {:?}", raw) };
+        assert_eq!(problem.detail.as_deref(), Some(detail));
+        assert_eq!(problem.field.as_deref(), Some("FileName"));
+    }
+
+    // composing a name is no rewrite
+    let file = upload_named(&client, "cafe\u{301}.txt").await.unwrap();
+    assert_eq!(file.name, "caf\u{e9}.txt");
+    assert_eq!(file.raw_name.as_deref(), Some("cafe\u{301}.txt"));
+}
@@ -0,0 +1,296 @@
+mod common;
+
+use std::collections::HashMap;
+
+use common::{free_port, get, json_body, request, scratch_dir, spawn_server, tcp, Server};
+use serde_json::json;
+
+
+// the tenant uploads from this test process are recorded under
+const TEAM_A: &str = "anonymous@127.0.0.1";
+
+// a registry as a previous run would have persisted it, returns the uuid of every file by name
+fn seed_registry(dir: &std::path::Path) -> HashMap<&'static str, uuid::Uuid> {
+    let files = [
+        ("alpha.csv", 100, "Complete", "2024-01-01T00:00:00Z", TEAM_A, json!({ "project": "apollo", "source": "ci" }), json!(["signed"])),
+        ("beta.csv", 2_000, "Complete", "2024-01-02T00:00:00Z", TEAM_A, json!({ "project": "gemini" }), json!([])),
+        ("report-1.pdf", 30_000, "Complete", "2024-01-03T00:00:00Z", "team-b", json!({ "project": "apollo" }), json!(["nightly", "signed"])),
+        ("report-2.pdf", 400_000, "Failed", "2024-01-04T00:00:00Z", "team-b", json!({}), json!([])),
+        ("zeta.bin", 5_000_000, "Complete", "2024-01-05T00:00:00Z", TEAM_A, json!({ "project": "apollo", "kind": "raw" }), json!(["nightly"])),
+    ];
+
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    let mut uuids = HashMap::new();
+
+    let registry: Vec<_> = files
+        .iter()
+        .map(|(name, size, state, created_at, tenant, metadata, tags)| {
+            let uuid = uuid::Uuid::new_v4();
+            uuids.insert(*name, uuid);
+            std::fs::write(dir.join("data").join(uuid.to_string()), vec![b'x'; (*size).min(4096)]).unwrap();
+
+            json!({
+                "path": "./data",
+                "state": state,
+                "file_size": size,
+                "name": name,
+                "uuid": uuid,
+                "hash": [],
+                "created_at": created_at,
+                "tenant": tenant,
+                "metadata": metadata,
+                "tags": tags,
+            })
+        })
+        .collect();
+
+    std::fs::write(dir.join("data/registry.json"), serde_json::to_vec(&registry).unwrap()).unwrap();
+    uuids
+}
+
+fn start() -> (Server, u16, HashMap<&'static str, uuid::Uuid>) {
+    let dir = scratch_dir("files");
+    let uuids = seed_registry(&dir);
+
+    let port = free_port();
+    let settings = format!("[server]\nbind = \"127.0.0.1:{}\"\n\n[metadata]\nexpose = [\"source\", \"build_id\"]\n", port);
+    let server = spawn_server(dir, &settings);
+    (server, port, uuids)
+}
+
+// names of the files a listing returns, `/files` for the caller or `/admin/files` for every tenant
+async fn listed(port: u16, path: &str) -> Vec<String> {
+    let response = get(tcp(port).await, path).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    json_body(&response)["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| file["name"].as_str().unwrap().to_string())
+        .collect()
+}
+
+async fn names(port: u16, query: &str) -> Vec<String> {
+    listed(port, &format!("/files?{}", query)).await
+}
+
+async fn all_names(port: u16, query: &str) -> Vec<String> {
+    listed(port, &format!("/admin/files?{}", query)).await
+}
+
+#[tokio::test]
+async fn filters_and_sorts_files() {
+    let (_server, port, _) = start();
+
+    assert_eq!(all_names(port, "").await, ["alpha.csv", "beta.csv", "report-1.pdf", "report-2.pdf", "zeta.bin"]);
+    assert_eq!(all_names(port, "state=complete&sort=size&order=desc").await, ["zeta.bin", "report-1.pdf", "beta.csv", "alpha.csv"]);
+    assert_eq!(all_names(port, "name_prefix=report-&sort=name").await, ["report-1.pdf", "report-2.pdf"]);
+    assert_eq!(all_names(port, "min_size=1000&max_size=400000&sort=size").await, ["beta.csv", "report-1.pdf", "report-2.pdf"]);
+    assert_eq!(all_names(port, "created_after=2024-01-02T00:00:00Z&created_before=2024-01-03T12:00:00Z").await, ["beta.csv", "report-1.pdf"]);
+    assert_eq!(all_names(port, &format!("tenant={}&metadata=project=apollo", TEAM_A)).await, ["alpha.csv", "zeta.bin"]);
+    assert_eq!(all_names(port, "metadata=project=apollo,kind=raw").await, ["zeta.bin"]);
+    assert_eq!(all_names(port, "tags=nightly,signed").await, ["report-1.pdf"]);
+}
+
+#[tokio::test]
+async fn lists_only_the_uploads_of_the_caller() {
+    let (_server, port, _) = start();
+
+    assert_eq!(names(port, "").await, ["alpha.csv", "beta.csv", "zeta.bin"]);
+    assert_eq!(names(port, "state=complete&sort=size&order=desc").await, ["zeta.bin", "beta.csv", "alpha.csv"]);
+    assert_eq!(names(port, &format!("tenant={}&metadata=project=apollo", TEAM_A)).await, ["alpha.csv", "zeta.bin"]);
+
+    // asking for another tenant does not widen the listing
+    assert!(names(port, "tenant=team-b").await.is_empty());
+    assert!(names(port, "tags=nightly,signed").await.is_empty());
+    assert_eq!(all_names(port, "tenant=team-b").await, ["report-1.pdf", "report-2.pdf"]);
+}
+
+#[tokio::test]
+async fn paginates_with_a_cursor() {
+    let (_server, port, _) = start();
+
+    let mut seen = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let query = match &cursor {
+            Some(cursor) => format!("/files?sort=name&order=desc&limit=2&cursor={}", cursor),
+            None => "/files?sort=name&order=desc&limit=2".to_string(),
+        };
+        let page = json_body(&get(tcp(port).await, &query).await);
+
+        for file in page["files"].as_array().unwrap() {
+            seen.push(file["name"].as_str().unwrap().to_string());
+        }
+        match page["next_cursor"].as_str() {
+            Some(next) => cursor = Some(next.to_string()),
+            None => break,
+        }
+    }
+
+    assert_eq!(seen, ["zeta.bin", "beta.csv", "alpha.csv"]);
+}
+
+#[tokio::test]
+async fn cursors_outlive_the_entry_they_point_at() {
+    let (_server, port, uuids) = start();
+
+    let page = json_body(&get(tcp(port).await, "/files?sort=size&limit=2").await);
+    assert_eq!(page["files"][1]["name"], "beta.csv");
+    assert!(page["files"][1].get("path").is_none(), "{}", page);
+    let cursor = page["next_cursor"].as_str().unwrap().to_string();
+
+    let response = request(tcp(port).await, "DELETE", &format!("/files/{}", uuids["beta.csv"]), "", b"").await;
+    assert!(response.starts_with("HTTP/1.1 2"), "{}", response);
+
+    let page = json_body(&get(tcp(port).await, &format!("/files?sort=size&limit=2&cursor={}", cursor)).await);
+    let names: Vec<_> = page["files"].as_array().unwrap().iter().map(|file| file["name"].as_str().unwrap()).collect();
+    assert_eq!(names, ["zeta.bin"]);
+
+    // a cursor continues the order it came from and no other
+    let response = get(tcp(port).await, &format!("/files?sort=name&cursor={}", cursor)).await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    assert_eq!(json_body(&response)["field"], "cursor");
+}
+
+#[tokio::test]
+async fn rejects_invalid_filters() {
+    let (_server, port, _) = start();
+
+    let response = get(tcp(port).await, "/files?state=sideways").await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    assert_eq!(json_body(&response)["code"], "query_invalid");
+}
+
+#[tokio::test]
+async fn updates_metadata_and_tags() {
+    let (_server, port, uuids) = start();
+    let uuid = uuids["beta.csv"];
+
+    let patch = json!({ "metadata": { "build_id": "1234", "project": null }, "tags": ["release"] });
+    let response = request(tcp(port).await, "PATCH", &format!("/files/{}", uuid), "Content-Type: application/json\r\n", patch.to_string().as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let updated = json_body(&response);
+    assert_eq!(updated["metadata"], json!({ "build_id": "1234" }));
+    assert_eq!(updated["tags"], json!(["release"]));
+
+    // the listing index follows the update
+    assert_eq!(names(port, "metadata=build_id=1234").await, ["beta.csv"]);
+    assert!(names(port, "metadata=project=gemini").await.is_empty());
+
+    let invalid = json!({ "metadata": { "Not A Key": "value" } });
+    let response = request(tcp(port).await, "PATCH", &format!("/files/{}", uuid), "", invalid.to_string().as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 422"), "{}", response);
+}
+
+#[tokio::test]
+async fn refuses_label_updates_from_other_tenants() {
+    let (_server, port, uuids) = start();
+
+    // the upload of another tenant looks no different from one that does not exist
+    let patch = json!({ "tags": ["hijacked"] });
+    let response = request(tcp(port).await, "PATCH", &format!("/files/{}", uuids["report-1.pdf"]), "", patch.to_string().as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    assert_eq!(all_names(port, "tags=hijacked").await, Vec::<String>::new());
+    assert_eq!(all_names(port, "tags=nightly,signed").await, ["report-1.pdf"]);
+}
+
+#[tokio::test]
+async fn schedules_with_tus_encoded_metadata() {
+    let (_server, port, _) = start();
+
+    // `key base64(value)` pairs, a key without a value is kept empty
+    let headers = "Upload-Metadata: source Y2ksIG5pZ2h0bHk=, reviewed\r\nUpload-Tags: fresh\r\n";
+    let body = json!({ "fileHash": "c".repeat(64), "Length": 10 });
+    let response = request(tcp(port).await, "POST", "/schedule_upload", headers, body.to_string().as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let listed = json_body(&get(tcp(port).await, "/files?tags=fresh").await);
+    assert_eq!(listed["files"][0]["metadata"], json!({ "reviewed": "", "source": "ci, nightly" }));
+
+    for invalid in ["source=ci", "source not-base64!", "Source Y2k="] {
+        let headers = format!("Upload-Metadata: {}\r\n", invalid);
+        let response = request(tcp(port).await, "POST", "/schedule_upload", &headers, body.to_string().as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 422"), "{}: {}", invalid, response);
+        assert_eq!(json_body(&response)["field"], "Upload-Metadata");
+    }
+}
+
+#[tokio::test]
+async fn downloads_expose_selected_metadata() {
+    let (_server, port, uuids) = start();
+
+    let response = get(tcp(port).await, &format!("/files/{}/content", uuids["alpha.csv"])).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let head = response.split_once("\r\n\r\n").unwrap().0.to_ascii_lowercase();
+    assert!(head.contains("x-lofty-meta-source: ci"), "{}", head);
+    assert!(!head.contains("x-lofty-meta-project"), "{}", head);
+    assert!(head.contains("content-disposition: attachment; filename=\"alpha.csv\""), "{}", head);
+    assert!(head.contains("content-length: 100"), "{}", head);
+
+    // an upload still waiting for its content is not served yet
+    let body = json!({ "fileHash": "e".repeat(64), "Length": 10 });
+    let scheduled = json_body(&request(tcp(port).await, "POST", "/schedule_upload", "", body.to_string().as_bytes()).await);
+    let response = get(tcp(port).await, &format!("/files/{}/content", scheduled["uuid"].as_str().unwrap())).await;
+    assert!(response.starts_with("HTTP/1.1 409"), "{}", response);
+
+    // nor is the content of another tenant
+    let response = get(tcp(port).await, &format!("/files/{}/content", uuids["report-1.pdf"])).await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+}
+
+#[tokio::test]
+async fn leaves_other_files_editable_while_an_upload_streams() {
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (_server, port, uuids) = start();
+    let content = vec![b'y'; 32 * 1024];
+
+    let body = json!({ "fileHash": "f".repeat(64), "Length": content.len() });
+    let response = request(tcp(port).await, "POST", "/schedule_upload", "", body.to_string().as_bytes()).await;
+    let uuid = json_body(&response)["uuid"].as_str().unwrap().to_string();
+
+    let mut streaming = tcp(port).await;
+    let head = format!(
+        "GET /upload_file HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\nFileName: held.bin\r\nuuid: {}\r\n\r\n",
+        content.len(), uuid
+    );
+    streaming.write_all(head.as_bytes()).await.unwrap();
+    streaming.write_all(&content[..1024]).await.unwrap();
+
+    // none of these wait on the upload, whichever part of the registry the files share with it
+    let within = Duration::from_secs(5);
+    let mut progress = false;
+    for _ in 0..100 {
+        let status = tokio::time::timeout(within, request(tcp(port).await, "GET", "/status", &format!("uuid: {}\r\n", uuid), b"")).await.expect("status waited on the upload");
+        progress = json_body(&status)["status"].get("Progress").is_some();
+        if progress {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(progress, "the upload never reported progress");
+
+    let patch = json!({ "tags": ["touched"] }).to_string();
+    for name in ["alpha.csv", "beta.csv", "zeta.bin"] {
+        let path = format!("/files/{}", uuids[name]);
+        let response = tokio::time::timeout(within, request(tcp(port).await, "PATCH", &path, "", patch.as_bytes())).await.expect("patch waited on the upload");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+    let path = format!("/files/{}", uuids["zeta.bin"]);
+    let response = tokio::time::timeout(within, request(tcp(port).await, "DELETE", &path, "", b"")).await.expect("delete waited on the upload");
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+
+    // the upload itself is busy until it finished
+    let response = request(tcp(port).await, "DELETE", &format!("/files/{}", uuid), "", b"").await;
+    assert!(response.starts_with("HTTP/1.1 409"), "{}", response);
+
+    streaming.write_all(&content[1024..]).await.unwrap();
+    let mut response = String::new();
+    streaming.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+}
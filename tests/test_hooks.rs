@@ -0,0 +1,127 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{scratch_dir, Server};
+use lofty_client::{Client, ListQuery, Source, UploadState};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+
+// rejects content mentioning FORBIDDEN, labels everything else with what it saw
+const SCRIPT: &str = r#"
+if grep -q FORBIDDEN "$LOFTY_FILE"; then
+    echo "found forbidden content in $LOFTY_NAME" >&2
+    exit 3
+fi
+echo "checked_size=$LOFTY_SIZE"
+echo "origin=$LOFTY_META_ORIGIN"
+"#;
+
+async fn start(settings: &str) -> (Server, Client) {
+    let (server, _, port) = common::start("hooks", settings).await;
+    let client = Client::builder(format!("http://127.0.0.1:{}", port)).header("Upload-Metadata", "origin aG9va3MtdGVzdA==").build().unwrap();
+    (server, client)
+}
+
+fn exec_hook(script: &str) -> String {
+    format!("[[hooks]]\nname = \"validate\"\nexec = \"/bin/sh\"\nargs = [\"-c\", '''{}''']\ntimeout = 5\n", script)
+}
+
+// the state once the hooks are done with the upload
+async fn settled(client: &Client, uuid: Uuid) -> UploadState {
+    for _ in 0..100 {
+        match client.status(uuid).await.unwrap() {
+            UploadState::Processing => tokio::time::sleep(Duration::from_millis(20)).await,
+            state => return state,
+        }
+    }
+    panic!("{} still processing", uuid);
+}
+
+#[tokio::test]
+async fn exec_hooks_accept_label_and_reject_uploads() {
+    let (_server, client) = start(&exec_hook(SCRIPT)).await;
+
+    let accepted = client.upload_file(&Source::bytes(b"harmless content\n".to_vec()).with_name("fine.txt")).await.unwrap();
+    let rejected = client.upload_file(&Source::bytes(b"some FORBIDDEN content\n".to_vec()).with_name("bad.txt")).await.unwrap();
+
+    assert_eq!(settled(&client, accepted).await, UploadState::Complete);
+    assert_eq!(settled(&client, rejected).await, UploadState::Rejected);
+
+    let files = client.list_all(&ListQuery::default()).await.unwrap();
+    let fine = files.iter().find(|file| file.uuid == accepted).unwrap();
+    assert_eq!(fine.metadata.get("checked_size").map(String::as_str), Some("17"));
+    assert_eq!(fine.metadata.get("origin").map(String::as_str), Some("hooks-test"));
+    assert_eq!(fine.rejection, None);
+
+    let bad = files.iter().find(|file| file.uuid == rejected).unwrap();
+    assert_eq!(bad.rejection.as_deref(), Some("validate: found forbidden content in bad.txt"));
+
+    let listed = client.list_all(&ListQuery { state: Some("rejected".to_string()), ..Default::default() }).await.unwrap();
+    assert_eq!(listed.len(), 1);
+
+    // rejected content is kept for inspection but never served
+    let dest = scratch_dir("hooks-download").join("copy");
+    assert!(client.download(rejected, &dest).await.is_err());
+    client.download(accepted, &dest).await.unwrap();
+    assert_eq!(std::fs::read(&dest).unwrap(), b"harmless content\n");
+}
+
+#[tokio::test]
+async fn failing_hooks_reject_uploads() {
+    // the pipeline stops at the first stage that fails, the second one is never reached
+    let settings = format!("{}{}", exec_hook("sleep 10"), "[[hooks]]\nname = \"missing\"\nexec = \"/nonexistent/hook\"\n");
+    let (_server, client) = start(&settings.replace("timeout = 5", "timeout = 1")).await;
+
+    let uuid = client.upload_file(&Source::bytes(b"anything\n".to_vec())).await.unwrap();
+    assert_eq!(client.status(uuid).await.unwrap(), UploadState::Processing);
+    assert_eq!(settled(&client, uuid).await, UploadState::Rejected);
+
+    let files = client.list_all(&ListQuery::default()).await.unwrap();
+    assert_eq!(files[0].rejection.as_deref(), Some("validate failed: timed out after 1s"));
+}
+
+// the hook reads the text and hands back a rewritten copy, whatever layout the upload is stored in
+async fn replaces_the_content(layout: &str) {
+    let script = r#"
+grep -q "plain words" "$LOFTY_FILE" || exit 4
+{ tr a-z A-Z < "$LOFTY_FILE"; echo done; } > "$LOFTY_OUTPUT"
+"#;
+    let (server, client) = start(&format!("{}{}", layout, exec_hook(script))).await;
+    let content = b"plain words, ".repeat(400);
+
+    let uuid = client.upload_file(&Source::bytes(content.clone()).with_name("words.txt")).await.unwrap();
+    assert_eq!(settled(&client, uuid).await, UploadState::Complete);
+
+    let mut replaced = content.to_ascii_uppercase();
+    replaced.extend_from_slice(b"done\n");
+    let dest = scratch_dir("hooks-download").join("copy");
+    client.download(uuid, &dest).await.unwrap();
+    assert_eq!(std::fs::read(&dest).unwrap(), replaced);
+
+    let files = client.list_all(&ListQuery::default()).await.unwrap();
+    assert_eq!(files[0].file_size, replaced.len() as u64);
+    // sealed uploads are never deduplicated and carry no digest
+    if !files[0].encrypted {
+        assert_eq!(files[0].digest.as_deref(), Some(hex::encode(Sha256::digest(&replaced)).as_str()));
+    }
+
+    // neither the staged plaintext nor the replacement is left behind
+    let leftovers: Vec<_> = std::fs::read_dir(server.dir.join("data"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .filter(|name| name.ends_with(".hook") || name.ends_with(".out"))
+        .collect();
+    assert!(leftovers.is_empty(), "{:?}", leftovers);
+}
+
+#[tokio::test]
+async fn hooks_replace_compressed_content() {
+    replaces_the_content("[compression]\nenabled = true\n\n[store]\ndedup = true\n\n").await;
+}
+
+#[tokio::test]
+async fn hooks_replace_encrypted_content() {
+    replaces_the_content("[encryption]\nenabled = true\nsegment_size = 1024\n\n").await;
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{json_body, request, start, tcp};
+use lofty_client::{ListQuery, UploadState};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+#[tokio::test]
+async fn throttles_a_principal_past_its_burst() {
+    let (_server, _, port) = start("limits", "[limits]\nrequests_per_second = 0.5\nburst = 2\n").await;
+
+    for _ in 0..2 {
+        let response = request(tcp(port).await, "GET", "/files", "", b"").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+
+    // the bucket is empty, a token comes back every two seconds
+    let response = request(tcp(port).await, "GET", "/files", "", b"").await;
+    assert!(response.starts_with("HTTP/1.1 429"), "{}", response);
+    assert_eq!(header(&response, "retry-after"), Some("2"));
+    assert_eq!(header(&response, "content-type"), Some("application/problem+json"));
+    assert_eq!(json_body(&response)["code"], "rate_limited");
+}
+
+#[tokio::test]
+async fn releases_the_upload_slot_once_the_stream_ends() {
+    let (_server, client, port) = start("limits", "[limits]\nmax_active_uploads_per_principal = 1\nretry_after = 3\n").await;
+
+    let content = vec![b'x'; 64 * 1024];
+    let first = client.schedule(&"0".repeat(64), content.len() as u64).await.unwrap().uuid;
+    let second = client.schedule(&"1".repeat(64), content.len() as u64).await.unwrap().uuid;
+
+    // half of the first body is sent, its stream keeps the only slot of this principal
+    let mut held = tcp(port).await;
+    let head = format!(
+        "GET /upload_file HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\nFileName: first.bin\r\nuuid: {}\r\n\r\n",
+        content.len(), first
+    );
+    held.write_all(head.as_bytes()).await.unwrap();
+    held.write_all(&content[..content.len() / 2]).await.unwrap();
+
+    for _ in 0..100 {
+        let files = client.list_all(&ListQuery::default()).await.unwrap();
+        if files.iter().any(|file| file.uuid == first && matches!(file.state, UploadState::Progress(n) if n > 0)) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let headers = format!("FileName: second.bin\r\nuuid: {}\r\n", second);
+    let response = request(tcp(port).await, "GET", "/upload_file", &headers, &content).await;
+    assert!(response.starts_with("HTTP/1.1 429"), "{}", response);
+    assert_eq!(header(&response, "retry-after"), Some("3"));
+
+    held.write_all(&content[content.len() / 2..]).await.unwrap();
+    let mut response = String::new();
+    held.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let response = request(tcp(port).await, "GET", "/upload_file", &headers, &content).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+}
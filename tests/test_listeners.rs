@@ -0,0 +1,58 @@
+mod common;
+
+use common::{free_port, get, scratch_dir, spawn_server, tcp};
+
+
+#[tokio::test]
+async fn listeners_only_expose_their_mounts() {
+    let public = free_port();
+    let internal = free_port();
+    let _server = spawn_server(scratch_dir("listeners"), &format!(
+        "[[server.listeners]]\nname = \"public\"\ntcp = \"127.0.0.1:{}\"\nmounts = [\"uploads\"]\n\n\
+         [[server.listeners]]\nname = \"internal\"\ntcp = \"127.0.0.1:{}\"\nmounts = [\"metrics\", \"admin\"]\n",
+        public, internal
+    ));
+
+    assert!(get(tcp(public).await, "/status").await.starts_with("HTTP/1.1 "));
+    assert!(get(tcp(public).await, "/metrics").await.starts_with("HTTP/1.1 404"));
+    assert!(get(tcp(public).await, "/admin/audit/verify").await.starts_with("HTTP/1.1 404"));
+    assert!(get(tcp(public).await, "/dashboard/").await.starts_with("HTTP/1.1 404"));
+
+    assert!(get(tcp(internal).await, "/metrics").await.starts_with("HTTP/1.1 200"));
+    assert!(get(tcp(internal).await, "/admin/audit/verify").await.starts_with("HTTP/1.1 200"));
+    assert!(get(tcp(internal).await, "/upload_file").await.starts_with("HTTP/1.1 404"));
+}
+
+#[tokio::test]
+async fn serves_the_dashboard_from_its_directory() {
+    let dir = scratch_dir("dashboard");
+    std::fs::create_dir_all(dir.join("ui")).unwrap();
+    std::fs::write(dir.join("ui").join("index.html"), "<h1>lofty</h1>").unwrap();
+
+    let port = free_port();
+    let _server = spawn_server(dir, &format!("[server]\nbind = \"127.0.0.1:{}\"\ndashboard = \"./ui\"\n", port));
+
+    let response = get(tcp(port).await, "/dashboard/index.html").await;
+    assert!(response.starts_with("HTTP/1.1 200") && response.ends_with("<h1>lofty</h1>"), "{}", response);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn serves_on_a_unix_socket_with_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let socket = std::env::temp_dir().join(format!("lofty-{}.sock", uuid::Uuid::new_v4()));
+    let server = spawn_server(scratch_dir("unix"), &format!(
+        "[[server.listeners]]\nname = \"admin\"\nunix = \"{}\"\nmode = 0o600\nmounts = [\"metrics\", \"admin\"]\n",
+        socket.display()
+    ));
+
+    let response = get(common::unix(&socket).await, "/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    drop(server);
+    let _ = std::fs::remove_file(&socket);
+}
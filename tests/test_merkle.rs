@@ -0,0 +1,204 @@
+mod common;
+
+use common::{content, free_port, get, json_body, request, scratch_dir, seed_pending, spawn_server, tcp, Server};
+use sha2::{Digest, Sha256};
+
+
+const BLOCK: usize = 1024;
+
+fn leaf(block: &[u8]) -> [u8; 32] {
+    Sha256::new().chain_update([0x00]).chain_update(block).finalize().into()
+}
+
+fn parents(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => Sha256::new().chain_update([0x01]).chain_update(left).chain_update(right).finalize().into(),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+// the root recomputed from leaves `first..` and the proof nodes, as a client would
+fn fold(mut nodes: Vec<[u8; 32]>, first: usize, count: usize, proof: &[[u8; 32]]) -> [u8; 32] {
+    let (mut first, mut last, mut count) = (first, first + nodes.len() - 1, count);
+    let mut proof = proof.iter();
+
+    while count > 1 {
+        if first % 2 == 1 {
+            nodes.insert(0, *proof.next().unwrap());
+            first -= 1;
+        }
+        if last % 2 == 0 && last + 1 < count {
+            nodes.push(*proof.next().unwrap());
+            last += 1;
+        }
+        nodes = parents(&nodes);
+        first /= 2;
+        last /= 2;
+        count = count.div_ceil(2);
+    }
+    assert!(proof.next().is_none(), "unused proof nodes");
+    nodes[0]
+}
+
+fn decode(node: &str) -> [u8; 32] {
+    hex::decode(node).unwrap().try_into().unwrap()
+}
+
+fn start(size: usize) -> (Server, u16, uuid::Uuid) {
+    let dir = scratch_dir("merkle");
+    let uuid = seed_pending(&dir, "leaves.txt", size);
+
+    let port = free_port();
+    let settings = format!("[server]\nbind = \"127.0.0.1:{}\"\n\n[merkle]\nblock_size = {}\n", port, BLOCK);
+    (spawn_server(dir, &settings), port, uuid)
+}
+
+async fn upload(port: u16, uuid: uuid::Uuid, content: &[u8]) -> String {
+    let headers = format!("FileName: leaves.txt\r\nuuid: {}\r\n", uuid);
+    request(tcp(port).await, "GET", "/upload_file", &headers, content).await
+}
+
+async fn resume(port: u16, uuid: uuid::Uuid, pointer: usize, content: &[u8]) -> String {
+    let headers = format!("uuid: {}\r\nContent-Pointer: {}\r\n", uuid, pointer);
+    request(tcp(port).await, "GET", "/resume_upload", &headers, content).await
+}
+
+async fn manifest(port: u16, uuid: uuid::Uuid) -> serde_json::Value {
+    json_body(&get(tcp(port).await, &format!("/files/{}/manifest", uuid)).await)
+}
+
+fn header<'a>(response: &'a str, name: &str) -> &'a str {
+    response
+        .lines()
+        .find_map(|line| line.split_once(": ").filter(|(key, _)| key.eq_ignore_ascii_case(name)))
+        .map(|(_, value)| value)
+        .unwrap_or_else(|| panic!("no {} header in {}", name, response))
+}
+
+fn leaves(manifest: &serde_json::Value) -> Vec<[u8; 32]> {
+    manifest["leaves"].as_array().unwrap().iter().map(|node| decode(node.as_str().unwrap())).collect()
+}
+
+#[tokio::test]
+async fn range_downloads_prove_their_leaves() {
+    let content = content(10_000);
+    let (_server, port, uuid) = start(content.len());
+
+    let response = upload(port, uuid, &content).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let manifest = manifest(port, uuid).await;
+    let expected: Vec<_> = content.chunks(BLOCK).map(leaf).collect();
+    assert_eq!(manifest["complete"], true);
+    assert_eq!(manifest["block_size"], BLOCK);
+    assert_eq!(leaves(&manifest), expected);
+
+    let root = decode(manifest["root"].as_str().unwrap());
+    assert_eq!(fold(expected.clone(), 0, expected.len(), &[]), root);
+
+    // leaves 2 to 4, aligned so every byte of the range can be checked
+    let response = request(tcp(port).await, "GET", &format!("/files/{}/content", uuid), "Range: bytes=2048-5119\r\n", b"").await;
+    assert!(response.starts_with("HTTP/1.1 206"), "{}", response);
+    assert_eq!(header(&response, "x-lofty-merkle-leaves"), "2-4/10");
+    assert_eq!(decode(header(&response, "x-lofty-merkle-root")), root);
+
+    let body = response.split_once("\r\n\r\n").unwrap().1.as_bytes();
+    let proof: Vec<_> = header(&response, "x-lofty-merkle-proof").split(',').map(decode).collect();
+    assert_eq!(fold(body.chunks(BLOCK).map(leaf).collect(), 2, 10, &proof), root);
+
+    // the last, shorter leaf
+    let response = request(tcp(port).await, "GET", &format!("/files/{}/content", uuid), "Range: bytes=9216-\r\n", b"").await;
+    let body = response.split_once("\r\n\r\n").unwrap().1.as_bytes();
+    let proof: Vec<_> = header(&response, "x-lofty-merkle-proof").split(',').map(decode).collect();
+    assert_eq!(fold(vec![leaf(body)], 9, 10, &proof), root);
+    assert!(!response.to_ascii_lowercase().contains("x-lofty-merkle-edge-leaves"), "{}", response);
+}
+
+#[tokio::test]
+async fn unaligned_ranges_send_their_edge_leaves() {
+    let content = content(10_000);
+    let (_server, port, uuid) = start(content.len());
+
+    let response = upload(port, uuid, &content).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let expected: Vec<_> = content.chunks(BLOCK).map(leaf).collect();
+    let root = fold(expected.clone(), 0, expected.len(), &[]);
+
+    // starts inside leaf 2 and ends inside leaf 5, the range itself is served as asked
+    let response = request(tcp(port).await, "GET", &format!("/files/{}/content", uuid), "Range: bytes=2100-5199\r\n", b"").await;
+    assert!(response.starts_with("HTTP/1.1 206"), "{}", response);
+    let body = response.split_once("\r\n\r\n").unwrap().1.as_bytes();
+    assert_eq!(body, &content[2100..5200]);
+    assert_eq!(header(&response, "x-lofty-merkle-leaves"), "2-5/10");
+
+    // the edge leaves come as hashes, the whole ones in between are hashed from the body
+    let edges: Vec<(usize, [u8; 32])> = header(&response, "x-lofty-merkle-edge-leaves")
+        .split(',')
+        .map(|edge| edge.split_once('=').unwrap())
+        .map(|(index, node)| (index.parse().unwrap(), decode(node)))
+        .collect();
+    assert_eq!(edges, [(2, expected[2]), (5, expected[5])]);
+
+    let inner = &body[3 * BLOCK - 2100..5 * BLOCK - 2100];
+    let mut nodes = vec![edges[0].1];
+    nodes.extend(inner.chunks(BLOCK).map(leaf));
+    nodes.push(edges[1].1);
+    let proof: Vec<_> = header(&response, "x-lofty-merkle-proof").split(',').map(decode).collect();
+    assert_eq!(fold(nodes, 2, 10, &proof), root);
+
+    // a range within a single leaf only has that leaf to send
+    let response = request(tcp(port).await, "GET", &format!("/files/{}/content", uuid), "Range: bytes=10-19\r\n", b"").await;
+    assert_eq!(header(&response, "x-lofty-merkle-edge-leaves"), format!("0={}", hex::encode(expected[0])));
+}
+
+#[tokio::test]
+async fn resume_continues_the_tree() {
+    let content = content(10_000);
+    let (_server, port, uuid) = start(content.len());
+
+    let response = upload(port, uuid, &content[..4_500]).await;
+    assert_eq!(json_body(&response)["offset"], 4_500, "{}", response);
+
+    let partial = manifest(port, uuid).await;
+    assert_eq!(partial["complete"], false);
+    assert_eq!(partial["length"], 4 * BLOCK);
+
+    let response = resume(port, uuid, 4_500, &content[4_500..]).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let expected: Vec<_> = content.chunks(BLOCK).map(leaf).collect();
+    assert_eq!(leaves(&manifest(port, uuid).await), expected);
+}
+
+#[tokio::test]
+async fn resume_refuses_a_corrupted_prefix() {
+    let content = content(10_000);
+    let (server, port, uuid) = start(content.len());
+
+    let response = upload(port, uuid, &content[..4_500]).await;
+    assert_eq!(json_body(&response)["offset"], 4_500, "{}", response);
+
+    // damage the last whole leaf before the pointer
+    let path = server.dir.join("data").join(uuid.to_string());
+    let mut stored = std::fs::read(&path).unwrap();
+    stored[3_500] ^= 0xff;
+    std::fs::write(&path, stored).unwrap();
+
+    let response = resume(port, uuid, 4_500, &content[4_500..]).await;
+    assert!(response.starts_with("HTTP/1.1 409"), "{}", response);
+    let problem = json_body(&response);
+    assert_eq!(problem["code"], "stored_prefix_corrupt");
+    assert_eq!(problem["offset"], 3 * BLOCK);
+
+    let response = resume(port, uuid, 3 * BLOCK, &content[3 * BLOCK..]).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let expected: Vec<_> = content.chunks(BLOCK).map(leaf).collect();
+    assert_eq!(leaves(&manifest(port, uuid).await), expected);
+    let response = request(tcp(port).await, "GET", &format!("/files/{}/content", uuid), "", b"").await;
+    assert_eq!(response.split_once("\r\n\r\n").unwrap().1.as_bytes(), &content[..]);
+}
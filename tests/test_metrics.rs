@@ -0,0 +1,62 @@
+mod common;
+
+use std::collections::HashMap;
+
+use common::{free_port, get, request, scratch_dir, spawn_server, tcp};
+use lofty_client::{Client, Source};
+use uuid::Uuid;
+
+
+// `name{labels} value` lines of the exposition, comments left out
+async fn scrape(port: u16) -> HashMap<String, f64> {
+    let response = get(tcp(port).await, "/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    body.lines()
+        .filter(|line| !line.starts_with('#') && !line.is_empty())
+        .filter_map(|line| {
+            let (series, value) = line.rsplit_once(' ')?;
+            Some((series.to_string(), value.parse().ok()?))
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn counts_uploads_bytes_and_errors() {
+    let dir = scratch_dir("metrics");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    let port = free_port();
+    let _server = spawn_server(dir, &format!("[server]\nbind = \"127.0.0.1:{}\"\n", port));
+    drop(tcp(port).await);
+
+    let client = Client::new(format!("http://127.0.0.1:{}", port)).unwrap();
+    let uuid = client.upload_file(&Source::bytes(vec![b'm'; 1000]).with_name("counted.bin")).await.unwrap();
+    let response = get(tcp(port).await, &format!("/files/{}/content", uuid)).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let metrics = scrape(port).await;
+    assert_eq!(metrics["lofty_uploads_total{event=\"started\"}"], 1.0);
+    assert_eq!(metrics["lofty_uploads_total{event=\"completed\"}"], 1.0);
+    assert_eq!(metrics["lofty_bytes_received_total"], 1000.0);
+    assert_eq!(metrics["lofty_bytes_served_total"], 1000.0);
+    assert_eq!(metrics["lofty_active_uploads"], 0.0);
+    assert_eq!(metrics["lofty_job_handle_entries"], 1.0);
+    assert_eq!(metrics["lofty_upload_duration_seconds_count"], 1.0);
+    assert!(!metrics.keys().any(|series| series.starts_with("lofty_errors_total")), "{:?}", metrics);
+
+    // a missing upload and a body past the declared size, each counted under its variant
+    let response = get(tcp(port).await, &format!("/files/{}/content", Uuid::new_v4())).await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    let scheduled = client.schedule(&"0".repeat(64), 4).await.unwrap();
+    let headers = format!("FileName: short.bin\r\nuuid: {}\r\n", scheduled.uuid);
+    let response = request(tcp(port).await, "GET", "/upload_file", &headers, b"too long").await;
+    assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+
+    let metrics = scrape(port).await;
+    assert_eq!(metrics["lofty_errors_total{variant=\"UploadNotFound\"}"], 1.0);
+    assert_eq!(metrics["lofty_errors_total{variant=\"UploadSizeExceeded\"}"], 1.0);
+    assert_eq!(metrics["lofty_uploads_total{event=\"started\"}"], 2.0);
+    assert_eq!(metrics["lofty_uploads_total{event=\"failed\"}"], 1.0);
+    assert_eq!(metrics["lofty_job_handle_entries"], 2.0);
+}
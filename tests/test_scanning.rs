@@ -0,0 +1,175 @@
+mod common;
+
+use std::{net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+
+use axum::async_trait;
+use bytes::Bytes;
+use common::{scratch_dir, start};
+use futures::{stream::BoxStream, StreamExt};
+use lofty::{CompletedUpload, LoadConfig, LocalStorage, LoftyServer, ScanError, ScanVerdict, Scanner};
+use lofty_client::{Client, ListQuery, Source, UploadState};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, sync::Notify};
+use uuid::Uuid;
+
+
+// A stand-in clamd speaking INSTREAM, flags any stream containing EICAR and records the chunk sizes it got
+#[derive(Clone, Default)]
+struct FakeClamd {
+    chunks: Arc<Mutex<Vec<usize>>>,
+}
+
+impl FakeClamd {
+    async fn listen_tcp(&self) -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let clamd = self.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(clamd.clone().session(stream));
+            }
+        });
+        port
+    }
+
+    async fn listen_unix(&self, path: &std::path::Path) {
+        let listener = tokio::net::UnixListener::bind(path).unwrap();
+        let clamd = self.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(clamd.clone().session(stream));
+            }
+        });
+    }
+
+    async fn session(self, mut stream: impl AsyncRead + AsyncWrite + Unpin) {
+        let mut command = [0u8; 10];
+        stream.read_exact(&mut command).await.unwrap();
+        assert_eq!(&command, b"zINSTREAM\0");
+
+        let mut content = Vec::new();
+        loop {
+            let len = stream.read_u32().await.unwrap() as usize;
+            if len == 0 {
+                break;
+            }
+            self.chunks.lock().unwrap().push(len);
+            let mut chunk = vec![0u8; len];
+            stream.read_exact(&mut chunk).await.unwrap();
+            content.extend(chunk);
+        }
+
+        let reply: &[u8] = match content.windows(5).any(|window| window == b"EICAR") {
+            true => b"stream: Eicar-Test-Signature FOUND\0",
+            false => b"stream: OK\0",
+        };
+        stream.write_all(reply).await.unwrap();
+    }
+}
+
+// the state once the scanner is done with the upload
+async fn settled(client: &Client, uuid: Uuid) -> UploadState {
+    for _ in 0..200 {
+        match client.status(uuid).await.unwrap() {
+            UploadState::Processing => tokio::time::sleep(Duration::from_millis(20)).await,
+            state => return state,
+        }
+    }
+    panic!("{} still processing", uuid);
+}
+
+#[tokio::test]
+async fn quarantines_what_clamd_flags() {
+    let clamd = FakeClamd::default();
+    let clamd_port = clamd.listen_tcp().await;
+    // compressed uploads are scanned as they were sent
+    let settings = format!("[scanning]\ntcp = \"127.0.0.1:{}\"\nchunk_size = 1000\n\n[compression]\nenabled = true\n", clamd_port);
+    let (_server, client, _) = start("scanning", &settings).await;
+
+    let harmless: Vec<u8> = (0..).flat_map(|i: u32| format!("line {:>5}\n", i).into_bytes()).take(5000).collect();
+    let clean = client.upload_file(&Source::bytes(harmless.clone()).with_name("clean.txt")).await.unwrap();
+    let infected = client.upload_file(&Source::bytes(b"X5O!P%@AP EICAR-STANDARD-ANTIVIRUS-TEST-FILE".to_vec()).with_name("eicar.com")).await.unwrap();
+
+    assert_eq!(settled(&client, clean).await, UploadState::Clean);
+    assert_eq!(settled(&client, infected).await, UploadState::Quarantined);
+    assert!(clamd.chunks.lock().unwrap().iter().all(|len| *len <= 1000));
+
+    let dest = scratch_dir("scanning-download").join("copy");
+    client.download(clean, &dest).await.unwrap();
+    assert_eq!(std::fs::read(&dest).unwrap(), harmless);
+    assert!(client.download(infected, &dest).await.is_err());
+
+    let quarantined = client.list_all(&ListQuery { state: Some("quarantined".to_string()), ..Default::default() }).await.unwrap();
+    assert_eq!(quarantined.len(), 1);
+    assert_eq!(quarantined[0].uuid, infected);
+    assert_eq!(quarantined[0].rejection.as_deref(), Some("clamd: Eicar-Test-Signature found"));
+}
+
+#[tokio::test]
+async fn scans_through_a_unix_socket_and_quarantines_when_clamd_is_down() {
+    let dir = scratch_dir("clamd");
+    let socket = dir.join("clamd.sock");
+    let clamd = FakeClamd::default();
+    clamd.listen_unix(&socket).await;
+
+    let settings = format!("[scanning]\nunix = \"{}\"\n", socket.display());
+    let (_server, client, _) = start("scanning", &settings).await;
+    let uuid = client.upload_file(&Source::bytes(b"harmless".to_vec())).await.unwrap();
+    assert_eq!(settled(&client, uuid).await, UploadState::Clean);
+
+    // an upload that could not be checked is never served either
+    let settings = format!("[scanning]\nunix = \"{}\"\nattempts = 2\nretry_delay = 0\n", dir.join("missing.sock").display());
+    let (_server, client, _) = start("scanning", &settings).await;
+    let uuid = client.upload_file(&Source::bytes(b"harmless".to_vec())).await.unwrap();
+    assert_eq!(settled(&client, uuid).await, UploadState::Quarantined);
+
+    let files = client.list_all(&ListQuery::default()).await.unwrap();
+    assert!(files[0].rejection.as_deref().unwrap().starts_with("clamd failed: "), "{:?}", files[0].rejection);
+}
+
+// holds every scan until released
+#[derive(Clone, Default)]
+struct GatedScanner(Arc<Notify>);
+
+#[async_trait]
+impl Scanner for GatedScanner {
+    fn name(&self) -> &str {
+        "gated"
+    }
+
+    async fn scan(&self, _upload: &CompletedUpload, mut content: BoxStream<'static, std::io::Result<Bytes>>) -> Result<ScanVerdict, ScanError> {
+        while content.next().await.transpose()?.is_some() {}
+        self.0.notified().await;
+        Ok(ScanVerdict::Clean)
+    }
+}
+
+#[tokio::test]
+async fn blocks_downloads_until_the_scanner_passed_the_upload() {
+    let dir = scratch_dir("scanning-embed");
+    let scanner = GatedScanner::default();
+    let server = LoftyServer::builder()
+        .config(LoadConfig::from_toml(&format!("[audit]\ndir = \"{}\"\n", dir.join("audit").display())).unwrap())
+        .storage(LocalStorage::new(dir.join("data")))
+        .scanner(scanner.clone())
+        .build()
+        .await
+        .unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let app = server.router();
+    tokio::spawn(async move {
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+    });
+    let client = Client::new(format!("http://127.0.0.1:{}", port)).unwrap();
+
+    let uuid = client.upload_file(&Source::bytes(b"pending scan".to_vec())).await.unwrap();
+    assert_eq!(client.status(uuid).await.unwrap(), UploadState::Processing);
+    let dest = dir.join("copy");
+    assert!(client.download(uuid, &dest).await.is_err());
+
+    scanner.0.notify_one();
+    assert_eq!(settled(&client, uuid).await, UploadState::Clean);
+    client.download(uuid, &dest).await.unwrap();
+    assert_eq!(std::fs::read(&dest).unwrap(), b"pending scan");
+}
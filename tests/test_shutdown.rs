@@ -0,0 +1,58 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{free_port, get, json_body, request, scratch_dir, seed_pending, spawn_server, tcp};
+use serde_json::json;
+use tokio::io::AsyncWriteExt;
+
+
+async fn status(port: u16, uuid: uuid::Uuid) -> serde_json::Value {
+    let response = request(tcp(port).await, "GET", "/status", &format!("uuid: {}\r\n", uuid), b"").await;
+    json_body(&response)["status"].clone()
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn interrupted_uploads_resume_after_a_restart() {
+    let content: Vec<u8> = (0..100_u8).collect();
+    let dir = scratch_dir("shutdown");
+    let uuid = seed_pending(&dir, "big.bin", content.len());
+    let port = free_port();
+    let mut server = spawn_server(dir, &format!("[server]\nbind = \"127.0.0.1:{}\"\n\n[shutdown]\ngrace_period = 1\n", port));
+
+    // sixty bytes are in when the signal arrives, the rest never comes
+    let mut stream = tcp(port).await;
+    let head = format!(
+        "GET /upload_file HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\nFileName: big.bin\r\nuuid: {}\r\n\r\n",
+        content.len(),
+        uuid
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(&content[..60]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    // the writer checkpoints once the grace period is over and the registry is written on exit
+    server.stop();
+    drop(stream);
+    let registry: serde_json::Value = serde_json::from_slice(&std::fs::read(server.dir.join("data/registry.json")).unwrap()).unwrap();
+    assert_eq!(registry[0]["state"], json!({ "Broken": 60 }), "{}", registry);
+
+    server.restart();
+    drop(tcp(port).await);
+    assert_eq!(status(port, uuid).await, json!({ "Broken": 60 }));
+
+    let headers = format!("uuid: {}\r\nContent-Pointer: 60\r\n", uuid);
+    let response = request(tcp(port).await, "GET", "/resume_upload", &headers, &content[60..]).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let (_, body) = common::request_bytes(tcp(port).await, "GET", &format!("/files/{}/content", uuid), "", b"").await;
+    assert_eq!(body, content);
+    let response = get(tcp(port).await, "/files").await;
+    assert_eq!(json_body(&response)["files"][0]["state"], "Complete", "{}", response);
+
+    // the crc covers the bytes of the first attempt too
+    server.stop();
+    let registry: serde_json::Value = serde_json::from_slice(&std::fs::read(server.dir.join("data/registry.json")).unwrap()).unwrap();
+    assert_eq!(registry[0]["crc32"], crc32fast::hash(&content), "{}", registry);
+}
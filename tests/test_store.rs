@@ -0,0 +1,114 @@
+mod common;
+
+use std::collections::HashMap;
+
+use common::{free_port, json_body, request, scratch_dir, seed_pending, spawn_server, tcp};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+
+async fn upload(port: u16, uuid: uuid::Uuid, name: &str, content: &[u8]) -> String {
+    let headers = format!("FileName: {}\r\nuuid: {}\r\n", name, uuid);
+    request(tcp(port).await, "GET", "/upload_file", &headers, content).await
+}
+
+async fn listed(port: u16, uuid: uuid::Uuid) -> serde_json::Value {
+    let response = request(tcp(port).await, "GET", "/files", "", b"").await;
+    json_body(&response)["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|file| file["uuid"] == json!(uuid))
+        .cloned()
+        .unwrap_or(serde_json::Value::Null)
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn duplicate_uploads_share_a_blob_until_deleted() {
+    use std::os::unix::fs::MetadataExt;
+
+    let content = vec![7u8; 64 * 1024];
+    let digest = format!("{:x}", Sha256::digest(&content));
+
+    let dir = scratch_dir("store");
+    let uuids = HashMap::from(["first.bin", "second.bin"].map(|name| (name, seed_pending(&dir, name, content.len()))));
+    let port = free_port();
+    let server = spawn_server(dir, &format!("[server]\nbind = \"127.0.0.1:{}\"\n\n[store]\ndedup = true\n", port));
+
+    for name in ["first.bin", "second.bin"] {
+        let response = upload(port, uuids[name], name, &content).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert_eq!(listed(port, uuids[name]).await["digest"], json!(digest));
+    }
+
+    let blob = server.dir.join("data/blobs").join(&digest[..2]).join(&digest);
+    let first = server.dir.join("data").join(uuids["first.bin"].to_string());
+    let second = server.dir.join("data").join(uuids["second.bin"].to_string());
+    assert_eq!(std::fs::metadata(&blob).unwrap().ino(), std::fs::metadata(&first).unwrap().ino());
+    assert_eq!(std::fs::metadata(&blob).unwrap().ino(), std::fs::metadata(&second).unwrap().ino());
+
+    let response = request(tcp(port).await, "DELETE", &format!("/files/{}", uuids["first.bin"]), "", b"").await;
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+    assert!(!first.exists());
+    assert!(blob.exists(), "blob collected while still referenced");
+
+    let response = request(tcp(port).await, "DELETE", &format!("/files/{}", uuids["second.bin"]), "", b"").await;
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+    assert!(!blob.exists(), "unreferenced blob left behind");
+
+    let response = request(tcp(port).await, "DELETE", &format!("/files/{}", uuids["second.bin"]), "", b"").await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn blobs_outlive_a_registry_lost_in_a_crash() {
+    let content = vec![5u8; 32 * 1024];
+    let digest = format!("{:x}", Sha256::digest(&content));
+
+    let dir = scratch_dir("store-crash");
+    let uuids = HashMap::from(["first.bin", "second.bin"].map(|name| (name, seed_pending(&dir, name, content.len()))));
+    let port = free_port();
+    let mut server = spawn_server(dir, &format!("[server]\nbind = \"127.0.0.1:{}\"\n\n[store]\ndedup = true\n", port));
+
+    let response = upload(port, uuids["first.bin"], "first.bin", &content).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    // the registry on disk still has the first upload pending, only its link knows better
+    server.crash();
+    server.restart();
+
+    let response = upload(port, uuids["second.bin"], "second.bin", &content).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    let response = request(tcp(port).await, "DELETE", &format!("/files/{}", uuids["second.bin"]), "", b"").await;
+    assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+
+    let blob = server.dir.join("data/blobs").join(&digest[..2]).join(&digest);
+    assert!(blob.exists(), "blob collected while an upload is still linked to it");
+    assert_eq!(std::fs::read(server.dir.join("data").join(uuids["first.bin"].to_string())).unwrap(), content);
+}
+
+#[tokio::test]
+async fn finished_uploads_are_not_written_again() {
+    let content = vec![3u8; 16 * 1024];
+
+    let dir = scratch_dir("store-resend");
+    let uuids = HashMap::from(["first.bin", "second.bin"].map(|name| (name, seed_pending(&dir, name, content.len()))));
+    let port = free_port();
+    let server = spawn_server(dir, &format!("[server]\nbind = \"127.0.0.1:{}\"\n\n[store]\ndedup = true\n", port));
+
+    for name in ["first.bin", "second.bin"] {
+        let response = upload(port, uuids[name], name, &content).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+
+    // the file of the first upload is the blob the second one reads from
+    let response = upload(port, uuids["first.bin"], "first.bin", &vec![9u8; content.len()]).await;
+    assert!(response.starts_with("HTTP/1.1 409"), "{}", response);
+    assert_eq!(json_body(&response)["code"], "header_mismatch");
+
+    let second = server.dir.join("data").join(uuids["second.bin"].to_string());
+    assert_eq!(std::fs::read(second).unwrap(), content);
+    assert_eq!(listed(port, uuids["first.bin"]).await["state"], "Complete");
+}
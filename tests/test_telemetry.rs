@@ -0,0 +1,74 @@
+mod common;
+
+use std::{io::{BufRead, BufReader}, process::Stdio};
+
+use common::{free_port, json_body, lofty, request, scratch_dir, spawn_server, tcp};
+use uuid::Uuid;
+
+
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+#[tokio::test]
+async fn echoes_the_request_id() {
+    let dir = scratch_dir("telemetry");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    let port = free_port();
+    let _server = spawn_server(dir, &format!("[server]\nbind = \"127.0.0.1:{}\"\n", port));
+
+    let response = request(tcp(port).await, "GET", "/files", "x-request-id: build-1234\r\n", b"").await;
+    assert_eq!(header(&response, "x-request-id"), Some("build-1234"));
+
+    // without one, or with one too long to log, an id is made up
+    let generated = request(tcp(port).await, "GET", "/files", "", b"").await;
+    let generated = header(&generated, "x-request-id").unwrap();
+    assert!(Uuid::parse_str(generated).is_ok(), "{}", generated);
+    let oversized = request(tcp(port).await, "GET", "/files", &format!("x-request-id: {}\r\n", "x".repeat(200)), b"").await;
+    assert!(Uuid::parse_str(header(&oversized, "x-request-id").unwrap()).is_ok(), "{}", oversized);
+
+    // problem documents carry the id as well
+    let response = request(tcp(port).await, "GET", &format!("/files/{}/content", Uuid::new_v4()), "x-request-id: lost-file\r\n", b"").await;
+    assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    assert_eq!(header(&response, "x-request-id"), Some("lost-file"));
+    assert_eq!(json_body(&response)["request_id"], "lost-file");
+}
+
+#[tokio::test]
+async fn logs_request_spans_as_json() {
+    let dir = scratch_dir("telemetry-json");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+    let port = free_port();
+    let settings = format!("data = \"./data\"\n\n[logging]\nformat = \"json\"\nlevel = \"info\"\n\n[server]\nbind = \"127.0.0.1:{}\"\n", port);
+    std::fs::write(dir.join("settings.toml"), settings).unwrap();
+    let mut child = lofty(&dir).stdout(Stdio::piped()).spawn().unwrap();
+
+    let uuid = Uuid::new_v4();
+    let response = request(tcp(port).await, "GET", "/status", &format!("x-request-id: span-check\r\nuuid: {}\r\n", uuid), b"").await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    // the event closing the request is logged before its response is written
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    let finished = loop {
+        let line = lines.next().expect("no request event logged").unwrap();
+        let event: serde_json::Value = serde_json::from_str(&line).unwrap_or_else(|_| panic!("not a json line: {}", line));
+        if event["span"]["request_id"] == "span-check" && event["fields"]["message"] == "request finished" {
+            break event;
+        }
+    };
+    let _ = child.kill();
+    let _ = child.wait();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let span = &finished["span"];
+    assert_eq!(span["name"], "request");
+    assert_eq!(span["method"], "GET");
+    assert_eq!(span["route"], "/status");
+    assert_eq!(span["uuid"], uuid.to_string());
+    assert_eq!(span["status"], 200);
+    assert!(span["principal"].as_str().unwrap().starts_with("anonymous@127.0.0.1"), "{}", span);
+    assert!(finished["fields"]["elapsed_ms"].is_u64(), "{}", finished);
+}
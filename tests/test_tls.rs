@@ -0,0 +1,159 @@
+mod common;
+
+use std::{path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use common::{free_port, scratch_dir, spawn_server, Server};
+use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, DnType, IsCa, KeyPair};
+use rustls::{pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName}, ClientConfig, RootCertStore};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+
+fn self_signed(dir: &Path, name: &str) -> CertificateDer<'static> {
+    let CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    std::fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+    std::fs::write(dir.join(format!("{}.key", name)), key_pair.serialize_pem()).unwrap();
+    cert.der().clone()
+}
+
+// a server terminating TLS with `tls_section`, and the port it listens on
+fn start(dir: PathBuf, tls_section: &str) -> (Server, u16) {
+    let port = free_port();
+    let settings = format!("[server]\nbind = \"127.0.0.1:{}\"\n\n[tls]\nenabled = true\n{}\n", port, tls_section);
+    (spawn_server(dir, &settings), port)
+}
+
+fn client_config(root: CertificateDer<'static>, identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>, alpn: &[&[u8]]) -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    roots.add(root).unwrap();
+
+    let builder = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+
+    let mut config = match identity {
+        Some((chain, key)) => builder.with_client_auth_cert(chain, key).unwrap(),
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = alpn.iter().map(|proto| proto.to_vec()).collect();
+    config
+}
+
+async fn connect(port: u16, config: ClientConfig) -> std::io::Result<TlsStream<TcpStream>> {
+    let connector = TlsConnector::from(Arc::new(config));
+    let tcp = TcpStream::connect(("127.0.0.1", port)).await?;
+    connector.connect(ServerName::try_from("localhost").unwrap(), tcp).await
+}
+
+// keep retrying while the server binary is still starting up
+async fn connect_retrying(port: u16, config: ClientConfig) -> TlsStream<TcpStream> {
+    for _ in 0..100 {
+        if let Ok(stream) = connect(port, config.clone()).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("server never accepted a tls connection");
+}
+
+async fn get(stream: &mut TlsStream<TcpStream>, path: &str, headers: &str) -> std::io::Result<String> {
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n", path, headers);
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    Ok(response)
+}
+
+#[tokio::test]
+async fn negotiates_h2_and_http1_over_tls() {
+    let dir = scratch_dir("tls-alpn");
+    let root = self_signed(&dir, "server");
+    let (_server, port) = start(dir, "cert = \"server.pem\"\nkey = \"server.key\"");
+
+    let stream = connect_retrying(port, client_config(root.clone(), None, &[b"h2"])).await;
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+    let mut stream = connect_retrying(port, client_config(root, None, &[b"http/1.1"])).await;
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+
+    let response = get(&mut stream, "/metrics", "").await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+}
+
+#[tokio::test]
+async fn reloads_certificates_without_restart() {
+    let dir = scratch_dir("tls-reload");
+    let first = self_signed(&dir, "server");
+    let (_server, port) = start(dir.clone(), "cert = \"server.pem\"\nkey = \"server.key\"\nreload_interval = 1");
+
+    connect_retrying(port, client_config(first.clone(), None, &[b"http/1.1"])).await;
+
+    // make sure the modification time moves even on coarse grained filesystems
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let second = self_signed(&dir, "server");
+
+    let mut reloaded = false;
+    for _ in 0..50 {
+        if connect(port, client_config(second.clone(), None, &[b"http/1.1"])).await.is_ok() {
+            reloaded = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(reloaded, "server kept presenting the old certificate");
+
+    assert!(connect(port, client_config(first, None, &[b"http/1.1"])).await.is_err());
+}
+
+#[tokio::test]
+async fn maps_client_certificates_to_principals() {
+    let dir = scratch_dir("tls-mtls");
+    let root = self_signed(&dir, "server");
+
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(vec![]).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params.distinguished_name.push(DnType::CommonName, "lofty test ca");
+    let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+    std::fs::write(dir.join("ca.pem"), ca_cert.pem()).unwrap();
+
+    let client_key = KeyPair::generate().unwrap();
+    let mut client_params = CertificateParams::new(vec![]).unwrap();
+    client_params.distinguished_name.push(DnType::CommonName, "ingest-bot");
+    let client_cert = client_params.signed_by(&client_key, &ca_cert, &ca_key).unwrap();
+
+    let (_server, port) = start(
+        dir.clone(),
+        "cert = \"server.pem\"\nkey = \"server.key\"\nclient_ca = \"ca.pem\"\nclient_auth = \"required\"",
+    );
+
+    let identity = (
+        vec![client_cert.der().clone()],
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(client_key.serialize_der())),
+    );
+    let mut stream = connect_retrying(port, client_config(root.clone(), Some(identity), &[b"http/1.1"])).await;
+
+    let uuid = uuid::Uuid::new_v4();
+    let response = get(&mut stream, "/status", &format!("uuid: {}\r\n", uuid)).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    // the audit trail records who asked, under the principal derived from the certificate
+    let mut audited = String::new();
+    for _ in 0..50 {
+        audited = std::fs::read_to_string(dir.join("data/audit/audit.log")).unwrap_or_default();
+        if audited.contains(&uuid.to_string()) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(audited.contains("\"principal\":\"cn:ingest-bot\""), "{}", audited);
+
+    // without a certificate the server refuses to serve anything
+    let anonymous = match connect(port, client_config(root, None, &[b"http/1.1"])).await {
+        Ok(mut stream) => get(&mut stream, "/metrics", "").await.unwrap_or_default(),
+        Err(_) => String::new(),
+    };
+    assert!(!anonymous.starts_with("HTTP/1.1 200"));
+}
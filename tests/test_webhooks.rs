@@ -0,0 +1,181 @@
+mod common;
+
+use std::{net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+
+use axum::{http::{HeaderMap, StatusCode}, routing, Extension, Router};
+use common::{free_port, get, json_body, scratch_dir, spawn_server, tcp, Server};
+use lofty_client::{Client, Source};
+use ring::hmac;
+use uuid::Uuid;
+
+
+const SECRET: &str = "webhook-test-secret";
+
+#[derive(Debug, Clone)]
+struct Received {
+    event: String,
+    payload: serde_json::Value,
+}
+
+// A stand-in receiver, answers 500 to the first `failures` requests and records the rest
+#[derive(Clone, Default)]
+struct Receiver {
+    received: Arc<Mutex<Vec<Received>>>,
+    failures: Arc<Mutex<usize>>,
+}
+
+impl Receiver {
+    async fn listen(&self, port: u16) {
+        let app = Router::new()
+            .route("/hook", routing::post(receive))
+            .layer(Extension(self.clone()));
+
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
+        });
+    }
+
+    fn events(&self, upload: Uuid) -> Vec<Received> {
+        let received = self.received.lock().unwrap();
+        received.iter().filter(|received| received.payload["file"]["uuid"] == upload.to_string()).cloned().collect()
+    }
+
+    // wait until `count` events of `upload` arrived
+    async fn wait_for(&self, upload: Uuid, count: usize) -> Vec<Received> {
+        for _ in 0..200 {
+            let events = self.events(upload);
+            if events.len() >= count {
+                return events;
+            }
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+        panic!("expected {} events for {}, got {:?}", count, upload, self.events(upload));
+    }
+}
+
+async fn receive(Extension(receiver): Extension<Receiver>, headers: HeaderMap, body: String) -> StatusCode {
+    {
+        let mut failures = receiver.failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+
+    // t=<timestamp>,v1=<hex hmac of "<timestamp>.<body>">
+    let signature = headers.get("lofty-signature").unwrap().to_str().unwrap();
+    let (timestamp, tag) = signature.split_once(",v1=").unwrap();
+    let timestamp = timestamp.strip_prefix("t=").unwrap();
+    let key = hmac::Key::new(hmac::HMAC_SHA256, SECRET.as_bytes());
+    hmac::verify(&key, format!("{}.{}", timestamp, body).as_bytes(), &hex::decode(tag).unwrap()).expect("signature mismatch");
+
+    let event = headers.get("lofty-event").unwrap().to_str().unwrap().to_string();
+    let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(payload["event"], event);
+    receiver.received.lock().unwrap().push(Received { event, payload });
+    StatusCode::NO_CONTENT
+}
+
+async fn start(hook_port: u16) -> (Server, Client, u16) {
+    let dir = scratch_dir("webhooks");
+    std::fs::create_dir_all(dir.join("data")).unwrap();
+
+    let port = free_port();
+    let settings = format!(
+        "[server]\nbind = \"127.0.0.1:{}\"\n\n[webhooks]\nsecret = \"{}\"\ninitial_backoff_ms = 50\nmax_backoff_ms = 200\n\n[[webhooks.endpoints]]\nurl = \"http://127.0.0.1:{}/hook\"\n",
+        port, SECRET, hook_port,
+    );
+    let server = spawn_server(dir, &settings);
+    drop(tcp(port).await);
+
+    (server, Client::new(format!("http://127.0.0.1:{}", port)).unwrap(), port)
+}
+
+fn sorted(events: &[Received]) -> Vec<String> {
+    let mut events: Vec<_> = events.iter().map(|received| received.event.clone()).collect();
+    events.sort();
+    events
+}
+
+#[tokio::test]
+async fn reports_the_lifecycle_of_uploads() {
+    let hook_port = free_port();
+    let receiver = Receiver::default();
+    receiver.listen(hook_port).await;
+    let (_server, client, _) = start(hook_port).await;
+
+    let uuid = client.upload_file(&Source::bytes(vec![b'x'; 4000]).with_name("four.txt")).await.unwrap();
+    let events = receiver.wait_for(uuid, 5).await;
+    assert_eq!(sorted(&events), ["completed", "created", "progress", "progress", "progress"]);
+
+    let mut milestones: Vec<_> = events.iter().filter_map(|received| received.payload["progress"].as_u64()).collect();
+    milestones.sort();
+    assert_eq!(milestones, [25, 50, 75]);
+
+    let completed = events.iter().find(|received| received.event == "completed").unwrap();
+    assert_eq!(completed.payload["file"]["name"], "four.txt");
+    assert_eq!(completed.payload["file"]["file_size"], 4000);
+
+    // an upload removed before it finished is reported as cancelled
+    let scheduled = client.schedule(&"ab".repeat(32), 100).await.unwrap();
+    client.delete(scheduled.uuid).await.unwrap();
+    let events = receiver.wait_for(scheduled.uuid, 2).await;
+    assert_eq!(sorted(&events), ["cancelled", "created"]);
+}
+
+#[tokio::test]
+async fn retries_failed_deliveries_and_logs_them() {
+    let hook_port = free_port();
+    let receiver = Receiver::default();
+    *receiver.failures.lock().unwrap() = 2;
+    receiver.listen(hook_port).await;
+    let (_server, client, port) = start(hook_port).await;
+
+    // the first two attempts of the `created` delivery are turned down
+    let scheduled = client.schedule(&"cd".repeat(32), 100).await.unwrap();
+    let events = receiver.wait_for(scheduled.uuid, 1).await;
+    assert_eq!(events[0].event, "created");
+
+    let path = format!("/admin/webhooks/deliveries?upload={}&event=created", scheduled.uuid);
+    let log = json_body(&get(tcp(port).await, &path).await);
+    let outcomes: Vec<_> = log.as_array().unwrap().iter().map(|record| (record["attempt"].clone(), record["outcome"].clone())).collect();
+    assert_eq!(outcomes, [(1.into(), "retrying".into()), (2.into(), "retrying".into()), (3.into(), "delivered".into())]);
+    assert_eq!(log[0]["status"], 500);
+
+    let delivered = json_body(&get(tcp(port).await, "/admin/webhooks/deliveries?outcome=delivered").await);
+    assert_eq!(delivered.as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn delivers_the_outbox_after_a_restart() {
+    // nothing listens on the endpoint yet, deliveries pile up in the outbox
+    let hook_port = free_port();
+    let (mut server, client, _) = start(hook_port).await;
+
+    let scheduled = client.schedule(&"ef".repeat(32), 100).await.unwrap();
+    let outbox = server.dir.join("data/webhooks/outbox");
+    for _ in 0..100 {
+        if std::fs::read_dir(&outbox).unwrap().count() > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    server.stop();
+    assert_eq!(std::fs::read_dir(&outbox).unwrap().count(), 1);
+
+    let receiver = Receiver::default();
+    receiver.listen(hook_port).await;
+    server.restart();
+
+    let events = receiver.wait_for(scheduled.uuid, 1).await;
+    assert_eq!(events[0].event, "created");
+
+    for _ in 0..100 {
+        if std::fs::read_dir(&outbox).unwrap().count() == 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("delivered event still in the outbox");
+}